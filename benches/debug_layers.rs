@@ -0,0 +1,36 @@
+// Frames per second with the PPU's debug layer capture off and on. Run with
+// cargo bench --bench debug_layers
+use gb_emulator::Gameboy;
+
+use std::time::Instant;
+
+const FRAMES: u32 = 600;
+
+fn fps(rom: &[u8], debug_layers: bool) -> f64 {
+    let mut gameboy = Gameboy::new(rom).unwrap();
+    gameboy.cpu.bus.ppu.debug_layers = debug_layers;
+    // Past the boot screens so there's something on all the layers
+    for _ in 0..300 {
+        gameboy.run_frame();
+    }
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        gameboy.run_frame();
+    }
+    FRAMES as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let rom = std::fs::read("roms/tetris.gb").expect("roms/tetris.gb is needed for the benchmark");
+    // Best of a few runs, alternating so both see the same machine load
+    let (mut off, mut on) = (0.0f64, 0.0f64);
+    for _ in 0..3 {
+        off = off.max(fps(&rom, false));
+        on = on.max(fps(&rom, true));
+    }
+    println!("debug layers off: {off:.0} fps");
+    println!(
+        "debug layers on:  {on:.0} fps ({:+.1}%)",
+        100.0 * (on / off - 1.0)
+    );
+}
@@ -1,67 +1,149 @@
-const AUDIO_LENGTH: usize = 800;
+use bitflags::bitflags;
+use std::collections::VecDeque;
+
+use crate::resampler::Resampler;
+use crate::state::{State, StateError, StateReader, StateWriter};
+
+// Samples of each channel kept for the oscilloscope plots
+pub const SCOPE_LENGTH: usize = 1024;
+// Output rate until the frontend sets the device's
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
 
 pub struct Apu {
     pub square1: SquareChannel,
     pub square2: SquareChannel,
     pub wave: WaveChannel,
     pub noise: NoiseChannel,
-    frame_seq_cycles: usize,
     pub frame: u8,
-    output_cycles: usize,
+    pub resampler: Resampler,
+    pub samples: Vec<f32>, // Interleaved stereo at the device rate, taken by Bus once a frame
     audio_on: bool,
     sound_panning: u8,
     volume: u8,
+    capacitor: (f32, f32), // High-pass filter charge for (left, right)
+    charge_factor: f32,    // Charge the capacitor keeps per output sample
+    // CGB clears the length counters on power off and blocks writes to them while off
+    cgb: bool,
 
     // GUI
-    pub square1_output: [f32; AUDIO_LENGTH],
-    pub square2_output: [f32; AUDIO_LENGTH],
-    pub wave_output: [f32; AUDIO_LENGTH],
-    pub noise_output: [f32; AUDIO_LENGTH],
-    output_index: usize,
-    pub audio_select: AudioSelect,
+    pub square1_output: VecDeque<f32>,
+    pub square2_output: VecDeque<f32>,
+    pub wave_output: VecDeque<f32>,
+    pub noise_output: VecDeque<f32>,
+    pub record_scope: bool, // Only fill the buffers above while something is plotting them
+    pub enabled_channels: Channels, // Channels that make it into the mix
+}
+
+// GUI buffers and the channel mask are not part of the state
+impl State for Apu {
+    fn save(&self, w: &mut StateWriter) {
+        self.square1.save(w);
+        self.square2.save(w);
+        self.wave.save(w);
+        self.noise.save(w);
+        self.frame.save(w);
+        self.resampler.save(w);
+        self.samples.save(w);
+        self.audio_on.save(w);
+        self.sound_panning.save(w);
+        self.volume.save(w);
+        self.capacitor.0.save(w);
+        self.capacitor.1.save(w);
+        self.cgb.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.square1.load(r)?;
+        self.square2.load(r)?;
+        self.wave.load(r)?;
+        self.noise.load(r)?;
+        self.frame.load(r)?;
+        self.resampler.load(r)?;
+        self.samples.load(r)?;
+        self.audio_on.load(r)?;
+        self.sound_panning.load(r)?;
+        self.volume.load(r)?;
+        self.capacitor.0.load(r)?;
+        self.capacitor.1.load(r)?;
+        self.cgb.load(r)?;
+        Ok(())
+    }
+}
+
+// Hardware capacitor keeps 0.999958 of its charge per T-cycle
+fn charge_factor(sample_rate: u32) -> f32 {
+    0.999958f32.powf(4_194_304.0 / sample_rate as f32)
 }
 
 impl Apu {
-    pub fn new() -> Self {
+    pub fn new(cgb: bool) -> Self {
         Self {
             square1: SquareChannel::new(true),
             square2: SquareChannel::new(false),
-            wave: WaveChannel::new(),
+            wave: WaveChannel::new(cgb),
             noise: NoiseChannel::new(),
-            frame_seq_cycles: 0,
             frame: 0,
-            output_cycles: 0,
+            resampler: Resampler::new(DEFAULT_SAMPLE_RATE),
+            samples: Vec::with_capacity(2 * DEFAULT_SAMPLE_RATE as usize / 50),
             audio_on: false,
-            sound_panning: 0,
-            volume: 0,
+            // Values the boot ROM leaves in NR51 and NR50
+            sound_panning: 0xf3,
+            volume: 0x77,
+            capacitor: (0.0, 0.0),
+            charge_factor: charge_factor(DEFAULT_SAMPLE_RATE),
+            cgb,
 
             // GUI
-            square1_output: [0.0; AUDIO_LENGTH],
-            square2_output: [0.0; AUDIO_LENGTH],
-            wave_output: [0.0; AUDIO_LENGTH],
-            noise_output: [0.0; AUDIO_LENGTH],
-            output_index: 0,
-            audio_select: AudioSelect::All,
+            square1_output: VecDeque::with_capacity(SCOPE_LENGTH),
+            square2_output: VecDeque::with_capacity(SCOPE_LENGTH),
+            wave_output: VecDeque::with_capacity(SCOPE_LENGTH),
+            noise_output: VecDeque::with_capacity(SCOPE_LENGTH),
+            record_scope: false,
+            enabled_channels: Channels::all(),
         }
     }
 
-    pub fn tick(&mut self) -> Option<f32> {
+    // Mute or unmute channels in the mix. They keep running and NR52 still shows them on
+    pub fn set_muted(&mut self, channels: Channels, muted: bool) {
+        self.enabled_channels.set(channels, !muted);
+    }
+
+    // Play only these channels
+    pub fn solo(&mut self, channels: Channels) {
+        self.enabled_channels = channels;
+    }
+
+    // Resample to the device rate. Defaults to DEFAULT_SAMPLE_RATE
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(sample_rate);
+        self.charge_factor = charge_factor(sample_rate);
+    }
+
+    // Called once per M-cycle. Output samples collect in samples
+    pub fn tick(&mut self) {
         self.square1.tick();
         self.square2.tick();
         self.wave.tick();
         self.wave.tick();
         self.noise.tick();
-        self.frame_cycle();
-        self.output_cycles += 1;
-        if self.output_cycles == 23 {
-            self.output_cycles = 0;
-            Some(self.output())
-        } else {
-            None
+        // Only the ticks either side of an output sample are mixed
+        if !self.resampler.tick() {
+            return;
+        }
+        let channels = self.channel_outputs();
+        if let Some((left, right)) = self.resampler.push(self.mix(channels)) {
+            if self.record_scope {
+                self.push_scope(channels);
+            }
+            let left = self.high_pass(left, true);
+            let right = self.high_pass(right, false);
+            // Scaled down to the level the frontends have always played at
+            self.samples.extend([left / 10.0, right / 10.0]);
         }
     }
 
-    pub fn output(&mut self) -> f32 {
+    // Each channel's DAC output, 0 for ones that are off
+    fn channel_outputs(&self) -> [f32; 4] {
         let mut s1 = 0.0;
         let mut s2 = 0.0;
         let mut wave = 0.0;
@@ -78,21 +160,64 @@ impl Apu {
         if self.noise.dac_on && self.audio_on {
             noise = self.noise.output();
         }
+        [s1, s2, wave, noise]
+    }
 
-        self.square1_output[self.output_index] = s1;
-        self.square2_output[self.output_index] = s2;
-        self.wave_output[self.output_index] = wave;
-        self.noise_output[self.output_index] = noise;
-        self.output_index += 1;
-        self.output_index %= AUDIO_LENGTH;
-
-        match self.audio_select {
-            AudioSelect::All => (s1 + s2 + noise + wave) / 4.0,
-            AudioSelect::SquareOne => s1 / 4.0,
-            AudioSelect::SquareTwo => s2 / 4.0,
-            AudioSelect::Noise => noise / 4.0,
-            AudioSelect::Wave => wave / 4.0,
+    fn push_scope(&mut self, channels: [f32; 4]) {
+        let [s1, s2, wave, noise] = channels;
+        for (buffer, sample) in [
+            (&mut self.square1_output, s1),
+            (&mut self.square2_output, s2),
+            (&mut self.wave_output, wave),
+            (&mut self.noise_output, noise),
+        ] {
+            if buffer.len() == SCOPE_LENGTH {
+                buffer.pop_front();
+            }
+            buffer.push_back(sample);
+        }
+    }
+
+    // Pan and scale the channels into a (left, right) sample, before the high-pass filter
+    fn mix(&self, channels: [f32; 4]) -> (f32, f32) {
+        // NR51: lower nibble sends channels 1-4 to the right, upper nibble to the left.
+        // Channels bits are in the same order
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, amp) in channels.into_iter().enumerate() {
+            if !self
+                .enabled_channels
+                .contains(Channels::from_bits_retain(1 << i))
+            {
+                continue;
+            }
+            if self.sound_panning & (0x10 << i) > 0 {
+                left += amp;
+            }
+            if self.sound_panning & (0x01 << i) > 0 {
+                right += amp;
+            }
         }
+
+        // NR50: master volume 0-7 per side, applied as (vol + 1) / 8
+        let left_volume = ((self.volume >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (self.volume & 0x07) as f32 + 1.0;
+        (
+            left / 4.0 * left_volume / 8.0,
+            right / 4.0 * right_volume / 8.0,
+        )
+    }
+
+    // Removes the DC offset of the DACs so the output is centered on 0
+    fn high_pass(&mut self, input: f32, left: bool) -> f32 {
+        let capacitor = if left {
+            &mut self.capacitor.0
+        } else {
+            &mut self.capacitor.1
+        };
+        let output = input - *capacitor;
+        *capacitor = input - output * self.charge_factor;
+        output
     }
 
     // 0xFF24 NR50
@@ -117,11 +242,16 @@ impl Apu {
         self.sound_panning
     }
 
+    // NR11, NR21, NR31 and NR41 length writes still land while powered off on DMG
+    pub fn length_writable(&self) -> bool {
+        self.audio_on || !self.cgb
+    }
+
     // 0xFF26 NR52
     pub fn master_control_write(&mut self, val: u8) {
         let prev_on = self.audio_on;
         self.audio_on = val & 0b1000_0000 > 0;
-        // Powering Off
+        // Powering Off. Every register but the length counters is cleared
         if !self.audio_on {
             self.square1.power_down();
             self.square2.power_down();
@@ -129,9 +259,15 @@ impl Apu {
             self.noise.power_down();
             self.sound_panning = 0;
             self.volume = 0;
+            if self.cgb {
+                self.square1.length_counter.set(0);
+                self.square2.length_counter.set(0);
+                self.wave.length_counter.set(0);
+                self.noise.length_counter.set(0);
+            }
         }
 
-        // Powering On
+        // Powering On. The next frame sequencer step is 0
         if !prev_on && self.audio_on {
             self.frame = 7;
             self.square1.duty_step = 0;
@@ -154,10 +290,10 @@ impl Apu {
         (audio_on | chnl4 | chnl3 | chnl2 | chnl1) | 0x70
     }
 
-    fn frame_cycle(&mut self) {
-        self.frame_seq_cycles += 1;
-        if self.frame_seq_cycles == 2047 {
-            self.frame_seq_cycles = 0;
+    // Frame sequencer step. Clocked by the Bus on each falling edge of DIV bit 4 (512 Hz),
+    // so writing DIV can clock it early. Doesn't run while the APU is off
+    pub fn frame_cycle_external(&mut self) {
+        if self.audio_on {
             self.frame += 1;
             self.frame %= 8;
 
@@ -184,7 +320,7 @@ impl Apu {
                 _ => {}
             }
 
-            if self.frame % 2 == 0 {
+            if self.frame.is_multiple_of(2) {
                 self.square1.length_counter.next_frame_no_clock = true;
                 self.square2.length_counter.next_frame_no_clock = true;
                 self.wave.length_counter.next_frame_no_clock = true;
@@ -208,6 +344,25 @@ struct Envelope {
     counter: u8,
 }
 
+impl State for Envelope {
+    fn save(&self, w: &mut StateWriter) {
+        self.init_vol.save(w);
+        self.volume.save(w);
+        self.mode.save(w);
+        self.period.save(w);
+        self.counter.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.init_vol.load(r)?;
+        self.volume.load(r)?;
+        self.mode.load(r)?;
+        self.period.load(r)?;
+        self.counter.load(r)?;
+        Ok(())
+    }
+}
+
 impl Envelope {
     fn new() -> Self {
         Self {
@@ -224,6 +379,13 @@ impl Envelope {
         self.volume = vol;
     }
 
+    // Reload the timer and restart from the initial volume. Period 0 reloads as 8 like
+    // hardware, though tick still leaves the volume alone
+    fn trigger(&mut self) {
+        self.counter = if self.period == 0 { 8 } else { self.period };
+        self.volume = self.init_vol;
+    }
+
     fn read(&self) -> u8 {
         let vol = self.init_vol << 4;
         let dir = (self.mode as u8) << 3;
@@ -254,23 +416,49 @@ impl Envelope {
 struct LengthCounter {
     enabled: bool,
     counter: u16,
-    reset_val: u16,
+    max: u16, // 64, or 256 for the wave channel
     next_frame_no_clock: bool,
 }
 
+impl State for LengthCounter {
+    fn save(&self, w: &mut StateWriter) {
+        self.enabled.save(w);
+        self.counter.save(w);
+        self.next_frame_no_clock.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled.load(r)?;
+        self.counter.load(r)?;
+        self.next_frame_no_clock.load(r)?;
+        Ok(())
+    }
+}
+
 impl LengthCounter {
-    fn new() -> Self {
+    fn new(max: u16) -> Self {
         Self {
             enabled: false,
             counter: 0,
-            reset_val: 0,
+            max,
             next_frame_no_clock: false,
         }
     }
 
     fn set(&mut self, val: u16) {
         self.counter = val;
-        self.reset_val = val;
+    }
+
+    // An expired counter reloads to the max. If the next frame sequencer step won't clock
+    // length and the counter is enabled it loses one straight away
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = if self.next_frame_no_clock && self.enabled {
+                self.max - 1
+            } else {
+                self.max
+            };
+        }
     }
 
     fn enable(&mut self, enabled: bool) -> bool {
@@ -296,6 +484,29 @@ struct Sweep {
     neg_calc_made: bool,
 }
 
+impl State for Sweep {
+    fn save(&self, w: &mut StateWriter) {
+        self.enabled.save(w);
+        self.period.save(w);
+        self.shadow_freq.save(w);
+        self.direction.save(w);
+        self.shift.save(w);
+        self.counter.save(w);
+        self.neg_calc_made.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled.load(r)?;
+        self.period.load(r)?;
+        self.shadow_freq.load(r)?;
+        self.direction.load(r)?;
+        self.shift.load(r)?;
+        self.counter.load(r)?;
+        self.neg_calc_made.load(r)?;
+        Ok(())
+    }
+}
+
 impl Sweep {
     fn new() -> Self {
         Self {
@@ -332,6 +543,35 @@ pub struct SquareChannel {
     length_counter: LengthCounter,
 }
 
+impl State for SquareChannel {
+    fn save(&self, w: &mut StateWriter) {
+        self.power_on.save(w);
+        self.enabled.save(w);
+        self.dac_on.save(w);
+        self.sweep.save(w);
+        self.wave_pattern.save(w);
+        self.duty_step.save(w);
+        self.period.save(w);
+        self.period_divider.save(w);
+        self.envelope.save(w);
+        self.length_counter.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.power_on.load(r)?;
+        self.enabled.load(r)?;
+        self.dac_on.load(r)?;
+        self.sweep.load(r)?;
+        self.wave_pattern.load(r)?;
+        self.duty_step.load(r)?;
+        self.period.load(r)?;
+        self.period_divider.load(r)?;
+        self.envelope.load(r)?;
+        self.length_counter.load(r)?;
+        Ok(())
+    }
+}
+
 impl SquareChannel {
     const WAVEFORM: [[u8; 8]; 4] = [
         [0, 0, 0, 0, 0, 0, 0, 1],
@@ -352,7 +592,7 @@ impl SquareChannel {
             period: 0,
             period_divider: 0,
             envelope: Envelope::new(),
-            length_counter: LengthCounter::new(),
+            length_counter: LengthCounter::new(64),
         }
     }
 
@@ -369,17 +609,11 @@ impl SquareChannel {
 
     fn trigger(&mut self) {
         self.enabled = self.dac_on;
-        if self.length_counter.counter == 0
-            && self.length_counter.next_frame_no_clock
-            && self.length_counter.enabled
-        {
-            self.length_counter.counter = 63;
-        } else if self.length_counter.counter == 0 {
-            self.length_counter.counter = 64;
-        }
-        self.period_divider = self.period;
-        self.envelope.counter = self.envelope.period;
-        self.envelope.volume = self.envelope.init_vol;
+        self.length_counter.trigger();
+        // The timer reloads 4 T-cycles (1 tick) late. duty_step keeps its position,
+        // only powering the APU off resets it
+        self.period_divider = self.period.saturating_sub(1);
+        self.envelope.trigger();
         if self.sweep_enabled {
             self.sweep.neg_calc_made = false;
             self.sweep.shadow_freq = self.period;
@@ -559,15 +793,52 @@ pub struct WaveChannel {
     sample: u8,
     position: usize,
     recent_access_cycles: u8,
+    // Retriggering while playing corrupts wave RAM on DMG only
+    cgb: bool,
+}
+
+impl State for WaveChannel {
+    fn save(&self, w: &mut StateWriter) {
+        self.power_on.save(w);
+        self.enabled.save(w);
+        self.dac_on.save(w);
+        self.length_counter.save(w);
+        self.volume.save(w);
+        self.output_level.save(w);
+        self.period.save(w);
+        self.period_divider.save(w);
+        self.wave_ram.save(w);
+        self.sample.save(w);
+        self.position.save(w);
+        self.recent_access_cycles.save(w);
+        self.cgb.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.power_on.load(r)?;
+        self.enabled.load(r)?;
+        self.dac_on.load(r)?;
+        self.length_counter.load(r)?;
+        self.volume.load(r)?;
+        self.output_level.load(r)?;
+        self.period.load(r)?;
+        self.period_divider.load(r)?;
+        self.wave_ram.load(r)?;
+        self.sample.load(r)?;
+        self.position.load(r)?;
+        self.recent_access_cycles.load(r)?;
+        self.cgb.load(r)?;
+        Ok(())
+    }
 }
 
 impl WaveChannel {
-    pub fn new() -> Self {
+    pub fn new(cgb: bool) -> Self {
         Self {
             power_on: false,
             enabled: false,
             dac_on: false,
-            length_counter: LengthCounter::new(),
+            length_counter: LengthCounter::new(256),
             volume: 0,
             output_level: 0,
             period: 0,
@@ -586,6 +857,7 @@ impl WaveChannel {
             sample: 0,
             position: 0,
             recent_access_cycles: 0,
+            cgb,
         }
     }
 
@@ -597,17 +869,24 @@ impl WaveChannel {
     }
 
     fn trigger(&mut self) {
-        self.enabled = self.dac_on;
-        if self.length_counter.counter == 0
-            && self.length_counter.next_frame_no_clock
-            && self.length_counter.enabled
-        {
-            self.length_counter.counter = 255;
-        } else if self.length_counter.counter == 0 {
-            self.length_counter.counter = 256;
+        // On DMG, retriggering just as the next byte is fetched overwrites the start of wave RAM
+        // with that byte, or with its aligned 4 byte group if it is past the first 4 bytes
+        if !self.cgb && self.enabled && self.period_divider == 0x7ff {
+            let index = ((self.position + 1) % 32) / 2;
+            if index < 4 {
+                self.wave_ram[0] = self.wave_ram[index];
+            } else {
+                let group = index & !3;
+                self.wave_ram.copy_within(group..group + 4, 0);
+            }
         }
+
+        self.enabled = self.dac_on;
+        self.length_counter.trigger();
         self.volume = self.output_level;
-        self.period_divider = self.period;
+        // The first fetch is delayed by 6 T-cycles (3 ticks) and the sample buffer is
+        // not refilled, so the previous sample plays until then
+        self.period_divider = self.period.saturating_sub(3);
         self.position = 0;
     }
 
@@ -685,17 +964,26 @@ impl WaveChannel {
         if !self.enabled {
             let offset = (addr - 0xff30) as usize;
             self.wave_ram[offset] = val;
-        } else if self.recent_access_cycles > 0 {
+        } else if self.recent_access_cycles > 0 || self.cgb {
+            // Only reaches the byte being fetched (any time on CGB), which also
+            // lands in the sample buffer
             self.wave_ram[self.position / 2] = val;
+            self.sample = val;
         }
     }
 
-    pub fn wave_ram_read(&mut self, addr: u16) -> u8 {
+    // The 32 4-bit samples in playing order, high nibble of each byte first. For the debugger,
+    // so it reads the RAM directly whether or not the channel is playing
+    pub fn wave_samples(&self) -> [u8; 32] {
+        std::array::from_fn(|i| (self.wave_ram[i / 2] >> (4 * (1 - i % 2))) & 0x0f)
+    }
+
+    pub fn wave_ram_read(&self, addr: u16) -> u8 {
         //println!("Wave RAM read. Position: {}", self.position);
         if !self.enabled {
             let offset = (addr - 0xff30) as usize;
             self.wave_ram[offset]
-        } else if self.recent_access_cycles > 0 {
+        } else if self.recent_access_cycles > 0 || self.cgb {
             self.sample
         } else {
             0xff
@@ -730,7 +1018,7 @@ impl WaveChannel {
     }
 
     fn output(&self) -> f32 {
-        let sample = if self.position % 2 == 0 {
+        let sample = if self.position.is_multiple_of(2) {
             (self.sample & 0xf0) >> 4
         } else {
             self.sample & 0x0f
@@ -765,13 +1053,42 @@ pub struct NoiseChannel {
     timer: usize,
 }
 
+impl State for NoiseChannel {
+    fn save(&self, w: &mut StateWriter) {
+        self.power_on.save(w);
+        self.enabled.save(w);
+        self.dac_on.save(w);
+        self.length_counter.save(w);
+        self.envelope.save(w);
+        self.clock_shift.save(w);
+        self.lfsr_width.save(w);
+        self.lfsr.save(w);
+        self.clock_divider.save(w);
+        self.timer.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.power_on.load(r)?;
+        self.enabled.load(r)?;
+        self.dac_on.load(r)?;
+        self.length_counter.load(r)?;
+        self.envelope.load(r)?;
+        self.clock_shift.load(r)?;
+        self.lfsr_width.load(r)?;
+        self.lfsr.load(r)?;
+        self.clock_divider.load(r)?;
+        self.timer.load(r)?;
+        Ok(())
+    }
+}
+
 impl NoiseChannel {
     pub fn new() -> Self {
         Self {
             power_on: false,
             enabled: false,
             dac_on: false,
-            length_counter: LengthCounter::new(),
+            length_counter: LengthCounter::new(64),
             envelope: Envelope::new(),
             clock_shift: 0,
             lfsr_width: false,
@@ -798,17 +1115,10 @@ impl NoiseChannel {
 
     fn trigger(&mut self) {
         self.enabled = self.dac_on;
-        if self.length_counter.counter == 0
-            && self.length_counter.next_frame_no_clock
-            && self.length_counter.enabled
-        {
-            self.length_counter.counter = 63;
-        } else if self.length_counter.counter == 0 {
-            self.length_counter.counter = 64;
-        }
-        self.envelope.counter = self.envelope.period;
-        self.envelope.volume = self.envelope.init_vol;
-        self.lfsr = 0x7ff;
+        self.length_counter.trigger();
+        self.envelope.trigger();
+        // All 15 bits set. Equivalent to hardware clearing the LFSR and using XNOR taps
+        self.lfsr = 0x7fff;
     }
 
     fn tick(&mut self) {
@@ -912,11 +1222,336 @@ impl NoiseChannel {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum AudioSelect {
-    All,
-    SquareOne,
-    SquareTwo,
-    Noise,
-    Wave,
+bitflags! {
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub struct Channels: u8 {
+        const square1 = 0b0001;
+        const square2 = 0b0010;
+        const wave = 0b0100;
+        const noise = 0b1000;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_on() -> Apu {
+        let mut apu = Apu::new(false);
+        apu.master_control_write(0x80);
+        apu
+    }
+
+    #[test]
+    fn test_nr50_scales_output() {
+        let mut apu = powered_on();
+        apu.sound_panning_write(0x11);
+        let channels = [1.0, 0.0, 0.0, 0.0];
+        apu.volume_write(0x77);
+        assert_eq!(apu.mix(channels), (0.25, 0.25));
+        // Left at 3, right at 0: (3 + 1) / 8 and (0 + 1) / 8 of full volume
+        apu.volume_write(0x30);
+        assert_eq!(apu.mix(channels), (0.125, 0.25 / 8.0));
+    }
+
+    #[test]
+    fn test_high_pass_decays_to_zero() {
+        let mut apu = powered_on();
+        let output: Vec<_> = (0..DEFAULT_SAMPLE_RATE)
+            .map(|_| apu.high_pass(1.0, true))
+            .collect();
+        assert_eq!(output[0], 1.0);
+        assert!(output.windows(2).all(|pair| pair[1] <= pair[0]));
+        // About 0.996 a sample at 44.1 kHz, so down to a fifth after 10 ms
+        assert!((0.1..0.25).contains(&output[441]), "{}", output[441]);
+        assert!(output[output.len() - 1].abs() < 1e-3);
+        // The other side has its own capacitor
+        assert_eq!(apu.high_pass(1.0, false), 1.0);
+    }
+
+    // Wave RAM holds 0x00, 0x11 .. 0xFF and the channel plays it, stepping every 256 ticks
+    fn playing_wave(cgb: bool) -> Apu {
+        let mut apu = Apu::new(cgb);
+        apu.master_control_write(0x80);
+        for i in 0..16 {
+            apu.wave.wave_ram_write(0xff30 + i, i as u8 * 0x11);
+        }
+        apu.wave.dac_enable_write(0x80);
+        apu.wave.output_level_write(0x20);
+        apu.wave.period_low_write(0x00);
+        apu.wave.control_write(0x87);
+        apu
+    }
+
+    // Run until the next tick fetches the sample after position
+    fn tick_to_fetch(wave: &mut WaveChannel, position: usize) {
+        while wave.position != position || wave.period_divider != 0x7ff {
+            wave.tick();
+        }
+    }
+
+    #[test]
+    fn test_wave_trigger_corruption() {
+        // About to fetch from byte 1, one of the first four, so only byte 0 is overwritten
+        let mut apu = playing_wave(false);
+        tick_to_fetch(&mut apu.wave, 1);
+        apu.wave.control_write(0x87);
+        assert_eq!(apu.wave.wave_ram[..4], [0x11, 0x11, 0x22, 0x33]);
+        assert_eq!(
+            apu.wave.wave_ram[4..],
+            playing_wave(false).wave.wave_ram[4..]
+        );
+
+        // About to fetch from byte 5, so its aligned group 4-7 is copied over 0-3
+        let mut apu = playing_wave(false);
+        tick_to_fetch(&mut apu.wave, 9);
+        apu.wave.control_write(0x87);
+        assert_eq!(
+            apu.wave.wave_ram[..8],
+            [0x44, 0x55, 0x66, 0x77, 0x44, 0x55, 0x66, 0x77]
+        );
+
+        // Not on the fetch, or on CGB, leaves it alone
+        let mut apu = playing_wave(false);
+        tick_to_fetch(&mut apu.wave, 9);
+        apu.wave.tick();
+        apu.wave.control_write(0x87);
+        assert_eq!(apu.wave.wave_ram, playing_wave(false).wave.wave_ram);
+        let mut apu = playing_wave(true);
+        tick_to_fetch(&mut apu.wave, 9);
+        apu.wave.control_write(0x87);
+        assert_eq!(apu.wave.wave_ram, playing_wave(true).wave.wave_ram);
+    }
+
+    #[test]
+    fn test_wave_trigger_keeps_sample() {
+        let mut apu = playing_wave(false);
+        tick_to_fetch(&mut apu.wave, 4);
+        apu.wave.tick();
+        assert_eq!(apu.wave.sample, 0x22);
+        // Retriggering restarts at position 0 without refetching, and the first fetch is
+        // 3 ticks later than a normal step
+        apu.wave.control_write(0x87);
+        assert_eq!((apu.wave.position, apu.wave.sample), (0, 0x22));
+        for _ in 0..0x100 + 2 {
+            apu.wave.tick();
+        }
+        assert_eq!((apu.wave.position, apu.wave.sample), (0, 0x22));
+        apu.wave.tick();
+        assert_eq!((apu.wave.position, apu.wave.sample), (1, 0x00));
+    }
+
+    #[test]
+    fn test_wave_ram_access_while_playing() {
+        // On DMG the CPU only reaches the byte being fetched, right as it is fetched
+        let mut apu = playing_wave(false);
+        tick_to_fetch(&mut apu.wave, 2);
+        apu.wave.tick();
+        assert_eq!(apu.wave.wave_ram_read(0xff30), 0x11);
+        assert_eq!(apu.wave.wave_ram_read(0xff3f), 0x11);
+        apu.wave.wave_ram_write(0xff3f, 0xab);
+        assert_eq!(apu.wave.wave_ram[1], 0xab);
+        assert_eq!(apu.wave.wave_ram[15], 0xff);
+        apu.wave.tick();
+        assert_eq!(apu.wave.wave_ram_read(0xff31), 0xff);
+        apu.wave.wave_ram_write(0xff31, 0xcd);
+        assert_eq!(apu.wave.wave_ram[1], 0xab);
+
+        // CGB can get at it any time
+        let mut apu = playing_wave(true);
+        tick_to_fetch(&mut apu.wave, 2);
+        for _ in 0..10 {
+            apu.wave.tick();
+        }
+        assert_eq!(apu.wave.wave_ram_read(0xff35), 0x11);
+    }
+
+    #[test]
+    fn test_square_trigger_keeps_phase() {
+        // 75% duty at full volume, stepping every 16 ticks
+        let mut apu = powered_on();
+        let square = &mut apu.square2;
+        square.length_timer_write(0xc0);
+        square.envelope_write(0xf0);
+        square.period_low_write(0xf0);
+        square.control_write(0x87);
+        while square.duty_step != 5 {
+            square.tick();
+        }
+        square.tick();
+        let output = square.output();
+
+        // Retriggering mid-waveform carries on from the same step, with the timer reloaded
+        // one tick late
+        square.control_write(0x87);
+        assert_eq!(square.duty_step, 5);
+        assert_eq!(square.output(), output);
+        for _ in 0..16 {
+            square.tick();
+        }
+        assert_eq!(square.duty_step, 5);
+        square.tick();
+        assert_eq!(square.duty_step, 6);
+
+        // Only powering the APU back on restarts the waveform
+        apu.master_control_write(0x00);
+        apu.master_control_write(0x80);
+        assert_eq!(apu.square2.duty_step, 0);
+    }
+
+    fn length_counters(apu: &Apu) -> [u16; 4] {
+        [
+            apu.square1.length_counter.counter,
+            apu.square2.length_counter.counter,
+            apu.wave.length_counter.counter,
+            apu.noise.length_counter.counter,
+        ]
+    }
+
+    // Length writes the way Bus gates them
+    fn write_lengths(apu: &mut Apu) {
+        if apu.length_writable() {
+            apu.square1.length_timer_write(0x3f);
+            apu.square2.length_timer_write(0x3e);
+            apu.wave.length_timer(0xfd);
+            apu.noise.length_timer(0x3c);
+        }
+    }
+
+    #[test]
+    fn test_length_counters_during_power_off() {
+        let mut apu = powered_on();
+        apu.square1.length_timer_write(0x30);
+        apu.square1.envelope_write(0xf3);
+        apu.volume_write(0x55);
+        apu.master_control_write(0x00);
+        assert_eq!(apu.square1.length_counter.counter, 16);
+        // Everything else reads back as cleared
+        assert_eq!(apu.square1.sweep_read(), 0x80);
+        assert_eq!(apu.square1.envelope_read(), 0x00);
+        assert_eq!(apu.volume_read(), 0x00);
+        assert_eq!(apu.master_control_read(), 0x70);
+
+        // DMG still takes length writes while off and keeps them on power on
+        write_lengths(&mut apu);
+        apu.master_control_write(0x80);
+        assert_eq!(length_counters(&apu), [1, 2, 3, 4]);
+        // The frame sequencer restarts so the next step is 0
+        assert_eq!(apu.frame, 7);
+
+        // CGB clears them on power off and ignores the writes
+        let mut apu = Apu::new(true);
+        apu.master_control_write(0x80);
+        write_lengths(&mut apu);
+        apu.master_control_write(0x00);
+        assert_eq!(length_counters(&apu), [0; 4]);
+        write_lengths(&mut apu);
+        assert_eq!(length_counters(&apu), [0; 4]);
+    }
+
+    // One LFSR shift. With a divider of 8 and no shift it shifts every 8 ticks, the first
+    // straight after a trigger
+    fn clock_lfsr(noise: &mut NoiseChannel) {
+        for _ in 0..8 {
+            noise.tick();
+        }
+    }
+
+    #[test]
+    fn test_lfsr_period() {
+        let mut apu = powered_on();
+        let noise = &mut apu.noise;
+        noise.envelope_write(0xf0);
+        noise.randomness_write(0x00);
+        noise.control_write(0x80);
+        assert_eq!(noise.lfsr, 0x7fff);
+        // 15 bit mode goes through every non-zero state before repeating
+        for shifts in 1..=32767 {
+            clock_lfsr(noise);
+            assert_eq!(noise.lfsr == 0x7fff, shifts == 32767, "{shifts}");
+        }
+
+        // 7 bit mode also feeds bit 6, so the low 7 bits repeat every 127 shifts
+        noise.randomness_write(0x08);
+        noise.control_write(0x80);
+        for shifts in 1..=127 {
+            clock_lfsr(noise);
+            assert_eq!(noise.lfsr & 0x7f == 0x7f, shifts == 127, "{shifts}");
+        }
+    }
+
+    #[test]
+    fn test_wave_length_ff() {
+        let mut apu = powered_on();
+        apu.wave.dac_enable_write(0x80);
+        apu.wave.length_timer(0xff);
+        apu.wave.control_write(0xc7);
+        assert_eq!(apu.master_control_read() & 0x04, 0x04);
+        // The first step after power on clocks length
+        apu.frame_cycle_external();
+        assert_eq!(apu.master_control_read() & 0x04, 0x00);
+
+        // An expired counter reloads to 256, less the one lost since the next step won't
+        // clock length
+        apu.wave.control_write(0xc7);
+        assert_eq!(apu.wave.length_counter.counter, 255);
+        assert!(apu.wave.enabled);
+    }
+
+    #[test]
+    fn test_envelope_period_zero() {
+        // Volume 10, decreasing, period 0
+        let mut apu = powered_on();
+        apu.square1.envelope_write(0xa0);
+        apu.square1.control_write(0x80);
+        assert_eq!(apu.square1.envelope.counter, 8);
+        // 8 envelope clocks
+        for _ in 0..64 {
+            apu.frame_cycle_external();
+        }
+        assert_eq!(apu.square1.envelope.volume, 10);
+
+        // A non-zero period does move it
+        apu.square1.envelope_write(0xa1);
+        apu.square1.control_write(0x80);
+        for _ in 0..64 {
+            apu.frame_cycle_external();
+        }
+        assert_eq!(apu.square1.envelope.volume, 2);
+    }
+
+    #[test]
+    fn test_muted_channel_still_enabled() {
+        let mut apu = powered_on();
+        apu.sound_panning_write(0x11);
+        apu.square1.envelope_write(0xf0);
+        apu.square1.control_write(0x80);
+        assert_ne!(apu.mix(apu.channel_outputs()), (0.0, 0.0));
+
+        apu.set_muted(Channels::square1, true);
+        assert_eq!(apu.mix(apu.channel_outputs()), (0.0, 0.0));
+        assert_eq!(apu.master_control_read() & 0x01, 0x01);
+        apu.set_muted(Channels::square1, false);
+        apu.solo(Channels::square2);
+        assert_eq!(apu.mix(apu.channel_outputs()), (0.0, 0.0));
+        apu.solo(Channels::square1);
+        assert_ne!(apu.mix(apu.channel_outputs()), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_scope_only_recorded_when_on() {
+        // A tenth of a second, which makes more than SCOPE_LENGTH output samples
+        const INPUT_TICKS: usize = 104_858;
+        let mut apu = powered_on();
+        for _ in 0..INPUT_TICKS {
+            apu.tick();
+        }
+        assert!(apu.square1_output.is_empty());
+        apu.record_scope = true;
+        for _ in 0..INPUT_TICKS {
+            apu.tick();
+        }
+        assert_eq!(apu.square1_output.len(), SCOPE_LENGTH);
+        assert_eq!(apu.noise_output.len(), SCOPE_LENGTH);
+    }
 }
@@ -1,10 +1,20 @@
 use bitflags::bitflags;
 
+#[cfg(feature = "debug_unimplemented")]
+use std::collections::HashMap;
+
 use crate::apu::Apu;
 use crate::cartridge::Mapper;
+#[cfg(feature = "accurate_ppu")]
+use crate::fifo::PixelFifo;
+use crate::header::{CartridgeHeader, HeaderError};
 use crate::joypad::Joypad;
-use crate::ppu::{DisplayStatus, Ppu};
-use crate::render::{self, Frame};
+use crate::ppu::{Control, DisplayStatus, Ppu};
+use crate::render::Frame;
+#[cfg(not(feature = "accurate_ppu"))]
+use crate::render::LineRenderer;
+use crate::serial::{Disconnected, SerialLink};
+use crate::state::{self, State, StateError, StateReader, StateWriter};
 use crate::timer::Timer;
 
 bitflags! {
@@ -24,7 +34,8 @@ bitflags! {
 }
 
 pub struct Bus {
-    pub cpu_ram: [u8; 0x2000], // not sure size of cpu ram
+    pub cpu_ram: [u8; 0x8000], // 8 banks of 4 KiB. DMG only uses banks 0 and 1
+    pub wram_bank: usize,      // Bank mapped to 0xD000 - 0xDFFF. Selected by SVBK (0xFF70) on CGB
     pub hram: [u8; 0x7F],      // CPU high ram 0xFF80 - 0xFFFE
     pub cartridge: Box<dyn Mapper>,
     pub joypad: Joypad,
@@ -35,26 +46,224 @@ pub struct Bus {
     pub frame: Frame,
     pub last_frame: Frame,
     pub apu: Apu,
-    pub audio_buffer: [f32; 735],
-    audio_buffer_index: usize,
+    pub audio_buffer: Vec<f32>, // Interleaved stereo samples of the last finished frame
+    frame_cycles: usize,        // M-cycles into the current emulated frame
+    pub cgb: bool,
+    pub speed_mode: u8, // 1 at CGB double speed. The CPU, timer, DMA and serial run twice as fast
+    pub speed_prepare: bool, // KEY1 bit 0. The next STOP switches speed
+    half_cycle: bool,   // At double speed, half a real time M-cycle left over from last tick
+    pub total_cycles: u64, // Machine cycles since power on. Timestamps input recordings
+    pub rom_hash: u64,  // Identifies the ROM in save states
+    header: Result<CartridgeHeader, HeaderError>,
+    pub boot_rom: [u8; 0x100],
+    pub boot_rom_active: bool, // Boot ROM overlays 0x0000 - 0x00FF until 0xFF50 is written
+    pub dma_active: bool,      // OAM DMA copies one byte per M-cycle for 160 M-cycles
+    pub dma_source: u16,
+    pub dma_register: u8, // Last value written to 0xFF46, which reads back as is
+    pub dma_cycles_remaining: u8,
+    // CGB VRAM DMA (HDMA1-5). Copies 16 byte blocks into VRAM, all at once in general
+    // purpose mode or one block per HBlank
+    pub hdma_source: u16,
+    pub hdma_dest: u16,            // Offset into VRAM
+    pub hdma_length: u8,           // Blocks left minus 1, as HDMA5 reads
+    pub hdma_mode: bool,           // true for HBlank DMA
+    pub hdma_active: bool,         // HBlank DMA waiting on more HBlanks
+    pub hdma_stall: u16,           // M-cycles the CPU is held for copies done since its last step
+    apu_div_prev: bool, // DIV bit 4 as of the last tick. The APU frame sequencer steps when it falls
+    pub serial_data: u8, // SB
+    pub serial_control: u8, // SC
+    pub serial_output: Vec<u8>, // Every byte sent over serial. Not saved
+    pub debug_serial: bool, // Echo each byte sent to stderr, for test ROMs reporting over serial
+    pub serial_cycles: u16, // M-cycles left in an internal clock transfer
+    pub link: Box<dyn SerialLink>, // Link cable. Not saved
+    #[cfg(feature = "accurate_ppu")]
+    pub fifo: PixelFifo,
+    #[cfg(not(feature = "accurate_ppu"))]
+    pub line_renderer: LineRenderer,
+    #[cfg(feature = "debug_unimplemented")]
+    pub unimplemented_reads: HashMap<u16, u32>, // Access counts of each unmapped address.
+    #[cfg(feature = "debug_unimplemented")]
+    pub unimplemented_writes: HashMap<u16, u32>, // Only the first access is logged
+    #[cfg(feature = "heatmap")]
+    pub read_counts: Box<[u32; 65536]>, // CPU accesses to each address
+    #[cfg(feature = "heatmap")]
+    pub write_counts: Box<[u32; 65536]>,
+}
+
+impl State for Bus {
+    fn save(&self, w: &mut StateWriter) {
+        self.cpu_ram.save(w);
+        self.wram_bank.save(w);
+        self.hram.save(w);
+        self.cartridge.save(w);
+        self.joypad.save(w);
+        self.timer.save(w);
+        self.interrupt_enable.bits().save(w);
+        self.interrupt_flag.bits().save(w);
+        self.ppu.save(w);
+        self.frame.save(w);
+        self.last_frame.save(w);
+        self.apu.save(w);
+        self.audio_buffer.save(w);
+        self.frame_cycles.save(w);
+        self.cgb.save(w);
+        self.speed_mode.save(w);
+        self.speed_prepare.save(w);
+        self.half_cycle.save(w);
+        self.total_cycles.save(w);
+        self.boot_rom.save(w);
+        self.boot_rom_active.save(w);
+        self.dma_active.save(w);
+        self.dma_source.save(w);
+        self.dma_register.save(w);
+        self.dma_cycles_remaining.save(w);
+        self.hdma_source.save(w);
+        self.hdma_dest.save(w);
+        self.hdma_length.save(w);
+        self.hdma_mode.save(w);
+        self.hdma_active.save(w);
+        self.hdma_stall.save(w);
+        self.apu_div_prev.save(w);
+        self.serial_data.save(w);
+        self.serial_control.save(w);
+        self.serial_cycles.save(w);
+        #[cfg(feature = "accurate_ppu")]
+        self.fifo.save(w);
+        #[cfg(not(feature = "accurate_ppu"))]
+        self.line_renderer.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.cpu_ram.load(r)?;
+        self.wram_bank.load(r)?;
+        self.hram.load(r)?;
+        self.cartridge.load(r)?;
+        self.joypad.load(r)?;
+        self.timer.load(r)?;
+        self.interrupt_enable = Interrupt::from_bits_retain(r.read()?);
+        self.interrupt_flag = Interrupt::from_bits_retain(r.read()?);
+        self.ppu.load(r)?;
+        self.frame.load(r)?;
+        self.last_frame.load(r)?;
+        self.apu.load(r)?;
+        self.audio_buffer.load(r)?;
+        self.frame_cycles.load(r)?;
+        self.cgb.load(r)?;
+        self.speed_mode.load(r)?;
+        self.speed_prepare.load(r)?;
+        self.half_cycle.load(r)?;
+        self.total_cycles.load(r)?;
+        self.boot_rom.load(r)?;
+        self.boot_rom_active.load(r)?;
+        self.dma_active.load(r)?;
+        self.dma_source.load(r)?;
+        self.dma_register.load(r)?;
+        self.dma_cycles_remaining.load(r)?;
+        self.hdma_source.load(r)?;
+        self.hdma_dest.load(r)?;
+        self.hdma_length.load(r)?;
+        self.hdma_mode.load(r)?;
+        self.hdma_active.load(r)?;
+        self.hdma_stall.load(r)?;
+        self.apu_div_prev.load(r)?;
+        self.serial_data.load(r)?;
+        self.serial_control.load(r)?;
+        self.serial_cycles.load(r)?;
+        #[cfg(feature = "accurate_ppu")]
+        self.fifo.load(r)?;
+        #[cfg(not(feature = "accurate_ppu"))]
+        self.line_renderer.load(r)?;
+        Ok(())
+    }
 }
 
 impl Bus {
-    pub fn new(cartridge: Box<dyn Mapper>) -> Self {
+    const FRAME_CYCLES: usize = 70224 / 4;
+
+    // Runs in CGB mode when the cartridge header asks for it unless force_dmg is set
+    pub fn new(cartridge: Box<dyn Mapper>, force_dmg: bool) -> Self {
+        let cgb = !force_dmg && cartridge.cgb_supported();
+        let rom_hash = state::rom_hash(cartridge.rom());
+        let header = CartridgeHeader::parse(cartridge.rom());
+        if let Err(err) = &header {
+            eprintln!("Cartridge header: {err}");
+        }
         Bus {
-            cpu_ram: [0; 0x2000],
+            cpu_ram: [0; 0x8000],
+            wram_bank: 1,
             hram: [0; 0x7F],
             cartridge,
             joypad: Joypad::new(),
             timer: Timer::new(),
             interrupt_enable: Interrupt::empty(),
             interrupt_flag: Interrupt::empty(),
-            ppu: Ppu::new(),
+            ppu: Ppu::new(cgb),
             frame: Frame::new(),
             last_frame: Frame::new(),
-            apu: Apu::new(),
-            audio_buffer: [0.0; 735],
-            audio_buffer_index: 0,
+            apu: Apu::new(cgb),
+            audio_buffer: Vec::new(),
+            frame_cycles: 0,
+            cgb,
+            speed_mode: 0,
+            speed_prepare: false,
+            half_cycle: false,
+            total_cycles: 0,
+            rom_hash,
+            header,
+            boot_rom: [0; 0x100],
+            boot_rom_active: false,
+            dma_active: false,
+            dma_source: 0,
+            dma_register: 0xff,
+            dma_cycles_remaining: 0,
+            hdma_source: 0,
+            hdma_dest: 0,
+            hdma_length: 0x7f,
+            hdma_mode: false,
+            hdma_active: false,
+            hdma_stall: 0,
+            apu_div_prev: false,
+            serial_data: 0,
+            serial_control: 0,
+            serial_output: Vec::new(),
+            debug_serial: false,
+            serial_cycles: 0,
+            link: Box::new(Disconnected),
+            #[cfg(feature = "accurate_ppu")]
+            fifo: PixelFifo::new(),
+            #[cfg(not(feature = "accurate_ppu"))]
+            line_renderer: LineRenderer::new(),
+            #[cfg(feature = "debug_unimplemented")]
+            unimplemented_reads: HashMap::new(),
+            #[cfg(feature = "debug_unimplemented")]
+            unimplemented_writes: HashMap::new(),
+            #[cfg(feature = "heatmap")]
+            read_counts: Box::new([0; 65536]),
+            #[cfg(feature = "heatmap")]
+            write_counts: Box::new([0; 65536]),
+        }
+    }
+
+    pub fn cartridge_header(&self) -> Result<&CartridgeHeader, &HeaderError> {
+        self.header.as_ref()
+    }
+
+    // Device rate the audio is resampled to. Defaults to 44.1 kHz
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    // Map the DMG boot ROM over the cartridge. Must be done before the Cpu is created
+    pub fn load_boot_rom(&mut self, boot_rom: [u8; 0x100]) {
+        self.boot_rom = boot_rom;
+        self.boot_rom_active = true;
+    }
+
+    // Index into cpu_ram for 0xC000 - 0xDFFF. 0xC000 - 0xCFFF is always bank 0
+    fn wram_index(&self, addr: u16) -> usize {
+        match addr {
+            0xC000..=0xCFFF => (addr - 0xC000) as usize,
+            _ => self.wram_bank * 0x1000 + (addr - 0xD000) as usize,
         }
     }
 
@@ -99,14 +308,26 @@ impl Bus {
     }
 
     pub fn tick(&mut self, cycles: u8) -> bool {
+        self.total_cycles += cycles as u64;
+
         // Timer
         let timer_interrupt = self.timer.tick(cycles);
         if timer_interrupt {
             self.interrupt_flag.insert(Interrupt::timer);
         }
 
+        // The PPU, APU and frame clock run in real time, so at double speed they only get
+        // one M-cycle for every two of the CPU's
+        let real_cycles = if self.is_double_speed() {
+            let total = cycles + self.half_cycle as u8;
+            self.half_cycle = total % 2 == 1;
+            total / 2
+        } else {
+            cycles
+        };
+
         // PPU
-        let (display_result, lcd_interrupt, vblank_interrupt) = self.ppu.tick(cycles);
+        let (display_result, lcd_interrupt, vblank_interrupt) = self.ppu.tick(real_cycles);
         if lcd_interrupt {
             self.interrupt_flag.insert(Interrupt::lcd);
         }
@@ -120,28 +341,58 @@ impl Bus {
             self.interrupt_flag.insert(Interrupt::joypad);
         }
 
-        // APU
-        let mut result = false;
+        // OAM DMA
         for _ in 0..cycles {
-            if let Some(amp) = self.apu.tick() {
-                if self.audio_buffer_index >= 735 {
-                    result = true;
-                    self.audio_buffer_index -= 735;
-                }
-                self.audio_buffer[self.audio_buffer_index] = amp / 10.0;
-                self.audio_buffer_index += 1;
-            }
+            self.dma_tick();
+        }
+
+        self.serial_tick(cycles);
+
+        // APU frame sequencer. A DIV write that clears bit 4 is caught here as well
+        let apu_div = self.timer.apu_signal(self.is_double_speed());
+        if self.apu_div_prev && !apu_div {
+            self.apu.frame_cycle_external();
         }
+        self.apu_div_prev = apu_div;
+
+        // APU
+        for _ in 0..real_cycles {
+            self.apu.tick();
+        }
+
+        let result = self.frame_tick(real_cycles);
+        if result && !self.ppu.control.contains(Control::lcd_enable) {
+            self.last_frame.blank(self.ppu.lcd_off_rgb());
+        }
+
+        // Pixel FIFO runs 4 dots per M-cycle through mode 3
+        #[cfg(feature = "accurate_ppu")]
+        self.fifo
+            .tick(&mut self.ppu, &mut self.frame, real_cycles as usize * 4);
+
+        // Otherwise pixels are drawn as the PPU reaches them
+        #[cfg(not(feature = "accurate_ppu"))]
+        self.line_renderer.tick(&mut self.ppu, &mut self.frame);
 
         match display_result {
             DisplayStatus::DoNothing => false,
+            DisplayStatus::HBlank => {
+                if self.hdma_active {
+                    self.hdma_block();
+                }
+                false
+            }
             DisplayStatus::OAMScan => {
                 // Mode 2 started
                 false
             }
             DisplayStatus::NewScanline => {
                 self.ppu.oam_scan();
-                render::render_scanline(&mut self.ppu, &mut self.frame); // Mode 3 started
+                // Mode 3 started
+                #[cfg(feature = "accurate_ppu")]
+                self.fifo.start_line(&self.ppu);
+                #[cfg(not(feature = "accurate_ppu"))]
+                self.line_renderer.start_line();
                 false
             }
             DisplayStatus::NewFrame => {
@@ -154,41 +405,207 @@ impl Bus {
         result
     }
 
+    pub fn is_double_speed(&self) -> bool {
+        self.speed_mode == 1
+    }
+
+    // STOP with KEY1 bit 0 set flips the speed instead of stopping
+    pub fn switch_speed(&mut self) {
+        self.speed_mode ^= 1;
+        self.speed_prepare = false;
+        self.half_cycle = false;
+    }
+
+    // While the CPU is in STOP the timer, PPU and APU are halted. Frames keep being
+    // handed out so the frontend stays paced and can deliver the button that wakes it
+    pub fn tick_stopped(&mut self, cycles: u8) -> bool {
+        self.total_cycles += cycles as u64;
+        self.frame_tick(cycles)
+    }
+
+    // Emulated frames run a fixed 70224 T-cycles so pacing still works with the LCD off.
+    // The audio produced during the frame is handed over with it
+    fn frame_tick(&mut self, cycles: u8) -> bool {
+        self.frame_cycles += cycles as usize;
+        if self.frame_cycles >= Bus::FRAME_CYCLES {
+            self.frame_cycles -= Bus::FRAME_CYCLES;
+            self.audio_buffer.clear();
+            self.audio_buffer.append(&mut self.apu.samples);
+            return true;
+        }
+        false
+    }
+
+    // Bit 7 starts a transfer, bit 0 picks the internal clock. 8 bits at 8192 Hz, or 262144 Hz
+    // with CGB's fast clock in bit 1. On the external clock the other side drives it
+    fn serial_write(&mut self, data: u8) {
+        self.serial_control = data;
+        if data & 0x81 == 0x81 {
+            self.serial_cycles = if self.cgb && data & 0x02 != 0 {
+                32
+            } else {
+                1024
+            };
+        }
+    }
+
+    fn serial_tick(&mut self, cycles: u8) {
+        if self.serial_control & 0x80 == 0 {
+            return;
+        }
+        let received = if self.serial_control & 0x01 != 0 {
+            self.serial_cycles = self.serial_cycles.saturating_sub(cycles as u16);
+            if self.serial_cycles > 0 {
+                return;
+            }
+            self.link.exchange(self.serial_data)
+        } else {
+            match self.link.respond(self.serial_data) {
+                Some(byte) => byte,
+                None => return,
+            }
+        };
+        self.serial_output.push(self.serial_data);
+        if self.debug_serial {
+            eprint!("{}", self.serial_data as char);
+        }
+        self.serial_data = received;
+        self.serial_control &= 0x7f;
+        self.interrupt_flag.insert(Interrupt::serial);
+    }
+
+    // Copy the next OAM DMA byte
+    fn dma_tick(&mut self) {
+        if !self.dma_active {
+            return;
+        }
+        let index = 0xA0 - self.dma_cycles_remaining as u16;
+        let byte = self.bus_read(self.dma_source + index);
+        self.ppu.oam_write(0xFE00 + index, byte);
+        self.dma_cycles_remaining -= 1;
+        self.dma_active = self.dma_cycles_remaining > 0;
+    }
+
+    // HDMA5 write. Bit 7 clear starts a general purpose copy of every block straight away,
+    // set starts HBlank DMA. Clearing bit 7 during HBlank DMA stops it instead
+    fn hdma_write(&mut self, data: u8) {
+        if self.hdma_active && data & 0x80 == 0 {
+            self.hdma_active = false;
+            return;
+        }
+        self.hdma_length = data & 0x7f;
+        self.hdma_mode = data & 0x80 > 0;
+        if self.hdma_mode {
+            self.hdma_active = true;
+            // Already in HBlank, the first block doesn't wait for the next one
+            if self.ppu.is_hblank() {
+                self.hdma_block();
+            }
+        } else {
+            for _ in 0..=self.hdma_length {
+                self.hdma_block();
+            }
+        }
+    }
+
+    // Copy one 16 byte block. The CPU is stopped for 8 M-cycles of real time per block, so
+    // twice that many of its own at double speed
+    fn hdma_block(&mut self) {
+        for _ in 0..0x10 {
+            let byte = self.bus_read(self.hdma_source);
+            self.ppu
+                .vram
+                .write(self.ppu.vram.bank, 0x8000 | self.hdma_dest, byte);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_dest = (self.hdma_dest + 1) & 0x1fff;
+        }
+        self.hdma_stall += 8 << self.speed_mode;
+        // Reads 0xFF once finished
+        self.hdma_length = self.hdma_length.wrapping_sub(1) & 0x7f;
+        if self.hdma_length == 0x7f {
+            self.hdma_active = false;
+        }
+    }
+
+    // While OAM DMA owns the bus the CPU only reaches HRAM, the IO registers and IE.
+    // Those sit on the CPU's own bus, so the DMA register itself stays reachable
+    fn dma_blocked(&self, addr: u16) -> bool {
+        self.dma_active && addr < 0xFF00
+    }
+
     pub fn mem_read(&mut self, addr: u16) -> u8 {
+        #[cfg(feature = "heatmap")]
+        {
+            self.read_counts[addr as usize] = self.read_counts[addr as usize].saturating_add(1);
+        }
+        if self.dma_blocked(addr) {
+            return 0xff;
+        }
+        self.bus_read(addr)
+    }
+
+    fn bus_read(&mut self, addr: u16) -> u8 {
+        match self.read_mapped(addr) {
+            Some(val) => val,
+            // Unmapped IO registers read as open bus
+            None => {
+                #[cfg(feature = "debug_unimplemented")]
+                {
+                    let count = self.unimplemented_reads.entry(addr).or_insert(0);
+                    *count += 1;
+                    if *count == 1 {
+                        eprintln!("Read from unimplemented address {addr:04X}");
+                    }
+                }
+                0xff
+            }
+        }
+    }
+
+    // Side effect free read for debug views. Ignores OAM DMA and the PPU's VRAM/OAM
+    // locking so they always show what is actually in memory
+    pub fn peek(&self, addr: u16) -> u8 {
         match addr {
+            0x8000..=0x9FFF => self.ppu.read_vram(addr),
+            0xFE00..=0xFE9F => self.ppu.oam_read(addr),
+            _ => self.read_mapped(addr).unwrap_or(0xff),
+        }
+    }
+
+    // None for addresses with nothing mapped
+    fn read_mapped(&self, addr: u16) -> Option<u8> {
+        let val = match addr {
+            // Boot ROM (while mapped)
+            0x0000..=0x00FF if self.boot_rom_active => self.boot_rom[addr as usize],
             // Cartridge ROM bank 0
             0x0000..=0x3FFF => self.cartridge.read_bank0(addr),
             // Cartridge ROM bank 01-NN. May be mapped
             0x4000..=0x7FFF => self.cartridge.read_bankn(addr),
             // VRAM
+            0x8000..=0x9FFF if self.ppu.is_mode3() => 0xff,
             0x8000..=0x9FFF => self.ppu.read_vram(addr),
             // Cartridge RAM (not always present)
             0xA000..=0xBFFF => self.cartridge.ram_read(addr),
             // CPU RAM
-            0xC000..=0xDFFF => {
-                let mirrored_addr = addr % 0x2000;
-                assert!(mirrored_addr <= 0x2000);
-                self.cpu_ram[mirrored_addr as usize]
-            }
-            // Echo RAM (Mirrors CPU Ram) - Shouldn't be used
-            0xE000..=0xFDFF => {
-                panic!("Echo RAM address used (Should not be used). Address: {addr:04X}")
-            }
+            0xC000..=0xDFFF => self.cpu_ram[self.wram_index(addr)],
+            // Echo RAM (Mirrors 0xC000-0xDDFF)
+            0xE000..=0xFDFF => self.cpu_ram[self.wram_index(addr - 0x2000)],
             // OAM RAM
+            0xFE00..=0xFE9F if self.ppu.is_mode2_or_3() => 0xff,
             0xFE00..=0xFE9F => self.ppu.oam_read(addr),
-            // Not usable
-            0xFEA0..=0xFEFF => {
-                //panic!("Address {:04X} is in unusable space 0xFEA0 - 0xFEFF", addr)
-                // returns 0 on reads
-                0
-            }
+            // Not usable. DMG reads 0xFF while the PPU owns OAM, 0x00 otherwise
+            0xFEA0..=0xFEFF if self.ppu.is_mode2_or_3() => 0xff,
+            0xFEA0..=0xFEFF => 0,
             // IO Registers 0xFF00 - 0xFF7F
             // Joypad Input
             0xFF00 => self.joypad.read(),
             // Serial transfer
-            0xFF01 | 0xFF02 => 0, //todo!("Implement serial transfer"),
+            0xFF01 => self.serial_data,
+            // Bit 1 is the CGB clock speed select, unused on DMG
+            0xFF02 if self.cgb => self.serial_control | 0b0111_1100,
+            0xFF02 => self.serial_control | 0b0111_1110,
             // DIV
-            0xFF04 => self.timer.divider_counter,
+            0xFF04 => self.timer.div_read(),
             // TIMA
             0xFF05 => self.timer.timer_counter,
             // TMA
@@ -258,10 +675,11 @@ impl Bus {
             // SCX
             0xFF43 => self.ppu.scx,
             // LY
-            0xFF44 => self.ppu.scanline,
+            0xFF44 => self.ppu.ly(),
             // LYC
             0xFF45 => self.ppu.lyc,
-            // OAM
+            // OAM DMA source as last written
+            0xFF46 => self.dma_register,
             // BGP
             0xFF47 => self.ppu.bg_palette,
             // OBP0
@@ -272,8 +690,26 @@ impl Bus {
             0xFF4A => self.ppu.wy,
             // WX
             0xFF4B => self.ppu.wx,
-            // KEY1 (CGB only)
-            0xFF4D => 0,
+            // KEY1 (CGB only). Bit 7 is the current speed, bit 0 a switch waiting on STOP
+            0xFF4D if self.cgb => (self.speed_mode << 7) | 0x7e | self.speed_prepare as u8,
+            0xFF4D => 0xff,
+            // VBK: VRAM bank (CGB only)
+            0xFF4F if self.cgb => self.ppu.vram.bank_read(),
+            0xFF4F => 0xff,
+            // Boot ROM disable
+            0xFF50 => 0xff,
+            // HDMA5 (CGB only). Bit 7 reads 0 while HBlank DMA is running. HDMA1-4 are write only
+            0xFF55 if self.cgb => ((!self.hdma_active as u8) << 7) | self.hdma_length,
+            0xFF51..=0xFF55 => 0xff,
+            // BCPS/BCPD/OCPS/OCPD: CGB palette RAM
+            0xFF68 if self.cgb => self.ppu.bg_palettes.spec_read(),
+            0xFF69 if self.cgb => self.ppu.bcpd_read(),
+            0xFF6A if self.cgb => self.ppu.obj_palettes.spec_read(),
+            0xFF6B if self.cgb => self.ppu.ocpd_read(),
+            0xFF68..=0xFF6B => 0xff,
+            // SVBK: WRAM bank (CGB only)
+            0xFF70 if self.cgb => 0xf8 | self.wram_bank as u8,
+            0xFF70 => 0xff,
 
             // High RAM
             0xFF80..=0xFFFE => {
@@ -282,11 +718,19 @@ impl Bus {
             }
             // Interrupt Enable
             0xFFFF => self.interrupt_enable.bits(),
-            _ => panic!("Address {addr:04X} not used in memory map"),
-        }
+            _ => return None,
+        };
+        Some(val)
     }
 
     pub fn mem_write(&mut self, addr: u16, data: u8) {
+        #[cfg(feature = "heatmap")]
+        {
+            self.write_counts[addr as usize] = self.write_counts[addr as usize].saturating_add(1);
+        }
+        if self.dma_blocked(addr) {
+            return;
+        }
         match addr {
             // Cartridge ROM bank 0
             0x0000..=0x3FFF => {
@@ -306,15 +750,16 @@ impl Bus {
             }
             // CPU RAM
             0xC000..=0xDFFF => {
-                let mirrored_addr = addr % 0x2000;
-                assert!(mirrored_addr <= 0x2000);
-                self.cpu_ram[mirrored_addr as usize] = data;
+                let index = self.wram_index(addr);
+                self.cpu_ram[index] = data;
             }
-            // Echo RAM (Mirrors CPU Ram) - Shouldn't be used
+            // Echo RAM (Mirrors 0xC000-0xDDFF)
             0xE000..=0xFDFF => {
-                panic!("Echo RAM address used (Should not be used). Address: {addr:04X}")
+                let index = self.wram_index(addr - 0x2000);
+                self.cpu_ram[index] = data;
             }
             // OAM RAM
+            0xFE00..=0xFE9F if self.ppu.is_mode2_or_3() => {}
             0xFE00..=0xFE9F => {
                 self.ppu.oam_write(addr, data);
             }
@@ -328,7 +773,8 @@ impl Bus {
                 self.joypad.write(data);
             }
             // Serial transfer
-            0xFF01 | 0xFF02 => {}
+            0xFF01 => self.serial_data = data,
+            0xFF02 => self.serial_write(data),
             // DIV
             0xFF04 => self.timer.div_write(),
             // TIMA
@@ -345,7 +791,7 @@ impl Bus {
             // Channel 1 Sweep
             0xFF10 => self.apu.square1.sweep_write(data),
             // Channel 1 length timer & duty cycle
-            0xFF11 => self.apu.square1.length_timer_write(data),
+            0xFF11 if self.apu.length_writable() => self.apu.square1.length_timer_write(data),
             // Channel 1 volume & envelope
             0xFF12 => self.apu.square1.envelope_write(data),
             // Channel 1 period low
@@ -357,7 +803,7 @@ impl Bus {
             // Not used
             0xFF15 => {}
             // Sound channel 2 length timer & duty cycle
-            0xFF16 => self.apu.square2.length_timer_write(data),
+            0xFF16 if self.apu.length_writable() => self.apu.square2.length_timer_write(data),
             // Sound channel 2 volume & envelope
             0xFF17 => self.apu.square2.envelope_write(data),
             // Sound channel 2 period low
@@ -367,7 +813,7 @@ impl Bus {
             // Sound channel 3 DAC enable
             0xFF1A => self.apu.wave.dac_enable_write(data),
             // Sound channel 3 length timer
-            0xFF1B => self.apu.wave.length_timer(data),
+            0xFF1B if self.apu.length_writable() => self.apu.wave.length_timer(data),
             // Sound channel 3 output level
             0xFF1C => self.apu.wave.output_level_write(data),
             // Sound channel 3 period low
@@ -377,7 +823,7 @@ impl Bus {
             // Not used
             0xFF1F => {}
             // Sound channel 4 length timer
-            0xFF20 => self.apu.noise.length_timer(data),
+            0xFF20 if self.apu.length_writable() => self.apu.noise.length_timer(data),
             // Sound channel 4 volume & envelope
             0xFF21 => self.apu.noise.envelope_write(data),
             // Sound channel 4 frequency & randomness
@@ -404,18 +850,17 @@ impl Bus {
             // SCX: Scroll X value
             0xFF43 => self.ppu.scx = data,
             // LCD Y coordinate is read only
-            0xFF44 => panic!("LCD Y coordinate is read-only. Addr: {addr} Data: {data}"),
+            0xFF44 => {}
             // LYC
-            0xFF45 => self.ppu.lyc = data,
-            // OAM DMA source address and start
+            0xFF45 => self.ppu.write_lyc(data),
+            // OAM DMA source address and start. Sources above 0xDF are undefined on hardware,
+            // they're treated as the WRAM echo like reads from 0xE000-0xFDFF
             0xFF46 => {
-                assert!(data <= 0xDF);
-                let start_addr = (data as u16) << 8;
-                let mut page: [u8; 0xA0] = [0; 0xA0];
-                for (i, byte) in page.iter_mut().enumerate() {
-                    *byte = self.mem_read(start_addr + i as u16);
-                }
-                self.ppu.oam_dma(page);
+                self.dma_register = data;
+                let page = if data > 0xDF { data - 0x20 } else { data };
+                self.dma_source = (page as u16) << 8;
+                self.dma_cycles_remaining = 0xA0;
+                self.dma_active = true;
             }
             // BGP: BG Palette data
             0xFF47 => self.ppu.bg_palette = data,
@@ -428,12 +873,38 @@ impl Bus {
             // Window X position
             0xFF4B => self.ppu.wx = data,
             // KEY1 (CGB only)
+            0xFF4D if self.cgb => self.speed_prepare = data & 0x01 != 0,
             0xFF4D => {}
+            // VBK: VRAM bank (CGB only)
+            0xFF4F if self.cgb => self.ppu.vram.bank_write(data),
+            0xFF4F => {}
+            // Boot ROM disable. Unmaps the boot ROM for good once bit 0 is set
+            0xFF50 if data & 0x01 > 0 => self.boot_rom_active = false,
+            // HDMA1-4 (CGB only): source and VRAM destination. The low 4 bits are ignored
+            0xFF51 if self.cgb => {
+                self.hdma_source = (self.hdma_source & 0x00ff) | ((data as u16) << 8)
+            }
+            0xFF52 if self.cgb => {
+                self.hdma_source = (self.hdma_source & 0xff00) | (data & 0xf0) as u16
+            }
+            0xFF53 if self.cgb => {
+                self.hdma_dest = (self.hdma_dest & 0x00f0) | (((data & 0x1f) as u16) << 8)
+            }
+            0xFF54 if self.cgb => self.hdma_dest = (self.hdma_dest & 0x1f00) | (data & 0xf0) as u16,
+            // HDMA5 (CGB only): length and mode, starts the copy
+            0xFF55 if self.cgb => self.hdma_write(data),
             // BCPS/BGPI: Background color palette specification
-            0xFF68 => self.ppu.bcps = data,
+            0xFF68 if self.cgb => self.ppu.bg_palettes.spec_write(data),
             // BCPD/BGPD: Background color palette data
-            0xFF69 => self.ppu.bcpd = data,
-            0xFF6A | 0xFF6B => todo!(),
+            0xFF69 if self.cgb => self.ppu.bcpd_write(data),
+            // OCPS/OBPI: OBJ color palette specification
+            0xFF6A if self.cgb => self.ppu.obj_palettes.spec_write(data),
+            // OCPD/OBPD: OBJ color palette data
+            0xFF6B if self.cgb => self.ppu.ocpd_write(data),
+            0xFF68..=0xFF6B => {}
+            // SVBK: WRAM bank (CGB only). Bank 0 selects bank 1
+            0xFF70 if self.cgb => self.wram_bank = ((data & 0x07) as usize).max(1),
+            0xFF70 => {}
             // Unused but doesn't crash run
             0xFF78..=0xFF7F => {}
             // High RAM
@@ -445,19 +916,158 @@ impl Bus {
             0xFFFF => {
                 self.interrupt_enable = Interrupt::from_bits_retain(data & 0b0001_1111);
             }
-            _ => panic!("Address {addr:04X} not used in memory map"),
+            // Writes to unmapped IO registers are ignored
+            _ => {
+                #[cfg(feature = "debug_unimplemented")]
+                {
+                    let count = self.unimplemented_writes.entry(addr).or_insert(0);
+                    *count += 1;
+                    if *count == 1 {
+                        eprintln!("Write to unimplemented address {addr:04X}");
+                    }
+                }
+            }
         }
     }
 
+    // Most read address. The lowest one wins a tie
+    #[cfg(feature = "heatmap")]
+    pub fn hottest_read_addr(&self) -> u16 {
+        hottest(&self.read_counts)
+    }
+
+    #[cfg(feature = "heatmap")]
+    pub fn hottest_write_addr(&self) -> u16 {
+        hottest(&self.write_counts)
+    }
+
+    #[cfg(feature = "heatmap")]
+    pub fn reset_heatmap(&mut self) {
+        self.read_counts.fill(0);
+        self.write_counts.fill(0);
+    }
+
     pub fn mem_read_u16(&mut self, addr: u16) -> u16 {
         let lo = self.mem_read(addr);
-        let hi = self.mem_read(addr + 1);
+        let hi = self.mem_read(addr.wrapping_add(1));
         u16::from_le_bytes([lo, hi])
     }
 
     pub fn mem_write_u16(&mut self, addr: u16, data: u16) {
         let bytes = data.to_le_bytes();
         self.mem_write(addr, bytes[0]);
-        self.mem_write(addr + 1, bytes[1]);
+        self.mem_write(addr.wrapping_add(1), bytes[1]);
+    }
+}
+
+#[cfg(feature = "heatmap")]
+fn hottest(counts: &[u32; 65536]) -> u16 {
+    let mut hottest = 0;
+    for (addr, &count) in counts.iter().enumerate() {
+        if count > counts[hottest] {
+            hottest = addr;
+        }
+    }
+    hottest as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{get_mapper, test_rom};
+    use crate::serial::ChannelLink;
+
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn setup() -> Bus {
+        Bus::new(get_mapper(&test_rom(&[])).unwrap(), true)
+    }
+
+    #[test]
+    fn test_echo_ram() {
+        let mut bus = setup();
+        bus.mem_write(0xC100, 0x42);
+        assert_eq!(bus.mem_read(0xE100), 0x42);
+        bus.mem_write(0xFDFF, 0x24);
+        assert_eq!(bus.mem_read(0xDDFF), 0x24);
+    }
+
+    #[test]
+    fn test_every_address_is_mapped() {
+        let mut bus = setup();
+        for addr in 0..=0xFFFF {
+            bus.mem_read(addr);
+        }
+        for addr in 0..=0xFFFF {
+            bus.mem_write(addr, 0x00);
+        }
+        // Let the OAM DMA started through 0xFF46 finish
+        bus.tick(160);
+        // Echo RAM reads the WRAM it mirrors, and the unusable area reads 0 with the LCD off
+        for addr in 0xE000..=0xFDFF {
+            assert_eq!(bus.mem_read(addr), bus.mem_read(addr - 0x2000));
+        }
+        for addr in 0xFEA0..=0xFEFF {
+            assert_eq!(bus.mem_read(addr), 0x00);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_from_wram() {
+        let mut bus = setup();
+        for i in 0..0xA0 {
+            bus.mem_write(0xC000 + i, i as u8 ^ 0x5a);
+        }
+        bus.mem_write(0xFF46, 0xC0);
+
+        // One byte per M-cycle. Meanwhile the CPU only reaches HRAM and the IO registers
+        bus.tick(80);
+        assert_eq!(bus.ppu.oam[79], 79 ^ 0x5a);
+        assert_eq!(bus.ppu.oam[80], 0);
+        assert_eq!(bus.mem_read(0xC000), 0xff);
+        assert_eq!(bus.mem_read(0xFF46), 0xC0);
+        bus.mem_write(0xFF80, 0x12);
+        assert_eq!(bus.mem_read(0xFF80), 0x12);
+
+        bus.tick(80);
+        assert!(!bus.dma_active);
+        for i in 0..0xA0 {
+            assert_eq!(bus.ppu.oam[i], i as u8 ^ 0x5a);
+        }
+        assert_eq!(bus.mem_read(0xC000), 0x5a);
+    }
+
+    #[test]
+    fn test_channel_link_transfer() {
+        let (master_link, follower_link) = ChannelLink::pair();
+        let mut master = setup();
+        let mut follower = setup();
+        master.link = Box::new(master_link);
+        follower.link = Box::new(follower_link);
+
+        // The follower waits on the external clock for the master's byte
+        follower.mem_write(0xFF01, 0x22);
+        follower.mem_write(0xFF02, 0x80);
+        let follower = thread::spawn(move || {
+            let start = Instant::now();
+            while follower.serial_control & 0x80 != 0 {
+                assert!(start.elapsed() < Duration::from_secs(5), "no transfer");
+                follower.tick(1);
+            }
+            follower
+        });
+
+        master.mem_write(0xFF01, 0x11);
+        master.mem_write(0xFF02, 0x81);
+        while master.serial_control & 0x80 != 0 {
+            master.tick(1);
+        }
+        let mut follower = follower.join().unwrap();
+
+        assert_eq!(master.mem_read(0xFF01), 0x22);
+        assert_eq!(follower.mem_read(0xFF01), 0x11);
+        assert!(master.interrupt_flag.contains(Interrupt::serial));
+        assert!(follower.interrupt_flag.contains(Interrupt::serial));
     }
 }
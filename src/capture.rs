@@ -0,0 +1,149 @@
+// PNG and BMP screenshots of frames. Used by the frontends' screenshot key and by tests
+// dumping frames to compare against
+use chrono::Local;
+
+use crate::render::Frame;
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+// Write the frame as a 160x144 PNG
+pub fn save_frame(frame: &Frame, path: &Path) -> io::Result<()> {
+    save_frame_scaled(frame, path, 1)
+}
+
+// Write the frame as a PNG scaled up by a whole number with nearest neighbour
+pub fn save_frame_scaled(frame: &Frame, path: &Path, scale: u32) -> io::Result<()> {
+    let scale = scale.max(1);
+    let (width, height) = (Frame::WIDTH as u32, Frame::HEIGHT as u32);
+    let image = image::RgbImage::from_fn(width * scale, height * scale, |x, y| {
+        let (r, g, b) = frame.pixel((x / scale) as usize, (y / scale) as usize);
+        image::Rgb([r, g, b])
+    });
+    image.save(path).map_err(io::Error::other)
+}
+
+// Write the frame as a 24 bit BMP. Simple enough to write by hand and to pick apart in a
+// hex editor. Rows are stored bottom up, pixels as BGR
+pub fn save_bmp(frame: &Frame, path: &Path) -> io::Result<()> {
+    const HEADER_LEN: u32 = 54;
+    // Each row is padded to a multiple of 4 bytes
+    let row_len = (3 * Frame::WIDTH).next_multiple_of(4);
+    let image_len = (row_len * Frame::HEIGHT) as u32;
+
+    let mut bmp = Vec::with_capacity((HEADER_LEN + image_len) as usize);
+    // File header
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&(HEADER_LEN + image_len).to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&HEADER_LEN.to_le_bytes());
+    // BITMAPINFOHEADER
+    bmp.extend_from_slice(&40u32.to_le_bytes());
+    bmp.extend_from_slice(&(Frame::WIDTH as i32).to_le_bytes());
+    bmp.extend_from_slice(&(Frame::HEIGHT as i32).to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes()); // Planes
+    bmp.extend_from_slice(&24u16.to_le_bytes()); // Bits per pixel
+    bmp.extend_from_slice(&0u32.to_le_bytes()); // No compression
+    bmp.extend_from_slice(&image_len.to_le_bytes());
+    bmp.extend_from_slice(&2835i32.to_le_bytes()); // 72 DPI
+    bmp.extend_from_slice(&2835i32.to_le_bytes());
+    bmp.extend_from_slice(&[0; 8]); // Palette size and important colours
+
+    for y in (0..Frame::HEIGHT).rev() {
+        for x in 0..Frame::WIDTH {
+            let (r, g, b) = frame.pixel(x, y);
+            bmp.extend_from_slice(&[b, g, r]);
+        }
+        bmp.resize(bmp.len() + row_len - 3 * Frame::WIDTH, 0);
+    }
+    fs::write(path, bmp)
+}
+
+// screenshots/<rom name>_YYYYMMDD_HHMMSS.png next to the ROM. The directory is created if needed
+pub fn screenshot_path(rom_path: &Path) -> io::Result<PathBuf> {
+    let dir = rom_path
+        .parent()
+        .unwrap_or(Path::new("."))
+        .join("screenshots");
+    fs::create_dir_all(&dir)?;
+    let name = rom_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "screenshot".to_string());
+    Ok(dir.join(format!(
+        "{name}_{}.png",
+        Local::now().format("%Y%m%d_%H%M%S")
+    )))
+}
+
+// Compare the frame against the golden hash in <dir>/<name>.hash. Setting
+// GB_UPDATE_GOLDENS writes the current hash instead. On a mismatch the frame is dumped to
+// <dir>/<name>.actual.png for a look. Returns whether it matched
+pub fn check_golden(frame: &Frame, dir: &Path, name: &str) -> io::Result<bool> {
+    let golden_path = dir.join(format!("{name}.hash"));
+    let hash = format!("{:016x}", frame.hash());
+    if env::var_os("GB_UPDATE_GOLDENS").is_some() {
+        fs::create_dir_all(dir)?;
+        fs::write(&golden_path, format!("{hash}\n"))?;
+        return Ok(true);
+    }
+
+    let golden = fs::read_to_string(&golden_path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "{}: {err}. Run with GB_UPDATE_GOLDENS=1 to create it",
+                golden_path.display()
+            ),
+        )
+    })?;
+    if golden.trim() == hash {
+        return Ok(true);
+    }
+    let actual_path = dir.join(format!("{name}.actual.png"));
+    save_frame(frame, &actual_path)?;
+    eprintln!(
+        "{name}: hash {hash} doesn't match golden {}, frame written to {}",
+        golden.trim(),
+        actual_path.display()
+    );
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_bmp() {
+        let mut frame = Frame::new();
+        frame.blank((255, 255, 255));
+        frame.set_pixel(0, 0, (10, 20, 30));
+        frame.set_pixel(159, 0, (40, 50, 60));
+        frame.set_pixel(0, 143, (70, 80, 90));
+
+        let path = env::temp_dir().join(format!("gb_emulator_test_{}.bmp", std::process::id()));
+        save_bmp(&frame, &path).unwrap();
+        let bmp = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bmp[offset..offset + 4].try_into().unwrap());
+        assert_eq!(&bmp[0..2], b"BM");
+        assert_eq!(bmp.len(), 54 + 160 * 3 * 144);
+        assert_eq!(u32_at(2) as usize, bmp.len());
+        assert_eq!(u32_at(10), 54);
+        assert_eq!((u32_at(18), u32_at(22)), (160, 144));
+
+        // Rows go bottom up and pixels are BGR
+        let pixel = |x: usize, y: usize| {
+            let offset = 54 + (143 - y) * 160 * 3 + 3 * x;
+            &bmp[offset..offset + 3]
+        };
+        assert_eq!(pixel(0, 0), [30, 20, 10]);
+        assert_eq!(pixel(159, 0), [60, 50, 40]);
+        assert_eq!(pixel(0, 143), [90, 80, 70]);
+        assert_eq!(pixel(80, 72), [255, 255, 255]);
+        assert_eq!(&bmp[54..57], [90, 80, 70]);
+    }
+}
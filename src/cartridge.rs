@@ -1,47 +1,141 @@
-use chrono::{offset::Local, Datelike, Timelike};
+use crate::header::{CartridgeHeader, HeaderError};
+use crate::rtc::{Rtc, SystemClock};
+use crate::state::{self, State, StateError, StateReader, StateWriter};
+
+use std::path::Path;
+use std::{fmt, io};
 
-const ROM_PAGE_SIZE: usize = 32768;
 const KIB: usize = 1024;
 const MIB: usize = 1048576;
 
-pub trait Mapper {
-    fn read_bank0(&mut self, addr: u16) -> u8;
-    fn read_bankn(&mut self, addr: u16) -> u8;
+// State covers cartridge RAM and banking registers. The ROM itself is never saved.
+// Send so the emulator can run on its own thread
+pub trait Mapper: State + Send {
+    fn read_bank0(&self, addr: u16) -> u8;
+    fn read_bankn(&self, addr: u16) -> u8;
     fn write_bank0(&mut self, addr: u16, val: u8);
     fn write_bankn(&mut self, addr: u16, val: u8);
-    fn ram_read(&mut self, addr: u16) -> u8;
+    fn ram_read(&self, addr: u16) -> u8;
     fn ram_write(&mut self, addr: u16, val: u8);
+    fn rom(&self) -> &[u8];
+
+    // Battery backed RAM, plus the clock on MBC3, as written to the .sav file
+    fn save_data(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Restore from a .sav file. Anything missing from a short file is left as it is
+    fn load_save_data(&mut self, _data: &[u8]) {}
+
+    // Header byte 0x0143. Bit 7 set means the game supports CGB (0x80) or requires it (0xC0)
+    fn cgb_supported(&self) -> bool {
+        self.read_bank0(0x0143) & 0x80 > 0
+    }
+}
+
+// Why a ROM couldn't be turned into a mapper
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    Header(HeaderError),
+    Truncated { declared: usize, len: usize }, // ROM file is smaller than its header says
+    UnsupportedRomSize(u8),                    // ROM size code at 0x0148
+    UnsupportedRamSize(u8),                    // RAM size code at 0x0149
+    UnsupportedMapper(u8),                     // Cartridge type at 0x0147
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "{err}"),
+            CartridgeError::Header(err) => write!(f, "{err}"),
+            CartridgeError::Truncated { declared, len } => write!(
+                f,
+                "ROM is {len} bytes but the header says it is 0x{declared:X}"
+            ),
+            CartridgeError::UnsupportedRomSize(code) => {
+                write!(f, "unsupported cartridge ROM size code {code:02X}")
+            }
+            CartridgeError::UnsupportedRamSize(code) => {
+                write!(f, "unsupported cartridge RAM size code {code:02X}")
+            }
+            CartridgeError::UnsupportedMapper(mapper) => {
+                write!(f, "mapper {mapper:02X} is not implemented yet")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+impl From<HeaderError> for CartridgeError {
+    fn from(err: HeaderError) -> Self {
+        CartridgeError::Header(err)
+    }
+}
+
+// Copies as much of a .sav file into RAM as fits
+fn load_ram(ram: &mut [u8], data: &[u8]) {
+    let len = ram.len().min(data.len());
+    ram[..len].copy_from_slice(&data[..len]);
+}
+
+// Read a ROM file from disk and build its mapper. Shared by the CLI and the game select screen
+pub fn load_rom(path: &Path) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let bytes = std::fs::read(path)?;
+    get_mapper(&bytes)
 }
 
 // Function to get the mapper as indicated by the code (i.e byte 0x0147)
-pub fn get_mapper(raw: &[u8]) -> Box<dyn Mapper> {
-    // let header = &raw[0x0100..=0x014F];
-    // let cgb = raw[0x0143];
-    // let sgb = raw[0x0146];
-
-    let rom_size = ROM_PAGE_SIZE * (1 << raw[0x0148]);
-    let ram_size = match raw[0x0149] {
-        0 => 0,
-        2 => 8 * KIB,
-        3 => 32 * KIB,
-        4 => 128 * KIB,
-        5 => 64 * KIB,
-        _ => panic!(
-            "Cartridge RAM should not be value other than 0,2,3,4,5. Received: {}",
-            raw[0x0149]
-        ),
-    };
-
-    let mapper = raw[0x0147];
+// A bad header checksum is only warned about, some homebrew never fills it in
+pub fn get_mapper(raw: &[u8]) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let header = CartridgeHeader::parse(raw)?;
+    if let Err(err) = header.validate() {
+        eprintln!("Warning: {err}");
+    }
+
+    let rom_size = header
+        .rom_size()
+        .ok_or(CartridgeError::UnsupportedRomSize(header.rom_size_code))?;
+    let ram_size = header
+        .ram_size()
+        .ok_or(CartridgeError::UnsupportedRamSize(header.ram_size_code))?;
+    if raw.len() < rom_size {
+        return Err(CartridgeError::Truncated {
+            declared: rom_size,
+            len: raw.len(),
+        });
+    }
+
+    let mapper = header.mapper_type;
     eprintln!("Mapper is: {mapper}");
     eprintln!("Rom Size: 0x{rom_size:X}, Ram Size: 0x{ram_size:X}");
-    match mapper {
+    Ok(match mapper {
         0 => Box::new(Mbc0::new(raw, ram_size)),
         1..=3 => Box::new(Mbc1::new(raw, rom_size, ram_size)),
-        5..=6 => Box::new(Mbc2::new(raw, ram_size)),
-        16..=19 => Box::new(Mbc3::new(raw, ram_size)),
-        _ => panic!("Mapper value {mapper} not implemented yet"),
+        5..=6 => Box::new(Mbc2::new(raw, rom_size)),
+        15..=19 => Box::new(Mbc3::new(raw, ram_size, header.has_timer())),
+        _ => return Err(CartridgeError::UnsupportedMapper(mapper)),
+    })
+}
+
+// ROM only cartridge image with a valid header checksum and code at the given addresses.
+// The entry point jumps over the header to 0x0150
+#[cfg(test)]
+pub(crate) fn test_rom(code: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut rom = vec![0; 0x8000];
+    rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+    for &(addr, bytes) in code {
+        rom[addr as usize..addr as usize + bytes.len()].copy_from_slice(bytes);
     }
+    rom[0x014D] = CartridgeHeader::checksum(&rom);
+    rom
 }
 
 pub struct Mbc3 {
@@ -52,17 +146,12 @@ pub struct Mbc3 {
     rom_bank: u8,
     bank_or_register: u8,
     rtc_prior_val: bool,
-    rtc_s: u8,
-    rtc_m: u8,
-    rtc_h: u8,
-    rtc_dl: u8,
-    rtc_day_upper: bool,
-    rtc_halt: bool,
-    rtc_carry: bool,
+    has_rtc: bool, // MBC3+TIMER cartridges, 0x0F and 0x10
+    rtc: Rtc,
 }
 
 impl Mbc3 {
-    fn new(rom: &[u8], ram_size: usize) -> Self {
+    fn new(rom: &[u8], ram_size: usize, has_rtc: bool) -> Self {
         let cartridge_rom = rom.to_vec();
         let cartridge_ram = vec![0; ram_size];
         Self {
@@ -73,24 +162,43 @@ impl Mbc3 {
             rom_bank: 1,
             bank_or_register: 0,
             rtc_prior_val: false,
-            rtc_s: 0,
-            rtc_m: 0,
-            rtc_h: 0,
-            rtc_dl: 0,
-            rtc_day_upper: false,
-            rtc_halt: false,
-            rtc_carry: false,
+            has_rtc,
+            rtc: Rtc::new(Box::new(SystemClock)),
         }
     }
 }
 
+impl State for Mbc3 {
+    fn save(&self, w: &mut StateWriter) {
+        self.ram_enabled.save(w);
+        self.rom_bank.save(w);
+        self.bank_or_register.save(w);
+        self.rtc_prior_val.save(w);
+        self.rtc.save(w);
+        self.cartridge_ram.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled.load(r)?;
+        self.rom_bank.load(r)?;
+        self.bank_or_register.load(r)?;
+        self.rtc_prior_val.load(r)?;
+        self.rtc.load(r)?;
+        state::load_buffer(&mut self.cartridge_ram, r)
+    }
+}
+
 impl Mapper for Mbc3 {
-    fn read_bank0(&mut self, addr: u16) -> u8 {
+    fn rom(&self) -> &[u8] {
+        &self.cartridge_rom
+    }
+
+    fn read_bank0(&self, addr: u16) -> u8 {
         let addr = addr as usize;
         self.cartridge_rom[addr]
     }
 
-    fn read_bankn(&mut self, addr: u16) -> u8 {
+    fn read_bankn(&self, addr: u16) -> u8 {
         let addr = addr as usize - 0x4000; // get addr relative to base
         let bank_base = (self.rom_bank as usize) << 14;
         self.cartridge_rom[addr + bank_base]
@@ -119,35 +227,23 @@ impl Mapper for Mbc3 {
                 self.rtc_prior_val = true;
             } else if self.rtc_prior_val && val == 1 {
                 self.rtc_prior_val = false;
-                let now = Local::now();
-
-                self.rtc_s = now.second() as u8;
-                self.rtc_m = now.minute() as u8;
-                self.rtc_h = now.hour() as u8;
-                let day = now.ordinal0();
-                self.rtc_dl = day as u8;
-                self.rtc_day_upper = day & 0xf0 > 0;
+                self.rtc.latch();
             } else {
                 self.rtc_prior_val = false;
             }
         }
     }
 
-    fn ram_read(&mut self, addr: u16) -> u8 {
+    fn ram_read(&self, addr: u16) -> u8 {
         match self.bank_or_register {
             0..=0x07 => {
                 let addr = addr - 0xA000;
-                self.cartridge_ram[addr as usize]
-            }
-            0x08 => self.rtc_s,
-            0x09 => self.rtc_m,
-            0x0a => self.rtc_h,
-            0x0b => self.rtc_dl,
-            0x0c => {
-                let carry = (self.rtc_carry as u8) << 7;
-                let halt = (self.rtc_halt as u8) << 6;
-                carry + halt + self.rtc_day_upper as u8
+                self.cartridge_ram
+                    .get(addr as usize)
+                    .copied()
+                    .unwrap_or(0xff)
             }
+            0x08..=0x0c => self.rtc.read(self.bank_or_register - 0x08),
             _ => panic!("Impossible"),
         }
     }
@@ -156,56 +252,86 @@ impl Mapper for Mbc3 {
         match self.bank_or_register {
             0..=0x07 => {
                 let addr = addr - 0xA000;
-                self.cartridge_ram[addr as usize] = val;
-            }
-            0x08 => self.rtc_s = val,
-            0x09 => self.rtc_m = val,
-            0x0a => self.rtc_h = val,
-            0x0b => self.rtc_dl = val,
-            0x0c => {
-                self.rtc_carry = val & 0b1000_0000 > 0;
-                self.rtc_halt = val & 0b0100_0000 > 0;
-                self.rtc_day_upper = val & 0b0000_0001 > 0;
+                if let Some(byte) = self.cartridge_ram.get_mut(addr as usize) {
+                    *byte = val;
+                }
             }
+            0x08..=0x0c => self.rtc.write(self.bank_or_register - 0x08, val),
             _ => panic!("Impossible"),
         }
     }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.cartridge_ram.clone();
+        if self.has_rtc {
+            data.extend(self.rtc.footer());
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.cartridge_ram, data);
+        if self.has_rtc {
+            if let Some(footer) = data.get(self.cartridge_ram.len()..) {
+                self.rtc.load_footer(footer);
+            }
+        }
+    }
 }
 
+// MBC2 has 512 half-bytes of RAM built in, so the header RAM size is 0
 pub struct Mbc2 {
     ram_enabled: bool,
     rom_bank: u8,
-    ram_size: usize,
+    rom_size: usize,
     cartridge_rom: Vec<u8>,
-    cartridge_ram: Vec<u8>,
+    cartridge_ram: [u8; 512],
 }
 
 impl Mbc2 {
-    fn new(rom: &[u8], ram_size: usize) -> Self {
+    fn new(rom: &[u8], rom_size: usize) -> Self {
         let cartridge_rom = rom.to_vec();
-        let cartridge_ram = vec![0; ram_size];
         Self {
             rom_bank: 1,
             ram_enabled: false,
-            ram_size,
+            rom_size,
             cartridge_rom,
-            cartridge_ram,
+            cartridge_ram: [0; 512],
         }
     }
 }
 
+impl State for Mbc2 {
+    fn save(&self, w: &mut StateWriter) {
+        self.ram_enabled.save(w);
+        self.rom_bank.save(w);
+        self.cartridge_ram.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled.load(r)?;
+        self.rom_bank.load(r)?;
+        self.cartridge_ram.load(r)
+    }
+}
+
 impl Mapper for Mbc2 {
-    fn read_bank0(&mut self, addr: u16) -> u8 {
+    fn rom(&self) -> &[u8] {
+        &self.cartridge_rom
+    }
+
+    fn read_bank0(&self, addr: u16) -> u8 {
         let addr = addr as usize;
         self.cartridge_rom[addr]
     }
 
-    fn read_bankn(&mut self, addr: u16) -> u8 {
+    fn read_bankn(&self, addr: u16) -> u8 {
         let addr = addr as usize - 0x4000; // get addr relative to base
         let bank_base = (self.rom_bank as usize) << 14;
-        self.cartridge_rom[addr + bank_base]
+        self.cartridge_rom[(addr + bank_base) % self.rom_size]
     }
 
+    // Address bit 8 picks the register: clear is RAM enable, set is ROM bank
     fn write_bank0(&mut self, addr: u16, val: u8) {
         if addr & 0x0100 > 0 {
             self.rom_bank = val & 0x0f;
@@ -221,20 +347,29 @@ impl Mapper for Mbc2 {
         // does nothing
     }
 
-    fn ram_read(&mut self, addr: u16) -> u8 {
-        if !self.ram_enabled || self.ram_size == 0 {
-            return 0;
+    // 0xA200-0xBFFF mirror the 512 entries. Only the low nibble exists, the upper reads as 1s
+    fn ram_read(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
         }
         let addr = ((addr as usize) - 0xA000) & 0x1FF;
-        self.cartridge_ram[addr]
+        self.cartridge_ram[addr] | 0xf0
     }
 
     fn ram_write(&mut self, addr: u16, val: u8) {
-        if !self.ram_enabled || self.ram_size == 0 {
+        if !self.ram_enabled {
             return;
         }
         let addr = ((addr as usize) - 0xA000) & 0x1FF;
-        self.cartridge_ram[addr] = val;
+        self.cartridge_ram[addr] = val & 0x0f;
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.cartridge_ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.cartridge_ram, data);
     }
 }
 
@@ -269,8 +404,30 @@ impl Mbc1 {
     }
 }
 
+impl State for Mbc1 {
+    fn save(&self, w: &mut StateWriter) {
+        self.ram_enabled.save(w);
+        self.rom_bank.save(w);
+        self.ram_bank.save(w);
+        self.banking_mode.save(w);
+        self.cartridge_ram.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.ram_enabled.load(r)?;
+        self.rom_bank.load(r)?;
+        self.ram_bank.load(r)?;
+        self.banking_mode.load(r)?;
+        state::load_buffer(&mut self.cartridge_ram, r)
+    }
+}
+
 impl Mapper for Mbc1 {
-    fn read_bank0(&mut self, addr: u16) -> u8 {
+    fn rom(&self) -> &[u8] {
+        &self.cartridge_rom
+    }
+
+    fn read_bank0(&self, addr: u16) -> u8 {
         let addr = addr as usize;
         if self.banking_mode && self.rom_size > MIB {
             // mode = 1
@@ -284,7 +441,7 @@ impl Mapper for Mbc1 {
 
     // Addr should be between 0x4000 and 0x7FFF
     // bits 19-20: Upper bank, 14-18: bank register, 0-13: from addr
-    fn read_bankn(&mut self, addr: u16) -> u8 {
+    fn read_bankn(&self, addr: u16) -> u8 {
         let addr = addr as usize - 0x4000; // get addr relative to base
         let bank_base = (self.rom_bank as usize) << 14;
         //println!("Addr: {:04X}, bank: {:04X}", addr, self.rom_bank);
@@ -341,9 +498,12 @@ impl Mapper for Mbc1 {
         }
     }
 
-    fn ram_read(&mut self, addr: u16) -> u8 {
+    fn ram_read(&self, addr: u16) -> u8 {
         // make addr relative to base address
         let addr = (addr as usize) - 0xA000;
+        if addr >= self.ram_size {
+            return 0xff;
+        }
         if self.banking_mode && self.ram_size > 512 * KIB {
             // Mode 1
             let bank = (self.ram_bank as usize) << 13;
@@ -353,6 +513,14 @@ impl Mapper for Mbc1 {
             self.cartridge_ram[addr]
         }
     }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.cartridge_ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.cartridge_ram, data);
+    }
 }
 
 pub struct Mbc0 {
@@ -370,12 +538,26 @@ impl Mbc0 {
     }
 }
 
+impl State for Mbc0 {
+    fn save(&self, w: &mut StateWriter) {
+        self.cartridge_ram.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        state::load_buffer(&mut self.cartridge_ram, r)
+    }
+}
+
 impl Mapper for Mbc0 {
-    fn read_bank0(&mut self, addr: u16) -> u8 {
+    fn rom(&self) -> &[u8] {
+        &self.cartridge_rom
+    }
+
+    fn read_bank0(&self, addr: u16) -> u8 {
         self.cartridge_rom[addr as usize]
     }
 
-    fn read_bankn(&mut self, addr: u16) -> u8 {
+    fn read_bankn(&self, addr: u16) -> u8 {
         self.cartridge_rom[addr as usize]
     }
 
@@ -387,11 +569,24 @@ impl Mapper for Mbc0 {
         // do nothing
     }
 
+    // Cartridges without RAM read 0xFF and ignore writes
     fn ram_write(&mut self, addr: u16, val: u8) {
-        self.cartridge_ram[addr as usize] = val;
+        let addr = (addr - 0xA000) as usize;
+        if let Some(byte) = self.cartridge_ram.get_mut(addr) {
+            *byte = val;
+        }
+    }
+
+    fn ram_read(&self, addr: u16) -> u8 {
+        let addr = (addr - 0xA000) as usize;
+        self.cartridge_ram.get(addr).copied().unwrap_or(0xff)
+    }
+
+    fn save_data(&mut self) -> Vec<u8> {
+        self.cartridge_ram.to_vec()
     }
 
-    fn ram_read(&mut self, addr: u16) -> u8 {
-        self.cartridge_ram[addr as usize]
+    fn load_save_data(&mut self, data: &[u8]) {
+        load_ram(&mut self.cartridge_ram, data);
     }
 }
@@ -0,0 +1,339 @@
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+
+use gb_emulator::joypad::JoypadAction;
+
+use crate::frontend::{MAX_SCALE, SCREEN_SCALE};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+const MAX_RECENT_ROMS: usize = 10;
+// How much the volume keys change master_volume by
+const VOLUME_STEP: f32 = 0.1;
+
+// User settings stored at ~/.config/gb_emulator/config.toml
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub keys: KeyBindings,
+    pub palette: Option<String>, // DMG palette preset name or path to a palette file
+    pub scale: u32,              // Screen scale in the window, 1 to MAX_SCALE
+    pub master_volume: f32,      // 0.0 - 1.0 on top of the game's own volume
+    pub muted: bool,
+    pub last_rom: Option<PathBuf>, // The file dialog starts in its directory
+    pub recent_roms: Vec<PathBuf>, // Most recent first
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            keys: KeyBindings::default(),
+            palette: None,
+            scale: SCREEN_SCALE,
+            master_volume: 1.0,
+            muted: false,
+            last_rom: None,
+            recent_roms: Vec::new(),
+        }
+    }
+}
+
+// Anything a key can be bound to
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Action {
+    Button(JoypadAction),
+    Turbo,
+    Rewind,
+    Screenshot,
+    Slower,
+    Faster,
+    Pause,
+    Mute,
+    VolumeDown,
+    VolumeUp,
+}
+
+impl Action {
+    pub const ALL: [Action; 17] = [
+        Action::Button(JoypadAction::Up),
+        Action::Button(JoypadAction::Down),
+        Action::Button(JoypadAction::Left),
+        Action::Button(JoypadAction::Right),
+        Action::Button(JoypadAction::A),
+        Action::Button(JoypadAction::B),
+        Action::Button(JoypadAction::Start),
+        Action::Button(JoypadAction::Select),
+        Action::Turbo,
+        Action::Rewind,
+        Action::Screenshot,
+        Action::Slower,
+        Action::Faster,
+        Action::Pause,
+        Action::Mute,
+        Action::VolumeDown,
+        Action::VolumeUp,
+    ];
+
+    pub fn name(self) -> String {
+        match self {
+            Action::Button(action) => format!("{action:?}"),
+            Action::Turbo => "Turbo".to_string(),
+            Action::Rewind => "Rewind".to_string(),
+            Action::Screenshot => "Screenshot".to_string(),
+            Action::Slower => "Slower".to_string(),
+            Action::Faster => "Faster".to_string(),
+            Action::Pause => "Pause".to_string(),
+            Action::Mute => "Mute".to_string(),
+            Action::VolumeDown => "Volume Down".to_string(),
+            Action::VolumeUp => "Volume Up".to_string(),
+        }
+    }
+}
+
+// Keys are stored by name (egui's names, e.g. "ArrowUp", "Enter", "A") so the file
+// doesn't depend on a frontend. Other frontends translate their key names to these
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub buttons: HashMap<String, JoypadAction>,
+    pub turbo: String,
+    pub rewind: String,
+    pub screenshot: String,
+    pub slower: String,
+    pub faster: String,
+    pub pause: String,
+    pub mute: String,
+    pub volume_down: String,
+    pub volume_up: String,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        let buttons = [
+            ("ArrowDown", JoypadAction::Down),
+            ("ArrowUp", JoypadAction::Up),
+            ("ArrowLeft", JoypadAction::Left),
+            ("ArrowRight", JoypadAction::Right),
+            ("Enter", JoypadAction::Start),
+            ("Space", JoypadAction::Select),
+            ("S", JoypadAction::B),
+            ("A", JoypadAction::A),
+        ];
+        Self {
+            buttons: buttons
+                .into_iter()
+                .map(|(key, action)| (key.to_string(), action))
+                .collect(),
+            turbo: "Tab".to_string(),
+            rewind: "Backspace".to_string(),
+            screenshot: "F12".to_string(),
+            slower: "Comma".to_string(),
+            faster: "Period".to_string(),
+            pause: "P".to_string(),
+            mute: "M".to_string(),
+            volume_down: "Minus".to_string(),
+            volume_up: "Equals".to_string(),
+        }
+    }
+}
+
+impl KeyBindings {
+    // Action bound to the named key, if any
+    pub fn action(&self, key: &str) -> Option<Action> {
+        if key == self.turbo {
+            Some(Action::Turbo)
+        } else if key == self.rewind {
+            Some(Action::Rewind)
+        } else if key == self.screenshot {
+            Some(Action::Screenshot)
+        } else if key == self.slower {
+            Some(Action::Slower)
+        } else if key == self.faster {
+            Some(Action::Faster)
+        } else if key == self.pause {
+            Some(Action::Pause)
+        } else if key == self.mute {
+            Some(Action::Mute)
+        } else if key == self.volume_down {
+            Some(Action::VolumeDown)
+        } else if key == self.volume_up {
+            Some(Action::VolumeUp)
+        } else {
+            self.buttons.get(key).map(|&action| Action::Button(action))
+        }
+    }
+
+    // Replace every key bound to action with the new key
+    pub fn rebind(&mut self, action: Action, key: &str) {
+        self.buttons.remove(key);
+        match action {
+            Action::Button(action) => {
+                self.buttons.retain(|_, bound| *bound != action);
+                self.buttons.insert(key.to_string(), action);
+            }
+            Action::Turbo => self.turbo = key.to_string(),
+            Action::Rewind => self.rewind = key.to_string(),
+            Action::Screenshot => self.screenshot = key.to_string(),
+            Action::Slower => self.slower = key.to_string(),
+            Action::Faster => self.faster = key.to_string(),
+            Action::Pause => self.pause = key.to_string(),
+            Action::Mute => self.mute = key.to_string(),
+            Action::VolumeDown => self.volume_down = key.to_string(),
+            Action::VolumeUp => self.volume_up = key.to_string(),
+        }
+    }
+
+    // Names of the keys bound to action, sorted for display
+    pub fn keys_for(&self, action: Action) -> Vec<&str> {
+        match action {
+            Action::Button(action) => {
+                let mut keys: Vec<&str> = self
+                    .buttons
+                    .iter()
+                    .filter(|(_, bound)| **bound == action)
+                    .map(|(key, _)| key.as_str())
+                    .collect();
+                keys.sort();
+                keys
+            }
+            Action::Turbo => vec![self.turbo.as_str()],
+            Action::Rewind => vec![self.rewind.as_str()],
+            Action::Screenshot => vec![self.screenshot.as_str()],
+            Action::Slower => vec![self.slower.as_str()],
+            Action::Faster => vec![self.faster.as_str()],
+            Action::Pause => vec![self.pause.as_str()],
+            Action::Mute => vec![self.mute.as_str()],
+            Action::VolumeDown => vec![self.volume_down.as_str()],
+            Action::VolumeUp => vec![self.volume_up.as_str()],
+        }
+    }
+
+    // Drop bindings to key names no frontend knows, with a warning
+    fn remove_unknown_keys(&mut self) {
+        self.buttons.retain(|key, action| {
+            let known = egui::Key::from_name(key).is_some();
+            if !known {
+                eprintln!("Ignoring unknown key \"{key}\" bound to {action:?}");
+            }
+            known
+        });
+        let defaults = KeyBindings::default();
+        if egui::Key::from_name(&self.turbo).is_none() {
+            eprintln!("Ignoring unknown key \"{}\" bound to Turbo", self.turbo);
+            self.turbo = defaults.turbo;
+        }
+        if egui::Key::from_name(&self.rewind).is_none() {
+            eprintln!("Ignoring unknown key \"{}\" bound to Rewind", self.rewind);
+            self.rewind = defaults.rewind;
+        }
+        if egui::Key::from_name(&self.screenshot).is_none() {
+            eprintln!(
+                "Ignoring unknown key \"{}\" bound to Screenshot",
+                self.screenshot
+            );
+            self.screenshot = defaults.screenshot;
+        }
+        if egui::Key::from_name(&self.slower).is_none() {
+            eprintln!("Ignoring unknown key \"{}\" bound to Slower", self.slower);
+            self.slower = defaults.slower;
+        }
+        if egui::Key::from_name(&self.faster).is_none() {
+            eprintln!("Ignoring unknown key \"{}\" bound to Faster", self.faster);
+            self.faster = defaults.faster;
+        }
+        if egui::Key::from_name(&self.pause).is_none() {
+            eprintln!("Ignoring unknown key \"{}\" bound to Pause", self.pause);
+            self.pause = defaults.pause;
+        }
+        if egui::Key::from_name(&self.mute).is_none() {
+            eprintln!("Ignoring unknown key \"{}\" bound to Mute", self.mute);
+            self.mute = defaults.mute;
+        }
+        if egui::Key::from_name(&self.volume_down).is_none() {
+            eprintln!(
+                "Ignoring unknown key \"{}\" bound to Volume Down",
+                self.volume_down
+            );
+            self.volume_down = defaults.volume_down;
+        }
+        if egui::Key::from_name(&self.volume_up).is_none() {
+            eprintln!(
+                "Ignoring unknown key \"{}\" bound to Volume Up",
+                self.volume_up
+            );
+            self.volume_up = defaults.volume_up;
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_default();
+        config_home.join("gb_emulator").join("config.toml")
+    }
+
+    // Load the config file, writing out the defaults if there is none.
+    // A malformed file falls back to the defaults rather than stopping the emulator
+    pub fn load() -> Self {
+        let path = Config::path();
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents).unwrap_or_else(|err| {
+                eprintln!("Could not parse {}: {err}", path.display());
+                Config::default()
+            }),
+            Err(_) => {
+                let config = Config::default();
+                if let Err(err) = config.save() {
+                    eprintln!("Could not write {}: {err}", path.display());
+                }
+                config
+            }
+        };
+        config.keys.remove_unknown_keys();
+        config.scale = config.scale.clamp(1, MAX_SCALE);
+        if !(0.0..=1.0).contains(&config.master_volume) {
+            config.master_volume = 1.0;
+        }
+        config
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let path = Config::path();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = toml::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, contents)
+    }
+
+    // Move a ROM that was just opened to the front of the recent list
+    pub fn add_recent_rom(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.recent_roms.retain(|recent| *recent != path);
+        self.recent_roms.insert(0, path.clone());
+        self.recent_roms.truncate(MAX_RECENT_ROMS);
+        self.last_rom = Some(path);
+    }
+
+    // Step master_volume up or down by VOLUME_STEP. Changing the volume unmutes
+    pub fn step_volume(&mut self, up: bool) {
+        let step = if up { VOLUME_STEP } else { -VOLUME_STEP };
+        // Rounded so repeated steps land back on whole percentages
+        self.master_volume = ((self.master_volume + step).clamp(0.0, 1.0) * 100.0).round() / 100.0;
+        self.muted = false;
+    }
+
+    // Gain the emulator applies to its audio, 0 while muted
+    pub fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.master_volume
+        }
+    }
+}
@@ -1,9 +1,17 @@
 use bitflags::bitflags;
-use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "profiler")]
+use std::collections::HashMap;
+#[cfg(feature = "stack_check")]
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::io::{self, Write};
 
 use crate::bus::{Bus, Interrupt};
+use crate::debugger::Debugger;
+use crate::disasm;
 use crate::opcodes::{self, Opcode, TargetReg};
 use crate::render;
+use crate::state::{State, StateError, StateReader, StateWriter};
 use crate::trace;
 
 bitflags! {
@@ -35,14 +43,83 @@ pub struct Cpu {
     pub bus: Bus,
     pub prefixed_mode: bool,
     pub halted: bool,
+    pub stopped: bool, // STOP executed. Nothing runs until a selected joypad line goes low
+    pub locked: bool,  // An unused opcode ran. The CPU hangs until reset as on hardware
     pub frame_ready: bool,
-    cycles: u8,
+    jumped: bool, // This step's instruction set PC itself, so its length isn't added on
+    cycles: u8,   // Extra M-cycles taken by this step (branches taken, interrupt dispatch)
+    access_cycles: u8, // M-cycles the bus has already been ticked for during this step
     pub prev_instrs: VecDeque<String>,
+    pub debugger: Debugger,
+    pub trace_writer: Option<Box<dyn Write + Send>>, // Where traces go. stderr if None
+    pub stack_top: u16, // SP as last set by LD SP, n16. Not saved, only used to estimate depth
+    pub call_stack: Vec<(u16, u16)>, // (call site, return address) of calls not yet returned from
+    pub untracked_calls: usize, // Calls deeper than CALL_STACK_DEPTH, only counted
+    #[cfg(feature = "stack_check")]
+    stack_warned: HashSet<u16>, // Instructions already reported, so a loop doesn't flood the log
+    #[cfg(feature = "profiler")]
+    pub profiler: HashMap<u8, u64>, // Times each opcode has run
+    #[cfg(feature = "profiler")]
+    pub prefixed_profiler: HashMap<u8, u64>, // Same for CB prefixed opcodes
+}
+
+// Instruction history and the debugger are GUI only and not saved
+impl State for Cpu {
+    fn save(&self, w: &mut StateWriter) {
+        self.a.save(w);
+        self.b.save(w);
+        self.c.save(w);
+        self.d.save(w);
+        self.e.save(w);
+        self.flags.bits().save(w);
+        self.h.save(w);
+        self.l.save(w);
+        self.stack_pointer.save(w);
+        self.program_counter.save(w);
+        self.ime.save(w);
+        self.halted.save(w);
+        self.stopped.save(w);
+        self.locked.save(w);
+        self.prefixed_mode.save(w);
+        self.frame_ready.save(w);
+        self.cycles.save(w);
+        self.access_cycles.save(w);
+        self.bus.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.a.load(r)?;
+        self.b.load(r)?;
+        self.c.load(r)?;
+        self.d.load(r)?;
+        self.e.load(r)?;
+        self.flags = CpuFlag::from_bits_retain(r.read()?);
+        self.h.load(r)?;
+        self.l.load(r)?;
+        self.stack_pointer.load(r)?;
+        self.program_counter.load(r)?;
+        self.ime.load(r)?;
+        self.halted.load(r)?;
+        self.stopped.load(r)?;
+        self.locked.load(r)?;
+        self.prefixed_mode.load(r)?;
+        self.frame_ready.load(r)?;
+        self.cycles.load(r)?;
+        self.access_cycles.load(r)?;
+        self.bus.load(r)?;
+        Ok(())
+    }
 }
 
 impl Cpu {
+    const INSTR_HISTORY: usize = 25;
+    pub const CALL_STACK_DEPTH: usize = 64;
+
+    // With a boot ROM everything starts at 0 and the boot ROM sets things up itself.
+    // Otherwise power_on_defaults puts the machine in the state the boot ROM would hand over
     pub fn new(bus: Bus) -> Self {
-        Self {
+        let boot = bus.boot_rom_active;
+        let mut cpu = Self {
             a: 0,
             b: 0,
             c: 0,
@@ -51,16 +128,85 @@ impl Cpu {
             flags: CpuFlag::empty(),
             h: 0,
             l: 0,
-            stack_pointer: 0xfffe,
-            program_counter: 0x0100,
+            stack_pointer: 0,
+            program_counter: 0,
             ime: false,
             bus,
             halted: false,
+            stopped: false,
+            locked: false,
             prefixed_mode: false,
             frame_ready: false,
+            jumped: false,
             cycles: 0,
+            access_cycles: 0,
             prev_instrs: VecDeque::new(),
+            debugger: Debugger::new(),
+            trace_writer: None,
+            stack_top: 0xfffe,
+            call_stack: Vec::new(),
+            untracked_calls: 0,
+            #[cfg(feature = "stack_check")]
+            stack_warned: HashSet::new(),
+            #[cfg(feature = "profiler")]
+            profiler: HashMap::new(),
+            #[cfg(feature = "profiler")]
+            prefixed_profiler: HashMap::new(),
+        };
+        if !boot {
+            cpu.power_on_defaults();
+        }
+        cpu
+    }
+
+    // Registers and IO as the DMG or CGB boot ROM hands over to the cartridge at 0x0100
+    pub fn power_on_defaults(&mut self) {
+        if self.bus.cgb {
+            // Games check A == 0x11 to detect CGB hardware
+            self.a = 0x11;
+            self.flags = CpuFlag::from_bits_retain(0x80);
+            self.set_bc(0x0000);
+            self.set_de(0xff56);
+            self.set_hl(0x000d);
+        } else {
+            self.a = 0x01;
+            self.flags = CpuFlag::from_bits_retain(0xb0);
+            self.set_bc(0x0013);
+            self.set_de(0x00d8);
+            self.set_hl(0x014d);
+            self.bus.timer.internal_counter = 0xabcc;
+        }
+        self.stack_pointer = 0xfffe;
+        self.program_counter = 0x0100;
+
+        self.bus.mem_write(0xFF26, 0x80); // NR52: APU on
+        self.bus.mem_write(0xFF40, 0x91); // LCDC: LCD and BG on, tiles at 0x8000
+        self.bus.mem_write(0xFF47, 0xfc); // BGP
+        self.bus.interrupt_flag = Interrupt::vblank;
+    }
+
+    // Snapshot of the whole machine. ROM contents are not included, only a hash of them
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new(self.bus.rom_hash);
+        self.save(&mut w);
+        w.data
+    }
+
+    // The header is checked before anything is overwritten. A truncated or corrupt state
+    // fails part way through, so the machine is put back as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data, self.bus.rom_hash)?;
+        let backup = self.save_state();
+        if let Err(err) = self.load(&mut r) {
+            StateReader::new(&backup, self.bus.rom_hash)
+                .and_then(|mut r| self.load(&mut r))
+                .expect("a state that was just saved loads back");
+            return Err(err);
         }
+        // Calls made before the state was saved can't be known
+        self.call_stack.clear();
+        self.untracked_calls = 0;
+        Ok(())
     }
 
     pub fn get_bc(&self) -> u16 {
@@ -100,9 +246,49 @@ impl Cpu {
         ((self.a as u16) << 8) | self.flags.bits() as u16
     }
 
+    // Every memory access takes one M-cycle, and the rest of the machine is advanced
+    // before it so reads see timer, PPU and DMA state from the right point in the instruction
+    fn tick(&mut self, cycles: u8) {
+        self.frame_ready |= self.bus.tick(cycles);
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        self.tick(1);
+        self.access_cycles += 1;
+        let val = self.bus.mem_read(addr);
+        self.debugger
+            .check_access(self.program_counter, addr, val, false);
+        val
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        self.tick(1);
+        self.access_cycles += 1;
+        self.debugger
+            .check_access(self.program_counter, addr, val, true);
+        self.bus.mem_write(addr, val);
+    }
+
+    fn read_u16(&mut self, addr: u16) -> u16 {
+        let lo = self.read(addr);
+        let hi = self.read(addr.wrapping_add(1));
+        u16::from_le_bytes([lo, hi])
+    }
+
+    fn write_u16(&mut self, addr: u16, val: u16) {
+        let [lo, hi] = val.to_le_bytes();
+        self.write(addr, lo);
+        self.write(addr.wrapping_add(1), hi);
+    }
+
     fn push_u8_to_stack(&mut self, val: u8) {
+        // Anything at or below 0xC000 pushes outside of WRAM and HRAM
+        #[cfg(feature = "stack_check")]
+        if self.stack_pointer <= 0xc000 {
+            self.stack_warning("overflow");
+        }
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
-        self.bus.mem_write(self.stack_pointer, val);
+        self.write(self.stack_pointer, val);
     }
 
     fn push_u16_to_stack(&mut self, val: u16) {
@@ -111,12 +297,82 @@ impl Cpu {
         self.push_u8_to_stack(lo);
     }
 
+    fn jump(&mut self, addr: u16) {
+        self.program_counter = addr;
+        self.jumped = true;
+    }
+
+    // JR offsets count from the end of the 2 byte instruction
+    fn jump_relative(&mut self, offset: i8) {
+        self.jump(
+            self.program_counter
+                .wrapping_add(2)
+                .wrapping_add_signed(offset as i16),
+        );
+    }
+
     fn pop_u16_from_stack(&mut self) -> u16 {
-        let val = self.bus.mem_read_u16(self.stack_pointer);
+        // Popping from 0xFFFD up reads IE or wraps past the top of memory
+        #[cfg(feature = "stack_check")]
+        if self.stack_pointer > 0xfffc {
+            self.stack_warning("underflow");
+        }
+        let val = self.read_u16(self.stack_pointer);
         self.stack_pointer = self.stack_pointer.wrapping_add(2);
         val
     }
 
+    // Games do move SP around and POP from ROM on purpose, so this only reports
+    #[cfg(feature = "stack_check")]
+    fn stack_warning(&mut self, kind: &str) {
+        let pc = self.program_counter;
+        if !self.stack_warned.insert(pc) {
+            return;
+        }
+        let (asm, _) = disasm::disassemble(&self.bus, pc);
+        eprintln!(
+            "Stack {kind} at PC {pc:04X} ({asm}), SP {:04X}",
+            self.stack_pointer
+        );
+    }
+
+    // Rough number of 2 byte entries (return addresses, pushed registers) on the stack
+    pub fn stack_depth(&self) -> usize {
+        if self.stack_pointer > self.stack_top {
+            return 0;
+        }
+        (self.stack_top - self.stack_pointer) as usize / 2
+    }
+
+    // CALL, RST and interrupts push an entry, RET and RETI pop one. Games that return with
+    // JP or pop the return address themselves will leave stale entries behind
+    fn call_pushed(&mut self, site: u16, return_addr: u16) {
+        if self.call_stack.len() < Cpu::CALL_STACK_DEPTH {
+            self.call_stack.push((site, return_addr));
+        } else {
+            self.untracked_calls += 1;
+        }
+    }
+
+    fn call_returned(&mut self) {
+        if self.untracked_calls > 0 {
+            self.untracked_calls -= 1;
+        } else {
+            self.call_stack.pop();
+        }
+    }
+
+    // Shared by JP, JR, CALL and RET cc. 0: NZ, 1: Z, 2: NC, 3: C
+    fn condition(&self, cond: u8) -> bool {
+        match cond {
+            0 => !self.flags.contains(CpuFlag::zero),
+            1 => self.flags.contains(CpuFlag::zero),
+            2 => !self.flags.contains(CpuFlag::carry),
+            3 => self.flags.contains(CpuFlag::carry),
+            _ => panic!("Condition Codes are 0-3. Received {cond}"),
+        }
+    }
+
     fn r8_read(&mut self, reg: u8) -> u8 {
         match reg {
             0 => self.b,
@@ -125,7 +381,7 @@ impl Cpu {
             3 => self.e,
             4 => self.h,
             5 => self.l,
-            6 => self.bus.mem_read(self.get_hl()),
+            6 => self.read(self.get_hl()),
             7 => self.a,
             _ => panic!("Invalid r8 Register: {reg}"),
         }
@@ -155,21 +411,21 @@ impl Cpu {
         match reg {
             0 => {
                 let addr = self.get_bc();
-                self.bus.mem_read(addr)
+                self.read(addr)
             }
             1 => {
                 let addr = self.get_de();
-                self.bus.mem_read(addr)
+                self.read(addr)
             }
             2 => {
                 let addr = self.get_hl();
-                let val = self.bus.mem_read(addr);
+                let val = self.read(addr);
                 self.set_hl(addr.wrapping_add(1));
                 val
             }
             3 => {
                 let addr = self.get_hl();
-                let val = self.bus.mem_read(addr);
+                let val = self.read(addr);
                 self.set_hl(addr.wrapping_sub(1));
                 val
             }
@@ -200,7 +456,7 @@ impl Cpu {
             4 => self.h = value,
             5 => self.l = value,
             6 => {
-                self.bus.mem_write(self.get_hl(), value);
+                self.write(self.get_hl(), value);
             }
             7 => self.a = value,
             _ => panic!("Impossible State. No reg value {reg}"),
@@ -230,89 +486,140 @@ impl Cpu {
     fn r16mem_write(&mut self, reg: u8, value: u8) {
         match reg {
             0 => {
-                self.bus.mem_write(self.get_bc(), value);
+                self.write(self.get_bc(), value);
             }
             1 => {
-                self.bus.mem_write(self.get_de(), value);
+                self.write(self.get_de(), value);
             }
             2 => {
                 let addr = self.get_hl();
-                self.bus.mem_write(addr, value);
+                self.write(addr, value);
                 self.set_hl(addr.wrapping_add(1));
             }
             3 => {
                 let addr = self.get_hl();
-                self.bus.mem_write(addr, value);
+                self.write(addr, value);
                 self.set_hl(addr.wrapping_sub(1));
             }
             _ => panic!("Invalid State. No r16mem value {reg}"),
         }
     }
 
+    // Highest priority interrupt both requested in IF and enabled in IE. Vblank is bit 0
+    // and highest, joypad is bit 4 and lowest
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        let pending = (self.bus.interrupt_flag.bits() & self.bus.interrupt_enable.bits()) & 0x1f;
+        (pending != 0).then(|| Interrupt::from_bits_retain(1 << pending.trailing_zeros()))
+    }
+
     fn interrupt_check(&mut self) {
         // Interrupt is serviced if IME is set and bit is set in both IE and IF flags
-        let vblank_interrupt = self.bus.vblank_flag() && self.bus.vblank_enabled();
-        let lcd_interrupt = self.bus.lcd_flag() && self.bus.lcd_enabled();
-        let timer_interrupt = self.bus.timer_flag() && self.bus.timer_enabled();
-        let serial_interrupt = self.bus.serial_flag() && self.bus.serial_enabled();
-        let joypad_interrupt = self.bus.joypad_flag() && self.bus.joypad_enabled();
-
-        let interrupt_pending = vblank_interrupt
-            || lcd_interrupt
-            || timer_interrupt
-            || serial_interrupt
-            || joypad_interrupt;
-
-        // Vblank has highest priority, Joypad has lowest priority. Only handle one interrupt at a time
+        let interrupt_pending = self.pending_interrupt().is_some();
+
         // Turn off interrupts then handle the current interrupt by priority
-        match (self.halted, self.ime, interrupt_pending) {
-            (_, _, false) => {}
+        let return_addr = match (self.halted, self.ime, interrupt_pending) {
+            (_, _, false) => return,
             (false, false, true) => {
                 return; // return early to avoid interrupt handling this case
             }
             (true, true, true) => {
-                self.ime = false;
                 self.halted = false;
-                self.push_u16_to_stack(self.program_counter + 1);
-                self.cycles += 5;
-            }
-            (false, true, true) => {
-                self.ime = false;
-                self.push_u16_to_stack(self.program_counter);
-                self.cycles += 5;
+                self.program_counter.wrapping_add(1)
             }
+            (false, true, true) => self.program_counter,
             (true, false, true) => {
                 self.halted = false;
-                self.program_counter += 1;
+                self.program_counter = self.program_counter.wrapping_add(1);
                 return; // return early to avoid interrupt handling this case
             }
+        };
+        self.ime = false;
+        self.cycles += 5;
+
+        // The vector is only picked after the high byte of PC is pushed. With SP at 0x0000
+        // that push lands on IE and can cancel the interrupt, in which case PC goes to 0x0000
+        let [lo, hi] = return_addr.to_le_bytes();
+        self.push_u8_to_stack(hi);
+        let interrupt = self.pending_interrupt();
+        self.push_u8_to_stack(lo);
+        self.call_pushed(self.program_counter, return_addr);
+
+        self.program_counter = match interrupt {
+            Some(interrupt) => {
+                let vector = 0x0040 + 8 * interrupt.bits().trailing_zeros() as u16;
+                self.bus.interrupt_flag.remove(interrupt);
+                vector
+            }
+            None => 0x0000,
+        };
+        self.debugger.check_interrupt(self.program_counter);
+    }
+
+    pub fn register_string(&self) -> String {
+        format!(
+            "AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}",
+            self.get_af(),
+            self.get_bc(),
+            self.get_de(),
+            self.get_hl(),
+            self.stack_pointer
+        )
+    }
+
+    // Keep the last few instructions for the debugger panel, newest first
+    fn record_instr(&mut self) {
+        let pc = self.program_counter;
+        let (asm, len) = disasm::disassemble(&self.bus, pc);
+        let bytes = disasm::instruction_bytes(&self.bus, pc, len);
+        let instr_string = format!(
+            "{pc:04X}    {bytes:<8}  {asm:<16}  {}",
+            self.register_string()
+        );
+        self.prev_instrs.push_front(instr_string);
+        if self.prev_instrs.len() > Cpu::INSTR_HISTORY {
+            let _ = self.prev_instrs.pop_back();
         }
+    }
 
-        // Interrupt handler
-        if vblank_interrupt {
-            self.bus.interrupt_flag.set(Interrupt::vblank, false);
-            self.program_counter = 0x0040;
-        } else if lcd_interrupt {
-            self.bus.interrupt_flag.set(Interrupt::lcd, false);
-            self.program_counter = 0x0048;
-        } else if timer_interrupt {
-            self.bus.interrupt_flag.set(Interrupt::timer, false);
-            self.program_counter = 0x0050;
-        } else if serial_interrupt {
-            self.bus.interrupt_flag.set(Interrupt::serial, false);
-            self.program_counter = 0x0058;
-        } else if joypad_interrupt {
-            self.bus.interrupt_flag.set(Interrupt::joypad, false);
-            self.program_counter = 0x0060;
+    // DIV is reset when entering STOP. This is also where a CGB speed switch
+    // armed through KEY1 takes place instead of stopping
+    fn stop(&mut self) {
+        self.bus.timer.div_write();
+        if self.bus.cgb && self.bus.speed_prepare {
+            self.bus.switch_speed();
+        } else {
+            self.stopped = true;
         }
     }
 
+    // Joypad lines are active low. Any selected button held down ends STOP, as does a press
+    // that raised the joypad interrupt even if it was released again before this step
+    fn stop_wake(&mut self) -> bool {
+        if !self.bus.joypad.interrupt && self.bus.joypad.read() & 0x0f == 0x0f {
+            return false;
+        }
+        self.stopped = false;
+        true
+    }
+
     // Main CPU step. Fetch instruction, decode and execute.
-    // Tell bus how much to step the ppu and apu.
+    // The bus is ticked on every memory access, then for any internal cycles left over.
     pub fn step<F>(&mut self, mut callback: F) -> Option<&render::Frame>
     where
         F: FnMut(&mut Cpu),
     {
+        self.frame_ready = false;
+
+        if self.stopped && !self.stop_wake() {
+            return self.bus.tick_stopped(1).then_some(&self.bus.last_frame);
+        }
+
+        // Interrupts can't get the CPU out of a lock up but the rest of the machine runs on
+        if self.locked {
+            self.tick(1);
+            return self.frame_ready.then_some(&self.bus.last_frame);
+        }
+
         // check for interrupts or halt
         self.interrupt_check();
 
@@ -320,94 +627,69 @@ impl Cpu {
 
         // Get opcode from prefixed or regular
         let (cycles, bytes) = if self.prefixed_mode {
-            let opcodes: &HashMap<u8, Opcode> = &opcodes::CPU_PREFIXED_OP_CODES;
-            let opcode_num = self.bus.mem_read(self.program_counter + 1);
-            let opcode = opcodes.get(&opcode_num).unwrap();
-
-            // Record CPU Instrs for display in GUI
-            let mut opcode_as_bytes = Vec::new();
-            for i in 1..opcode.bytes {
-                opcode_as_bytes.push(self.bus.mem_read(self.program_counter.wrapping_add(i)));
-            }
-
-            let mut opcode_format = format!("{opcode_num:02X}");
-            // Todo: Add Assembly style format of the opcode and values
-            // let mut asm_format = format!("{}", opcode.name);
-            if let Some(first_byte) = opcode_as_bytes.first() {
-                opcode_format = format!("{opcode_format} {first_byte:02X}");
-            }
-            if let Some(second_byte) = opcode_as_bytes.get(1) {
-                opcode_format = format!("{opcode_format} {second_byte:02X}");
+            let opcode_num = self.read(self.program_counter.wrapping_add(1));
+            let opcode = opcodes::lookup_prefixed(opcode_num)
+                .expect("every CB prefixed opcode is in the table");
+            #[cfg(feature = "profiler")]
+            {
+                *self.prefixed_profiler.entry(opcode_num).or_insert(0) += 1;
             }
 
-            let instr_string = format!(
-            "{:04X}    {:<8}  {:<5}  AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}",
-            self.program_counter,
-            opcode_format,
-            opcode.name,
-            self.get_af(),
-            self.get_bc(),
-            self.get_de(),
-            self.get_hl(),
-            self.stack_pointer
-        );
-            self.prev_instrs.push_front(instr_string);
-            if self.prev_instrs.len() > 25 {
-                let _ = self.prev_instrs.pop_back();
-            }
-            // End GUI stuff
-
             self.prefixed_mode = false;
             self.prefixed_opcodes(opcode_num, opcode);
             (opcode.cycles, opcode.bytes)
         } else {
-            let opcodes: &HashMap<u8, Opcode> = &opcodes::CPU_OP_CODES;
-            let opcode_num = self.bus.mem_read(self.program_counter);
-            let opcode = opcodes
-                .get(&opcode_num)
-                .unwrap_or_else(|| panic!("Invalid opcode received: {opcode_num:02X}"));
-
-            // Record CPU Instrs for display in GUI
-            let mut opcode_as_bytes = Vec::new();
-            for i in 1..opcode.bytes {
-                opcode_as_bytes.push(self.bus.mem_read(self.program_counter.wrapping_add(i)));
-            }
-
-            let mut opcode_format = format!("{opcode_num:02X}");
-            // Todo: Add Assembly style format of the opcode and values
-            // let mut asm_format = format!("{}", opcode.name);
-            if let Some(first_byte) = opcode_as_bytes.first() {
-                opcode_format = format!("{opcode_format} {first_byte:02X}");
-            }
-            if let Some(second_byte) = opcode_as_bytes.get(1) {
-                opcode_format = format!("{opcode_format} {second_byte:02X}");
-            }
-
-            let instr_string = format!(
-            "{:04X}    {:<8}  {:<5}  AF: {:04X}, BC: {:04X}, DE: {:04X}, HL: {:04X}, SP: {:04X}",
-            self.program_counter,
-            opcode_format,
-            opcode.name,
-            self.get_af(),
-            self.get_bc(),
-            self.get_de(),
-            self.get_hl(),
-            self.stack_pointer
-        );
-            self.prev_instrs.push_front(instr_string);
-            if self.prev_instrs.len() > 25 {
-                let _ = self.prev_instrs.pop_back();
-            }
-            // End GUI stuff
+            let opcode_num = self.read(self.program_counter);
+            let Some(opcode) = opcodes::lookup(opcode_num) else {
+                eprintln!(
+                    "Unused opcode {opcode_num:02X} at {:04X}, CPU locked up",
+                    self.program_counter
+                );
+                self.locked = true;
+                return self.frame_ready.then_some(&self.bus.last_frame);
+            };
+            #[cfg(feature = "profiler")]
+            {
+                *self.profiler.entry(opcode_num).or_insert(0) += 1;
+            }
+
+            // Record CPU Instrs for display in GUI. CB instructions are recorded whole
+            // with their prefix, so the prefixed step doesn't add another
+            self.record_instr();
 
             self.non_prefixed_opcodes(opcode_num, opcode);
             (opcode.cycles, opcode.bytes)
         };
 
-        self.frame_ready = self.bus.tick(cycles + self.cycles);
+        // Internal cycles with no memory access are run at the end. The CB prefix byte is
+        // fetched in its own step but counted in the prefixed opcode's cycles, so any surplus
+        // carries over to the next step
+        let cycles = cycles + self.cycles;
+        if self.access_cycles < cycles {
+            self.tick(cycles - self.access_cycles);
+            self.access_cycles = 0;
+        } else {
+            self.access_cycles -= cycles;
+        }
         self.cycles = 0;
 
-        self.program_counter = self.program_counter.wrapping_add(bytes);
+        // The CPU sits out VRAM DMA copies started since the last step
+        while self.bus.hdma_stall > 0 {
+            self.bus.hdma_stall -= 1;
+            self.tick(1);
+        }
+
+        // Jumps, calls and returns have already set PC
+        if !self.jumped {
+            self.program_counter = self.program_counter.wrapping_add(bytes);
+        }
+        self.jumped = false;
+
+        // Stop before the next instruction runs. The CB prefix leaves PC on itself so
+        // only check once the whole instruction is done
+        if !self.prefixed_mode && !self.halted {
+            self.debugger.check_pc(self.program_counter);
+        }
 
         // check if frame is ready to display
         if self.frame_ready {
@@ -417,18 +699,53 @@ impl Cpu {
         }
     }
 
+    #[allow(dead_code)]
     pub fn run(&mut self) {
         loop {
             let _ = self.step(|_| {});
         }
     }
 
+    // Print how often each opcode has run, most frequent first. CB prefixed opcodes are
+    // counted separately, the prefix itself shows up as CB in the main table
+    #[cfg(feature = "profiler")]
+    pub fn print_profile(&self) {
+        print_profile_table(&self.profiler, &opcodes::CPU_OP_CODES, "");
+        print_profile_table(
+            &self.prefixed_profiler,
+            &opcodes::CPU_PREFIXED_OP_CODES,
+            "CB ",
+        );
+    }
+
     pub fn step_with_trace(&mut self) -> Option<&render::Frame> {
         self.step(|cpu| {
-            trace::trace_cpu(cpu);
+            // Taken out while tracing since the trace needs the whole Cpu
+            let result = match cpu.trace_writer.take() {
+                Some(mut out) => {
+                    let result = trace::trace_cpu(cpu, &mut out);
+                    if result.is_ok() {
+                        cpu.trace_writer = Some(out);
+                    }
+                    result
+                }
+                None => trace::trace_cpu(cpu, &mut io::stderr().lock()),
+            };
+            if let Err(err) = result {
+                eprintln!("Could not write trace, sending it to stderr from now on: {err}");
+            }
         })
     }
 
+    // Buffered trace files only reach the disk once flushed
+    pub fn flush_trace(&mut self) {
+        if let Some(out) = &mut self.trace_writer {
+            if let Err(err) = out.flush() {
+                eprintln!("Could not flush trace: {err}");
+            }
+        }
+    }
+
     fn prefixed_opcodes(&mut self, byte: u8, opcode: &Opcode) {
         match byte {
             // bit u3, r8
@@ -603,7 +920,7 @@ impl Cpu {
             }
             // ADC A, imm8
             0xce => {
-                let arg = self.bus.mem_read(self.program_counter + 1);
+                let arg = self.read(self.program_counter.wrapping_add(1));
                 let sum = self.add_u8(self.a, arg, true);
 
                 self.a = sum;
@@ -620,14 +937,14 @@ impl Cpu {
             }
             // ADD A, imm8
             0xc6 => {
-                let arg = self.bus.mem_read(self.program_counter + 1);
+                let arg = self.read(self.program_counter.wrapping_add(1));
                 let sum = self.add_u8(self.a, arg, false);
 
                 self.a = sum;
             }
             // ADD SP, e8
             0xe8 => {
-                let arg = self.bus.mem_read(self.program_counter + 1);
+                let arg = self.read(self.program_counter.wrapping_add(1));
                 self.stack_pointer = self.add_e8(self.stack_pointer, arg);
                 self.flags.remove(CpuFlag::zero);
                 self.flags.remove(CpuFlag::subtraction);
@@ -638,7 +955,7 @@ impl Cpu {
                     panic!("Opcode needs R16 but it is not")
                 };
                 let arg = self.r16_read(*reg);
-                let sum = self.add_u16(self.get_hl(), arg, false);
+                let sum = self.add_u16(self.get_hl(), arg);
 
                 self.set_hl(sum);
             }
@@ -657,7 +974,7 @@ impl Cpu {
             }
             // AND A, imm8
             0xe6 => {
-                let arg = self.bus.mem_read(self.program_counter + 1);
+                let arg = self.read(self.program_counter.wrapping_add(1));
                 self.a &= arg;
 
                 self.flags.set(CpuFlag::zero, self.a == 0);
@@ -667,28 +984,24 @@ impl Cpu {
             }
             // CALL
             0xcd => {
-                let addr = self.bus.mem_read_u16(self.program_counter + 1);
+                let addr = self.read_u16(self.program_counter.wrapping_add(1));
                 self.push_u16_to_stack(self.program_counter.wrapping_add(3));
-                self.program_counter = addr.wrapping_sub(3);
+                self.call_pushed(self.program_counter, self.program_counter.wrapping_add(3));
+                self.jump(addr);
             }
             // CALL cc
             0xc4 | 0xcc | 0xd4 | 0xdc => {
                 let TargetReg::Cond(condition) = &opcode.reg1 else {
                     panic!("Expected Cond register")
                 };
-                let should_execute = match condition {
-                    0 => !self.flags.contains(CpuFlag::zero), // Cond(0) => zero flags is not set
-                    1 => self.flags.contains(CpuFlag::zero),  // Cond(1) => zero flag is set
-                    2 => !self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is set
-                    3 => self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is set
-                    _ => panic!("Condition Codes are 0-3. Received {condition}"),
-                };
+                let should_execute = self.condition(*condition);
                 if should_execute {
                     // inc cycle count
                     self.cycles += 3;
-                    let addr = self.bus.mem_read_u16(self.program_counter + 1);
+                    let addr = self.read_u16(self.program_counter.wrapping_add(1));
                     self.push_u16_to_stack(self.program_counter.wrapping_add(3));
-                    self.program_counter = addr.wrapping_sub(3);
+                    self.call_pushed(self.program_counter, self.program_counter.wrapping_add(3));
+                    self.jump(addr);
                 }
             }
             // CCF
@@ -707,7 +1020,7 @@ impl Cpu {
             }
             // CP A, imm8
             0xfe => {
-                let val = self.bus.mem_read(self.program_counter + 1);
+                let val = self.read(self.program_counter.wrapping_add(1));
                 let _result = self.sub_u8(self.a, val, false);
             }
             // CPL
@@ -800,53 +1113,42 @@ impl Cpu {
             }
             // JP
             0xc3 => {
-                let addr = self.bus.mem_read_u16(self.program_counter + 1);
-                self.program_counter = addr.wrapping_sub(3); // Subtract 3 bytes to account for the addition of 3 bytes from the JP opcode
+                let addr = self.read_u16(self.program_counter.wrapping_add(1));
+                self.jump(addr);
             }
             // JP HL
             0xe9 => {
-                self.program_counter = (self.get_hl()).wrapping_sub(1);
+                self.jump(self.get_hl());
             }
             // JP cc
             0xc2 | 0xca | 0xd2 | 0xda => {
                 let TargetReg::Cond(condition) = &opcode.reg1 else {
                     panic!("Expected Cond register")
                 };
-                let should_execute = match condition {
-                    0 => !self.flags.contains(CpuFlag::zero), // Cond(0) => zero flags is not set
-                    1 => self.flags.contains(CpuFlag::zero),  // Cond(1) => zero flag is set
-                    2 => !self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is set
-                    3 => self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is set
-                    _ => panic!("Condition Codes are 0-3. Received {condition}"),
-                };
+                let should_execute = self.condition(*condition);
                 if should_execute {
                     // inc cycle count
                     self.cycles += 1;
-                    self.program_counter = self.bus.mem_read_u16(self.program_counter + 1) - 3;
+                    let addr = self.read_u16(self.program_counter.wrapping_add(1));
+                    self.jump(addr);
                 }
             }
             // JR imm8
             0x18 => {
-                let offset = self.bus.mem_read(self.program_counter + 1) as i8;
-                self.program_counter = self.program_counter.wrapping_add_signed(offset as i16);
+                let offset = self.read(self.program_counter.wrapping_add(1)) as i8;
+                self.jump_relative(offset);
             }
             // JR cc, imm8
             0x20 | 0x28 | 0x30 | 0x38 => {
-                let offset = self.bus.mem_read(self.program_counter + 1) as i8;
+                let offset = self.read(self.program_counter.wrapping_add(1)) as i8;
                 let TargetReg::Cond(condition) = &opcode.reg1 else {
                     panic!("Expected Cond register")
                 };
-                let should_execute = match condition {
-                    0 => !self.flags.contains(CpuFlag::zero), // Cond(0) => zero flags is not set
-                    1 => self.flags.contains(CpuFlag::zero),  // Cond(1) => zero flag is set
-                    2 => !self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is not set
-                    3 => self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is set
-                    _ => panic!("Condition Codes are 0-3. Received {condition}"),
-                };
+                let should_execute = self.condition(*condition);
                 if should_execute {
                     // inc cycle count
                     self.cycles += 1;
-                    self.program_counter = self.program_counter.wrapping_add_signed(offset as i16);
+                    self.jump_relative(offset);
                 }
             }
             // LD r8, r8
@@ -862,27 +1164,30 @@ impl Cpu {
             }
             // LD r16, imm16
             0x01 | 0x11 | 0x21 | 0x31 => {
-                let val = self.bus.mem_read_u16(self.program_counter + 1);
+                let val = self.read_u16(self.program_counter.wrapping_add(1));
                 let TargetReg::R16(reg) = &opcode.reg1 else {
                     panic!("Opcode needs R16 but it is not")
                 };
                 self.r16_write(*reg, val);
+                if byte == 0x31 {
+                    self.stack_top = val;
+                }
             }
             // LD A, imm16
             0xfa => {
-                let addr = self.bus.mem_read_u16(self.program_counter + 1);
-                let val = self.bus.mem_read(addr);
+                let addr = self.read_u16(self.program_counter.wrapping_add(1));
+                let val = self.read(addr);
                 self.a = val;
             }
             // LD imm16, A
             0xea => {
-                let addr = self.bus.mem_read_u16(self.program_counter + 1);
-                self.bus.mem_write(addr, self.a);
+                let addr = self.read_u16(self.program_counter.wrapping_add(1));
+                self.write(addr, self.a);
             }
             // LD imm16, SP
             0x08 => {
-                let addr = self.bus.mem_read_u16(self.program_counter + 1);
-                self.bus.mem_write_u16(addr, self.stack_pointer);
+                let addr = self.read_u16(self.program_counter.wrapping_add(1));
+                self.write_u16(addr, self.stack_pointer);
             }
             // LD SP, HL
             0xf9 => {
@@ -905,7 +1210,7 @@ impl Cpu {
             }
             // LD r8, imm8
             0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e => {
-                let val = self.bus.mem_read(self.program_counter + 1);
+                let val = self.read(self.program_counter.wrapping_add(1));
                 let TargetReg::R8(reg) = &opcode.reg1 else {
                     panic!("Opcode needs R8 but it is not")
                 };
@@ -913,7 +1218,7 @@ impl Cpu {
             }
             // ld hl, sp + imm8
             0xf8 => {
-                let offset = self.bus.mem_read(self.program_counter + 1);
+                let offset = self.read(self.program_counter.wrapping_add(1));
                 let sum = self.add_e8(self.stack_pointer, offset);
                 self.set_hl(sum);
                 self.flags.set(CpuFlag::zero, false);
@@ -922,22 +1227,22 @@ impl Cpu {
             // LDH [C], A
             0xe2 => {
                 let addr = 0xff00 + self.c as u16;
-                self.bus.mem_write(addr, self.a);
+                self.write(addr, self.a);
             }
             // LDH A, [C]
             0xf2 => {
-                let val = self.bus.mem_read(0xff00 + self.c as u16);
+                let val = self.read(0xff00 + self.c as u16);
                 self.a = val;
             }
             // LDH imm8, A
             0xe0 => {
-                let addr_lo = self.bus.mem_read(self.program_counter + 1) as u16;
-                self.bus.mem_write(0xff00 + (addr_lo & 0x00ff), self.a);
+                let addr_lo = self.read(self.program_counter.wrapping_add(1)) as u16;
+                self.write(0xff00 + (addr_lo & 0x00ff), self.a);
             }
             // LDH A, imm8
             0xf0 => {
-                let addr_lo = self.bus.mem_read(self.program_counter + 1) as u16;
-                let val = self.bus.mem_read(0xff00 + (addr_lo & 0x00ff));
+                let addr_lo = self.read(self.program_counter.wrapping_add(1)) as u16;
+                let val = self.read(0xff00 + (addr_lo & 0x00ff));
                 self.a = val;
             }
             // NOP
@@ -959,7 +1264,7 @@ impl Cpu {
             }
             // OR A, imm8
             0xf6 => {
-                let val = self.bus.mem_read(self.program_counter + 1);
+                let val = self.read(self.program_counter.wrapping_add(1));
                 self.a |= val;
 
                 self.flags.set(CpuFlag::zero, self.a == 0);
@@ -990,29 +1295,29 @@ impl Cpu {
             }
             // RET
             0xc9 => {
-                self.program_counter = self.pop_u16_from_stack() - 1; // minus 1 to account for the added byte
+                self.call_returned();
+                let addr = self.pop_u16_from_stack();
+                self.jump(addr);
             }
             // RET cc
             0xc0 | 0xc8 | 0xd0 | 0xd8 => {
                 let TargetReg::Cond(condition) = &opcode.reg1 else {
                     panic!("Expected Cond register")
                 };
-                let should_execute = match condition {
-                    0 => !self.flags.contains(CpuFlag::zero), // Cond(0) => zero flags is not set
-                    1 => self.flags.contains(CpuFlag::zero),  // Cond(1) => zero flag is set
-                    2 => !self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is not set
-                    3 => self.flags.contains(CpuFlag::carry), // Cond(3) => carry flag is set
-                    _ => panic!("Condition Codes are 0-3. Received {condition}"),
-                };
+                let should_execute = self.condition(*condition);
                 if should_execute {
                     // inc cycle count
                     self.cycles += 3;
-                    self.program_counter = self.pop_u16_from_stack() - 1; // minus 1 to account for the added byte
+                    self.call_returned();
+                    let addr = self.pop_u16_from_stack();
+                    self.jump(addr);
                 }
             }
             // RETI
             0xd9 => {
-                self.program_counter = self.pop_u16_from_stack() - 1;
+                self.call_returned();
+                let addr = self.pop_u16_from_stack();
+                self.jump(addr);
                 self.ime = true;
             }
             // RLA
@@ -1062,8 +1367,10 @@ impl Cpu {
                 };
                 let addr = self.tgt3_read(*tgt);
                 // push next instruction onto the stack
-                self.push_u16_to_stack(self.program_counter + 1);
-                self.program_counter = addr.wrapping_sub(1); // -1 since rst instruction is one byte long
+                let return_addr = self.program_counter.wrapping_add(1);
+                self.push_u16_to_stack(return_addr);
+                self.call_pushed(self.program_counter, return_addr);
+                self.jump(addr);
             }
             // SBC A, r8
             0x98..=0x9f => {
@@ -1075,7 +1382,7 @@ impl Cpu {
             }
             // SBC A, imm8
             0xde => {
-                let val = self.bus.mem_read(self.program_counter + 1);
+                let val = self.read(self.program_counter.wrapping_add(1));
                 self.a = self.sub_u8(self.a, val, true);
             }
             // SCF
@@ -1084,10 +1391,8 @@ impl Cpu {
                 self.flags.remove(CpuFlag::half_carry);
                 self.flags.set(CpuFlag::carry, true);
             }
-            // STOP
-            0x10 => {
-                // does nothing
-            }
+            // STOP. Always 2 bytes here, even if the byte after it isn't 0x00
+            0x10 => self.stop(),
             // SUB A, r8
             0x90..=0x97 => {
                 let TargetReg::R8(reg) = &opcode.reg2 else {
@@ -1098,7 +1403,7 @@ impl Cpu {
             }
             // SUB A, imm8
             0xd6 => {
-                let val = self.bus.mem_read(self.program_counter + 1);
+                let val = self.read(self.program_counter.wrapping_add(1));
                 self.a = self.sub_u8(self.a, val, false);
             }
             // XOR A, r8
@@ -1116,7 +1421,7 @@ impl Cpu {
             }
             // XOR A, imm8
             0xee => {
-                let val = self.bus.mem_read(self.program_counter + 1);
+                let val = self.read(self.program_counter.wrapping_add(1));
                 self.a ^= val;
 
                 self.flags.set(CpuFlag::zero, self.a == 0);
@@ -1153,25 +1458,16 @@ impl Cpu {
         sum
     }
 
-    fn add_u16(&mut self, arg1: u16, arg2: u16, carry: bool) -> u16 {
-        let zero_flag = self.flags.contains(CpuFlag::zero);
-        // sum the lower 8 bits first
-        let lo_sum = self.add_u8(arg1 as u8, arg2 as u8, carry);
-        // sum the upper 8 bits. Carry from lower sum must be used
-        let hi_sum = self.add_u8(
-            (arg1 >> 8) as u8,
-            (arg2 >> 8) as u8,
-            self.flags.contains(CpuFlag::carry),
+    // ADD HL, r16. H is the carry out of bit 11 and C out of bit 15. Z is left alone
+    fn add_u16(&mut self, arg1: u16, arg2: u16) -> u16 {
+        let (sum, carry) = arg1.overflowing_add(arg2);
+        self.flags.remove(CpuFlag::subtraction);
+        self.flags.set(
+            CpuFlag::half_carry,
+            (arg1 & 0x0fff) + (arg2 & 0x0fff) > 0x0fff,
         );
-
-        // Set zero flags back to original
-        self.flags.set(CpuFlag::zero, zero_flag);
-        // subtraction flag already set to 0 by add_u8.
-        // half_carry flag set from add_u8
-        // carry flag set from add_u8
-
-        // Convert into u16 and return
-        (hi_sum as u16) << 8 | lo_sum as u16
+        self.flags.set(CpuFlag::carry, carry);
+        sum
     }
 
     fn sub_u8(&mut self, arg1: u8, arg2: u8, carry: bool) -> u8 {
@@ -1205,209 +1501,613 @@ impl Cpu {
     }
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use crate::cartridge::get_mapper;
-//     use crate::sdl2_setup;
-
-//     use super::*;
-//     use rand::prelude::*;
-//     use std::vec;
-
-//     fn setup(program: Vec<u8>) -> Cpu {
-//         let cartridge = get_mapper(&program);
-//         let (_event_pump, _audio_device) = sdl2_setup::setup();
-//         let bus = Bus::new(cartridge);
-//         let cpu = Cpu::new(bus);
-//         cpu
-//     }
-
-//     #[test]
-//     fn test_ld_r8_r8() {
-//         let mut rng = rand::thread_rng();
-//         for i in 0..8 {
-//             for j in 0..8 {
-//                 // skip opcode 0x76
-//                 if (i != 6) && (j != 6) {
-//                     let prg = vec![64 + 8 * i + j, 0x00, 0x76];
-//                     let mut cpu = setup(prg);
-//                     let mut value = rng.gen::<u8>();
-//                     let status = cpu.flags.clone();
-//                     // set hl to addr 2 so that Reg 6 does not affect program run.
-//                     // Also need to set h and l registers to values within our program (i.e not random).
-//                     cpu.set_hl(2);
-//                     if j == 4 {
-//                         cpu.r8_write(4, 0x00);
-//                         value = 0;
-//                     } else if j == 5 {
-//                         cpu.r8_write(5, 0x02);
-//                         value = 2;
-//                     } else {
-//                         cpu.r8_write(j, value);
-//                     }
-//                     cpu.run();
-
-//                     assert_eq!(cpu.r8_read(i), value);
-//                     assert_eq!(cpu.flags, status);
-//                 }
-//             }
-//         }
-//     }
-
-//     #[test]
-//     fn test_ld_r8_imm8() {
-//         let mut rng = rand::thread_rng();
-//         for i in 0..8 {
-//             let value = rng.gen::<u8>();
-//             let prg = vec![8 * i + 6, value, 0x76];
-//             let mut cpu = setup(prg);
-//             cpu.set_hl(3); // set HL reg to point to an addr in program
-//             let status = cpu.flags.bits();
-//             cpu.run();
-
-//             assert_eq!(cpu.r8_read(i), value);
-//             assert_eq!(cpu.flags.bits(), status);
-//         }
-//     }
-
-//     #[test]
-//     fn test_ld_r16_imm16() {
-//         let mut rng = rand::thread_rng();
-//         for i in 0..4 {
-//             let lo = rng.gen::<u8>();
-//             let hi = rng.gen::<u8>();
-//             let prg = vec![16 * i + 1, lo, hi, 0x76];
-//             println!("program: {:?}", prg);
-//             let mut cpu = setup(prg);
-//             let status = cpu.flags.bits();
-//             cpu.run();
-
-//             assert_eq!(cpu.r16_read(i), u16::from_le_bytes([lo, hi]));
-//             assert_eq!(cpu.flags.bits(), status);
-//         }
-//     }
-
-//     #[test]
-//     fn test_ld_r16_a() {
-//         let mut rng = rand::thread_rng();
-//         for i in 0..4 {
-//             let value = rng.gen::<u8>();
-//             // 0x3e loads A with an imm8
-//             let prg = vec![0x3e, value, 16 * i + 2, 0x76, 0x76, 0x76, 0x76];
-//             println!("program: {:?}", prg);
-//             let mut cpu = setup(prg);
-//             cpu.set_hl(5);
-//             let status = cpu.flags.bits();
-//             cpu.run();
-
-//             // Since HL+ and HL- change HL, we cannot use r16mem_read to see the change
-//             // we need to go back to the addr.
-//             let target = if i == 2 {
-//                 cpu.bus.mem_read(cpu.get_hl() - 1)
-//             } else if i == 3 {
-//                 cpu.bus.mem_read(cpu.get_hl() + 1)
-//             } else {
-//                 cpu.r16mem_read(i) as u8
-//             };
-
-//             assert_eq!(target, value);
-//             assert_eq!(cpu.flags.bits(), status);
-//         }
-//     }
-
-//     #[test]
-//     fn test_ld_a_r16() {
-//         let mut rng = rand::thread_rng();
-//         for i in 0..4 {
-//             let value = rng.gen::<u8>();
-//             let prg = vec![16 * i + 10, 0x76, 0x76, value, 0x76];
-//             println!("program: {:?}", prg);
-//             let mut cpu = setup(prg);
-//             cpu.set_bc(3);
-//             cpu.set_de(3);
-//             cpu.set_hl(3);
-//             let status = cpu.flags.bits();
-//             cpu.run();
-
-//             assert_eq!(cpu.a, value);
-//             assert_eq!(cpu.flags.bits(), status);
-//         }
-//     }
-
-//     #[test]
-//     fn test_ld_a_imm16() {
-//         let mut rng = rand::thread_rng();
-//         let value = rng.gen::<u8>();
-//         let prg = vec![0xfa, 0x05, 0x00, 0x00, 0x76, value];
-//         let mut cpu = setup(prg);
-//         let status = cpu.flags.bits();
-//         cpu.run();
-
-//         assert_eq!(cpu.a, value);
-//         assert_eq!(cpu.flags.bits(), status);
-//     }
-
-//     #[test]
-//     fn test_ld_imm16_a() {
-//         let mut rng = rand::thread_rng();
-//         let value = rng.gen::<u8>();
-//         // 0x3e loads a with imm8
-//         let prg = vec![0x3e, value, 0xea, 0x06, 0x00, 0x76, 0x76];
-//         let mut cpu = setup(prg);
-//         let status = cpu.flags.bits();
-//         cpu.run();
-
-//         assert_eq!(cpu.bus.mem_read(0x0006), value);
-//         assert_eq!(cpu.flags.bits(), status);
-//     }
-
-//     #[test]
-//     fn test_ld_imm16_sp() {
-//         let mut rng = rand::thread_rng();
-//         let value1 = rng.gen::<u8>();
-//         let value2 = rng.gen::<u8>();
-//         let prg = vec![0x08, 0x04, 0x00, 0x76, value1, value2];
-//         let mut cpu = setup(prg);
-//         let status = cpu.flags.bits();
-//         cpu.run();
-
-//         assert_eq!(cpu.bus.mem_read_u16(0x04), 0xfffe);
-//         assert_eq!(cpu.flags.bits(), status);
-//     }
-
-//     #[test]
-//     fn test_ld_hl_spimm8() {
-//         let prg = vec![0xf8, 0x01, 0x76];
-//         let mut cpu = setup(prg);
-//         let status = cpu.flags.bits();
-//         println!("SP: {}", cpu.stack_pointer);
-//         cpu.run();
-
-//         assert_eq!(cpu.get_hl(), 0xffff);
-//         assert_eq!(cpu.flags.bits(), status);
-
-//         // test negative behavior
-//         let prg = vec![0xf8, 0xf1, 0x76]; // offset = -0x0f
-//         let mut cpu = setup(prg);
-//         let status = cpu.flags.bits();
-//         cpu.run();
-
-//         assert_eq!(cpu.get_hl(), 0xffef);
-//         assert_eq!(cpu.flags.bits(), status | 0b0001_0000); // There is a carry in the sum
-//     }
-
-//     #[test]
-//     fn test_ld_sp_hl() {
-//         let mut rng = rand::thread_rng();
-//         let value1 = rng.gen::<u8>();
-//         let value2 = rng.gen::<u8>();
-//         // 0x21 loads imm16 into Reg HL.
-//         let prg = vec![0x21, value1, value2, 0xf9, 0x76];
-//         let mut cpu = setup(prg);
-//         let status = cpu.flags.bits();
-//         cpu.run();
-
-//         assert_eq!(cpu.stack_pointer, u16::from_le_bytes([value1, value2]));
-//         assert_eq!(cpu.flags.bits(), status);
-//     }
-// }
+#[cfg(feature = "profiler")]
+fn print_profile_table(counts: &HashMap<u8, u64>, opcodes: &[Option<Opcode>; 256], prefix: &str) {
+    let total: u64 = counts.values().sum();
+    let mut sorted: Vec<(u8, u64)> = counts.iter().map(|(&op, &n)| (op, n)).collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    println!("{:<8}{:<20}{:>12}{:>9}", "Opcode", "Mnemonic", "Count", "%");
+    for (op, count) in sorted {
+        let name = opcodes[op as usize]
+            .as_ref()
+            .map_or("???".to_string(), disasm::mnemonic);
+        let percent = 100.0 * count as f64 / total as f64;
+        println!(
+            "{:<8}{name:<20}{count:>12}{percent:>8.2}%",
+            format!("{prefix}{op:02X}")
+        );
+    }
+    println!("Total: {total}");
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::{get_mapper, test_rom};
+    use crate::debugger::BreakEvent;
+
+    use super::*;
+    use rand::prelude::*;
+
+    // Programs are placed right after the cartridge header and run from there
+    const START: u16 = 0x0150;
+    // WRAM scratch address for tests that load or store memory
+    const RAM: u16 = 0xc000;
+
+    fn setup(program: Vec<u8>) -> Cpu {
+        setup_rom(&[(START, &program)])
+    }
+
+    // Code placed at more than one ROM address, e.g. restart vectors
+    fn setup_rom(code: &[(u16, &[u8])]) -> Cpu {
+        let rom = test_rom(code);
+        let cartridge = get_mapper(&rom).unwrap();
+        let bus = Bus::new(cartridge, true);
+        let mut cpu = Cpu::new(bus);
+        cpu.program_counter = START;
+        cpu
+    }
+
+    // Step until the program's HALT
+    fn run(cpu: &mut Cpu) {
+        for _ in 0..10_000 {
+            if cpu.halted {
+                return;
+            }
+            let _ = cpu.step(|_| {});
+        }
+        panic!("Program never reached HALT");
+    }
+
+    // M-cycles the bus was ticked for during one step
+    fn step_cycles(cpu: &mut Cpu) -> u64 {
+        let before = cpu.bus.total_cycles;
+        let _ = cpu.step(|_| {});
+        cpu.bus.total_cycles - before
+    }
+
+    #[test]
+    fn test_ld_r8_r8() {
+        let mut rng = rand::thread_rng();
+        for i in 0..8 {
+            for j in 0..8 {
+                // (HL) is covered by the imm8 loads, and 0x76 is HALT
+                if (i != 6) && (j != 6) {
+                    let prg = vec![64 + 8 * i + j, 0x76];
+                    let mut cpu = setup(prg);
+                    let value = rng.gen::<u8>();
+                    let status = cpu.flags.clone();
+                    cpu.r8_write(j, value);
+                    run(&mut cpu);
+
+                    assert_eq!(cpu.r8_read(i), value);
+                    assert_eq!(cpu.flags, status);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ld_r8_imm8() {
+        let mut rng = rand::thread_rng();
+        for i in 0..8 {
+            let value = rng.gen::<u8>();
+            let prg = vec![8 * i + 6, value, 0x76];
+            let mut cpu = setup(prg);
+            cpu.set_hl(RAM); // LD (HL), n stores to WRAM
+            let status = cpu.flags.bits();
+            run(&mut cpu);
+
+            assert_eq!(cpu.r8_read(i), value);
+            assert_eq!(cpu.flags.bits(), status);
+        }
+    }
+
+    #[test]
+    fn test_ld_r16_imm16() {
+        let mut rng = rand::thread_rng();
+        for i in 0..4 {
+            let lo = rng.gen::<u8>();
+            let hi = rng.gen::<u8>();
+            let prg = vec![16 * i + 1, lo, hi, 0x76];
+            let mut cpu = setup(prg);
+            let status = cpu.flags.bits();
+            run(&mut cpu);
+
+            assert_eq!(cpu.r16_read(i), u16::from_le_bytes([lo, hi]));
+            assert_eq!(cpu.flags.bits(), status);
+        }
+    }
+
+    #[test]
+    fn test_ld_r16_a() {
+        let mut rng = rand::thread_rng();
+        for i in 0..4 {
+            let value = rng.gen::<u8>();
+            // 0x3e loads A with an imm8
+            let prg = vec![0x3e, value, 16 * i + 2, 0x76];
+            let mut cpu = setup(prg);
+            cpu.set_bc(RAM);
+            cpu.set_de(RAM);
+            cpu.set_hl(RAM);
+            let status = cpu.flags.bits();
+            run(&mut cpu);
+
+            assert_eq!(cpu.bus.mem_read(RAM), value);
+            // HL+ and HL- move HL on past the store
+            let hl = match i {
+                2 => RAM + 1,
+                3 => RAM - 1,
+                _ => RAM,
+            };
+            assert_eq!(cpu.get_hl(), hl);
+            assert_eq!(cpu.flags.bits(), status);
+        }
+    }
+
+    #[test]
+    fn test_ld_a_r16() {
+        let mut rng = rand::thread_rng();
+        for i in 0..4 {
+            let value = rng.gen::<u8>();
+            let prg = vec![16 * i + 10, 0x76];
+            let mut cpu = setup(prg);
+            cpu.bus.mem_write(RAM, value);
+            cpu.set_bc(RAM);
+            cpu.set_de(RAM);
+            cpu.set_hl(RAM);
+            let status = cpu.flags.bits();
+            run(&mut cpu);
+
+            assert_eq!(cpu.a, value);
+            assert_eq!(cpu.flags.bits(), status);
+        }
+    }
+
+    #[test]
+    fn test_ld_a_imm16() {
+        let mut rng = rand::thread_rng();
+        let value = rng.gen::<u8>();
+        let [lo, hi] = RAM.to_le_bytes();
+        let prg = vec![0xfa, lo, hi, 0x76];
+        let mut cpu = setup(prg);
+        cpu.bus.mem_write(RAM, value);
+        let status = cpu.flags.bits();
+        run(&mut cpu);
+
+        assert_eq!(cpu.a, value);
+        assert_eq!(cpu.flags.bits(), status);
+    }
+
+    #[test]
+    fn test_ld_imm16_a() {
+        let mut rng = rand::thread_rng();
+        let value = rng.gen::<u8>();
+        let [lo, hi] = RAM.to_le_bytes();
+        // 0x3e loads a with imm8
+        let prg = vec![0x3e, value, 0xea, lo, hi, 0x76];
+        let mut cpu = setup(prg);
+        let status = cpu.flags.bits();
+        run(&mut cpu);
+
+        assert_eq!(cpu.bus.mem_read(RAM), value);
+        assert_eq!(cpu.flags.bits(), status);
+    }
+
+    #[test]
+    fn test_ld_imm16_sp() {
+        let [lo, hi] = RAM.to_le_bytes();
+        let prg = vec![0x08, lo, hi, 0x76];
+        let mut cpu = setup(prg);
+        let status = cpu.flags.bits();
+        run(&mut cpu);
+
+        assert_eq!(cpu.bus.mem_read_u16(RAM), 0xfffe);
+        assert_eq!(cpu.flags.bits(), status);
+    }
+
+    #[test]
+    fn test_ld_hl_spimm8() {
+        let prg = vec![0xf8, 0x01, 0x76];
+        let mut cpu = setup(prg);
+        run(&mut cpu);
+
+        assert_eq!(cpu.get_hl(), 0xffff);
+        assert_eq!(cpu.flags, CpuFlag::empty());
+
+        // test negative behavior
+        let prg = vec![0xf8, 0xf1, 0x76]; // offset = -0x0f
+        let mut cpu = setup(prg);
+        run(&mut cpu);
+
+        assert_eq!(cpu.get_hl(), 0xffef);
+        assert_eq!(cpu.flags, CpuFlag::carry); // There is a carry in the low byte sum
+    }
+
+    #[test]
+    fn test_ld_sp_hl() {
+        let mut rng = rand::thread_rng();
+        let value1 = rng.gen::<u8>();
+        let value2 = rng.gen::<u8>();
+        // 0x21 loads imm16 into Reg HL.
+        let prg = vec![0x21, value1, value2, 0xf9, 0x76];
+        let mut cpu = setup(prg);
+        let status = cpu.flags.bits();
+        run(&mut cpu);
+
+        assert_eq!(cpu.stack_pointer, u16::from_le_bytes([value1, value2]));
+        assert_eq!(cpu.flags.bits(), status);
+    }
+
+    // DAA as documented: after an addition add 0x06 and/or 0x60 where a nibble is past 9
+    // or carried, after a subtraction take them away again only where H and C say so.
+    // Returns the result and the new carry
+    fn reference_daa(a: u8, n: bool, h: bool, c: bool) -> (u8, bool) {
+        let mut adjustment = 0;
+        let mut carry = c;
+        if h || (!n && a & 0x0f > 0x09) {
+            adjustment |= 0x06;
+        }
+        if c || (!n && a > 0x99) {
+            adjustment |= 0x60;
+            carry = true;
+        }
+        let result = if n {
+            a.wrapping_sub(adjustment)
+        } else {
+            a.wrapping_add(adjustment)
+        };
+        (result, carry)
+    }
+
+    #[test]
+    fn test_daa_exhaustive() {
+        let mut cpu = setup(vec![0x27, 0x76]);
+        for a in 0..=0xff {
+            for bits in 0..8 {
+                let n = bits & 0b100 != 0;
+                let h = bits & 0b010 != 0;
+                let c = bits & 0b001 != 0;
+                let mut flags = CpuFlag::empty();
+                flags.set(CpuFlag::subtraction, n);
+                flags.set(CpuFlag::half_carry, h);
+                flags.set(CpuFlag::carry, c);
+                cpu.a = a;
+                cpu.flags = flags;
+                cpu.program_counter = START;
+                cpu.halted = false;
+                run(&mut cpu);
+
+                let (result, carry) = reference_daa(a, n, h, c);
+                let mut expected = CpuFlag::empty();
+                expected.set(CpuFlag::zero, result == 0);
+                expected.set(CpuFlag::subtraction, n);
+                expected.set(CpuFlag::carry, carry);
+                assert_eq!(cpu.a, result, "A={a:02X} N={n} H={h} C={c}");
+                assert_eq!(cpu.flags, expected, "A={a:02X} N={n} H={h} C={c}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_rst_0_then_ret() {
+        let mut cpu = setup_rom(&[(START, &[0xc7, 0x76]), (0x0000, &[0xc9])]);
+        run(&mut cpu);
+
+        assert_eq!(cpu.program_counter, START + 1);
+        assert_eq!(cpu.stack_pointer, 0xfffe);
+    }
+
+    #[test]
+    fn test_jp_0000() {
+        let mut cpu = setup_rom(&[(START, &[0xc3, 0x00, 0x00]), (0x0000, &[0x76])]);
+        run(&mut cpu);
+
+        assert_eq!(cpu.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_jr_loop() {
+        // JR -2 jumps back onto itself
+        let mut cpu = setup(vec![0x18, 0xfe]);
+        for _ in 0..100 {
+            let _ = cpu.step(|_| {});
+            assert_eq!(cpu.program_counter, START);
+        }
+    }
+
+    #[test]
+    fn test_immediate_fetch_wraps() {
+        // LD B, n at 0xFFFF (IE) takes its operand from 0x0000, then PC wraps on to 0x0001
+        let mut cpu = setup_rom(&[(START, &[0xc3, 0xff, 0xff]), (0x0000, &[0x42, 0x76])]);
+        cpu.bus.mem_write(0xFFFF, 0x06);
+        run(&mut cpu);
+
+        assert_eq!(cpu.b, 0x42);
+        assert_eq!(cpu.program_counter, 0x0001);
+    }
+
+    #[test]
+    fn test_conditional_branches_exhaustive() {
+        const TARGET: u16 = 0x0200;
+        let [lo, hi] = TARGET.to_le_bytes();
+        // Opcode, condition, instruction, PC when taken, PC when not, cycles taken and not
+        let mut cases = Vec::new();
+        for cond in 0..4u8 {
+            let jr_target = START + 2 + 0x10;
+            cases.push((
+                0xc2 + 8 * cond,
+                cond,
+                vec![0xc2 + 8 * cond, lo, hi],
+                TARGET,
+                START + 3,
+                4,
+                3,
+            ));
+            cases.push((
+                0x20 + 8 * cond,
+                cond,
+                vec![0x20 + 8 * cond, 0x10],
+                jr_target,
+                START + 2,
+                3,
+                2,
+            ));
+            cases.push((
+                0xc4 + 8 * cond,
+                cond,
+                vec![0xc4 + 8 * cond, lo, hi],
+                TARGET,
+                START + 3,
+                6,
+                3,
+            ));
+            cases.push((
+                0xc0 + 8 * cond,
+                cond,
+                vec![0xc0 + 8 * cond],
+                TARGET,
+                START + 1,
+                5,
+                2,
+            ));
+        }
+
+        for (opcode, cond, program, taken_pc, not_taken_pc, taken_cycles, not_taken_cycles) in cases
+        {
+            for bits in (0..16u8).map(|n| n << 4) {
+                let flags = CpuFlag::from_bits_retain(bits);
+                let taken = match cond {
+                    0 => !flags.contains(CpuFlag::zero),
+                    1 => flags.contains(CpuFlag::zero),
+                    2 => !flags.contains(CpuFlag::carry),
+                    _ => flags.contains(CpuFlag::carry),
+                };
+
+                let mut cpu = setup(program.clone());
+                cpu.flags = flags;
+                // RET cc pops TARGET
+                cpu.stack_pointer = 0xfff0;
+                cpu.bus.mem_write(0xfff0, lo);
+                cpu.bus.mem_write(0xfff1, hi);
+                let cycles = step_cycles(&mut cpu);
+
+                let case = format!("opcode {opcode:02X} flags {bits:02X}");
+                if taken {
+                    assert_eq!(cpu.program_counter, taken_pc, "{case}");
+                    assert_eq!(cycles, taken_cycles, "{case}");
+                } else {
+                    assert_eq!(cpu.program_counter, not_taken_pc, "{case}");
+                    assert_eq!(cycles, not_taken_cycles, "{case}");
+                }
+                // CALL pushes the return address, RET pops it
+                let sp = match (opcode & 0x07, taken) {
+                    (0x04, true) => 0xffee,
+                    (0x00, true) if opcode >= 0xc0 => 0xfff2,
+                    _ => 0xfff0,
+                };
+                assert_eq!(cpu.stack_pointer, sp, "{case}");
+                assert_eq!(cpu.flags.bits(), bits, "{case}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_jp_hl() {
+        let mut cpu = setup(vec![0xe9]);
+        cpu.set_hl(0x1234);
+        let cycles = step_cycles(&mut cpu);
+
+        assert_eq!(cpu.program_counter, 0x1234);
+        assert_eq!(cycles, 1);
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // Instruction, flags, M-cycles from the Pan Docs. Z and C set together take NZ/NC
+        // the other way to Z/C
+        const ZC: u8 = 0x90;
+        let cases: [(&[u8], u8, u64); 22] = [
+            (&[0xc3, 0x00, 0x02], 0x00, 4), // JP nn
+            (&[0xc2, 0x00, 0x02], 0x00, 4), // JP NZ taken
+            (&[0xc2, 0x00, 0x02], ZC, 3),   // JP NZ not taken
+            (&[0xda, 0x00, 0x02], ZC, 4),   // JP C taken
+            (&[0xda, 0x00, 0x02], 0x00, 3), // JP C not taken
+            (&[0x18, 0x10], 0x00, 3),       // JR e
+            (&[0x20, 0x10], 0x00, 3),       // JR NZ taken
+            (&[0x20, 0x10], ZC, 2),         // JR NZ not taken
+            (&[0x38, 0x10], ZC, 3),         // JR C taken
+            (&[0x38, 0x10], 0x00, 2),       // JR C not taken
+            (&[0xcd, 0x00, 0x02], 0x00, 6), // CALL nn
+            (&[0xc4, 0x00, 0x02], 0x00, 6), // CALL NZ taken
+            (&[0xc4, 0x00, 0x02], ZC, 3),   // CALL NZ not taken
+            (&[0xdc, 0x00, 0x02], ZC, 6),   // CALL C taken
+            (&[0xdc, 0x00, 0x02], 0x00, 3), // CALL C not taken
+            (&[0xc9], 0x00, 4),             // RET
+            (&[0xd9], 0x00, 4),             // RETI
+            (&[0xc0], 0x00, 5),             // RET NZ taken
+            (&[0xc0], ZC, 2),               // RET NZ not taken
+            (&[0xd8], ZC, 5),               // RET C taken
+            (&[0xd8], 0x00, 2),             // RET C not taken
+            (&[0xff], 0x00, 4),             // RST 38H
+        ];
+        for (program, flags, expected) in cases {
+            let mut cpu = setup(program.to_vec());
+            cpu.flags = CpuFlag::from_bits_retain(flags);
+            cpu.stack_pointer = 0xfff0;
+            assert_eq!(
+                step_cycles(&mut cpu),
+                expected,
+                "{program:02X?} flags {flags:02X}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_halt_ticks_bus() {
+        let mut cpu = setup(vec![0x76]);
+        run(&mut cpu);
+        for _ in 0..10 {
+            assert_eq!(step_cycles(&mut cpu), 1);
+        }
+    }
+
+    #[test]
+    fn test_or_a_imm8() {
+        // Z only for a zero result, N, H and C always cleared
+        for (a, n, result, flags) in [
+            (0x00, 0x00, 0x00, CpuFlag::zero),
+            (0x5a, 0x0f, 0x5f, CpuFlag::empty()),
+            (0x80, 0x01, 0x81, CpuFlag::empty()),
+        ] {
+            let mut cpu = setup(vec![0xf6, n, 0x76]);
+            cpu.a = a;
+            cpu.flags = CpuFlag::all();
+            run(&mut cpu);
+
+            assert_eq!(cpu.a, result);
+            assert_eq!(cpu.flags, flags);
+        }
+    }
+
+    #[test]
+    fn test_add_hl_half_carry() {
+        // H is the carry out of bit 11, C out of bit 15. Z is left alone
+        for (hl, bc, result, flags) in [
+            (0x0fff, 0x0001, 0x1000, CpuFlag::zero | CpuFlag::half_carry),
+            (
+                0x8fff,
+                0x7001,
+                0x0000,
+                CpuFlag::zero | CpuFlag::half_carry | CpuFlag::carry,
+            ),
+            (0x00ff, 0x0001, 0x0100, CpuFlag::zero),
+        ] {
+            let mut cpu = setup(vec![0x09, 0x76]);
+            cpu.set_hl(hl);
+            cpu.set_bc(bc);
+            cpu.flags = CpuFlag::zero | CpuFlag::subtraction;
+            run(&mut cpu);
+
+            assert_eq!(cpu.get_hl(), result);
+            assert_eq!(cpu.flags, flags, "{hl:04X} + {bc:04X}");
+        }
+    }
+
+    #[test]
+    fn test_reads_see_cycles_before_the_access() {
+        // LDH A, (n) reads on its third M-cycle. DIV turns over 12 T-cycles in, so the read
+        // only sees 1 if the bus was ticked for all three accesses first
+        for (counter, div) in [(0x0100 - 12, 1), (0x0100 - 16, 0)] {
+            let mut cpu = setup(vec![0xf0, 0x04, 0x76]);
+            cpu.bus.timer.internal_counter = counter;
+            let _ = cpu.step(|_| {});
+
+            assert_eq!(cpu.a, div, "counter {counter:04X}");
+        }
+
+        // LD A, (HL) reads on its second
+        for (counter, div) in [(0x0100 - 8, 1), (0x0100 - 12, 0)] {
+            let mut cpu = setup(vec![0x7e, 0x76]);
+            cpu.set_hl(0xff04);
+            cpu.bus.timer.internal_counter = counter;
+            let _ = cpu.step(|_| {});
+
+            assert_eq!(cpu.a, div, "counter {counter:04X}");
+        }
+    }
+
+    #[test]
+    fn test_write_watchpoint() {
+        // NOP, LD A, 0x42, LDH (0x80), A
+        let mut cpu = setup(vec![0x00, 0x3e, 0x42, 0xe0, 0x80, 0x76]);
+        cpu.debugger.write_watchpoints.insert(0xff80);
+        while cpu.debugger.hit.is_none() && !cpu.halted {
+            let _ = cpu.step(|_| {});
+        }
+
+        assert_eq!(
+            cpu.debugger.hit,
+            Some(BreakEvent::Watchpoint {
+                pc: START + 3,
+                addr: 0xff80,
+                value: 0x42,
+                is_write: true,
+            })
+        );
+        assert_eq!(cpu.bus.mem_read(0xff80), 0x42);
+    }
+
+    #[test]
+    fn test_stop_resets_div() {
+        let mut cpu = setup(vec![0x10, 0x00]);
+        cpu.bus.timer.internal_counter = 0xabcc;
+        let _ = cpu.step(|_| {});
+
+        assert!(cpu.stopped);
+        assert_eq!(cpu.bus.mem_read(0xff04), 0);
+        // The timer stays halted while stopped
+        for _ in 0..1000 {
+            let _ = cpu.step(|_| {});
+        }
+        assert_eq!(cpu.bus.mem_read(0xff04), 0);
+    }
+
+    // mooneye's ie_push: the interrupt dispatch pushes PC onto IE
+    fn dispatch_with_sp(sp: u16) -> Cpu {
+        let mut cpu = setup(vec![0x00, 0x76]);
+        cpu.ime = true;
+        cpu.stack_pointer = sp;
+        cpu.bus.interrupt_enable = Interrupt::lcd;
+        cpu.bus.interrupt_flag = Interrupt::lcd;
+        let _ = cpu.step(|_| {});
+        cpu
+    }
+
+    #[test]
+    fn test_ie_push_high_byte_cancels() {
+        // SP 0x0000 writes PC's high byte 0x01 to IE (0xFFFF) before the vector is picked.
+        // That disables the LCD interrupt so nothing is dispatched and PC goes to 0x0000
+        let cpu = dispatch_with_sp(0x0000);
+
+        assert_eq!(cpu.bus.interrupt_enable.bits(), (START >> 8) as u8);
+        assert!(cpu.bus.interrupt_flag.contains(Interrupt::lcd));
+        assert_eq!(cpu.stack_pointer, 0xfffe);
+        assert!(!cpu.ime);
+        // The instruction at 0x0000 (a NOP) ran in the same step
+        assert_eq!(cpu.program_counter, 0x0001);
+    }
+
+    #[test]
+    fn test_ie_push_low_byte_dispatches() {
+        // SP 0x0001 writes the high byte to ROM and the low byte to IE once the vector is
+        // already picked, so the LCD interrupt still runs
+        let cpu = dispatch_with_sp(0x0001);
+
+        assert_eq!(cpu.bus.interrupt_enable.bits(), START as u8 & 0x1f);
+        assert!(!cpu.bus.interrupt_flag.contains(Interrupt::lcd));
+        assert_eq!(cpu.stack_pointer, 0xffff);
+        assert_eq!(cpu.program_counter, 0x0048 + 1);
+    }
+}
@@ -0,0 +1,63 @@
+// Breakpoints and watchpoints. Checked by the CPU, which leaves what it hit in `hit`
+// for the frontend to poll after each step
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakEvent {
+    Breakpoint(u16), // PC about to execute
+    Watchpoint {
+        pc: u16, // Instruction that made the access
+        addr: u16,
+        value: u8,
+        is_write: bool,
+    },
+    Interrupt(u16), // Handler address being jumped to
+}
+
+pub struct Debugger {
+    pub breakpoints: BTreeSet<u16>,
+    pub read_watchpoints: BTreeSet<u16>,
+    pub write_watchpoints: BTreeSet<u16>,
+    pub break_on_interrupt: bool,
+    pub hit: Option<BreakEvent>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            read_watchpoints: BTreeSet::new(),
+            write_watchpoints: BTreeSet::new(),
+            break_on_interrupt: false,
+            hit: None,
+        }
+    }
+
+    pub fn check_pc(&mut self, pc: u16) {
+        if self.breakpoints.contains(&pc) {
+            self.hit = Some(BreakEvent::Breakpoint(pc));
+        }
+    }
+
+    pub fn check_access(&mut self, pc: u16, addr: u16, value: u8, is_write: bool) {
+        let watchpoints = if is_write {
+            &self.write_watchpoints
+        } else {
+            &self.read_watchpoints
+        };
+        if watchpoints.contains(&addr) {
+            self.hit = Some(BreakEvent::Watchpoint {
+                pc,
+                addr,
+                value,
+                is_write,
+            });
+        }
+    }
+
+    pub fn check_interrupt(&mut self, vector: u16) {
+        if self.break_on_interrupt {
+            self.hit = Some(BreakEvent::Interrupt(vector));
+        }
+    }
+}
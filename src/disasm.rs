@@ -0,0 +1,192 @@
+// Turns the opcode tables into assembly text for the trace log and the debugger panel
+use crate::bus::Bus;
+use crate::opcodes::{self, Opcode, TargetReg};
+
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16_STK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const R16_MEM: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+const COND: [&str; 4] = ["NZ", "Z", "NC", "C"];
+
+// Returns the instruction at addr, e.g. "LDH A, ($FF44)" or "JR NZ, $0152", and its length.
+// CB prefixed instructions are decoded together with their prefix. Bytes that aren't an
+// opcode are shown as data. Memory is only peeked so disassembling never disturbs the machine
+pub fn disassemble(bus: &Bus, addr: u16) -> (String, u16) {
+    let byte = bus.peek(addr);
+    let (opcode, len) = if byte == 0xcb {
+        let Some(opcode) = opcodes::lookup_prefixed(bus.peek(addr.wrapping_add(1))) else {
+            return (format!("DB ${byte:02X}"), 1);
+        };
+        (opcode, 2)
+    } else {
+        let Some(opcode) = opcodes::lookup(byte) else {
+            return (format!("DB ${byte:02X}"), 1);
+        };
+        (opcode, opcode.bytes)
+    };
+
+    let imm8 = bus.peek(addr.wrapping_add(1));
+    let imm16 = u16::from_le_bytes([imm8, bus.peek(addr.wrapping_add(2))]);
+    let operands: Vec<String> = [&opcode.reg1, &opcode.reg2]
+        .into_iter()
+        .filter_map(|reg| operand(opcode, reg, addr, imm8, imm16))
+        .collect();
+
+    if operands.is_empty() {
+        (opcode.name.to_string(), len)
+    } else {
+        (format!("{} {}", opcode.name, operands.join(", ")), len)
+    }
+}
+
+// Instruction without its immediates filled in, e.g. "LDH A, (a8)" or "JR NZ, e8"
+pub fn mnemonic(opcode: &Opcode) -> String {
+    let operands: Vec<String> = [&opcode.reg1, &opcode.reg2]
+        .into_iter()
+        .filter_map(|reg| match reg {
+            TargetReg::Imm8 => Some(
+                match opcode.name {
+                    "LDH" => "(a8)",
+                    "JR" => "e8",
+                    "ADD" if matches!(opcode.reg1, TargetReg::SP) => "e8",
+                    "LD" if matches!(opcode.reg1, TargetReg::R16(_)) => "SP+e8",
+                    _ => "n8",
+                }
+                .to_string(),
+            ),
+            TargetReg::R16(r) => Some(R16[*r as usize].to_string()),
+            TargetReg::Imm16 if matches!(opcode.reg2, TargetReg::SP) => Some("(a16)".to_string()),
+            TargetReg::Imm16 => Some("n16".to_string()),
+            TargetReg::Ptr => Some("(a16)".to_string()),
+            reg => operand(opcode, reg, 0, 0, 0),
+        })
+        .collect();
+
+    if operands.is_empty() {
+        opcode.name.to_string()
+    } else {
+        format!("{} {}", opcode.name, operands.join(", "))
+    }
+}
+
+// Raw bytes of the instruction at addr as hex, e.g. "FA 44 FF"
+pub fn instruction_bytes(bus: &Bus, addr: u16, len: u16) -> String {
+    (0..len)
+        .map(|i| format!("{:02X}", bus.peek(addr.wrapping_add(i))))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn operand(opcode: &Opcode, reg: &TargetReg, addr: u16, imm8: u8, imm16: u16) -> Option<String> {
+    let text = match reg {
+        TargetReg::None => return None,
+        TargetReg::R8(r) => R8[*r as usize].to_string(),
+        // LD HL, SP+e8 is the only R16 paired with an 8 bit immediate
+        TargetReg::R16(r) if matches!(opcode.reg2, TargetReg::Imm8) => {
+            format!("{}, SP{}", R16[*r as usize], signed(imm8))
+        }
+        TargetReg::R16(r) => R16[*r as usize].to_string(),
+        TargetReg::R16stk(r) => R16_STK[*r as usize].to_string(),
+        TargetReg::R16mem(r) => R16_MEM[*r as usize].to_string(),
+        TargetReg::Cond(c) => COND[*c as usize].to_string(),
+        TargetReg::B3(b) => b.to_string(),
+        TargetReg::Tgt3(t) => format!("${:02X}", t * 8),
+        TargetReg::A => "A".to_string(),
+        TargetReg::C => "(C)".to_string(),
+        TargetReg::SP => "SP".to_string(),
+        TargetReg::Imm8 => match opcode.name {
+            "LDH" => format!("($FF{imm8:02X})"),
+            // Relative jumps are shown resolved to their target
+            "JR" => format!(
+                "${:04X}",
+                addr.wrapping_add(2).wrapping_add_signed(imm8 as i8 as i16)
+            ),
+            "ADD" if matches!(opcode.reg1, TargetReg::SP) => signed(imm8),
+            // Already printed with HL for LD HL, SP+e8
+            "LD" if matches!(opcode.reg1, TargetReg::R16(_)) => return None,
+            _ => format!("${imm8:02X}"),
+        },
+        // LD (a16), SP stores to memory
+        TargetReg::Imm16 if matches!(opcode.reg2, TargetReg::SP) => format!("(${imm16:04X})"),
+        TargetReg::Imm16 => format!("${imm16:04X}"),
+        TargetReg::Ptr => format!("(${imm16:04X})"),
+    };
+    Some(text)
+}
+
+fn signed(val: u8) -> String {
+    let val = val as i8;
+    if val < 0 {
+        format!("-${:02X}", val.unsigned_abs())
+    } else {
+        format!("+${val:02X}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::{get_mapper, test_rom};
+
+    fn disassemble_bytes(code: &[u8]) -> (String, u16) {
+        let bus = Bus::new(get_mapper(&test_rom(&[(0x0150, code)])).unwrap(), true);
+        disassemble(&bus, 0x0150)
+    }
+
+    #[test]
+    fn test_addressing_forms() {
+        let cases: [(&[u8], &str); 26] = [
+            (&[0x00], "NOP"),
+            (&[0x3e, 0x42], "LD A, $42"),
+            (&[0x01, 0x34, 0x12], "LD BC, $1234"),
+            (&[0x7e], "LD A, (HL)"),
+            (&[0x36, 0x99], "LD (HL), $99"),
+            (&[0x22], "LD (HL+), A"),
+            (&[0x3a], "LD A, (HL-)"),
+            (&[0xea, 0x00, 0xc0], "LD ($C000), A"),
+            (&[0xfa, 0x00, 0xc0], "LD A, ($C000)"),
+            (&[0x08, 0x00, 0xc0], "LD ($C000), SP"),
+            (&[0xf0, 0x44], "LDH A, ($FF44)"),
+            (&[0xe0, 0x80], "LDH ($FF80), A"),
+            (&[0xe2], "LDH (C), A"),
+            (&[0xf8, 0x05], "LD HL, SP+$05"),
+            (&[0xe8, 0xfe], "ADD SP, -$02"),
+            (&[0xf5], "PUSH AF"),
+            (&[0x18, 0xfe], "JR $0150"),
+            (&[0x20, 0x05], "JR NZ, $0157"),
+            (&[0xc4, 0x00, 0x40], "CALL NZ, $4000"),
+            (&[0xc8], "RET Z"),
+            (&[0xe9], "JP HL"),
+            (&[0xff], "RST $38"),
+            (&[0xcb, 0x7c], "BIT 7, H"),
+            (&[0xcb, 0x36], "SWAP (HL)"),
+            (&[0xcb, 0xc7], "SET 0, A"),
+            (&[0xd3], "DB $D3"),
+        ];
+        for (code, text) in cases {
+            let (asm, len) = disassemble_bytes(code);
+            assert_eq!(asm, text);
+            assert_eq!(len as usize, code.len(), "{text}");
+        }
+    }
+
+    #[test]
+    fn test_instruction_bytes() {
+        let bus = Bus::new(
+            get_mapper(&test_rom(&[(0x0150, &[0xfa, 0x44, 0xff])])).unwrap(),
+            true,
+        );
+        assert_eq!(instruction_bytes(&bus, 0x0150, 3), "FA 44 FF");
+    }
+
+    #[test]
+    fn test_mnemonic() {
+        assert_eq!(mnemonic(opcodes::lookup(0xf0).unwrap()), "LDH A, (a8)");
+        assert_eq!(mnemonic(opcodes::lookup(0x20).unwrap()), "JR NZ, e8");
+        assert_eq!(mnemonic(opcodes::lookup(0xf8).unwrap()), "LD HL, SP+e8");
+        assert_eq!(
+            mnemonic(opcodes::lookup_prefixed(0x7c).unwrap()),
+            "BIT 7, H"
+        );
+    }
+}
@@ -0,0 +1,575 @@
+// Runs the emulator on its own thread so UI hitches don't stall emulation or audio.
+// Run control and input go over a command channel and finished frames come back over a
+// bounded one. Debug panels lock the shared Gameboy directly to read or poke at state
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gb_emulator::debugger::BreakEvent;
+use gb_emulator::disasm;
+use gb_emulator::limiter::{FrameLimiter, TURBO_SPEED};
+use gb_emulator::rewind::{self, Rewind};
+use gb_emulator::{Frame, Gameboy, JoypadAction};
+use sdl2::audio::AudioQueue;
+
+use crate::sdl2_setup;
+
+// Seconds of audio the queue is kept at. Twice this and new samples are dropped
+const AUDIO_LATENCY: f64 = 0.05;
+// Volume changes fade over this many seconds so muting doesn't click
+const VOLUME_RAMP: f32 = 0.005;
+// Most the resampling rate is stretched either way to keep the queue at its target. Small
+// enough that the pitch change can't be heard
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+// What emulation is paced off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    Timer,     // The frame limiter's clock
+    AudioSync, // Wait for the audio queue to drain to its target. Only at 1x
+}
+
+pub enum Command {
+    Press(JoypadAction),
+    Release(JoypadAction),
+    Pause(bool),
+    Step(StepKind), // Advance while paused
+    Turbo(bool),    // Run at TURBO_SPEED while held
+    Rewind(bool),   // Step back through recorded states while held
+    Speed(f64),     // Target speed multiplier
+    Slower,         // Step down through the limiter's SPEEDS
+    Faster,
+    Pacing(Pacing),
+    Volume(f32), // Master volume applied to the audio queued, 0 to mute
+}
+
+#[derive(Clone, Copy)]
+pub enum StepKind {
+    Instruction,
+    Scanline, // Until LY changes
+    Frame,    // Until the next vblank
+}
+
+// What a step ran, for the debugger panel
+#[derive(Clone)]
+pub struct StepInfo {
+    pub instr: String,  // Last instruction executed
+    pub before: String, // Registers before the step
+    pub after: String,
+}
+
+// Sent after each emulated frame, and after every command while paused
+pub struct Update {
+    pub frame: Frame,
+    pub paused: bool,
+    pub fps: f32,
+    pub speed: f64, // Persistent speed, not counting turbo
+    pub turbo: bool,
+    pub hit: Option<BreakEvent>, // Breakpoint or watchpoint that paused emulation
+    pub stepped: Option<StepInfo>,
+    pub underruns: u32,   // Times the audio queue ran dry while playing
+    pub audio_queue: f64, // Seconds of audio queued
+    pub audio_ratio: f64, // Resampling ratio the rate control applied
+}
+
+pub struct EmuHandle {
+    gameboy: Arc<Mutex<Gameboy>>,
+    commands: Sender<Command>,
+    updates: Receiver<Update>,
+}
+
+impl EmuHandle {
+    // Frames are dropped rather than queued if the UI falls this far behind
+    const FRAME_QUEUE: usize = 2;
+
+    pub fn spawn(gameboy: Gameboy, trace_on: bool) -> Self {
+        let gameboy = Arc::new(Mutex::new(gameboy));
+        let (commands, command_rx) = mpsc::channel();
+        let (update_tx, updates) = mpsc::sync_channel(EmuHandle::FRAME_QUEUE);
+        let shared = gameboy.clone();
+        thread::spawn(move || EmuThread::new(shared, command_rx, update_tx, trace_on).run());
+        Self {
+            gameboy,
+            commands,
+            updates,
+        }
+    }
+
+    pub fn send(&self, command: Command) {
+        // Only fails if the emulator thread has died, which it reports itself
+        let _ = self.commands.send(command);
+    }
+
+    // Newest update, waiting up to timeout for one. Older queued updates are skipped
+    pub fn latest(&self, timeout: Duration) -> Option<Update> {
+        let mut update = match self.updates.recv_timeout(timeout) {
+            Ok(update) => update,
+            Err(_) => return None,
+        };
+        while let Ok(newer) = self.updates.try_recv() {
+            update = newer;
+        }
+        Some(update)
+    }
+
+    // For the debug panels. Holding the lock stalls the emulator thread
+    pub fn shared(&self) -> Arc<Mutex<Gameboy>> {
+        self.gameboy.clone()
+    }
+}
+
+// A buffered trace file would otherwise lose its tail when the app closes
+impl Drop for EmuHandle {
+    fn drop(&mut self) {
+        if let Ok(mut gameboy) = self.gameboy.lock() {
+            gameboy.cpu.flush_trace();
+        }
+    }
+}
+
+struct EmuThread {
+    gameboy: Arc<Mutex<Gameboy>>,
+    commands: Receiver<Command>,
+    updates: SyncSender<Update>,
+    trace_on: bool,
+    paused: bool,
+    limiter: FrameLimiter,
+    rewind: Rewind,
+    rewinding: bool,
+    fps: f32,
+    frame_count: i32,
+    baseline: Instant,
+    skipped: u32,              // Frames not sent to the UI since the last one that was
+    stepped: Option<StepInfo>, // Goes out with the next update
+    audio_playing: bool,       // Audio was queued last frame, so an empty queue is an underrun
+    underruns: u32,
+    pacing: Pacing,
+    audio_target: u32,    // Bytes in the queue for AUDIO_LATENCY at the device rate
+    audio_byte_rate: f64, // Bytes the device plays per second
+    audio_queue: f64,
+    audio_ratio: f64,
+    volume: f32,    // Target master volume
+    gain: f32,      // Volume being applied, ramping towards volume
+    ramp_step: f32, // Most gain moves per stereo sample
+}
+
+impl EmuThread {
+    fn new(
+        gameboy: Arc<Mutex<Gameboy>>,
+        commands: Receiver<Command>,
+        updates: SyncSender<Update>,
+        trace_on: bool,
+    ) -> Self {
+        Self {
+            gameboy,
+            commands,
+            updates,
+            trace_on,
+            paused: false,
+            limiter: FrameLimiter::new(),
+            rewind: Rewind::new(
+                rewind::DEFAULT_INTERVAL,
+                rewind::DEFAULT_CAPACITY,
+                rewind::DEFAULT_MAX_BYTES,
+            ),
+            rewinding: false,
+            fps: 0.0,
+            frame_count: 0,
+            baseline: Instant::now(),
+            skipped: 0,
+            stepped: None,
+            audio_playing: false,
+            underruns: 0,
+            pacing: Pacing::Timer,
+            audio_target: 0,
+            audio_byte_rate: 1.0,
+            audio_queue: 0.0,
+            audio_ratio: 1.0,
+            volume: 1.0,
+            gain: 1.0,
+            ramp_step: 1.0,
+        }
+    }
+
+    fn run(mut self) {
+        // The SDL audio queue can't be moved between threads so it's opened here
+        let audio_device = sdl2_setup::setup(48_000);
+        let sample_rate = audio_device.spec().freq as u32;
+        self.lock().set_sample_rate(sample_rate);
+        // Interleaved stereo f32
+        self.audio_byte_rate = sample_rate as f64 * 2.0 * 4.0;
+        self.audio_target = (self.audio_byte_rate * AUDIO_LATENCY) as u32;
+        self.ramp_step = 1.0 / (VOLUME_RAMP * sample_rate as f32);
+
+        loop {
+            // While paused nothing runs until a command arrives
+            let command = if self.paused {
+                match self.commands.recv_timeout(Duration::from_millis(100)) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            } else {
+                self.commands.try_recv().ok()
+            };
+            if let Some(command) = command {
+                self.handle(command);
+                continue;
+            }
+            if self.paused {
+                continue;
+            }
+
+            if self.rewinding {
+                let frame = {
+                    let mut gameboy = self.gameboy.lock().unwrap();
+                    self.rewind.rewind(&mut gameboy.cpu);
+                    gameboy.cpu.bus.last_frame.clone()
+                };
+                self.limiter.wait();
+                if !self.send(frame, None) {
+                    return;
+                }
+                continue;
+            }
+
+            let (frame, hit) = self.run_frame();
+            if hit.is_some() {
+                self.paused = true;
+            } else {
+                // play audio. Turbo's samples are dropped entirely rather than played fast, and
+                // above 1x the queue would grow without bound, so drop the extra. That way
+                // nothing is left queued to play late when returning to 1x
+                let queued = audio_device.size();
+                if self.audio_playing && queued == 0 {
+                    self.underruns += 1;
+                }
+                self.audio_playing = !self.limiter.turbo;
+                if !self.limiter.turbo && queued < 2 * self.audio_target {
+                    self.queue_audio(&audio_device);
+                }
+                self.adjust_audio_rate(audio_device.size());
+                self.rewind.record(&self.gameboy.lock().unwrap().cpu);
+                self.wait(&audio_device);
+                self.count_frame();
+
+                // Turbo only shows every Nth frame, the UI can't draw them all anyway
+                if self.limiter.turbo && self.skipped + 1 < TURBO_SPEED as u32 {
+                    self.skipped += 1;
+                    continue;
+                }
+                self.skipped = 0;
+            }
+            if !self.send(frame, hit) {
+                return;
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        // Pausing and rewinding let the queue drain, which isn't an underrun
+        self.audio_playing = false;
+        match command {
+            // The joypad is ignored while rewinding, states bring their own
+            Command::Press(_) | Command::Release(_) if self.rewinding => {}
+            Command::Press(action) => self.lock().press_button(action),
+            Command::Release(action) => self.lock().release_button(action),
+            Command::Rewind(rewinding) => self.rewinding = rewinding,
+            Command::Turbo(turbo) => self.limiter.turbo = turbo,
+            Command::Speed(speed) => self.limiter.speed = speed,
+            Command::Slower => self.limiter.slower(),
+            Command::Faster => self.limiter.faster(),
+            Command::Pacing(pacing) => self.pacing = pacing,
+            Command::Volume(volume) => self.volume = volume,
+            Command::Pause(paused) => {
+                self.paused = paused;
+                let frame = self.lock().cpu.bus.last_frame.clone();
+                self.send(frame, None);
+            }
+            Command::Step(kind) if self.paused => {
+                let (frame, hit) = self.step(kind);
+                self.send(frame, hit);
+            }
+            Command::Step(_) => {}
+        }
+    }
+
+    // Run whole instructions until kind is done or a breakpoint is hit. Capped at a frame's
+    // worth of M-cycles so stepping to the next line with the LCD off still comes back
+    fn step(&mut self, kind: StepKind) -> (Frame, Option<BreakEvent>) {
+        const MAX_STEPS: u32 = 17556;
+        let trace_on = self.trace_on;
+        let mut gameboy = self.gameboy.lock().unwrap();
+        let before = gameboy.cpu.register_string();
+        let line = gameboy.cpu.bus.ppu.scanline;
+        let mut instr = String::new();
+        let mut hit = None;
+        for _ in 0..MAX_STEPS {
+            // A CB prefixed instruction takes two steps, one for the prefix
+            if !gameboy.cpu.prefixed_mode {
+                let pc = gameboy.cpu.program_counter;
+                instr = format!("{pc:04X}  {}", disasm::disassemble(&gameboy.cpu.bus, pc).0);
+            }
+            let frame_ready = gameboy.step(trace_on).is_some();
+            hit = gameboy.cpu.debugger.hit.take();
+            if hit.is_some() {
+                break;
+            }
+            if gameboy.cpu.prefixed_mode {
+                continue;
+            }
+            let done = match kind {
+                StepKind::Instruction => true,
+                StepKind::Scanline => gameboy.cpu.bus.ppu.scanline != line,
+                StepKind::Frame => frame_ready,
+            };
+            if done {
+                break;
+            }
+        }
+        self.stepped = Some(StepInfo {
+            instr,
+            before,
+            after: gameboy.cpu.register_string(),
+        });
+        (gameboy.cpu.bus.last_frame.clone(), hit)
+    }
+
+    // Run until a frame is done or a breakpoint is hit
+    fn run_frame(&mut self) -> (Frame, Option<BreakEvent>) {
+        let trace_on = self.trace_on;
+        let mut gameboy = self.lock();
+        loop {
+            let frame_ready = gameboy.step(trace_on).is_some();
+            if let Some(hit) = gameboy.cpu.debugger.hit.take() {
+                return (gameboy.cpu.bus.last_frame.clone(), Some(hit));
+            }
+            if frame_ready {
+                return (gameboy.cpu.bus.last_frame.clone(), None);
+            }
+        }
+    }
+
+    // Dynamic rate control on the audio queue. Below the target each frame makes a little
+    // more sound, above it a little less, so drift between the emulated and device clocks
+    // neither runs the queue dry nor lets latency build up. Off the normal speed the rate is
+    // left alone
+    fn adjust_audio_rate(&mut self, queued: u32) {
+        let target = self.audio_target as f64;
+        let ratio = if self.limiter.current_speed() != 1.0 {
+            1.0
+        } else {
+            let error = (target - queued as f64) / target;
+            (1.0 + MAX_RATE_ADJUST * error).clamp(1.0 - MAX_RATE_ADJUST, 1.0 + MAX_RATE_ADJUST)
+        };
+        self.lock().cpu.bus.apu.resampler.set_ratio(ratio);
+        self.audio_queue = queued as f64 / self.audio_byte_rate;
+        self.audio_ratio = ratio;
+    }
+
+    // Queue the frame's audio at the master volume. The game's own NR50 volume is already in it
+    fn queue_audio(&mut self, audio_device: &AudioQueue<f32>) {
+        let gameboy = self.gameboy.lock().unwrap();
+        let samples = gameboy.audio_samples();
+        let result = if self.gain == 1.0 && self.volume == 1.0 {
+            audio_device.queue_audio(samples)
+        } else {
+            let scaled = apply_gain(samples, &mut self.gain, self.volume, self.ramp_step);
+            audio_device.queue_audio(&scaled)
+        };
+        result.unwrap();
+    }
+
+    // Pace to the next frame. Audio sync runs once the queue is back down to its target, so
+    // the device's clock sets the speed. At other speeds the queue would drain or pile up, so
+    // those always use the timer
+    fn wait(&mut self, audio_device: &AudioQueue<f32>) {
+        if self.pacing == Pacing::AudioSync && self.limiter.current_speed() == 1.0 {
+            while audio_device.size() > self.audio_target {
+                thread::sleep(Duration::from_millis(1));
+            }
+        } else {
+            self.limiter.wait();
+        }
+    }
+
+    fn count_frame(&mut self) {
+        if self.frame_count == 0 {
+            self.baseline = Instant::now();
+        } else if self.frame_count == 30 {
+            let thirty_frame_time = self.baseline.elapsed().as_secs_f32();
+            self.frame_count = 1;
+            self.baseline = Instant::now();
+            self.fps = 30.0 / thirty_frame_time;
+        }
+        self.frame_count += 1;
+    }
+
+    // Returns false once the UI has gone away
+    fn send(&mut self, frame: Frame, hit: Option<BreakEvent>) -> bool {
+        let update = Update {
+            frame,
+            paused: self.paused,
+            fps: self.fps,
+            speed: self.limiter.speed,
+            turbo: self.limiter.turbo,
+            hit,
+            stepped: self.stepped.take(),
+            underruns: self.underruns,
+            audio_queue: self.audio_queue,
+            audio_ratio: self.audio_ratio,
+        };
+        !matches!(
+            self.updates.try_send(update),
+            Err(TrySendError::Disconnected(_))
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Gameboy> {
+        self.gameboy.lock().unwrap()
+    }
+}
+
+// Scale interleaved stereo samples by gain, moving gain at most step per left/right pair
+// towards target
+fn apply_gain(samples: &[f32], gain: &mut f32, target: f32, step: f32) -> Vec<f32> {
+    let mut scaled = Vec::with_capacity(samples.len());
+    for pair in samples.chunks(2) {
+        *gain = if *gain < target {
+            (*gain + step).min(target)
+        } else {
+            (*gain - step).max(target)
+        };
+        scaled.extend(pair.iter().map(|sample| sample * *gain));
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    // The thread without its run loop, so commands can be handled one at a time and no
+    // audio device is opened
+    fn setup() -> (EmuThread, Receiver<Update>) {
+        let rom = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/tetris.gb")).unwrap();
+        let gameboy = Arc::new(Mutex::new(Gameboy::new(&rom).unwrap()));
+        let (_, command_rx) = mpsc::channel();
+        let (update_tx, updates) = mpsc::sync_channel(EmuHandle::FRAME_QUEUE);
+        (
+            EmuThread::new(gameboy, command_rx, update_tx, false),
+            updates,
+        )
+    }
+
+    // Low nibble of P1 with the buttons selected. Start is bit 3, pressed reads 0
+    fn buttons(thread: &EmuThread) -> u8 {
+        let mut gameboy = thread.lock();
+        gameboy.cpu.bus.joypad.write(0x10);
+        gameboy.cpu.bus.joypad.read() & 0x0f
+    }
+
+    #[test]
+    fn test_pause_sends_update() {
+        let (mut thread, updates) = setup();
+        thread.handle(Command::Pause(true));
+        assert!(updates.try_recv().unwrap().paused);
+        thread.handle(Command::Pause(false));
+        assert!(!updates.try_recv().unwrap().paused);
+    }
+
+    #[test]
+    fn test_step_only_while_paused() {
+        let (mut thread, updates) = setup();
+        thread.handle(Command::Step(StepKind::Instruction));
+        assert!(updates.try_recv().is_err());
+        assert_eq!(thread.lock().cpu.program_counter, 0x0100);
+
+        thread.handle(Command::Pause(true));
+        updates.try_recv().unwrap();
+        thread.handle(Command::Step(StepKind::Instruction));
+        let stepped = updates.try_recv().unwrap().stepped.unwrap();
+        assert!(stepped.instr.starts_with("0100"));
+        assert_ne!(thread.lock().cpu.program_counter, 0x0100);
+    }
+
+    #[test]
+    fn test_step_scanline() {
+        let (mut thread, updates) = setup();
+        thread.handle(Command::Pause(true));
+        updates.try_recv().unwrap();
+        let line = thread.lock().cpu.bus.ppu.scanline;
+        thread.handle(Command::Step(StepKind::Scanline));
+        assert!(updates.try_recv().unwrap().stepped.is_some());
+        assert_ne!(thread.lock().cpu.bus.ppu.scanline, line);
+    }
+
+    #[test]
+    fn test_joypad_ignored_while_rewinding() {
+        let (mut thread, _updates) = setup();
+        thread.handle(Command::Press(JoypadAction::Start));
+        assert_eq!(buttons(&thread), 0x07);
+        thread.handle(Command::Release(JoypadAction::Start));
+        assert_eq!(buttons(&thread), 0x0f);
+
+        thread.handle(Command::Rewind(true));
+        thread.handle(Command::Press(JoypadAction::Start));
+        assert_eq!(buttons(&thread), 0x0f);
+        thread.handle(Command::Rewind(false));
+        thread.handle(Command::Press(JoypadAction::Start));
+        assert_eq!(buttons(&thread), 0x07);
+    }
+
+    #[test]
+    fn test_speed_commands() {
+        let (mut thread, _updates) = setup();
+        thread.handle(Command::Speed(0.5));
+        thread.handle(Command::Faster);
+        assert_eq!(thread.limiter.speed, 0.75);
+        thread.handle(Command::Slower);
+        thread.handle(Command::Slower);
+        assert_eq!(thread.limiter.speed, 0.25);
+        thread.handle(Command::Turbo(true));
+        assert_eq!(thread.limiter.current_speed(), TURBO_SPEED);
+        thread.handle(Command::Pacing(Pacing::AudioSync));
+        assert_eq!(thread.pacing, Pacing::AudioSync);
+        thread.handle(Command::Volume(0.0));
+        assert_eq!(thread.volume, 0.0);
+    }
+
+    #[test]
+    fn test_steady_gain() {
+        let mut gain = 0.5;
+        let scaled = apply_gain(&[1.0, -1.0, 0.5, 0.25], &mut gain, 0.5, 0.1);
+        assert_eq!(scaled, [0.5, -0.5, 0.25, 0.125]);
+        assert_eq!(gain, 0.5);
+    }
+
+    #[test]
+    fn test_mute_ramp() {
+        let mut gain = 1.0;
+        let scaled = apply_gain(&[1.0; 8], &mut gain, 0.0, 0.25);
+        // Both samples of a pair get the same gain
+        assert_eq!(scaled, [0.75, 0.75, 0.5, 0.5, 0.25, 0.25, 0.0, 0.0]);
+        assert_eq!(gain, 0.0);
+        let scaled = apply_gain(&[1.0; 4], &mut gain, 1.0, 0.25);
+        assert_eq!(scaled, [0.25, 0.25, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_ramp_step_bound() {
+        let mut gain = 1.0;
+        let scaled = apply_gain(&[1.0; 240], &mut gain, 0.0, 0.01);
+        for pair in scaled.chunks(2).collect::<Vec<_>>().windows(2) {
+            assert!((pair[0][0] - pair[1][0]).abs() <= 0.01 + f32::EPSILON);
+        }
+        assert_eq!(gain, 0.0);
+        // A step bigger than the distance lands on the target, not past it
+        let mut gain = 0.2;
+        apply_gain(&[1.0; 2], &mut gain, 0.5, 1.0);
+        assert_eq!(gain, 0.5);
+    }
+}
@@ -0,0 +1,427 @@
+// Pixel FIFO renderer. Used in place of LineRenderer with the accurate_ppu feature.
+// Tiles are fetched 8 pixels at a time and shifted out one pixel per dot, so writes to
+// SCX, LCDC or the palettes during mode 3 take effect part way through the line
+use std::collections::VecDeque;
+
+use crate::ppu::{Control, Ppu};
+use crate::render::{self, BgPixel, Frame, SpritePixel};
+use crate::state::{State, StateError, StateReader, StateWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PixelEntry {
+    pub color_id: u8,      // 2-bit color index. 0 is transparent for sprites
+    pub palette: u8,       // BG: CGB palette 0-7. OBJ: DMG 0 = OBP0, 1 = OBP1, CGB palette 0-7
+    pub bg_priority: bool, // BG colors 1-3 are drawn over sprites (BG attribute or OBJ attribute)
+}
+
+impl State for PixelEntry {
+    fn save(&self, w: &mut StateWriter) {
+        self.color_id.save(w);
+        self.palette.save(w);
+        self.bg_priority.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.color_id.load(r)?;
+        self.palette.load(r)?;
+        self.bg_priority.load(r)?;
+        Ok(())
+    }
+}
+
+pub struct BgFifo {
+    pub pixels: VecDeque<PixelEntry>,
+}
+
+pub struct ObjFifo {
+    pub pixels: VecDeque<PixelEntry>,
+    pub oam_index: VecDeque<usize>, // Sprite each pixel came from, for CGB priority
+}
+
+// Tile, DataLow and DataHigh take 2 dots each. Push waits until the BG FIFO is empty
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FetcherState {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+pub struct PixelFifo {
+    pub bg: BgFifo,
+    pub obj: ObjFifo,
+    pub state: FetcherState,
+    pub active: bool, // Still drawing the current line
+    step_dots: u8,
+    fetcher_x: u8, // Tile column relative to the start of the BG or window
+    tile_id: u8,
+    attributes: u8, // CGB BG map attributes. 0 on DMG
+    data_low: u8,
+    data_high: u8,
+    lcd_x: u8,                   // Next screen pixel to be drawn
+    discard: u8,                 // Pixels dropped from the start of the line for SCX % 8
+    window: bool,                // Fetching from the window map
+    stall: u8,                   // Dots left on a sprite fetch
+    sprites_fetched: [bool; 10], // Indexed like scanline_oams
+}
+
+impl State for PixelFifo {
+    fn save(&self, w: &mut StateWriter) {
+        self.bg.pixels.save(w);
+        self.obj.pixels.save(w);
+        self.obj.oam_index.save(w);
+        (self.state as u8).save(w);
+        self.active.save(w);
+        self.step_dots.save(w);
+        self.fetcher_x.save(w);
+        self.tile_id.save(w);
+        self.attributes.save(w);
+        self.data_low.save(w);
+        self.data_high.save(w);
+        self.lcd_x.save(w);
+        self.discard.save(w);
+        self.window.save(w);
+        self.stall.save(w);
+        self.sprites_fetched.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.bg.pixels.load(r)?;
+        self.obj.pixels.load(r)?;
+        self.obj.oam_index.load(r)?;
+        self.state = match r.read::<u8>()? {
+            0 => FetcherState::Tile,
+            1 => FetcherState::DataLow,
+            2 => FetcherState::DataHigh,
+            _ => FetcherState::Push,
+        };
+        self.active.load(r)?;
+        self.step_dots.load(r)?;
+        self.fetcher_x.load(r)?;
+        self.tile_id.load(r)?;
+        self.attributes.load(r)?;
+        self.data_low.load(r)?;
+        self.data_high.load(r)?;
+        self.lcd_x.load(r)?;
+        self.discard.load(r)?;
+        self.window.load(r)?;
+        self.stall.load(r)?;
+        self.sprites_fetched.load(r)?;
+        Ok(())
+    }
+}
+
+impl PixelFifo {
+    // A sprite fetch pauses the pipeline for this many dots
+    const SPRITE_FETCH_DOTS: u8 = 6;
+
+    pub fn new() -> Self {
+        Self {
+            bg: BgFifo {
+                pixels: VecDeque::with_capacity(16),
+            },
+            obj: ObjFifo {
+                pixels: VecDeque::with_capacity(8),
+                oam_index: VecDeque::with_capacity(8),
+            },
+            state: FetcherState::Tile,
+            active: false,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile_id: 0,
+            attributes: 0,
+            data_low: 0,
+            data_high: 0,
+            lcd_x: 0,
+            discard: 0,
+            window: false,
+            stall: 0,
+            sprites_fetched: [false; 10],
+        }
+    }
+
+    // Called when mode 3 starts, after the OAM scan
+    pub fn start_line(&mut self, ppu: &Ppu) {
+        self.bg.pixels.clear();
+        self.obj.pixels.clear();
+        self.obj.oam_index.clear();
+        self.state = FetcherState::Tile;
+        self.active = true;
+        self.step_dots = 0;
+        self.fetcher_x = 0;
+        self.lcd_x = 0;
+        self.discard = ppu.scx % 8;
+        self.window = false;
+        self.stall = 0;
+        self.sprites_fetched = [false; 10];
+    }
+
+    // Run for the given number of dots. The PPU stretches mode 3 for SCX, the window and
+    // sprite fetches, so the line is done by the time it ends. Finishing the rest at once
+    // when the PPU leaves mode 3 is only a safety net against a line left half drawn
+    pub fn tick(&mut self, ppu: &mut Ppu, frame: &mut Frame, dots: usize) {
+        for _ in 0..dots {
+            if !self.active {
+                return;
+            }
+            self.dot(ppu, frame);
+        }
+        if !ppu.is_mode3() {
+            while self.active {
+                self.dot(ppu, frame);
+            }
+        }
+    }
+
+    fn dot(&mut self, ppu: &mut Ppu, frame: &mut Frame) {
+        if self.stall > 0 {
+            self.stall -= 1;
+            return;
+        }
+
+        // Reaching the window throws away the BG pixels and restarts the fetcher on the window map
+        if !self.window
+            && ppu.control.contains(Control::window_enable)
+            && ppu.scanline >= ppu.wy
+            && self.lcd_x as usize + 7 >= ppu.wx as usize
+        {
+            self.window = true;
+            self.bg.pixels.clear();
+            self.state = FetcherState::Tile;
+            self.step_dots = 0;
+            self.fetcher_x = 0;
+            // WX below 7 starts the window off the left edge
+            self.discard = if self.lcd_x == 0 {
+                7u8.saturating_sub(ppu.wx)
+            } else {
+                0
+            };
+        }
+
+        // This dot is the first of the fetch
+        if self.discard == 0 && self.fetch_sprite(ppu) {
+            self.stall = PixelFifo::SPRITE_FETCH_DOTS - 1;
+            return;
+        }
+
+        self.fetcher_step(ppu);
+
+        let Some(bg) = self.bg.pixels.pop_front() else {
+            return;
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        self.obj.oam_index.pop_front();
+        let sprite = self
+            .obj
+            .pixels
+            .pop_front()
+            .filter(|pixel| pixel.color_id != 0)
+            .map(|pixel| SpritePixel {
+                color_id: pixel.color_id,
+                palette: pixel.palette,
+                bg_priority: pixel.bg_priority,
+            });
+        let bg = BgPixel {
+            color_id: bg.color_id,
+            attributes: bg.palette | ((bg.bg_priority as u8) << 7),
+            is_window: self.window,
+        };
+        let y = ppu.scanline as usize;
+        render::draw_pixel(ppu, self.lcd_x as usize, y, bg, sprite, frame);
+
+        self.lcd_x += 1;
+        if self.lcd_x == 160 {
+            self.active = false;
+        }
+    }
+
+    // Load the next sprite that starts at the current pixel into the OBJ FIFO.
+    // Returns true if one was fetched
+    fn fetch_sprite(&mut self, ppu: &Ppu) -> bool {
+        if !ppu.control.contains(Control::obj_enable) {
+            return false;
+        }
+        let lcd_x = self.lcd_x as usize;
+        let y = ppu.scanline as usize;
+        let next = ppu.scanline_oams.iter().enumerate().find(|&(i, &sprite)| {
+            !self.sprites_fetched[i] && ppu.oam[4 * sprite + 1] as usize <= lcd_x + 8
+        });
+        let Some((i, &sprite_index)) = next else {
+            return false;
+        };
+        self.sprites_fetched[i] = true;
+
+        // Screen columns covered by the sprite. Those left of the screen are dropped
+        let x_byte = ppu.oam[4 * sprite_index + 1] as usize;
+        for x in x_byte.saturating_sub(8).max(lcd_x)..x_byte {
+            let slot = x - lcd_x;
+            let pixel = render::sprite_pixel(ppu, x, y, sprite_index).map(|pixel| PixelEntry {
+                color_id: pixel.color_id,
+                palette: pixel.palette,
+                bg_priority: pixel.bg_priority,
+            });
+            while self.obj.pixels.len() <= slot {
+                self.obj.pixels.push_back(PixelEntry {
+                    color_id: 0,
+                    palette: 0,
+                    bg_priority: false,
+                });
+                self.obj.oam_index.push_back(usize::MAX);
+            }
+            // On DMG sprites are fetched in priority order, so earlier sprites keep their
+            // opaque pixels. On CGB a lower OAM index wins even if it was fetched later
+            if let Some(pixel) = pixel {
+                let existing = self.obj.pixels[slot];
+                let wins =
+                    existing.color_id == 0 || (ppu.cgb && sprite_index < self.obj.oam_index[slot]);
+                if wins {
+                    self.obj.pixels[slot] = pixel;
+                    self.obj.oam_index[slot] = sprite_index;
+                }
+            }
+        }
+        true
+    }
+
+    fn fetcher_step(&mut self, ppu: &Ppu) {
+        if self.state == FetcherState::Push {
+            if self.bg.pixels.is_empty() {
+                self.push_tile();
+                self.fetcher_x = self.fetcher_x.wrapping_add(1);
+                self.state = FetcherState::Tile;
+            }
+            return;
+        }
+
+        self.step_dots += 1;
+        if self.step_dots < 2 {
+            return;
+        }
+        self.step_dots = 0;
+
+        self.state = match self.state {
+            FetcherState::Tile => {
+                let map_addr = self.map_addr(ppu);
+                self.tile_id = ppu.vram.read(0, map_addr);
+                self.attributes = if ppu.cgb {
+                    ppu.vram.read(1, map_addr)
+                } else {
+                    0
+                };
+                FetcherState::DataLow
+            }
+            FetcherState::DataLow => {
+                self.data_low = ppu.vram.read(self.bank(), self.row_addr(ppu));
+                FetcherState::DataHigh
+            }
+            FetcherState::DataHigh => {
+                self.data_high = ppu.vram.read(self.bank(), self.row_addr(ppu) + 1);
+                FetcherState::Push
+            }
+            FetcherState::Push => FetcherState::Push,
+        };
+    }
+
+    // SCX and SCY are read on every fetch so changes apply from the next tile
+    fn map_addr(&self, ppu: &Ppu) -> u16 {
+        let (area, tile_x, tile_y) = if self.window {
+            (
+                Control::window_map_area,
+                self.fetcher_x as u16 & 31,
+                ppu.window_line_counter as u16 / 8,
+            )
+        } else {
+            let y = ppu.scanline.wrapping_add(ppu.scy);
+            (
+                Control::bg_tile_area,
+                (ppu.scx as u16 / 8 + self.fetcher_x as u16) & 31,
+                y as u16 / 8,
+            )
+        };
+        let base = if ppu.control.contains(area) {
+            0x9c00
+        } else {
+            0x9800
+        };
+        base + tile_x + 32 * tile_y
+    }
+
+    fn row_addr(&self, ppu: &Ppu) -> u16 {
+        let mut row = if self.window {
+            ppu.window_line_counter as u16 % 8
+        } else {
+            ppu.scanline.wrapping_add(ppu.scy) as u16 % 8
+        };
+        // Y flip
+        if self.attributes & 0b0100_0000 > 0 {
+            row = 7 - row;
+        }
+        render::tile_addr(ppu, self.tile_id, false) + 2 * row
+    }
+
+    fn bank(&self) -> usize {
+        (self.attributes & 0b0000_1000 > 0) as usize
+    }
+
+    fn push_tile(&mut self) {
+        let x_flip = self.attributes & 0b0010_0000 > 0;
+        for i in 0..8 {
+            let bit = if x_flip { i } else { 7 - i };
+            let lo = (self.data_low >> bit) & 1;
+            let hi = (self.data_high >> bit) & 1;
+            self.bg.pixels.push_back(PixelEntry {
+                color_id: (hi << 1) | lo,
+                palette: self.attributes & 0b0000_0111,
+                bg_priority: self.attributes & 0b1000_0000 > 0,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::DisplayStatus;
+
+    // LCD on at the start of line 0's mode 3 with sprites at xs, all on line 0
+    fn line_start(scx: u8, xs: &[u8]) -> Ppu {
+        let mut ppu = Ppu::new(false);
+        ppu.scx = scx;
+        for (i, &x) in xs.iter().enumerate() {
+            ppu.oam[4 * i] = 16;
+            ppu.oam[4 * i + 1] = x;
+        }
+        // LCD, tile data at 0x8000, sprites and background on
+        ppu.write_to_ctrl(0x93);
+        while !matches!(ppu.tick(1).0, DisplayStatus::NewScanline) {}
+        ppu.oam_scan();
+        ppu
+    }
+
+    #[test]
+    fn test_line_done_by_mode3_end() {
+        for scx in 0..8 {
+            for xs in [
+                &[][..],
+                &[8],
+                &[0, 8, 20, 21, 100],
+                &[8, 16, 24, 32, 40, 48, 56, 64, 72, 80],
+                &[13; 10],
+            ] {
+                let mut ppu = line_start(scx, xs);
+                let mut fifo = PixelFifo::new();
+                let mut frame = Frame::new();
+                fifo.start_line(&ppu);
+                // Still in mode 3, so all 160 pixels come from the FIFO's own timing
+                let dots = ppu.mode3_dots;
+                fifo.tick(&mut ppu, &mut frame, dots);
+                assert!(ppu.is_mode3());
+                assert!(!fifo.active, "SCX {scx}, sprites at {xs:?}");
+                assert_eq!(fifo.lcd_x, 160);
+            }
+        }
+    }
+}
@@ -1,53 +1,129 @@
 use eframe::egui::{self, Event};
 use egui_plot::{Line, Plot, PlotPoints};
-use sdl2::audio::AudioQueue;
 
-use lazy_static::lazy_static;
+use crate::config::{Action, Config};
+use crate::emu_thread::{Command, EmuHandle, Pacing, StepInfo, StepKind};
+use gb_emulator::cpu::Cpu;
+use gb_emulator::debugger::BreakEvent;
+use gb_emulator::limiter::{SPEEDS, TURBO_SPEED};
+use gb_emulator::ppu::{Control, Ppu};
+use gb_emulator::{apu, capture, header, render, Gameboy};
 
-use crate::apu;
-use crate::render;
-use crate::Cpu;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use std::collections::HashMap;
-use std::time::Instant;
-use std::{fs, path::PathBuf};
+// The screen is drawn at this many times its native size, unless --scale says otherwise
+pub const SCREEN_SCALE: u32 = 3;
+pub const MAX_SCALE: u32 = 6;
+const SIDE_PANEL_WIDTH: f32 = 512.0;
+
+// Window size that fits the screen at scale next to the side panel
+pub fn window_size(scale: u32) -> egui::Vec2 {
+    egui::vec2(
+        SIDE_PANEL_WIDTH + 160.0 * scale as f32,
+        126.0 + 144.0 * scale as f32,
+    )
+}
+
+// A slider has a new value to save: a drag has finished or it changed without one. Saves
+// once instead of every frame of the drag
+fn settled(response: &egui::Response) -> bool {
+    response.drag_stopped() || (response.changed() && !response.dragged())
+}
+
+// Largest whole multiple of 160x144 that fits, so pixels stay square. At least 1
+fn fit_scale(available: egui::Vec2) -> u32 {
+    ((available.x / 160.0).min(available.y / 144.0) as u32).max(1)
+}
 
 pub struct GameSelect<'a> {
-    filepaths: Vec<PathBuf>,
+    recent: Vec<(PathBuf, String)>, // Recently opened ROMs, listed first
+    filepaths: Vec<(PathBuf, String)>, // With the title from each ROM's header
     selected_item: Option<PathBuf>,
     selected_game: &'a mut Option<PathBuf>,
 }
 
 impl<'a> GameSelect<'a> {
-    pub fn new(selected_game: &'a mut Option<PathBuf>) -> Self {
-        let paths = fs::read_dir("roms/games/").unwrap();
+    pub fn new(selected_game: &'a mut Option<PathBuf>, recent_roms: &[PathBuf]) -> Self {
+        // Recent ROMs that are still there
+        let recent = recent_roms
+            .iter()
+            .filter_map(|path| {
+                let rom = fs::read(path).ok()?;
+                Some((path.clone(), header::title(&rom)))
+            })
+            .collect();
         let mut filepaths = Vec::new();
-        for path in paths {
-            filepaths.push(path.unwrap().path());
+        if let Ok(paths) = fs::read_dir("roms/games/") {
+            for path in paths.flatten() {
+                let path = path.path();
+                let title = fs::read(&path)
+                    .map(|rom| header::title(&rom))
+                    .unwrap_or_default();
+                filepaths.push((path, title));
+            }
         }
         Self {
-            filepaths: filepaths,
+            recent,
+            filepaths,
             selected_item: None,
             selected_game,
         }
     }
 }
 
+// Native file dialog filtered to Game Boy ROMs, starting next to the last ROM opened
+pub fn pick_rom_file(last_rom: Option<&Path>) -> Option<PathBuf> {
+    let mut dialog = rfd::FileDialog::new().add_filter("Game Boy ROM", &["gb", "gbc"]);
+    if let Some(dir) = last_rom.and_then(Path::parent) {
+        dialog = dialog.set_directory(dir);
+    }
+    dialog.pick_file()
+}
+
 impl eframe::App for GameSelect<'_> {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
             if self.selected_item.is_none() {
+                if !self.recent.is_empty() {
+                    ui.heading("Recent");
+                    for (file, title) in &self.recent {
+                        let name = file.file_name().unwrap_or_default().to_string_lossy();
+                        let label = if title.is_empty() {
+                            name.into_owned()
+                        } else {
+                            format!("{title}  ({name})")
+                        };
+                        if ui
+                            .button(label)
+                            .on_hover_text(file.display().to_string())
+                            .clicked()
+                        {
+                            self.selected_item = Some(file.clone());
+                        }
+                    }
+                    ui.separator();
+                }
+                if ui.button("Open ROM").clicked() {
+                    let last_rom = self.recent.first().map(|(file, _)| file.as_path());
+                    self.selected_item = pick_rom_file(last_rom);
+                }
                 egui::ComboBox::from_label("Select a Game: ").show_ui(ui, |ui| {
-                    for file in &self.filepaths {
-                        ui.selectable_value(
-                            &mut self.selected_item,
-                            Some(file.clone()),
-                            file.to_string_lossy().strip_prefix("roms/games/").unwrap(),
-                        );
+                    for (file, title) in &self.filepaths {
+                        let name = file.to_string_lossy();
+                        let name = name.strip_prefix("roms/games/").unwrap();
+                        let label = if title.is_empty() {
+                            egui::RichText::new(name)
+                        } else {
+                            egui::RichText::new(format!("{title}  ({name})")).strong()
+                        };
+                        ui.selectable_value(&mut self.selected_item, Some(file.clone()), label);
                     }
                 });
             } else {
                 *self.selected_game = self.selected_item.clone();
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
             }
         });
     }
@@ -58,40 +134,86 @@ pub struct MyApp {
     map_options: MapOptions,
     audio_display: AudioDisplay,
     side_panel: SidePanel,
+    config: Config,
+    rebinding: Option<Action>, // Next key pressed is bound to this action
     paused: bool,
+    break_input: String,             // Hex address typed into the debugger panel
+    last_break: Option<BreakEvent>,  // What paused emulation, shown in the debugger panel
+    last_executed: Option<StepInfo>, // What the last F, N or V step ran
+    memory_region: MemoryRegion,
+    memory_start: String, // Hex start address for MemoryRegion::Custom
+    memory_edit: Option<(u16, String)>, // Byte being edited in the memory viewer
+    speed: f64,           // Target speed multiplier sent to the emulator thread
+    turbo: bool,
     fps: f32,
-    frame_count: i32,
-    baseline: Instant,
-    trace_on: bool,
-    audio_device: AudioQueue<f32>,
-    cpu: Cpu,
+    underruns: u32,       // Audio queue underruns reported by the emulator thread
+    audio_queue: f64,     // Seconds of audio queued, for the APU panel
+    audio_ratio: f64,     // Resampling ratio the emulator thread's rate control applied
+    audio_sync: bool,     // Pace emulation off the audio queue instead of the timer
+    frame: render::Frame, // Latest frame from the emulator thread
+    emu: EmuHandle,
+    rom_path: PathBuf,
+    state_path: PathBuf,               // Quicksave slot 0, next to the ROM
+    scale: u32,                        // Windowed screen scale, shrunk if the window is too small
+    fullscreen: bool,                  // Only the screen, at the largest scale that fits
+    screenshot_scaled: bool,           // Save screenshots at scale rather than 160x144
+    status: Option<(String, Instant)>, // Message shown under the screen for a few seconds
     texture: egui::TextureHandle,
     tilemap_one_texture: egui::TextureHandle,
     tilemap_two_texture: egui::TextureHandle,
     sprite_texture: egui::TextureHandle,
+    tile_data_texture: egui::TextureHandle,
+    show_viewport: bool, // Outline the screen and window on the tile map views
+    wave_ram: [u8; 32],  // Wave channel samples as of the last frame, unless frozen
+    wave_ram_frozen: bool,
+    #[cfg(feature = "heatmap")]
+    heatmap_texture: egui::TextureHandle,
+    #[cfg(feature = "heatmap")]
+    heatmap_writes: bool, // Show write counts instead of reads
 }
 
 impl MyApp {
     pub fn new(
-        frame_count: i32,
-        baseline: Instant,
         trace_on: bool,
-        audio_device: AudioQueue<f32>,
-        cpu: Cpu,
+        gameboy: Gameboy,
+        config: Config,
+        rom_path: PathBuf,
+        scale: u32,
         cc: &eframe::CreationContext<'_>,
     ) -> Self {
+        let frame = gameboy.cpu.bus.last_frame.clone();
+        let emu = EmuHandle::spawn(gameboy, trace_on);
+        // The emulator thread starts at full volume
+        emu.send(Command::Volume(config.gain()));
         Self {
             screen_options: ScreenOptions::All,
             map_options: MapOptions::Tilemap1,
             audio_display: AudioDisplay::SquareOne,
             side_panel: SidePanel::Cpu,
+            config,
+            rebinding: None,
             paused: false,
+            break_input: String::new(),
+            last_break: None,
+            last_executed: None,
+            memory_region: MemoryRegion::Wram,
+            memory_start: String::new(),
+            memory_edit: None,
+            speed: 1.0,
+            turbo: false,
             fps: 0.0,
-            frame_count,
-            baseline,
-            trace_on,
-            audio_device,
-            cpu,
+            underruns: 0,
+            audio_queue: 0.0,
+            audio_ratio: 1.0,
+            audio_sync: false,
+            frame,
+            emu,
+            state_path: rom_path.with_extension("ss0"),
+            rom_path,
+            scale,
+            fullscreen: false,
+            screenshot_scaled: false,
+            status: None,
             texture: cc.egui_ctx.load_texture(
                 "Noise",
                 egui::ColorImage::example(),
@@ -112,68 +234,172 @@ impl MyApp {
                 egui::ColorImage::example(),
                 egui::TextureOptions::NEAREST,
             ),
+            tile_data_texture: cc.egui_ctx.load_texture(
+                "Noise",
+                egui::ColorImage::example(),
+                egui::TextureOptions::NEAREST,
+            ),
+            show_viewport: true,
+            wave_ram: [0; 32],
+            wave_ram_frozen: false,
+            #[cfg(feature = "heatmap")]
+            heatmap_texture: cc.egui_ctx.load_texture(
+                "Noise",
+                egui::ColorImage::example(),
+                egui::TextureOptions::NEAREST,
+            ),
+            #[cfg(feature = "heatmap")]
+            heatmap_writes: false,
         }
     }
 }
 
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        // Step CPU and capture latest frame
-        let mut new_frame = None;
-        while new_frame.is_none() && !self.paused {
-            new_frame = self.step_gb();
+        // Wait for the emulator thread's next frame. While paused there may not be one
+        if let Some(update) = self.emu.latest(Duration::from_millis(20)) {
+            self.frame = update.frame;
+            self.paused = update.paused;
+            self.fps = update.fps;
+            self.underruns = update.underruns;
+            self.audio_queue = update.audio_queue;
+            self.audio_ratio = update.audio_ratio;
+            self.speed = update.speed;
+            self.turbo = update.turbo;
+            if update.hit.is_some() {
+                self.last_break = update.hit;
+            }
+            if update.stepped.is_some() {
+                self.last_executed = update.stepped;
+            }
         }
+        // Debug panels read and poke at the emulator while it's locked
+        let shared = self.emu.shared();
+        let mut gameboy = shared.lock().unwrap();
+        let gameboy = &mut *gameboy;
 
-        if self.paused {
-            new_frame = Some(self.cpu.bus.last_frame.clone());
-        };
-
+        // Viewport commands can't be sent while input is locked
+        let mut toggle_fullscreen = false;
         ctx.input(|i| {
             for event in &i.events {
+                // Capture the next key for the binding editor instead of passing it to the game
+                if let (
+                    Some(action),
+                    Event::Key {
+                        key, pressed: true, ..
+                    },
+                ) = (self.rebinding, event)
+                {
+                    self.config.keys.rebind(action, key.name());
+                    self.save_config();
+                    self.rebinding = None;
+                    continue;
+                }
+
                 match event {
                     Event::Key {
                         key: egui::Key::Escape,
                         ..
-                    } => std::process::exit(0),
-                    // Pause Emulation
+                    } => {
+                        gameboy.cpu.flush_trace();
+                        self.save_battery(gameboy);
+                        std::process::exit(0)
+                    }
+                    // Print the opcode profile to stdout
+                    #[cfg(feature = "profiler")]
                     Event::Key {
-                        key: egui::Key::P,
+                        key: egui::Key::X,
                         pressed: true,
                         ..
-                    } => {
-                        self.paused = !self.paused;
-                    }
-                    // Step CPU by one
+                    } => gameboy.cpu.print_profile(),
+                    // Quicksave to slot 0
                     Event::Key {
-                        key: egui::Key::F,
+                        key: egui::Key::F5,
                         pressed: true,
                         ..
                     } => {
-                        if self.paused {
-                            self.step_gb();
-                            new_frame = Some(self.cpu.bus.last_frame.clone());
+                        let state = gameboy.cpu.save_state();
+                        match fs::write(&self.state_path, state) {
+                            Ok(()) => eprintln!("Saved state to {}", self.state_path.display()),
+                            Err(err) => eprintln!("Could not save state: {err}"),
                         }
                     }
+                    // Quickload slot 0
                     Event::Key {
-                        pressed: true, key, ..
-                    } => {
-                        if let Some(&(mode, button)) = KEY_MAP.get(&key) {
-                            self.cpu
-                                .bus
-                                .joypad
-                                .button_pressed_status(mode, button, true);
+                        key: egui::Key::F8,
+                        pressed: true,
+                        ..
+                    } => match fs::read(&self.state_path) {
+                        Ok(state) => {
+                            if let Err(err) = gameboy.cpu.load_state(&state) {
+                                eprintln!("Could not load state: {err}");
+                            }
+                            self.frame = gameboy.cpu.bus.last_frame.clone();
                         }
-                    }
+                        Err(err) => eprintln!("Could not read state: {err}"),
+                    },
+                    // Toggle fullscreen with F11 or Alt+Enter
                     Event::Key {
-                        pressed: false,
-                        key,
+                        key: egui::Key::F11,
+                        pressed: true,
                         ..
-                    } => {
-                        if let Some(&(mode, button)) = KEY_MAP.get(&key) {
-                            self.cpu
-                                .bus
-                                .joypad
-                                .button_pressed_status(mode, button, false);
+                    } => toggle_fullscreen = true,
+                    Event::Key {
+                        key: egui::Key::Enter,
+                        pressed: true,
+                        modifiers,
+                        ..
+                    } if modifiers.alt => toggle_fullscreen = true,
+                    // Step CPU by one instruction, to the next scanline or to the next vblank
+                    Event::Key {
+                        key: egui::Key::F,
+                        pressed: true,
+                        ..
+                    } if self.paused => self.emu.send(Command::Step(StepKind::Instruction)),
+                    Event::Key {
+                        key: egui::Key::N,
+                        pressed: true,
+                        ..
+                    } if self.paused => self.emu.send(Command::Step(StepKind::Scanline)),
+                    Event::Key {
+                        key: egui::Key::V,
+                        pressed: true,
+                        ..
+                    } if self.paused => self.emu.send(Command::Step(StepKind::Frame)),
+                    Event::Key { key, pressed, .. } => {
+                        match (self.config.keys.action(key.name()), pressed) {
+                            (Some(Action::Button(action)), true) => {
+                                self.emu.send(Command::Press(action))
+                            }
+                            (Some(Action::Button(action)), false) => {
+                                self.emu.send(Command::Release(action))
+                            }
+                            // Hold to fast forward
+                            (Some(Action::Turbo), _) => self.emu.send(Command::Turbo(*pressed)),
+                            // Hold to run backwards
+                            (Some(Action::Rewind), _) => self.emu.send(Command::Rewind(*pressed)),
+                            (Some(Action::Screenshot), true) => self.screenshot(),
+                            (Some(Action::Mute), true) => {
+                                self.config.muted = !self.config.muted;
+                                self.volume_changed();
+                            }
+                            (Some(Action::VolumeDown), true) => {
+                                self.config.step_volume(false);
+                                self.volume_changed();
+                            }
+                            (Some(Action::VolumeUp), true) => {
+                                self.config.step_volume(true);
+                                self.volume_changed();
+                            }
+                            (Some(Action::Slower), true) => self.emu.send(Command::Slower),
+                            (Some(Action::Faster), true) => self.emu.send(Command::Faster),
+                            // Pause Emulation
+                            (Some(Action::Pause), true) => {
+                                self.paused = !self.paused;
+                                self.emu.send(Command::Pause(self.paused));
+                                self.last_break = None;
+                            }
+                            _ => {}
                         }
                     }
                     _ => {}
@@ -181,30 +407,53 @@ impl eframe::App for MyApp {
             }
         });
 
-        // PPU Screen Option. Decide which frame to render
-        let frame = match self.screen_options {
-            ScreenOptions::All => new_frame.unwrap().data,
-            ScreenOptions::BackgroundOnly => self.cpu.bus.ppu.bg_screen.to_vec(),
-            ScreenOptions::WindowOnly => self.cpu.bus.ppu.win_screen.to_vec(),
-            ScreenOptions::SpritesOnly => self.cpu.bus.ppu.spr_screen.to_vec(),
+        if toggle_fullscreen {
+            self.fullscreen = !self.fullscreen;
+            ctx.send_viewport_cmd(egui::ViewportCommand::Fullscreen(self.fullscreen));
+        }
+
+        // PPU Screen Option. Decide which frame to render. The layers are only drawn while shown
+        gameboy.cpu.bus.ppu.debug_layers = self.screen_options != ScreenOptions::All;
+        let layers = &gameboy.cpu.bus.ppu.layers;
+        let frame: &[u8] = match self.screen_options {
+            ScreenOptions::All => self.frame.as_rgb_bytes(),
+            ScreenOptions::BackgroundOnly => layers.background.as_rgb_bytes(),
+            ScreenOptions::WindowOnly => layers.window.as_rgb_bytes(),
+            ScreenOptions::SpritesOnly => layers.sprites.as_rgb_bytes(),
         };
 
         self.texture.set(
-            egui::ColorImage {
-                size: [160, 144],
-                source_size: egui::Vec2 { x: 160.0, y: 144.0 },
-                pixels: frame,
-            },
+            egui::ColorImage::from_rgb([160, 144], frame),
             egui::TextureOptions::NEAREST,
         );
         let sized_texture = egui::load::SizedTexture::new(self.texture.id(), [160.0, 144.0]);
 
         // UI Layout
 
+        // Fullscreen is just the screen, centred with black bars
+        if self.fullscreen {
+            egui::CentralPanel::default()
+                .frame(egui::Frame::NONE.fill(egui::Color32::BLACK))
+                .show(ctx, |ui| {
+                    let area = ui.max_rect();
+                    let scale = fit_scale(area.size()) as f32;
+                    let rect = egui::Rect::from_center_size(
+                        area.center(),
+                        egui::vec2(scale * 160.0, scale * 144.0),
+                    );
+                    ui.put(
+                        rect,
+                        egui::Image::new(sized_texture).fit_to_exact_size(rect.size()),
+                    );
+                });
+            ctx.request_repaint();
+            return;
+        }
+
         // Side Panel
         egui::SidePanel::right("right_panel")
             .resizable(true)
-            .default_width(400.0)
+            .default_width(SIDE_PANEL_WIDTH)
             .width_range(500.0..=1200.0)
             .show(ctx, |ui| {
                 ui.vertical_centered(|ui| {
@@ -212,14 +461,35 @@ impl eframe::App for MyApp {
                         ui.selectable_value(&mut self.side_panel, SidePanel::Cpu, "CPU");
                         ui.selectable_value(&mut self.side_panel, SidePanel::Ppu, "PPU");
                         ui.selectable_value(&mut self.side_panel, SidePanel::Apu, "APU");
+                        ui.selectable_value(
+                            &mut self.side_panel,
+                            SidePanel::Cartridge,
+                            "Cartridge Info",
+                        );
+                        ui.selectable_value(&mut self.side_panel, SidePanel::Memory, "Memory");
+                        #[cfg(feature = "heatmap")]
+                        ui.selectable_value(&mut self.side_panel, SidePanel::Heatmap, "Heatmap");
+                        ui.selectable_value(
+                            &mut self.side_panel,
+                            SidePanel::Settings,
+                            "Settings",
+                        );
                     })
                 });
 
+                gameboy.cpu.bus.apu.record_scope = self.side_panel == SidePanel::Apu;
+                if !self.wave_ram_frozen {
+                    self.wave_ram = gameboy.cpu.bus.apu.wave.wave_samples();
+                }
                 match self.side_panel {
                     SidePanel::Cpu => {
-                        for string in &self.cpu.prev_instrs {
+                        self.debugger_ui(ui, gameboy);
+                        ui.separator();
+                        for string in &gameboy.cpu.prev_instrs {
                             ui.add(egui::Label::new(string));
                         }
+                        ui.separator();
+                        call_stack_ui(ui, &gameboy.cpu);
                     }
                     SidePanel::Ppu => {
                         ui.horizontal(|ui| {
@@ -248,14 +518,14 @@ impl eframe::App for MyApp {
                         ui.heading("Current PPU State: ");
                         let ppu_str = format!(
                             "Cycles: {}, Scanline: {},\nScroll X, Y: ({}, {}), Window X, Y: ({}, {})\nPPU Status: {:08b}     PPU Control: {:08b}",
-                            self.cpu.bus.ppu.cycle,
-                            self.cpu.bus.ppu.scanline,
-                            self.cpu.bus.ppu.scx,
-                            self.cpu.bus.ppu.scy,
-                            self.cpu.bus.ppu.wx,
-                            self.cpu.bus.ppu.wy,
-                            self.cpu.bus.ppu.status.bits(),
-                            self.cpu.bus.ppu.control.bits(),
+                            gameboy.cpu.bus.ppu.cycle,
+                            gameboy.cpu.bus.ppu.scanline,
+                            gameboy.cpu.bus.ppu.scx,
+                            gameboy.cpu.bus.ppu.scy,
+                            gameboy.cpu.bus.ppu.wx,
+                            gameboy.cpu.bus.ppu.wy,
+                            gameboy.cpu.bus.ppu.status.bits(),
+                            gameboy.cpu.bus.ppu.control.bits(),
                         );
                         ui.heading(ppu_str);
 
@@ -275,18 +545,25 @@ impl eframe::App for MyApp {
                                 MapOptions::Sprites,
                                 "Sprites",
                             );
+                            ui.selectable_value(
+                                &mut self.map_options,
+                                MapOptions::Tiles,
+                                "Tile Data",
+                            );
                         });
+                        if matches!(
+                            self.map_options,
+                            MapOptions::Tilemap1 | MapOptions::Tilemap2
+                        ) {
+                            ui.checkbox(&mut self.show_viewport, "Show screen and window");
+                        }
 
                         match self.map_options {
                             MapOptions::Tilemap1 => {
-                                render::tilemap_one(&mut self.cpu.bus.ppu);
+                                render::tilemap_one(&mut gameboy.cpu.bus.ppu);
 
                                 self.tilemap_one_texture.set(
-                                    egui::ColorImage {
-                                        size: [256, 256],
-                                        source_size: egui::Vec2 { x: 256.0, y: 256.0 },
-                                        pixels: self.cpu.bus.ppu.tilemap_one.to_vec(),
-                                    },
+                                    egui::ColorImage::from_rgb([256, 256], &gameboy.cpu.bus.ppu.tilemap_one[..]),
                                     egui::TextureOptions::NEAREST,
                                 );
                                 let tilemap_one = egui::load::SizedTexture::new(
@@ -294,20 +571,19 @@ impl eframe::App for MyApp {
                                     [256.0, 256.0],
                                 );
 
-                                ui.add(
+                                let response = ui.add(
                                     egui::Image::new(tilemap_one)
                                         .fit_to_exact_size(egui::vec2(256.0, 256.0)),
                                 );
+                                if self.show_viewport {
+                                    map_overlay(ui, response.rect, &gameboy.cpu.bus.ppu, false);
+                                }
                             }
                             MapOptions::Tilemap2 => {
-                                render::tilemap_two(&mut self.cpu.bus.ppu);
+                                render::tilemap_two(&mut gameboy.cpu.bus.ppu);
 
                                 self.tilemap_two_texture.set(
-                                    egui::ColorImage {
-                                        size: [256, 256],
-                                        source_size: egui::Vec2 { x: 256.0, y: 256.0 },
-                                        pixels: self.cpu.bus.ppu.tilemap_two.to_vec(),
-                                    },
+                                    egui::ColorImage::from_rgb([256, 256], &gameboy.cpu.bus.ppu.tilemap_two[..]),
                                     egui::TextureOptions::NEAREST,
                                 );
                                 let tilemap_two = egui::load::SizedTexture::new(
@@ -315,20 +591,19 @@ impl eframe::App for MyApp {
                                     [256.0, 256.0],
                                 );
 
-                                ui.add(
+                                let response = ui.add(
                                     egui::Image::new(tilemap_two)
                                         .fit_to_exact_size(egui::vec2(256.0, 256.0)),
                                 );
+                                if self.show_viewport {
+                                    map_overlay(ui, response.rect, &gameboy.cpu.bus.ppu, true);
+                                }
                             }
                             MapOptions::Sprites => {
-                                render::oam_map(&mut self.cpu.bus.ppu);
+                                render::oam_map(&mut gameboy.cpu.bus.ppu);
 
                                 self.sprite_texture.set(
-                                    egui::ColorImage {
-                                        size: [64, 40],
-                                        source_size: egui::Vec2 { x: 64.0, y: 40.0 },
-                                        pixels: self.cpu.bus.ppu.sprites.to_vec(),
-                                    },
+                                    egui::ColorImage::from_rgb([64, 40], &gameboy.cpu.bus.ppu.sprites),
                                     egui::TextureOptions::NEAREST,
                                 );
                                 let sprites = egui::load::SizedTexture::new(
@@ -340,6 +615,32 @@ impl eframe::App for MyApp {
                                         .fit_to_exact_size(egui::vec2(3.0 * 64.0, 3.0 * 40.0)),
                                 );
                             }
+                            MapOptions::Tiles => {
+                                self.tile_data_texture.set(
+                                    egui::ColorImage::from_rgb([128, 192], &render::tile_data(&gameboy.cpu.bus.ppu)),
+                                    egui::TextureOptions::NEAREST,
+                                );
+                                let tiles = egui::load::SizedTexture::new(
+                                    self.tile_data_texture.id(),
+                                    [128.0, 192.0],
+                                );
+                                let response = ui.add(
+                                    egui::Image::new(tiles)
+                                        .fit_to_exact_size(egui::vec2(2.0 * 128.0, 2.0 * 192.0))
+                                        .sense(egui::Sense::hover()),
+                                );
+                                // Tile under the cursor. Image is drawn at 2x
+                                if let Some(pos) = response.hover_pos() {
+                                    let offset = (pos - response.rect.min) / 2.0;
+                                    let tile = (offset.y as usize / 8).min(23) * 16
+                                        + (offset.x as usize / 8).min(15);
+                                    ui.label(format!(
+                                        "Tile {tile} (0x{:02X}), address {:04X}",
+                                        tile % 256,
+                                        0x8000 + 16 * tile
+                                    ));
+                                }
+                            }
                         }
                     }
                     SidePanel::Apu => {
@@ -368,25 +669,25 @@ impl eframe::App for MyApp {
 
                         let points = match self.audio_display {
                             AudioDisplay::SquareOne => {
-                                let points: PlotPoints = self.cpu.bus.apu.square1_output.iter().enumerate().map(|(index, value)| {
+                                let points: PlotPoints = gameboy.cpu.bus.apu.square1_output.iter().enumerate().map(|(index, value)| {
                                     [index as f64, *value as f64]
                                 }).collect();
                                 points
                             }
                             AudioDisplay::SquareTwo => {
-                                let points: PlotPoints = self.cpu.bus.apu.square2_output.iter().enumerate().map(|(index, value)| {
+                                let points: PlotPoints = gameboy.cpu.bus.apu.square2_output.iter().enumerate().map(|(index, value)| {
                                     [index as f64, *value as f64]
                                 }).collect();
                                 points
                             }
                             AudioDisplay::Wave => {
-                                let points: PlotPoints = self.cpu.bus.apu.wave_output.iter().enumerate().map(|(index, value)| {
+                                let points: PlotPoints = gameboy.cpu.bus.apu.wave_output.iter().enumerate().map(|(index, value)| {
                                     [index as f64, *value as f64]
                                 }).collect();
                                 points
                             }
                             AudioDisplay::Noise => {
-                                let points: PlotPoints = self.cpu.bus.apu.noise_output.iter().enumerate().map(|(index, value)| {
+                                let points: PlotPoints = gameboy.cpu.bus.apu.noise_output.iter().enumerate().map(|(index, value)| {
                                     [index as f64, *value as f64]
                                 }).collect();
                                 points
@@ -396,34 +697,237 @@ impl eframe::App for MyApp {
                         let line = Line::new("S1", points);
                         Plot::new("my_plot").view_aspect(2.0).show(ui, |plot_ui| plot_ui.line(line));
 
-                        ui.heading("Play only these audios:");
+                        ui.label(format!(
+                            "Audio queue: {:.1} ms   Rate ratio: {:.4}   Underruns: {}",
+                            1000.0 * self.audio_queue,
+                            self.audio_ratio,
+                            self.underruns
+                        ));
 
                         ui.horizontal(|ui| {
-                            ui.selectable_value(
-                                &mut self.cpu.bus.apu.audio_select,
-                                apu::AudioSelect::All,
-                                "All",
-                            );
-                            ui.selectable_value(
-                                &mut self.cpu.bus.apu.audio_select,
-                                apu::AudioSelect::SquareOne,
-                                "Square 1",
-                            );
-                            ui.selectable_value(
-                                &mut self.cpu.bus.apu.audio_select,
-                                apu::AudioSelect::SquareTwo,
-                                "Square 2",
-                            );
-                            ui.selectable_value(
-                                &mut self.cpu.bus.apu.audio_select,
-                                apu::AudioSelect::Wave,
-                                "Wave",
-                            );
-                            ui.selectable_value(
-                                &mut self.cpu.bus.apu.audio_select,
-                                apu::AudioSelect::Noise,
-                                "Noise",
-                            );
+                            ui.heading("Wave RAM");
+                            ui.checkbox(&mut self.wave_ram_frozen, "Freeze");
+                        });
+                        // Each sample is held flat across its slot, the way the channel plays it
+                        let points: PlotPoints = self
+                            .wave_ram
+                            .iter()
+                            .enumerate()
+                            .flat_map(|(i, &sample)| {
+                                [[i as f64, sample as f64], [(i + 1) as f64, sample as f64]]
+                            })
+                            .collect();
+                        Plot::new("wave_ram_plot")
+                            .view_aspect(4.0)
+                            .include_y(0.0)
+                            .include_y(15.0)
+                            .allow_drag(false)
+                            .allow_zoom(false)
+                            .allow_scroll(false)
+                            .show(ui, |plot_ui| plot_ui.line(Line::new("Wave RAM", points)));
+                        egui::Grid::new("wave_ram_values").show(ui, |ui| {
+                            for (i, sample) in self.wave_ram.iter().enumerate() {
+                                ui.monospace(format!("{sample:X}"));
+                                if i % 16 == 15 {
+                                    ui.end_row();
+                                }
+                            }
+                        });
+
+                        ui.heading("Channels");
+
+                        let apu = &mut gameboy.cpu.bus.apu;
+                        egui::Grid::new("audio_channels").show(ui, |ui| {
+                            for (name, channel) in [
+                                ("Square 1", apu::Channels::square1),
+                                ("Square 2", apu::Channels::square2),
+                                ("Wave", apu::Channels::wave),
+                                ("Noise", apu::Channels::noise),
+                            ] {
+                                ui.label(name);
+                                let mut muted = !apu.enabled_channels.contains(channel);
+                                if ui.checkbox(&mut muted, "Mute").changed() {
+                                    apu.set_muted(channel, muted);
+                                }
+                                if ui.button("Solo").clicked() {
+                                    apu.solo(channel);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                        if ui.button("Play all").clicked() {
+                            apu.solo(apu::Channels::all());
+                        }
+                    }
+                    SidePanel::Cartridge => match gameboy.cpu.bus.cartridge_header() {
+                        Ok(header) => {
+                            egui::Grid::new("cartridge_info").striped(true).show(ui, |ui| {
+                                ui.label("Title");
+                                ui.label(&header.title);
+                                ui.end_row();
+                                ui.label("CGB Flag");
+                                ui.label(format!("{:02X}", header.cgb_flag));
+                                ui.end_row();
+                                ui.label("SGB Flag");
+                                ui.label(format!("{:02X}", header.sgb_flag));
+                                ui.end_row();
+                                ui.label("Mapper");
+                                ui.label(format!(
+                                    "{:02X} ({:?})",
+                                    header.mapper_type, header.cartridge_type
+                                ));
+                                ui.end_row();
+                                ui.label("ROM Size Code");
+                                ui.label(format!("{:02X}", header.rom_size_code));
+                                ui.end_row();
+                                ui.label("RAM Size Code");
+                                ui.label(format!("{:02X}", header.ram_size_code));
+                                ui.end_row();
+                                ui.label("Destination");
+                                ui.label(format!("{:?}", header.destination));
+                                ui.end_row();
+                                ui.label("Header Checksum");
+                                ui.label(format!("{:02X}", header.header_checksum));
+                                ui.end_row();
+                                ui.label("Global Checksum");
+                                ui.label(format!("{:04X}", header.global_checksum));
+                                ui.end_row();
+                            });
+                            // Bad checksums don't stop the game running, just flag them
+                            for check in [header.validate(), header.validate_global()] {
+                                if let Err(err) = check {
+                                    ui.colored_label(egui::Color32::YELLOW, err.to_string());
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            ui.label(format!("Invalid cartridge header: {err}"));
+                        }
+                    },
+                    SidePanel::Memory => self.memory_ui(ui, gameboy),
+                    #[cfg(feature = "heatmap")]
+                    SidePanel::Heatmap => self.heatmap_ui(ui, gameboy),
+                    SidePanel::Settings => {
+                        ui.heading("Emulation");
+                        let speed = ui.add(
+                            egui::Slider::new(&mut self.speed, SPEEDS[0]..=SPEEDS[SPEEDS.len() - 1])
+                                .text("Speed")
+                                .suffix("x"),
+                        );
+                        if speed.changed() {
+                            self.emu.send(Command::Speed(self.speed));
+                        }
+                        if ui
+                            .checkbox(&mut self.audio_sync, "Sync to audio")
+                            .on_hover_text("Pace off the audio queue instead of a timer. Only at 1x")
+                            .changed()
+                        {
+                            let pacing = if self.audio_sync {
+                                Pacing::AudioSync
+                            } else {
+                                Pacing::Timer
+                            };
+                            self.emu.send(Command::Pacing(pacing));
+                        }
+                        ui.label(format!(
+                            "Hold {} to run at {TURBO_SPEED}x, {} and {} to step the speed",
+                            self.config.keys.turbo, self.config.keys.slower, self.config.keys.faster
+                        ));
+                        ui.separator();
+
+                        ui.heading("Display");
+                        let scale = ui.add(
+                            egui::Slider::new(&mut self.scale, 1..=MAX_SCALE)
+                                .text("Screen scale")
+                                .suffix("x"),
+                        );
+                        if scale.changed() {
+                            ui.ctx().send_viewport_cmd(egui::ViewportCommand::InnerSize(window_size(
+                                self.scale,
+                            )));
+                            self.config.scale = self.scale;
+                        }
+                        if settled(&scale) {
+                            self.save_config();
+                        }
+                        ui.label("F11 or Alt+Enter toggles fullscreen");
+                        ui.horizontal(|ui| {
+                            let current = self.config.palette.as_deref().unwrap_or("classic");
+                            let mut choice = None;
+                            egui::ComboBox::from_label("Palette")
+                                .selected_text(current)
+                                .show_ui(ui, |ui| {
+                                    for (name, _) in render::Palette::PRESETS {
+                                        if ui.selectable_label(current == name, name).clicked() {
+                                            choice = Some(name.to_string());
+                                        }
+                                    }
+                                });
+                            if ui.button("Load palette file").clicked() {
+                                choice = rfd::FileDialog::new()
+                                    .pick_file()
+                                    .map(|path| path.to_string_lossy().into_owned());
+                            }
+                            if let Some(choice) = choice {
+                                match render::Palette::load(&choice) {
+                                    Ok(palette) => {
+                                        gameboy.cpu.bus.ppu.set_palette(palette);
+                                        self.config.palette = Some(choice);
+                                        self.save_config();
+                                    }
+                                    Err(err) => {
+                                        self.status =
+                                            Some((format!("Could not load palette: {err}"), Instant::now()))
+                                    }
+                                }
+                            }
+                        });
+                        ui.checkbox(
+                            &mut self.screenshot_scaled,
+                            format!("Save screenshots at {}x", self.scale),
+                        );
+                        ui.separator();
+
+                        ui.heading("Audio");
+                        let volume = ui.add(
+                            egui::Slider::new(&mut self.config.master_volume, 0.0..=1.0)
+                                .text("Volume")
+                                .custom_formatter(|volume, _| format!("{:.0}%", 100.0 * volume)),
+                        );
+                        let mute = ui.checkbox(&mut self.config.muted, "Mute");
+                        if volume.changed() || mute.changed() {
+                            self.emu.send(Command::Volume(self.config.gain()));
+                        }
+                        if settled(&volume) || mute.changed() {
+                            self.save_config();
+                        }
+                        ui.separator();
+
+                        ui.heading("Recent ROMs");
+                        for rom in &self.config.recent_roms {
+                            ui.label(rom.display().to_string());
+                        }
+                        if ui.button("Clear").clicked() {
+                            self.config.recent_roms.clear();
+                            self.save_config();
+                        }
+                        ui.separator();
+
+                        ui.heading("Key Bindings");
+                        ui.label("Click a binding then press the new key");
+                        egui::Grid::new("key_bindings").striped(true).show(ui, |ui| {
+                            for action in Action::ALL {
+                                ui.label(action.name());
+                                let text = if self.rebinding == Some(action) {
+                                    "Press a key...".to_string()
+                                } else {
+                                    self.config.keys.keys_for(action).join(", ")
+                                };
+                                if ui.button(text).clicked() {
+                                    self.rebinding = Some(action);
+                                }
+                                ui.end_row();
+                            }
                         });
                     }
                 }
@@ -431,31 +935,44 @@ impl eframe::App for MyApp {
 
         // Central Panel
         egui::CentralPanel::default().show(ctx, |ui| {
+            // Shrink rather than stretch if the window was made smaller than the scale needs
+            let scale = self.scale.min(fit_scale(ui.available_size())) as f32;
             ui.add(egui::Image::new(sized_texture)
-                .fit_to_exact_size(egui::vec2(3.0 * 160.0, 3.0 * 144.0)),
+                .fit_to_exact_size(egui::vec2(scale * 160.0, scale * 144.0)),
             );
+            if let Some((message, shown)) = &self.status {
+                if shown.elapsed() < Duration::from_secs(3) {
+                    ui.label(message);
+                } else {
+                    self.status = None;
+                }
+            }
 
             ui.heading("Current CPU State");
 
             let cpu_state = format!(
                 "A: {:02X}   F: {:02X}   B: {:02X}   C: {:02X}   D: {:02X}   E: {:02X}   H: {:02X}   L: {:02X}\nStack Pointer: {:04X}   Program Counter: {:04X}\nIME: {}   IE: {:08b}   IF: {:08b}",
-                self.cpu.a,
-                self.cpu.flags.bits(),
-                self.cpu.b,
-                self.cpu.c,
-                self.cpu.d,
-                self.cpu.e,
-                self.cpu.h,
-                self.cpu.l,
-                self.cpu.stack_pointer,
-                self.cpu.program_counter,
-                self.cpu.ime,
-                self.cpu.bus.interrupt_enable,
-                self.cpu.bus.interrupt_flag,
+                gameboy.cpu.a,
+                gameboy.cpu.flags.bits(),
+                gameboy.cpu.b,
+                gameboy.cpu.c,
+                gameboy.cpu.d,
+                gameboy.cpu.e,
+                gameboy.cpu.h,
+                gameboy.cpu.l,
+                gameboy.cpu.stack_pointer,
+                gameboy.cpu.program_counter,
+                gameboy.cpu.ime,
+                gameboy.cpu.bus.interrupt_enable,
+                gameboy.cpu.bus.interrupt_flag,
             );
 
             ui.heading(cpu_state);
-            ui.heading(format!("FPS: {}", self.fps));
+            let speed = if self.turbo { TURBO_SPEED } else { self.speed };
+            ui.heading(format!(
+                "FPS: {:.1} ({speed}x)  Audio underruns: {}",
+                self.fps, self.underruns
+            ));
             // ui.add(egui::Slider::new(&mut self.value, 0.0..=10.0).text("value"));
             // if ui.button("Increment").clicked() {
             //     self.value += 1.0;
@@ -465,73 +982,284 @@ impl eframe::App for MyApp {
 
         ctx.request_repaint();
     }
+
+    // Closing the window, as opposed to Escape which exits straight away
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        let shared = self.emu.shared();
+        let mut gameboy = shared.lock().unwrap();
+        self.save_battery(&mut gameboy);
+    }
 }
 
 impl MyApp {
-    // Display frame if result returned is true
-    fn step_gb(&mut self) -> Option<render::Frame> {
-        if self.frame_count == 0 {
-            self.baseline = Instant::now();
-        } else if self.frame_count == 30 {
-            let thirty_frame_time = self.baseline.elapsed().as_secs_f32();
-            self.frame_count = 1;
-            self.baseline = Instant::now();
-            let fps = 30.0 / thirty_frame_time;
-            //println!("FPS is {fps}");
-            self.fps = fps;
+    fn save_config(&self) {
+        if let Err(err) = self.config.save() {
+            eprintln!("Could not save settings: {err}");
         }
+    }
 
-        let frame = if self.trace_on {
-            self.cpu.step_with_trace()
+    // After a volume key. Shows the new level under the screen
+    fn volume_changed(&mut self) {
+        self.emu.send(Command::Volume(self.config.gain()));
+        self.save_config();
+        let message = if self.config.muted {
+            "Muted".to_string()
         } else {
-            self.cpu.step(|_| {})
+            format!("Volume {:.0}%", 100.0 * self.config.master_volume)
+        };
+        self.status = Some((message, Instant::now()));
+    }
+
+    // Save the last frame under screenshots/ next to the ROM
+    fn screenshot(&mut self) {
+        let scale = if self.screenshot_scaled {
+            self.scale
+        } else {
+            1
+        };
+        let result = capture::screenshot_path(&self.rom_path).and_then(|path| {
+            capture::save_frame_scaled(&self.frame, &path, scale)?;
+            Ok(path)
+        });
+        let message = match result {
+            Ok(path) => format!("Saved screenshot to {}", path.display()),
+            Err(err) => format!("Could not save screenshot: {err}"),
         };
+        eprintln!("{message}");
+        self.status = Some((message, Instant::now()));
+    }
+
+    fn save_battery(&self, gameboy: &mut Gameboy) {
+        if let Err(err) = gameboy.save_battery(&self.rom_path.with_extension("sav")) {
+            eprintln!("Could not write save file: {err}");
+        }
+    }
 
-        if let Some(frame) = frame {
-            let frame = frame.clone();
-            /*
-            // present frame
-            texture.update(None, &frame.data, 160 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
-            */
-            // play audio
-            self.audio_device
-                .queue_audio(&self.cpu.bus.audio_buffer)
-                .unwrap();
-            while self.audio_device.size() > 4500 {
+    fn debugger_ui(&mut self, ui: &mut egui::Ui, gameboy: &mut Gameboy) {
+        ui.heading("Debugger");
+        if let Some(event) = self.last_break {
+            let text = match event {
+                BreakEvent::Breakpoint(pc) => format!("Hit breakpoint at {pc:04X}"),
+                BreakEvent::Watchpoint {
+                    pc,
+                    addr,
+                    value,
+                    is_write,
+                } => {
+                    let access = if is_write { "Write" } else { "Read" };
+                    format!("{access} of {value:02X} at {addr:04X} by instruction at {pc:04X}")
+                }
+                BreakEvent::Interrupt(vector) => format!("Interrupt to {vector:04X}"),
+            };
+            ui.colored_label(egui::Color32::RED, text);
+        }
+        if let Some(step) = self.last_executed.as_ref().filter(|_| self.paused) {
+            ui.label(format!("Executed {}", step.instr));
+            ui.monospace(format!("Before  {}", step.before));
+            ui.monospace(format!("After   {}", step.after));
+        }
+        ui.label("F: step, N: next scanline, V: next vblank");
+
+        let debugger = &mut gameboy.cpu.debugger;
+        let addr = u16::from_str_radix(self.break_input.trim_start_matches("0x"), 16).ok();
+        ui.horizontal(|ui| {
+            ui.label("Address (hex):");
+            ui.add(egui::TextEdit::singleline(&mut self.break_input).desired_width(60.0));
+            if let Some(addr) = addr {
+                if ui.button("Break").clicked() {
+                    debugger.breakpoints.insert(addr);
+                }
+                if ui.button("Watch read").clicked() {
+                    debugger.read_watchpoints.insert(addr);
+                }
+                if ui.button("Watch write").clicked() {
+                    debugger.write_watchpoints.insert(addr);
+                }
+            }
+        });
+        ui.checkbox(&mut debugger.break_on_interrupt, "Break on interrupt");
 
+        // Click an entry to remove it
+        for (label, set) in [
+            ("Breakpoints", &mut debugger.breakpoints),
+            ("Read watchpoints", &mut debugger.read_watchpoints),
+            ("Write watchpoints", &mut debugger.write_watchpoints),
+        ] {
+            if set.is_empty() {
+                continue;
             }
+            ui.horizontal_wrapped(|ui| {
+                ui.label(format!("{label}:"));
+                let mut removed = None;
+                for addr in set.iter() {
+                    if ui.button(format!("{addr:04X} x")).clicked() {
+                        removed = Some(*addr);
+                    }
+                }
+                if let Some(addr) = removed {
+                    set.remove(&addr);
+                }
+            });
+        }
+    }
 
-            // check user input
-            //sdl2_setup::get_user_input(&mut self.event_pump, &mut self.cpu.bus.joypad);
+    // Hex and ASCII dump, 16 bytes per row. Only visible rows are drawn so any region is cheap.
+    // Reads go through Bus::peek so viewing doesn't disturb the game. Click a byte to edit it
+    // One pixel per address, a row per 256 byte page. Untouched addresses are black, the rest
+    // go from blue to red on a log scale so a tight loop stands out
+    #[cfg(feature = "heatmap")]
+    fn heatmap_ui(&mut self, ui: &mut egui::Ui, gameboy: &mut Gameboy) {
+        let bus = &mut gameboy.cpu.bus;
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut self.heatmap_writes, false, "Reads");
+            ui.radio_value(&mut self.heatmap_writes, true, "Writes");
+            if ui.button("Reset").clicked() {
+                bus.reset_heatmap();
+            }
+        });
 
-            // If FPS enabled, increment counter
-            self.frame_count += 1;
+        let counts = if self.heatmap_writes {
+            &bus.write_counts
+        } else {
+            &bus.read_counts
+        };
+        let max = counts.iter().copied().max().unwrap_or(0).max(1) as f32;
+        let pixels: Vec<egui::Color32> = counts
+            .iter()
+            .map(|&count| {
+                if count == 0 {
+                    return egui::Color32::BLACK;
+                }
+                let heat = (count as f32).ln_1p() / max.ln_1p();
+                egui::Color32::from_rgb((255.0 * heat) as u8, 0, (255.0 * (1.0 - heat)) as u8)
+            })
+            .collect();
+        self.heatmap_texture.set(
+            egui::ColorImage::new([256, 256], pixels),
+            egui::TextureOptions::NEAREST,
+        );
+        let heatmap = egui::load::SizedTexture::new(self.heatmap_texture.id(), [256.0, 256.0]);
+        let response = ui.add(
+            egui::Image::new(heatmap)
+                .fit_to_exact_size(egui::vec2(2.0 * 256.0, 2.0 * 256.0))
+                .sense(egui::Sense::hover()),
+        );
+        // Address under the cursor. Image is drawn at 2x
+        if let Some(pos) = response.hover_pos() {
+            let offset = (pos - response.rect.min) / 2.0;
+            let addr = (offset.y as usize).min(255) * 256 + (offset.x as usize).min(255);
+            ui.label(format!("{addr:04X}: {}", counts[addr]));
+        }
+        ui.label(format!(
+            "Hottest read {:04X}, hottest write {:04X}",
+            bus.hottest_read_addr(),
+            bus.hottest_write_addr()
+        ));
+    }
 
-            return Some(frame);
+    fn memory_ui(&mut self, ui: &mut egui::Ui, gameboy: &mut Gameboy) {
+        ui.horizontal(|ui| {
+            for region in MemoryRegion::ALL {
+                ui.selectable_value(&mut self.memory_region, region, region.name());
+            }
+        });
+        if self.memory_region == MemoryRegion::Custom {
+            ui.horizontal(|ui| {
+                ui.label("Start (hex):");
+                ui.add(egui::TextEdit::singleline(&mut self.memory_start).desired_width(60.0));
+            });
         }
+        let start =
+            u16::from_str_radix(self.memory_start.trim_start_matches("0x"), 16).unwrap_or(0);
+        let (start, end) = self.memory_region.range(start);
+        let rows = (end as usize - start as usize) / 16 + 1;
+
+        let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+        egui::ScrollArea::vertical().auto_shrink(false).show_rows(
+            ui,
+            row_height,
+            rows,
+            |ui, visible_rows| {
+                for row in visible_rows {
+                    let row_addr = start + 16 * row as u16;
+                    ui.horizontal(|ui| {
+                        ui.monospace(format!("{row_addr:04X}"));
+                        let mut ascii = String::new();
+                        for i in 0..16 {
+                            let addr = row_addr.wrapping_add(i);
+                            let val = gameboy.cpu.bus.peek(addr);
+                            ascii.push(if val.is_ascii_graphic() {
+                                val as char
+                            } else {
+                                '.'
+                            });
+                            self.memory_cell(ui, gameboy, addr, val);
+                        }
+                        ui.monospace(ascii);
+                    });
+                }
+            },
+        );
+    }
 
-        None
+    fn memory_cell(&mut self, ui: &mut egui::Ui, gameboy: &mut Gameboy, addr: u16, val: u8) {
+        match &mut self.memory_edit {
+            Some((edit_addr, text)) if *edit_addr == addr => {
+                let response = ui.add(
+                    egui::TextEdit::singleline(text)
+                        .font(egui::TextStyle::Monospace)
+                        .desired_width(16.0)
+                        .char_limit(2),
+                );
+                response.request_focus();
+                if response.lost_focus() {
+                    if let Ok(new_val) = u8::from_str_radix(text, 16) {
+                        gameboy.cpu.bus.mem_write(addr, new_val);
+                    }
+                    self.memory_edit = None;
+                }
+            }
+            _ => {
+                let label = egui::Label::new(egui::RichText::new(format!("{val:02X}")).monospace())
+                    .sense(egui::Sense::click());
+                if ui.add(label).clicked() {
+                    self.memory_edit = Some((addr, String::new()));
+                }
+            }
+        }
     }
 }
 
-lazy_static! {
-    static ref KEY_MAP: HashMap<egui::Key, (bool, u8)> = {
-        let mut key_map = HashMap::new();
-
-        // true = select mode, false = dpad mode
-        key_map.insert(egui::Key::ArrowDown, (false, 0b0000_1000));
-        key_map.insert(egui::Key::ArrowUp, (false, 0b0000_0100));
-        key_map.insert(egui::Key::ArrowLeft, (false, 0b0000_0010));
-        key_map.insert(egui::Key::ArrowRight, (false, 0b0000_0001));
-        key_map.insert(egui::Key::Enter, (true, 0b0000_1000));
-        key_map.insert(egui::Key::Space, (true, 0b0000_0100));
-        key_map.insert(egui::Key::S, (true, 0b0000_0010));
-        key_map.insert(egui::Key::A, (true, 0b0000_0001));
-
-        key_map
+// Outline what part of a 256x256 tile map drawn at `rect` is on screen. The BG viewport
+// wraps around the map edges. The window always starts from the top left of its map
+fn map_overlay(ui: &egui::Ui, rect: egui::Rect, ppu: &Ppu, is_9c00: bool) {
+    let painter = ui.painter_at(rect);
+    let scale = rect.width() / 256.0;
+    let outline = |x: f32, y: f32, w: f32, h: f32, color| {
+        let min = rect.min + egui::vec2(x, y) * scale;
+        painter.rect_stroke(
+            egui::Rect::from_min_size(min, egui::vec2(w, h) * scale),
+            0.0,
+            egui::Stroke::new(1.0, color),
+            egui::StrokeKind::Inside,
+        );
     };
+
+    if ppu.control.contains(Control::bg_tile_area) == is_9c00 {
+        let (scx, scy) = (ppu.scx as f32, ppu.scy as f32);
+        for (dx, dy) in [(0.0, 0.0), (-256.0, 0.0), (0.0, -256.0), (-256.0, -256.0)] {
+            outline(scx + dx, scy + dy, 160.0, 144.0, egui::Color32::RED);
+        }
+    }
+    if ppu.control.contains(Control::window_enable)
+        && ppu.control.contains(Control::window_map_area) == is_9c00
+        && ppu.wx <= 166
+        && ppu.wy < 144
+    {
+        let width = 167.0 - ppu.wx as f32;
+        let height = 144.0 - ppu.wy as f32;
+        outline(0.0, 0.0, width, height, egui::Color32::BLUE);
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -539,6 +1267,55 @@ enum SidePanel {
     Cpu,
     Ppu,
     Apu,
+    Cartridge,
+    Memory,
+    #[cfg(feature = "heatmap")]
+    Heatmap,
+    Settings,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MemoryRegion {
+    Wram,
+    Vram,
+    Oam,
+    Io,
+    Hram,
+    Custom, // From the typed start address to the end of memory
+}
+
+impl MemoryRegion {
+    const ALL: [MemoryRegion; 6] = [
+        MemoryRegion::Wram,
+        MemoryRegion::Vram,
+        MemoryRegion::Oam,
+        MemoryRegion::Io,
+        MemoryRegion::Hram,
+        MemoryRegion::Custom,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            MemoryRegion::Wram => "WRAM",
+            MemoryRegion::Vram => "VRAM",
+            MemoryRegion::Oam => "OAM",
+            MemoryRegion::Io => "IO",
+            MemoryRegion::Hram => "HRAM",
+            MemoryRegion::Custom => "Custom",
+        }
+    }
+
+    // Inclusive address range. Custom starts on the row containing `start`
+    fn range(self, start: u16) -> (u16, u16) {
+        match self {
+            MemoryRegion::Wram => (0xc000, 0xdfff),
+            MemoryRegion::Vram => (0x8000, 0x9fff),
+            MemoryRegion::Oam => (0xfe00, 0xfe9f),
+            MemoryRegion::Io => (0xff00, 0xff7f),
+            MemoryRegion::Hram => (0xff80, 0xffff),
+            MemoryRegion::Custom => (start & 0xfff0, 0xffff),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -554,6 +1331,7 @@ pub enum MapOptions {
     Tilemap1,
     Tilemap2,
     Sprites,
+    Tiles,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -563,3 +1341,16 @@ pub enum AudioDisplay {
     Wave,
     Noise,
 }
+
+// Innermost call first
+fn call_stack_ui(ui: &mut egui::Ui, cpu: &Cpu) {
+    ui.heading("Call stack");
+    if cpu.untracked_calls > 0 {
+        ui.label(format!("{} more calls not tracked", cpu.untracked_calls));
+    }
+    for (depth, (site, return_addr)) in cpu.call_stack.iter().enumerate().rev() {
+        ui.monospace(format!(
+            "{depth:>2}  return to {return_addr:04X}  (called from {site:04X})"
+        ));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::bus::Bus;
+use crate::cartridge::{self, CartridgeError, Mapper};
+use crate::cpu::Cpu;
+use crate::joypad::JoypadAction;
+use crate::render::Frame;
+use crate::replay::{InputRecorder, JoypadEvent};
+
+use std::path::Path;
+use std::{fs, io};
+
+// Headless front door to the emulator. Frontends, fuzzers and ports only need this
+pub struct Gameboy {
+    pub cpu: Cpu,
+    pub recorder: Option<InputRecorder>,
+}
+
+impl Gameboy {
+    // Picks CGB or DMG mode from the cartridge header
+    pub fn new(rom: &[u8]) -> Result<Self, CartridgeError> {
+        Ok(Self::from_cartridge(
+            cartridge::get_mapper(rom)?,
+            false,
+            None,
+        ))
+    }
+
+    // Starts from the boot ROM when one is given, otherwise straight at the cartridge entry point
+    pub fn from_cartridge(
+        cartridge: Box<dyn Mapper>,
+        force_dmg: bool,
+        boot_rom: Option<[u8; 0x100]>,
+    ) -> Self {
+        let mut bus = Bus::new(cartridge, force_dmg);
+        if let Some(boot_rom) = boot_rom {
+            bus.load_boot_rom(boot_rom);
+        }
+        Self {
+            cpu: Cpu::new(bus),
+            recorder: None,
+        }
+    }
+
+    // Run one instruction. Returns the finished frame if this step completed one
+    pub fn step(&mut self, trace: bool) -> Option<&Frame> {
+        // Recorded input replaces live input during playback
+        if let Some(recorder) = &mut self.recorder {
+            while let Some(event) = recorder.poll(self.cpu.bus.total_cycles) {
+                let (mode, button) = event.action.button();
+                self.cpu
+                    .bus
+                    .joypad
+                    .button_pressed_status(mode, button, event.pressed);
+            }
+        }
+
+        let frame_ready = if trace {
+            self.cpu.step_with_trace().is_some()
+        } else {
+            self.cpu.step(|_| {}).is_some()
+        };
+        frame_ready.then_some(&self.cpu.bus.last_frame)
+    }
+
+    // Run until the next frame is ready and return it
+    pub fn run_frame(&mut self) -> &Frame {
+        while self.step(false).is_none() {}
+        &self.cpu.bus.last_frame
+    }
+
+    pub fn press_button(&mut self, action: JoypadAction) {
+        self.input(JoypadEvent {
+            action,
+            pressed: true,
+        });
+    }
+
+    pub fn release_button(&mut self, action: JoypadAction) {
+        self.input(JoypadEvent {
+            action,
+            pressed: false,
+        });
+    }
+
+    fn has_battery(&self) -> bool {
+        self.cpu
+            .bus
+            .cartridge_header()
+            .is_ok_and(|header| header.has_battery())
+    }
+
+    // Restore battery backed RAM from a .sav file. A missing file is a new game, not an error
+    pub fn load_battery(&mut self, path: &Path) -> io::Result<()> {
+        if !self.has_battery() {
+            return Ok(());
+        }
+        match fs::read(path) {
+            Ok(data) => {
+                self.cpu.bus.cartridge.load_save_data(&data);
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save_battery(&mut self, path: &Path) -> io::Result<()> {
+        if !self.has_battery() {
+            return Ok(());
+        }
+        fs::write(path, self.cpu.bus.cartridge.save_data())
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus.set_sample_rate(sample_rate);
+    }
+
+    // Interleaved stereo samples produced during the last frame
+    pub fn audio_samples(&self) -> &[f32] {
+        &self.cpu.bus.audio_buffer
+    }
+
+    fn input(&mut self, event: JoypadEvent) {
+        if let Some(recorder) = &mut self.recorder {
+            if recorder.is_playback() {
+                return;
+            }
+            if let Err(err) = recorder.push(self.cpu.bus.total_cycles, event) {
+                eprintln!("Could not write input recording: {err}");
+            }
+        }
+        let (mode, button) = event.action.button();
+        self.cpu
+            .bus
+            .joypad
+            .button_pressed_status(mode, button, event.pressed);
+    }
+}
@@ -0,0 +1,276 @@
+// Cartridge header at 0x0100 - 0x014F. Parsing only needs the bytes to be there, the
+// checksums are checked separately so a ROM with a bad one can still be played
+use std::fmt;
+
+const KIB: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeaderError {
+    TooShort(usize), // ROM is smaller than the 0x0150 bytes needed for a header
+    ChecksumMismatch { expected: u8, computed: u8 },
+    GlobalChecksumMismatch { expected: u16, computed: u16 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooShort(len) => {
+                write!(f, "ROM is {len} bytes, too small to hold a header")
+            }
+            HeaderError::ChecksumMismatch { expected, computed } => write!(
+                f,
+                "header checksum is {expected:02X} but the header sums to {computed:02X}"
+            ),
+            HeaderError::GlobalChecksumMismatch { expected, computed } => write!(
+                f,
+                "global checksum is {expected:04X} but the ROM sums to {computed:04X}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+// Cartridge type at 0x0147, without the RAM/battery/timer extras
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CartridgeType {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+    Unknown(u8),
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 | 0x08 | 0x09 => CartridgeType::RomOnly,
+            0x01..=0x03 => CartridgeType::Mbc1,
+            0x05 | 0x06 => CartridgeType::Mbc2,
+            0x0b..=0x0d => CartridgeType::Mmm01,
+            0x0f..=0x13 => CartridgeType::Mbc3,
+            0x19..=0x1e => CartridgeType::Mbc5,
+            0x20 => CartridgeType::Mbc6,
+            0x22 => CartridgeType::Mbc7,
+            0xfc => CartridgeType::PocketCamera,
+            0xfd => CartridgeType::Tama5,
+            0xfe => CartridgeType::HuC3,
+            0xff => CartridgeType::HuC1,
+            code => CartridgeType::Unknown(code),
+        }
+    }
+}
+
+// Destination code at 0x014A
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Destination {
+    Japan,
+    Overseas,
+    Unknown(u8),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: u8,    // 0x0143
+    pub sgb_flag: u8,    // 0x0146
+    pub mapper_type: u8, // 0x0147
+    pub cartridge_type: CartridgeType,
+    pub rom_size_code: u8, // 0x0148
+    pub ram_size_code: u8, // 0x0149
+    pub destination: Destination,
+    pub header_checksum: u8,  // 0x014D
+    pub global_checksum: u16, // 0x014E - 0x014F, big endian
+    computed_header_checksum: u8,
+    computed_global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    // Only fails if the ROM is too small to have a header
+    pub fn parse(raw: &[u8]) -> Result<Self, HeaderError> {
+        if raw.len() < 0x0150 {
+            return Err(HeaderError::TooShort(raw.len()));
+        }
+        let mapper_type = raw[0x0147];
+        Ok(Self {
+            title: title(raw),
+            cgb_flag: raw[0x0143],
+            sgb_flag: raw[0x0146],
+            mapper_type,
+            cartridge_type: CartridgeType::from_code(mapper_type),
+            rom_size_code: raw[0x0148],
+            ram_size_code: raw[0x0149],
+            destination: match raw[0x014A] {
+                0x00 => Destination::Japan,
+                0x01 => Destination::Overseas,
+                code => Destination::Unknown(code),
+            },
+            header_checksum: raw[0x014D],
+            global_checksum: u16::from_be_bytes([raw[0x014E], raw[0x014F]]),
+            computed_header_checksum: Self::checksum(raw),
+            computed_global_checksum: Self::global_checksum(raw),
+        })
+    }
+
+    // Same checksum the boot ROM verifies: x = x - byte - 1 over 0x0134 - 0x014C
+    pub fn checksum(raw: &[u8]) -> u8 {
+        raw[0x0134..=0x014C]
+            .iter()
+            .fold(0u8, |x, &byte| x.wrapping_sub(byte).wrapping_sub(1))
+    }
+
+    // Sum of every ROM byte except the global checksum itself
+    pub fn global_checksum(raw: &[u8]) -> u16 {
+        raw.iter()
+            .enumerate()
+            .filter(|&(i, _)| i != 0x014E && i != 0x014F)
+            .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+    }
+
+    // The boot ROM locks up on a bad header checksum. It usually means the file isn't a
+    // ROM or is corrupt
+    pub fn validate(&self) -> Result<(), HeaderError> {
+        if self.header_checksum != self.computed_header_checksum {
+            return Err(HeaderError::ChecksumMismatch {
+                expected: self.header_checksum,
+                computed: self.computed_header_checksum,
+            });
+        }
+        Ok(())
+    }
+
+    // Nothing on hardware checks the global checksum, plenty of homebrew gets it wrong
+    pub fn validate_global(&self) -> Result<(), HeaderError> {
+        if self.global_checksum != self.computed_global_checksum {
+            return Err(HeaderError::GlobalChecksumMismatch {
+                expected: self.global_checksum,
+                computed: self.computed_global_checksum,
+            });
+        }
+        Ok(())
+    }
+
+    // 32 KiB doubled per step of the size code. None past 8 MiB
+    pub fn rom_size(&self) -> Option<usize> {
+        (self.rom_size_code <= 8).then(|| (32 * KIB) << self.rom_size_code)
+    }
+
+    // None for code 1, which no released cartridge uses, and anything past 5
+    pub fn ram_size(&self) -> Option<usize> {
+        match self.ram_size_code {
+            0 => Some(0),
+            2 => Some(8 * KIB),
+            3 => Some(32 * KIB),
+            4 => Some(128 * KIB),
+            5 => Some(64 * KIB),
+            _ => None,
+        }
+    }
+
+    // Cartridge types with a battery keeping RAM (or the MBC3 clock) going when switched off
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.mapper_type,
+            0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff
+        )
+    }
+
+    // MBC3+TIMER cartridges, 0x0F and 0x10
+    pub fn has_timer(&self) -> bool {
+        matches!(self.mapper_type, 0x0f | 0x10)
+    }
+}
+
+// Game title at 0x0134 - 0x0143, up to the first zero. Empty if the ROM is too short to have one
+pub fn title(raw: &[u8]) -> String {
+    let Some(area) = raw.get(0x0134..0x0144) else {
+        return String::new();
+    };
+    // CGB games reuse the end of the title area for the CGB flag
+    let area = if area[15] & 0x80 > 0 {
+        &area[..15]
+    } else {
+        area
+    };
+    let len = area
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(area.len());
+    String::from_utf8_lossy(&area[..len]).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bare header with a title, the given RAM size code and a correct checksum
+    fn synthetic_header(ram_size_code: u8) -> Vec<u8> {
+        let mut raw = vec![0; 0x0150];
+        raw[0x0134..0x0138].copy_from_slice(b"TEST");
+        raw[0x0147] = 0x03; // MBC1+RAM+BATTERY
+        raw[0x0149] = ram_size_code;
+        raw[0x014A] = 0x01;
+        raw[0x014D] = CartridgeHeader::checksum(&raw);
+        raw
+    }
+
+    #[test]
+    fn test_ram_size_codes() {
+        let sizes = [
+            Some(0),
+            None,
+            Some(8 * KIB),
+            Some(32 * KIB),
+            Some(128 * KIB),
+            Some(64 * KIB),
+            None,
+        ];
+        for (code, size) in sizes.into_iter().enumerate() {
+            let header = CartridgeHeader::parse(&synthetic_header(code as u8)).unwrap();
+            assert_eq!(header.ram_size(), size, "RAM size code {code}");
+            assert_eq!(header.validate(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_fields() {
+        let header = CartridgeHeader::parse(&synthetic_header(2)).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!(header.cartridge_type, CartridgeType::Mbc1);
+        assert_eq!(header.destination, Destination::Overseas);
+        assert_eq!(header.rom_size(), Some(32 * KIB));
+        assert!(header.has_battery());
+        assert!(!header.has_timer());
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let mut raw = synthetic_header(0);
+        let expected = raw[0x014D].wrapping_add(1);
+        raw[0x014D] = expected;
+        let header = CartridgeHeader::parse(&raw).unwrap();
+        assert_eq!(
+            header.validate(),
+            Err(HeaderError::ChecksumMismatch {
+                expected,
+                computed: expected.wrapping_sub(1),
+            })
+        );
+    }
+
+    #[test]
+    fn test_too_short() {
+        assert_eq!(
+            CartridgeHeader::parse(&[0; 0x014F]),
+            Err(HeaderError::TooShort(0x014F))
+        );
+    }
+}
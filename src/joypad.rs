@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+use crate::state::{State, StateError, StateReader, StateWriter};
+
 // 1: is released, 0: is pressed
 pub struct SelectButtons(u8);
 
@@ -5,6 +9,7 @@ pub struct Dpad(u8);
 
 // If dpad_mode is false, then directional buttons can be read
 // If select_mode is false, then buttons start, select, a and b can be read
+// If both are false the two groups are ANDed together
 // If both dpad_mode and select_mode are true then lower nibble is $F
 
 pub struct Joypad {
@@ -15,6 +20,25 @@ pub struct Joypad {
     pub interrupt: bool,
 }
 
+impl State for Joypad {
+    fn save(&self, w: &mut StateWriter) {
+        self.select_mode.save(w);
+        self.dpad_mode.save(w);
+        self.select.0.save(w);
+        self.dpad.0.save(w);
+        self.interrupt.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.select_mode.load(r)?;
+        self.dpad_mode.load(r)?;
+        self.select.0.load(r)?;
+        self.dpad.0.load(r)?;
+        self.interrupt.load(r)?;
+        Ok(())
+    }
+}
+
 impl Joypad {
     pub fn new() -> Self {
         Self {
@@ -27,14 +51,15 @@ impl Joypad {
     }
 
     pub fn read(&self) -> u8 {
-        let lo_nib = if !self.select_mode {
-            self.select.0 & 0x0f
-        } else if !self.dpad_mode {
-            self.dpad.0 & 0x0f
-        } else {
-            0x0f
-        };
-        ((self.select_mode as u8) << 5) + ((self.dpad_mode as u8) << 4) + lo_nib
+        let mut lo_nib = 0x0f;
+        if !self.select_mode {
+            lo_nib &= self.select.0;
+        }
+        if !self.dpad_mode {
+            lo_nib &= self.dpad.0;
+        }
+        // Bits 6-7 are unused and read as 1
+        0b1100_0000 | ((self.select_mode as u8) << 5) | ((self.dpad_mode as u8) << 4) | lo_nib
     }
 
     pub fn write(&mut self, val: u8) {
@@ -43,19 +68,94 @@ impl Joypad {
     }
 
     // mode = true => select_mode, mode = false => dpad_mode
-    // High to low (i.e button pressed = true) causes an interrupt
+    // High to low (i.e button pressed = true) causes an interrupt, but only
+    // if the button's group is selected since otherwise the line doesn't change
     pub fn button_pressed_status(&mut self, mode: bool, button: u8, pressed: bool) {
         match (mode, pressed) {
             (true, true) => {
-                self.interrupt = true;
+                self.interrupt |= !self.select_mode;
                 self.select.0 &= !button;
             }
             (true, false) => self.select.0 |= button,
             (false, true) => {
-                self.interrupt = true;
+                self.interrupt |= !self.dpad_mode;
                 self.dpad.0 &= !button;
             }
             (false, false) => self.dpad.0 |= button,
         }
     }
 }
+
+// Logical Game Boy buttons that a key can be bound to
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum JoypadAction {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    Start,
+    Select,
+}
+
+impl JoypadAction {
+    pub const ALL: [JoypadAction; 8] = [
+        JoypadAction::Up,
+        JoypadAction::Down,
+        JoypadAction::Left,
+        JoypadAction::Right,
+        JoypadAction::A,
+        JoypadAction::B,
+        JoypadAction::Start,
+        JoypadAction::Select,
+    ];
+
+    // (mode, button) as used by Joypad::button_pressed_status
+    // true = select mode, false = dpad mode
+    pub fn button(self) -> (bool, u8) {
+        match self {
+            JoypadAction::Down => (false, 0b0000_1000),
+            JoypadAction::Up => (false, 0b0000_0100),
+            JoypadAction::Left => (false, 0b0000_0010),
+            JoypadAction::Right => (false, 0b0000_0001),
+            JoypadAction::Start => (true, 0b0000_1000),
+            JoypadAction::Select => (true, 0b0000_0100),
+            JoypadAction::B => (true, 0b0000_0010),
+            JoypadAction::A => (true, 0b0000_0001),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_combinations() {
+        let mut joypad = Joypad::new();
+        let (mode, button) = JoypadAction::A.button();
+        joypad.button_pressed_status(mode, button, true);
+        let (mode, button) = JoypadAction::Left.button();
+        joypad.button_pressed_status(mode, button, true);
+
+        // Neither group, buttons only, d-pad only, both ANDed together
+        for (select, read) in [(0x30, 0xff), (0x10, 0xde), (0x20, 0xed), (0x00, 0xcc)] {
+            joypad.write(select);
+            assert_eq!(joypad.read(), read, "select {select:02X}");
+        }
+    }
+
+    #[test]
+    fn test_press_interrupts_only_when_selected() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x20);
+        let (mode, button) = JoypadAction::Start.button();
+        joypad.button_pressed_status(mode, button, true);
+        assert!(!joypad.interrupt);
+
+        let (mode, button) = JoypadAction::Down.button();
+        joypad.button_pressed_status(mode, button, true);
+        assert!(joypad.interrupt);
+    }
+}
@@ -0,0 +1,37 @@
+// Emulator core. Has no SDL or window dependencies so it can be driven headlessly
+// Core types are built with new() and are never meant to be Default constructed
+#![allow(clippy::new_without_default)]
+
+pub mod apu;
+pub mod bus;
+pub mod capture;
+pub mod cartridge;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+#[cfg(feature = "accurate_ppu")]
+pub mod fifo;
+pub mod gameboy;
+pub mod header;
+pub mod joypad;
+pub mod limiter;
+pub mod opcodes;
+pub mod ppu;
+pub mod printer;
+pub mod render;
+pub mod replay;
+pub mod resampler;
+pub mod rewind;
+pub mod rtc;
+pub mod serial;
+pub mod state;
+pub mod testrom;
+pub mod timer;
+pub mod trace;
+
+pub use bus::Bus;
+pub use cartridge::{get_mapper, CartridgeError};
+pub use cpu::Cpu;
+pub use gameboy::Gameboy;
+pub use joypad::{Joypad, JoypadAction};
+pub use render::Frame;
@@ -0,0 +1,127 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// DMG refresh rate: 4194304 Hz / 70224 cycles per frame
+pub const FRAME_RATE: f64 = 59.7275;
+
+// Persistent speeds stepped through by slower()/faster()
+pub const SPEEDS: [f64; 6] = [0.25, 0.5, 0.75, 1.0, 1.5, 2.0];
+
+// Speed while turbo is held
+pub const TURBO_SPEED: f64 = 4.0;
+
+// Sleeping is only accurate to about a millisecond so the tail end is spun
+const SPIN_TIME: Duration = Duration::from_millis(1);
+
+// Paces emulation to real time. Call wait() once after each emulated frame
+pub struct FrameLimiter {
+    next_frame: Instant,
+    pub speed: f64,  // Target speed multiplier. 1.0 is real time
+    pub turbo: bool, // Run at TURBO_SPEED while set
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        Self {
+            next_frame: Instant::now(),
+            speed: 1.0,
+            turbo: false,
+        }
+    }
+
+    // Speed actually being paced to, taking turbo into account
+    pub fn current_speed(&self) -> f64 {
+        if self.turbo {
+            TURBO_SPEED
+        } else {
+            self.speed
+        }
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / (FRAME_RATE * self.current_speed()))
+    }
+
+    // Step to the next slower or faster entry in SPEEDS
+    pub fn slower(&mut self) {
+        self.speed = SPEEDS
+            .iter()
+            .rev()
+            .find(|&&speed| speed < self.speed)
+            .copied()
+            .unwrap_or(SPEEDS[0]);
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = SPEEDS
+            .iter()
+            .find(|&&speed| speed > self.speed)
+            .copied()
+            .unwrap_or(SPEEDS[SPEEDS.len() - 1]);
+    }
+
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        self.next_frame += self.frame_duration();
+        // Fell more than a frame behind (paused, window dragged, slow host). Don't try to catch up
+        if self.next_frame + self.frame_duration() < now {
+            self.next_frame = now;
+            return;
+        }
+
+        if let Some(remaining) = self.next_frame.checked_duration_since(now) {
+            if remaining > SPIN_TIME {
+                thread::sleep(remaining - SPIN_TIME);
+            }
+            while Instant::now() < self.next_frame {
+                std::hint::spin_loop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frames the limiter lets through in a quarter of a second
+    fn frames_in(limiter: &mut FrameLimiter) -> u32 {
+        let start = Instant::now();
+        limiter.next_frame = start;
+        let mut frames = 0;
+        while start.elapsed() < Duration::from_millis(250) {
+            limiter.wait();
+            frames += 1;
+        }
+        frames
+    }
+
+    #[test]
+    fn test_double_speed() {
+        let mut limiter = FrameLimiter::new();
+        let normal = frames_in(&mut limiter);
+        limiter.speed = 2.0;
+        let double = frames_in(&mut limiter);
+        let ratio = double as f64 / normal as f64;
+        assert!(
+            (1.7..2.3).contains(&ratio),
+            "{normal} frames at 1x, {double} at 2x"
+        );
+    }
+
+    #[test]
+    fn test_speed_steps() {
+        let mut limiter = FrameLimiter::new();
+        limiter.faster();
+        assert_eq!(limiter.speed, 1.5);
+        limiter.faster();
+        limiter.faster();
+        assert_eq!(limiter.speed, 2.0);
+        limiter.speed = 0.5;
+        limiter.slower();
+        limiter.slower();
+        assert_eq!(limiter.speed, 0.25);
+        limiter.turbo = true;
+        assert_eq!(limiter.current_speed(), TURBO_SPEED);
+    }
+}
@@ -1,79 +1,244 @@
-mod apu;
-mod bus;
-mod cartridge;
-mod cpu;
+mod config;
+mod emu_thread;
 mod frontend;
-mod joypad;
-mod opcodes;
-mod ppu;
-mod render;
 mod sdl2_setup;
-mod timer;
-mod trace;
 
-use bus::Bus;
-use cpu::Cpu;
 use frontend::MyApp;
+use gb_emulator::printer::Printer;
+use gb_emulator::render::Palette;
+use gb_emulator::replay::InputRecorder;
+use gb_emulator::serial::{SerialLink, TcpLink};
+use gb_emulator::{cartridge, header, testrom, Gameboy};
 
-use std::env;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
 
 use eframe::egui;
 
 use crate::frontend::GameSelect;
 
+// Value following a flag, e.g. the path in --rom <path>
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+}
+
+fn load_boot_rom(path: &String) -> io::Result<[u8; 0x100]> {
+    let bytes = fs::read(path)?;
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("boot ROM must be 256 bytes, got {}", bytes.len()),
+        )
+    })
+}
+
+// listen:PORT waits for the other instance, connect:HOST:PORT joins one that's listening
+fn open_link(spec: &str) -> io::Result<Box<dyn SerialLink>> {
+    if let Some(port) = spec.strip_prefix("listen:") {
+        let port = port
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad port"))?;
+        Ok(Box::new(TcpLink::listen(port)?))
+    } else if let Some(addr) = spec.strip_prefix("connect:") {
+        Ok(Box::new(TcpLink::connect(addr)?))
+    } else if spec == "printer" {
+        Ok(Box::new(Printer::new(PathBuf::from("."))))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "expected listen:PORT, connect:HOST:PORT or printer",
+        ))
+    }
+}
+
 fn main() -> eframe::Result {
-    let args: String = env::args().collect();
-    let audio_device = sdl2_setup::setup();
+    let args: Vec<String> = env::args().collect();
     //let texture_creator = canvas.texture_creator();
     //let mut texture = sdl2_setup::dummy_texture(&texture_creator).unwrap();
-    let mut game_name: Option<PathBuf> = None;
+    let mut config = config::Config::load();
+    // --scale <1-6> sets how big the egui window draws the screen this run, otherwise it's the
+    // saved scale
+    let scale = match arg_value(&args, "--scale").map(|scale| scale.parse::<u32>()) {
+        Some(Ok(scale @ 1..=frontend::MAX_SCALE)) => scale,
+        Some(_) => {
+            eprintln!(
+                "--scale takes a whole number from 1 to {}",
+                frontend::MAX_SCALE
+            );
+            return Ok(());
+        }
+        None => config.scale,
+    };
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([992.0, 558.0]),
+        viewport: egui::ViewportBuilder::default().with_inner_size(frontend::window_size(scale)),
         ..Default::default()
     };
-    let _ = eframe::run_native(
-        "Game Select",
-        options.clone(),
-        Box::new(|_cc| Ok(Box::<GameSelect>::new(GameSelect::new(&mut game_name)))),
-    );
-    // let bytes: Vec<u8> =
-    //     std::fs::read("roms/kirby's pinball land.gb").expect("No ROM File with that name");
-    let bytes: Vec<u8> = std::fs::read(game_name.unwrap()).unwrap();
-    let cartridge = cartridge::get_mapper(&bytes);
-    let bus = Bus::new(cartridge);
-    let cpu = Cpu::new(bus);
-
-    let trace_on = args.contains("trace");
+
+    // --test-roms <dir> runs every test ROM in dir headlessly and reports which passed.
+    // --frames <n> overrides how long each ROM gets
+    if let Some(dir) = arg_value(&args, "--test-roms") {
+        let timeout = arg_value(&args, "--frames")
+            .and_then(|frames| frames.parse().ok())
+            .unwrap_or(testrom::DEFAULT_TIMEOUT_FRAMES);
+        match testrom::run_dir(Path::new(dir), |_| timeout) {
+            Ok(results) => {
+                if !testrom::print_summary(&results) {
+                    std::process::exit(1);
+                }
+            }
+            Err(err) => {
+                eprintln!("Could not run test ROMs: {err}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    // ROM comes from --rom <path>, otherwise let the user pick one
+    let mut game_name: Option<PathBuf> = arg_value(&args, "--rom").map(PathBuf::from);
+    if game_name.is_none() {
+        let _ = eframe::run_native(
+            "Game Select",
+            options.clone(),
+            Box::new(|_cc| {
+                Ok(Box::<GameSelect>::new(GameSelect::new(
+                    &mut game_name,
+                    &config.recent_roms,
+                )))
+            }),
+        );
+    }
+    // Game select closed without a choice. Fall back to the native file dialog
+    if game_name.is_none() {
+        game_name = frontend::pick_rom_file(config.last_rom.as_deref());
+    }
+    let Some(game_name) = game_name else {
+        return Ok(());
+    };
+
+    let cartridge = match cartridge::load_rom(&game_name) {
+        Ok(cartridge) => cartridge,
+        Err(err) => {
+            rfd::MessageDialog::new()
+                .set_level(rfd::MessageLevel::Error)
+                .set_title("Could not open ROM")
+                .set_description(format!("{}: {err}", game_name.display()))
+                .show();
+            return Ok(());
+        }
+    };
+    config.add_recent_rom(&game_name);
+    if let Err(err) = config.save() {
+        eprintln!("Could not save recent ROMs: {err}");
+    }
+    // --dmg runs CGB enhanced games in original Game Boy mode
+    let force_dmg = args.iter().any(|arg| arg == "--dmg");
+    // --boot-rom <path> runs the 256 byte DMG boot ROM before the cartridge
+    let boot_rom = match arg_value(&args, "--boot-rom").map(load_boot_rom) {
+        Some(Ok(boot_rom)) => Some(boot_rom),
+        Some(Err(err)) => {
+            eprintln!("Could not load boot ROM: {err}");
+            return Ok(());
+        }
+        None => None,
+    };
+    // Audio is opened on the emulator thread, see emu_thread
+    let mut gameboy = Gameboy::from_cartridge(cartridge, force_dmg, boot_rom);
+
+    // Battery RAM and the MBC3 clock live in a .sav file next to the ROM
+    if let Err(err) = gameboy.load_battery(&game_name.with_extension("sav")) {
+        eprintln!("Could not load save file: {err}");
+    }
+
+    // --record <file> saves joypad input, --playback <file> replays it in place of the keyboard
+    let recorder = if let Some(path) = arg_value(&args, "--record") {
+        Some(InputRecorder::record(Path::new(path)))
+    } else {
+        arg_value(&args, "--playback").map(|path| InputRecorder::playback(Path::new(path)))
+    };
+    match recorder {
+        Some(Ok(recorder)) => gameboy.recorder = Some(recorder),
+        Some(Err(err)) => {
+            eprintln!("Could not open input recording: {err}");
+            return Ok(());
+        }
+        None => {}
+    }
+    // --palette <name|file> picks the DMG colours from a preset or a palette file, overriding
+    // the config
+    if let Some(spec) = arg_value(&args, "--palette") {
+        config.palette = Some(spec.clone());
+    }
+    if let Some(spec) = &config.palette {
+        match Palette::load(spec) {
+            Ok(palette) => gameboy.cpu.bus.ppu.set_palette(palette),
+            Err(err) => eprintln!("Could not load palette {spec}: {err}"),
+        }
+    }
+
+    // --debug-serial prints what the game sends over serial, like test ROM results
+    gameboy.cpu.bus.debug_serial = args.iter().any(|arg| arg == "--debug-serial");
+
+    let trace_on = args.iter().any(|arg| arg.contains("trace"));
     if trace_on {
         eprintln!("Trace is on");
     }
+    // --trace-file <path> writes the trace there instead of stderr
+    if let Some(path) = arg_value(&args, "--trace-file") {
+        match File::create(path) {
+            Ok(file) => gameboy.cpu.trace_writer = Some(Box::new(BufWriter::new(file))),
+            Err(err) => {
+                eprintln!("Could not create trace file {path}: {err}");
+                return Ok(());
+            }
+        }
+    }
+    // --link listen:PORT or --link connect:HOST:PORT plugs in a link cable to another instance.
+    // --link printer plugs in a Game Boy Printer, printouts are saved as PNGs
+    if let Some(spec) = arg_value(&args, "--link") {
+        match open_link(spec) {
+            Ok(link) => gameboy.cpu.bus.link = link,
+            Err(err) => {
+                eprintln!("Could not open link cable {spec}: {err}");
+                return Ok(());
+            }
+        }
+    }
     //let show_fps = args.contains("show-fps");
-    let frame_count = 0;
-    let baseline = Instant::now();
     // if show_fps {
     //     eprintln!("Show FPS is on");
     // };
 
+    // Window title shows the game's name from its header
+    let title = header::title(gameboy.cpu.bus.cartridge.rom());
+    let title = if title.is_empty() {
+        "GB Emulator".to_string()
+    } else {
+        format!("GB Emulator - {title}")
+    };
+    let options = eframe::NativeOptions {
+        viewport: options.viewport.with_title(title),
+        ..options
+    };
+
     // eframe setup
     eframe::run_native(
         "GB Emulator",
         options,
         Box::new(|cc| {
             Ok(Box::<MyApp>::new(MyApp::new(
-                frame_count,
-                baseline,
-                trace_on,
-                audio_device,
-                cpu,
-                cc,
+                trace_on, gameboy, config, game_name, scale, cc,
             )))
         }),
     )
 
     /*
     // Enter game loop
+    let mut limiter = FrameLimiter::new();
     loop {
         if show_fps && frame_count == 0 {
             baseline = Instant::now();
@@ -95,13 +260,13 @@ fn main() -> eframe::Result {
 
         if let Some(frame) = frame {
             // present frame
-            texture.update(None, &frame.data, 160 * 3).unwrap();
+            texture.update(None, frame.as_rgb_bytes(), 160 * 3).unwrap();
             canvas.copy(&texture, None, None).unwrap();
             canvas.present();
 
             // play audio
-            audio_device.queue_audio(&cpu.bus.audio_buffer).unwrap();
-            while audio_device.size() > 5000 {}
+            audio_device.queue_audio(gameboy.audio_samples()).unwrap();
+            limiter.wait();
 
             // check user input
             sdl2_setup::get_user_input(&mut event_pump, &mut cpu.bus.joypad);
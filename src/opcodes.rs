@@ -1,5 +1,4 @@
 use lazy_static::lazy_static;
-use std::collections::HashMap;
 
 #[derive(Debug)]
 pub enum TargetReg {
@@ -45,694 +44,806 @@ impl Opcode {
     }
 }
 
+// Opcode at byte, None for the 11 unused opcodes which lock up the CPU
+pub fn lookup(byte: u8) -> Option<&'static Opcode> {
+    CPU_OP_CODES[byte as usize].as_ref()
+}
+
+// CB prefixed opcode at byte. Every byte is a valid opcode here
+pub fn lookup_prefixed(byte: u8) -> Option<&'static Opcode> {
+    CPU_PREFIXED_OP_CODES[byte as usize].as_ref()
+}
+
 lazy_static! {
-    pub static ref CPU_OP_CODES: HashMap<u8, Opcode> = {
-        let mut map = HashMap::new();
+    pub static ref CPU_OP_CODES: [Option<Opcode>; 256] = {
+        let mut table: [Option<Opcode>; 256] = std::array::from_fn(|_| None);
 
         // adc a, r8
-        map.insert(0x88, Opcode::new("ADC", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0x89, Opcode::new("ADC", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0x8a, Opcode::new("ADC", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0x8b, Opcode::new("ADC", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0x8c, Opcode::new("ADC", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0x8d, Opcode::new("ADC", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0x8e, Opcode::new("ADC", TargetReg::A, TargetReg::R8(6), 1, 2)); // adc a, [hl]
-        map.insert(0x8f, Opcode::new("ADC", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0x88] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0x89] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0x8a] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0x8b] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0x8c] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0x8d] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0x8e] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(6), 1, 2)); // adc a, [hl]
+        table[0x8f] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // adc a, n8
-        map.insert(0xce, Opcode::new("ADC", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xce] = Some(Opcode::new("ADC", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // add a, r8
-        map.insert(0x80, Opcode::new("ADD", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0x81, Opcode::new("ADD", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0x82, Opcode::new("ADD", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0x83, Opcode::new("ADD", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0x84, Opcode::new("ADD", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0x85, Opcode::new("ADD", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0x86, Opcode::new("ADD", TargetReg::A, TargetReg::R8(6), 1, 2)); // add a, [hl]
-        map.insert(0x87, Opcode::new("ADD", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0x80] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0x81] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0x82] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0x83] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0x84] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0x85] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0x86] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(6), 1, 2)); // add a, [hl]
+        table[0x87] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // add a, r8
-        map.insert(0xc6, Opcode::new("ADD", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xc6] = Some(Opcode::new("ADD", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // add hl, r16
-        map.insert(0x09, Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(0), 1, 2));
-        map.insert(0x19, Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(1), 1, 2));
-        map.insert(0x29, Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(2), 1, 2));
-        map.insert(0x39, Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(3), 1, 2));
+        table[0x09] = Some(Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(0), 1, 2));
+        table[0x19] = Some(Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(1), 1, 2));
+        table[0x29] = Some(Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(2), 1, 2));
+        table[0x39] = Some(Opcode::new("ADD", TargetReg::R16(2), TargetReg::R16(3), 1, 2));
 
         // add sp, r8
-        map.insert(0xe8, Opcode::new("ADD", TargetReg::SP, TargetReg::Imm8, 2, 4));
+        table[0xe8] = Some(Opcode::new("ADD", TargetReg::SP, TargetReg::Imm8, 2, 4));
 
         // and a, r8
-        map.insert(0xa0, Opcode::new("AND", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0xa1, Opcode::new("AND", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0xa2, Opcode::new("AND", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0xa3, Opcode::new("AND", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0xa4, Opcode::new("AND", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0xa5, Opcode::new("AND", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0xa6, Opcode::new("AND", TargetReg::A, TargetReg::R8(6), 1, 2)); // and a, hl
-        map.insert(0xa7, Opcode::new("AND", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0xa0] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0xa1] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0xa2] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0xa3] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0xa4] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0xa5] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0xa6] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(6), 1, 2)); // and a, hl
+        table[0xa7] = Some(Opcode::new("AND", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // and a, r8
-        map.insert(0xe6, Opcode::new("AND", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xe6] = Some(Opcode::new("AND", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // call r16
-        map.insert(0xcd, Opcode::new("CALL", TargetReg::Imm16, TargetReg::None, 3, 6));
+        table[0xcd] = Some(Opcode::new("CALL", TargetReg::Imm16, TargetReg::None, 3, 6));
 
         // call cond, r16
-        map.insert(0xc4, Opcode::new("CALL", TargetReg::Cond(0), TargetReg::Imm16, 3, 3));
-        map.insert(0xcc, Opcode::new("CALL", TargetReg::Cond(1), TargetReg::Imm16, 3, 3));
-        map.insert(0xd4, Opcode::new("CALL", TargetReg::Cond(2), TargetReg::Imm16, 3, 3));
-        map.insert(0xdc, Opcode::new("CALL", TargetReg::Cond(3), TargetReg::Imm16, 3, 3));
+        table[0xc4] = Some(Opcode::new("CALL", TargetReg::Cond(0), TargetReg::Imm16, 3, 3));
+        table[0xcc] = Some(Opcode::new("CALL", TargetReg::Cond(1), TargetReg::Imm16, 3, 3));
+        table[0xd4] = Some(Opcode::new("CALL", TargetReg::Cond(2), TargetReg::Imm16, 3, 3));
+        table[0xdc] = Some(Opcode::new("CALL", TargetReg::Cond(3), TargetReg::Imm16, 3, 3));
 
         // ccf
-        map.insert(0x3f, Opcode::new("CCF", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x3f] = Some(Opcode::new("CCF", TargetReg::None, TargetReg::None, 1, 1));
 
         // cp a, r8
-        map.insert(0xb8, Opcode::new("CP", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0xb9, Opcode::new("CP", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0xba, Opcode::new("CP", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0xbb, Opcode::new("CP", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0xbc, Opcode::new("CP", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0xbd, Opcode::new("CP", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0xbe, Opcode::new("CP", TargetReg::A, TargetReg::R8(6), 1, 2)); // cp a, [hl]
-        map.insert(0xbf, Opcode::new("CP", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0xb8] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0xb9] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0xba] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0xbb] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0xbc] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0xbd] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0xbe] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(6), 1, 2)); // cp a, [hl]
+        table[0xbf] = Some(Opcode::new("CP", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // cp a, n8
-        map.insert(0xfe, Opcode::new("CP", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xfe] = Some(Opcode::new("CP", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // cpl
-        map.insert(0x2f, Opcode::new("CPL", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x2f] = Some(Opcode::new("CPL", TargetReg::None, TargetReg::None, 1, 1));
 
         // daa
-        map.insert(0x27, Opcode::new("DAA", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x27] = Some(Opcode::new("DAA", TargetReg::None, TargetReg::None, 1, 1));
 
         // dec r8
-        map.insert(0x05, Opcode::new("DEC", TargetReg::R8(0), TargetReg::None, 1, 1));
-        map.insert(0x0d, Opcode::new("DEC", TargetReg::R8(1), TargetReg::None, 1, 1));
-        map.insert(0x15, Opcode::new("DEC", TargetReg::R8(2), TargetReg::None, 1, 1));
-        map.insert(0x1d, Opcode::new("DEC", TargetReg::R8(3), TargetReg::None, 1, 1));
-        map.insert(0x25, Opcode::new("DEC", TargetReg::R8(4), TargetReg::None, 1, 1));
-        map.insert(0x2d, Opcode::new("DEC", TargetReg::R8(5), TargetReg::None, 1, 1));
-        map.insert(0x35, Opcode::new("DEC", TargetReg::R8(6), TargetReg::None, 1, 3)); // dec [hl]
-        map.insert(0x3d, Opcode::new("DEC", TargetReg::R8(7), TargetReg::None, 1, 1));
+        table[0x05] = Some(Opcode::new("DEC", TargetReg::R8(0), TargetReg::None, 1, 1));
+        table[0x0d] = Some(Opcode::new("DEC", TargetReg::R8(1), TargetReg::None, 1, 1));
+        table[0x15] = Some(Opcode::new("DEC", TargetReg::R8(2), TargetReg::None, 1, 1));
+        table[0x1d] = Some(Opcode::new("DEC", TargetReg::R8(3), TargetReg::None, 1, 1));
+        table[0x25] = Some(Opcode::new("DEC", TargetReg::R8(4), TargetReg::None, 1, 1));
+        table[0x2d] = Some(Opcode::new("DEC", TargetReg::R8(5), TargetReg::None, 1, 1));
+        table[0x35] = Some(Opcode::new("DEC", TargetReg::R8(6), TargetReg::None, 1, 3)); // dec [hl]
+        table[0x3d] = Some(Opcode::new("DEC", TargetReg::R8(7), TargetReg::None, 1, 1));
 
         // dec r16
-        map.insert(0x0b, Opcode::new("DEC", TargetReg::R16(0), TargetReg::None, 1, 2));
-        map.insert(0x1b, Opcode::new("DEC", TargetReg::R16(1), TargetReg::None, 1, 2));
-        map.insert(0x2b, Opcode::new("DEC", TargetReg::R16(2), TargetReg::None, 1, 2));
-        map.insert(0x3b, Opcode::new("DEC", TargetReg::R16(3), TargetReg::None, 1, 2));
+        table[0x0b] = Some(Opcode::new("DEC", TargetReg::R16(0), TargetReg::None, 1, 2));
+        table[0x1b] = Some(Opcode::new("DEC", TargetReg::R16(1), TargetReg::None, 1, 2));
+        table[0x2b] = Some(Opcode::new("DEC", TargetReg::R16(2), TargetReg::None, 1, 2));
+        table[0x3b] = Some(Opcode::new("DEC", TargetReg::R16(3), TargetReg::None, 1, 2));
 
         // di
-        map.insert(0xf3, Opcode::new("DI", TargetReg::None, TargetReg::None, 1, 1));
+        table[0xf3] = Some(Opcode::new("DI", TargetReg::None, TargetReg::None, 1, 1));
 
         // ei
-        map.insert(0xfb, Opcode::new("EI", TargetReg::None, TargetReg::None, 1, 1));
+        table[0xfb] = Some(Opcode::new("EI", TargetReg::None, TargetReg::None, 1, 1));
 
         // halt
-        map.insert(0x76, Opcode::new("HALT", TargetReg::None, TargetReg::None, 0, 1));
+        table[0x76] = Some(Opcode::new("HALT", TargetReg::None, TargetReg::None, 0, 1));
 
         // inc r8
-        map.insert(0x04, Opcode::new("INC", TargetReg::R8(0), TargetReg::None, 1, 1));
-        map.insert(0x0c, Opcode::new("INC", TargetReg::R8(1), TargetReg::None, 1, 1));
-        map.insert(0x14, Opcode::new("INC", TargetReg::R8(2), TargetReg::None, 1, 1));
-        map.insert(0x1c, Opcode::new("INC", TargetReg::R8(3), TargetReg::None, 1, 1));
-        map.insert(0x24, Opcode::new("INC", TargetReg::R8(4), TargetReg::None, 1, 1));
-        map.insert(0x2c, Opcode::new("INC", TargetReg::R8(5), TargetReg::None, 1, 1));
-        map.insert(0x34, Opcode::new("INC", TargetReg::R8(6), TargetReg::None, 1, 3)); // inc [hl]
-        map.insert(0x3c, Opcode::new("INC", TargetReg::R8(7), TargetReg::None, 1, 1));
+        table[0x04] = Some(Opcode::new("INC", TargetReg::R8(0), TargetReg::None, 1, 1));
+        table[0x0c] = Some(Opcode::new("INC", TargetReg::R8(1), TargetReg::None, 1, 1));
+        table[0x14] = Some(Opcode::new("INC", TargetReg::R8(2), TargetReg::None, 1, 1));
+        table[0x1c] = Some(Opcode::new("INC", TargetReg::R8(3), TargetReg::None, 1, 1));
+        table[0x24] = Some(Opcode::new("INC", TargetReg::R8(4), TargetReg::None, 1, 1));
+        table[0x2c] = Some(Opcode::new("INC", TargetReg::R8(5), TargetReg::None, 1, 1));
+        table[0x34] = Some(Opcode::new("INC", TargetReg::R8(6), TargetReg::None, 1, 3)); // inc [hl]
+        table[0x3c] = Some(Opcode::new("INC", TargetReg::R8(7), TargetReg::None, 1, 1));
 
         // inc r16
-        map.insert(0x03, Opcode::new("INC", TargetReg::R16(0), TargetReg::None, 1, 2));
-        map.insert(0x13, Opcode::new("INC", TargetReg::R16(1), TargetReg::None, 1, 2));
-        map.insert(0x23, Opcode::new("INC", TargetReg::R16(2), TargetReg::None, 1, 2));
-        map.insert(0x33, Opcode::new("INC", TargetReg::R16(3), TargetReg::None, 1, 2));
+        table[0x03] = Some(Opcode::new("INC", TargetReg::R16(0), TargetReg::None, 1, 2));
+        table[0x13] = Some(Opcode::new("INC", TargetReg::R16(1), TargetReg::None, 1, 2));
+        table[0x23] = Some(Opcode::new("INC", TargetReg::R16(2), TargetReg::None, 1, 2));
+        table[0x33] = Some(Opcode::new("INC", TargetReg::R16(3), TargetReg::None, 1, 2));
 
         // jp n16
-        map.insert(0xc3, Opcode::new("JP", TargetReg::Imm16, TargetReg::None, 3, 4));
+        table[0xc3] = Some(Opcode::new("JP", TargetReg::Imm16, TargetReg::None, 3, 4));
 
         // jp cc, n16
-        map.insert(0xc2, Opcode::new("JP", TargetReg::Cond(0), TargetReg::Imm16, 3, 3));
-        map.insert(0xca, Opcode::new("JP", TargetReg::Cond(1), TargetReg::Imm16, 3, 3));
-        map.insert(0xd2, Opcode::new("JP", TargetReg::Cond(2), TargetReg::Imm16, 3, 3));
-        map.insert(0xda, Opcode::new("JP", TargetReg::Cond(3), TargetReg::Imm16, 3, 3));
+        table[0xc2] = Some(Opcode::new("JP", TargetReg::Cond(0), TargetReg::Imm16, 3, 3));
+        table[0xca] = Some(Opcode::new("JP", TargetReg::Cond(1), TargetReg::Imm16, 3, 3));
+        table[0xd2] = Some(Opcode::new("JP", TargetReg::Cond(2), TargetReg::Imm16, 3, 3));
+        table[0xda] = Some(Opcode::new("JP", TargetReg::Cond(3), TargetReg::Imm16, 3, 3));
 
         // jp hl
-        map.insert(0xe9, Opcode::new("JP", TargetReg::R16(2), TargetReg::None, 1, 1));
+        table[0xe9] = Some(Opcode::new("JP", TargetReg::R16(2), TargetReg::None, 1, 1));
 
         // jr n8
-        map.insert(0x18, Opcode::new("JR", TargetReg::Imm8, TargetReg::None, 2, 3));
+        table[0x18] = Some(Opcode::new("JR", TargetReg::Imm8, TargetReg::None, 2, 3));
 
         // jr cc, n8
-        map.insert(0x20, Opcode::new("JR", TargetReg::Cond(0), TargetReg::Imm8, 2, 2));
-        map.insert(0x28, Opcode::new("JR", TargetReg::Cond(1), TargetReg::Imm8, 2, 2));
-        map.insert(0x30, Opcode::new("JR", TargetReg::Cond(2), TargetReg::Imm8, 2, 2));
-        map.insert(0x38, Opcode::new("JR", TargetReg::Cond(3), TargetReg::Imm8, 2, 2));
+        table[0x20] = Some(Opcode::new("JR", TargetReg::Cond(0), TargetReg::Imm8, 2, 2));
+        table[0x28] = Some(Opcode::new("JR", TargetReg::Cond(1), TargetReg::Imm8, 2, 2));
+        table[0x30] = Some(Opcode::new("JR", TargetReg::Cond(2), TargetReg::Imm8, 2, 2));
+        table[0x38] = Some(Opcode::new("JR", TargetReg::Cond(3), TargetReg::Imm8, 2, 2));
 
         // ld r8, r8
-        map.insert(0x40, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(0), 1, 1));
-        map.insert(0x41, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(1), 1, 1));
-        map.insert(0x42, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(2), 1, 1));
-        map.insert(0x43, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(3), 1, 1));
-        map.insert(0x44, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(4), 1, 1));
-        map.insert(0x45, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(5), 1, 1));
-        map.insert(0x46, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(6), 1, 2));
-        map.insert(0x47, Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(7), 1, 1));
-
-        map.insert(0x48, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(0), 1, 1));
-        map.insert(0x49, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(1), 1, 1));
-        map.insert(0x4a, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(2), 1, 1));
-        map.insert(0x4b, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(3), 1, 1));
-        map.insert(0x4c, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(4), 1, 1));
-        map.insert(0x4d, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(5), 1, 1));
-        map.insert(0x4e, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(6), 1, 2));
-        map.insert(0x4f, Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(7), 1, 1));
-
-        map.insert(0x50, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(0), 1, 1));
-        map.insert(0x51, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(1), 1, 1));
-        map.insert(0x52, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(2), 1, 1));
-        map.insert(0x53, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(3), 1, 1));
-        map.insert(0x54, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(4), 1, 1));
-        map.insert(0x55, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(5), 1, 1));
-        map.insert(0x56, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(6), 1, 2));
-        map.insert(0x57, Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(7), 1, 1));
-
-        map.insert(0x58, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(0), 1, 1));
-        map.insert(0x59, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(1), 1, 1));
-        map.insert(0x5a, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(2), 1, 1));
-        map.insert(0x5b, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(3), 1, 1));
-        map.insert(0x5c, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(4), 1, 1));
-        map.insert(0x5d, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(5), 1, 1));
-        map.insert(0x5e, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(6), 1, 2));
-        map.insert(0x5f, Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(7), 1, 1));
-
-        map.insert(0x60, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(0), 1, 1));
-        map.insert(0x61, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(1), 1, 1));
-        map.insert(0x62, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(2), 1, 1));
-        map.insert(0x63, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(3), 1, 1));
-        map.insert(0x64, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(4), 1, 1));
-        map.insert(0x65, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(5), 1, 1));
-        map.insert(0x66, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(6), 1, 2));
-        map.insert(0x67, Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(7), 1, 1));
-
-        map.insert(0x68, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(0), 1, 1));
-        map.insert(0x69, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(1), 1, 1));
-        map.insert(0x6a, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(2), 1, 1));
-        map.insert(0x6b, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(3), 1, 1));
-        map.insert(0x6c, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(4), 1, 1));
-        map.insert(0x6d, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(5), 1, 1));
-        map.insert(0x6e, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(6), 1, 2));
-        map.insert(0x6f, Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(7), 1, 1));
-
-        map.insert(0x70, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(0), 1, 2));
-        map.insert(0x71, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(1), 1, 2));
-        map.insert(0x72, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(2), 1, 2));
-        map.insert(0x73, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(3), 1, 2));
-        map.insert(0x74, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(4), 1, 2));
-        map.insert(0x75, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(5), 1, 2));
-        //map.insert(0x76, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(6), 1, 2)); 0x76 is halt opcode
-        map.insert(0x77, Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(7), 1, 2));
-
-        map.insert(0x78, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(0), 1, 1));
-        map.insert(0x79, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(1), 1, 1));
-        map.insert(0x7a, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(2), 1, 1));
-        map.insert(0x7b, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(3), 1, 1));
-        map.insert(0x7c, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(4), 1, 1));
-        map.insert(0x7d, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(5), 1, 1));
-        map.insert(0x7e, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(6), 1, 2));
-        map.insert(0x7f, Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(7), 1, 1));
+        table[0x40] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(0), 1, 1));
+        table[0x41] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(1), 1, 1));
+        table[0x42] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(2), 1, 1));
+        table[0x43] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(3), 1, 1));
+        table[0x44] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(4), 1, 1));
+        table[0x45] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(5), 1, 1));
+        table[0x46] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(6), 1, 2));
+        table[0x47] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::R8(7), 1, 1));
+
+        table[0x48] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(0), 1, 1));
+        table[0x49] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(1), 1, 1));
+        table[0x4a] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(2), 1, 1));
+        table[0x4b] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(3), 1, 1));
+        table[0x4c] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(4), 1, 1));
+        table[0x4d] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(5), 1, 1));
+        table[0x4e] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(6), 1, 2));
+        table[0x4f] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::R8(7), 1, 1));
+
+        table[0x50] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(0), 1, 1));
+        table[0x51] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(1), 1, 1));
+        table[0x52] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(2), 1, 1));
+        table[0x53] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(3), 1, 1));
+        table[0x54] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(4), 1, 1));
+        table[0x55] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(5), 1, 1));
+        table[0x56] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(6), 1, 2));
+        table[0x57] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::R8(7), 1, 1));
+
+        table[0x58] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(0), 1, 1));
+        table[0x59] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(1), 1, 1));
+        table[0x5a] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(2), 1, 1));
+        table[0x5b] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(3), 1, 1));
+        table[0x5c] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(4), 1, 1));
+        table[0x5d] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(5), 1, 1));
+        table[0x5e] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(6), 1, 2));
+        table[0x5f] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::R8(7), 1, 1));
+
+        table[0x60] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(0), 1, 1));
+        table[0x61] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(1), 1, 1));
+        table[0x62] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(2), 1, 1));
+        table[0x63] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(3), 1, 1));
+        table[0x64] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(4), 1, 1));
+        table[0x65] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(5), 1, 1));
+        table[0x66] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(6), 1, 2));
+        table[0x67] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::R8(7), 1, 1));
+
+        table[0x68] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(0), 1, 1));
+        table[0x69] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(1), 1, 1));
+        table[0x6a] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(2), 1, 1));
+        table[0x6b] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(3), 1, 1));
+        table[0x6c] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(4), 1, 1));
+        table[0x6d] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(5), 1, 1));
+        table[0x6e] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(6), 1, 2));
+        table[0x6f] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::R8(7), 1, 1));
+
+        table[0x70] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(0), 1, 2));
+        table[0x71] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(1), 1, 2));
+        table[0x72] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(2), 1, 2));
+        table[0x73] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(3), 1, 2));
+        table[0x74] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(4), 1, 2));
+        table[0x75] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(5), 1, 2));
+        //table[0x76] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(6), 1, 2)); 0x76 is halt opcode
+        table[0x77] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::R8(7), 1, 2));
+
+        table[0x78] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(0), 1, 1));
+        table[0x79] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(1), 1, 1));
+        table[0x7a] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(2), 1, 1));
+        table[0x7b] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(3), 1, 1));
+        table[0x7c] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(4), 1, 1));
+        table[0x7d] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(5), 1, 1));
+        table[0x7e] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(6), 1, 2));
+        table[0x7f] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::R8(7), 1, 1));
 
         // ld r8, imm8
-        map.insert(0x06, Opcode::new("LD", TargetReg::R8(0), TargetReg::Imm8, 2, 2));
-        map.insert(0x0e, Opcode::new("LD", TargetReg::R8(1), TargetReg::Imm8, 2, 2));
-        map.insert(0x16, Opcode::new("LD", TargetReg::R8(2), TargetReg::Imm8, 2, 2));
-        map.insert(0x1e, Opcode::new("LD", TargetReg::R8(3), TargetReg::Imm8, 2, 2));
-        map.insert(0x26, Opcode::new("LD", TargetReg::R8(4), TargetReg::Imm8, 2, 2));
-        map.insert(0x2e, Opcode::new("LD", TargetReg::R8(5), TargetReg::Imm8, 2, 2));
-        map.insert(0x36, Opcode::new("LD", TargetReg::R8(6), TargetReg::Imm8, 2, 3));
-        map.insert(0x3e, Opcode::new("LD", TargetReg::R8(7), TargetReg::Imm8, 2, 2));
+        table[0x06] = Some(Opcode::new("LD", TargetReg::R8(0), TargetReg::Imm8, 2, 2));
+        table[0x0e] = Some(Opcode::new("LD", TargetReg::R8(1), TargetReg::Imm8, 2, 2));
+        table[0x16] = Some(Opcode::new("LD", TargetReg::R8(2), TargetReg::Imm8, 2, 2));
+        table[0x1e] = Some(Opcode::new("LD", TargetReg::R8(3), TargetReg::Imm8, 2, 2));
+        table[0x26] = Some(Opcode::new("LD", TargetReg::R8(4), TargetReg::Imm8, 2, 2));
+        table[0x2e] = Some(Opcode::new("LD", TargetReg::R8(5), TargetReg::Imm8, 2, 2));
+        table[0x36] = Some(Opcode::new("LD", TargetReg::R8(6), TargetReg::Imm8, 2, 3));
+        table[0x3e] = Some(Opcode::new("LD", TargetReg::R8(7), TargetReg::Imm8, 2, 2));
 
         // ld r16, imm16
-        map.insert(0x01, Opcode::new("LD", TargetReg::R16(0), TargetReg::Imm16, 3, 3));
-        map.insert(0x11, Opcode::new("LD", TargetReg::R16(1), TargetReg::Imm16, 3, 3));
-        map.insert(0x21, Opcode::new("LD", TargetReg::R16(2), TargetReg::Imm16, 3, 3));
-        map.insert(0x31, Opcode::new("LD", TargetReg::R16(3), TargetReg::Imm16, 3, 3));
+        table[0x01] = Some(Opcode::new("LD", TargetReg::R16(0), TargetReg::Imm16, 3, 3));
+        table[0x11] = Some(Opcode::new("LD", TargetReg::R16(1), TargetReg::Imm16, 3, 3));
+        table[0x21] = Some(Opcode::new("LD", TargetReg::R16(2), TargetReg::Imm16, 3, 3));
+        table[0x31] = Some(Opcode::new("LD", TargetReg::R16(3), TargetReg::Imm16, 3, 3));
 
         // ld [r16mem], a
-        map.insert(0x02, Opcode::new("LD", TargetReg::R16mem(0), TargetReg::A, 1, 2));
-        map.insert(0x12, Opcode::new("LD", TargetReg::R16mem(1), TargetReg::A, 1, 2));
-        map.insert(0x22, Opcode::new("LD", TargetReg::R16mem(2), TargetReg::A, 1, 2));
-        map.insert(0x32, Opcode::new("LD", TargetReg::R16mem(3), TargetReg::A, 1, 2));
+        table[0x02] = Some(Opcode::new("LD", TargetReg::R16mem(0), TargetReg::A, 1, 2));
+        table[0x12] = Some(Opcode::new("LD", TargetReg::R16mem(1), TargetReg::A, 1, 2));
+        table[0x22] = Some(Opcode::new("LD", TargetReg::R16mem(2), TargetReg::A, 1, 2));
+        table[0x32] = Some(Opcode::new("LD", TargetReg::R16mem(3), TargetReg::A, 1, 2));
 
         // ldh [c], a
-        map.insert(0xe2, Opcode::new("LDH", TargetReg::C, TargetReg::A, 1, 2));
+        table[0xe2] = Some(Opcode::new("LDH", TargetReg::C, TargetReg::A, 1, 2));
 
         // ld a, [r16mem]
-        map.insert(0x0a, Opcode::new("LD", TargetReg::A, TargetReg::R16mem(0), 1, 2));
-        map.insert(0x1a, Opcode::new("LD", TargetReg::A, TargetReg::R16mem(1), 1, 2));
-        map.insert(0x2a, Opcode::new("LD", TargetReg::A, TargetReg::R16mem(2), 1, 2));
-        map.insert(0x3a, Opcode::new("LD", TargetReg::A, TargetReg::R16mem(3), 1, 2));
+        table[0x0a] = Some(Opcode::new("LD", TargetReg::A, TargetReg::R16mem(0), 1, 2));
+        table[0x1a] = Some(Opcode::new("LD", TargetReg::A, TargetReg::R16mem(1), 1, 2));
+        table[0x2a] = Some(Opcode::new("LD", TargetReg::A, TargetReg::R16mem(2), 1, 2));
+        table[0x3a] = Some(Opcode::new("LD", TargetReg::A, TargetReg::R16mem(3), 1, 2));
 
         // ld a, [imm16]
-        map.insert(0xfa, Opcode::new("LD", TargetReg::A, TargetReg::Ptr, 3, 4));
+        table[0xfa] = Some(Opcode::new("LD", TargetReg::A, TargetReg::Ptr, 3, 4));
 
         // ldh [imm8], a
-        map.insert(0xe0, Opcode::new("LDH", TargetReg::Imm8, TargetReg::A, 2, 3));
+        table[0xe0] = Some(Opcode::new("LDH", TargetReg::Imm8, TargetReg::A, 2, 3));
 
         // ld [imm16], a
-        map.insert(0xea, Opcode::new("LD", TargetReg::Ptr, TargetReg::A, 3, 4));
+        table[0xea] = Some(Opcode::new("LD", TargetReg::Ptr, TargetReg::A, 3, 4));
 
         // ldh a, [imm8]
-        map.insert(0xf0, Opcode::new("LDH", TargetReg::A, TargetReg::Imm8, 2, 3));
+        table[0xf0] = Some(Opcode::new("LDH", TargetReg::A, TargetReg::Imm8, 2, 3));
 
         // ldh a, [c]
-        map.insert(0xf2, Opcode::new("LDH", TargetReg::A, TargetReg::C, 1, 2));
+        table[0xf2] = Some(Opcode::new("LDH", TargetReg::A, TargetReg::C, 1, 2));
 
         // ld [imm16], sp
-        map.insert(0x08, Opcode::new("LD", TargetReg::Imm16, TargetReg::SP, 3, 5));
+        table[0x08] = Some(Opcode::new("LD", TargetReg::Imm16, TargetReg::SP, 3, 5));
 
         // ld hl, sp + imm8
-        map.insert(0xf8, Opcode::new("LD", TargetReg::R16(2), TargetReg::Imm8, 2, 3));
+        table[0xf8] = Some(Opcode::new("LD", TargetReg::R16(2), TargetReg::Imm8, 2, 3));
 
         // ld sp, hl
-        map.insert(0xf9, Opcode::new("LD", TargetReg::SP, TargetReg::R16(2), 1, 2));
+        table[0xf9] = Some(Opcode::new("LD", TargetReg::SP, TargetReg::R16(2), 1, 2));
 
         // NOP
-        map.insert(0x00, Opcode::new("NOP", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x00] = Some(Opcode::new("NOP", TargetReg::None, TargetReg::None, 1, 1));
 
         // or a, r8
-        map.insert(0xb0, Opcode::new("OR", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0xb1, Opcode::new("OR", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0xb2, Opcode::new("OR", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0xb3, Opcode::new("OR", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0xb4, Opcode::new("OR", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0xb5, Opcode::new("OR", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0xb6, Opcode::new("OR", TargetReg::A, TargetReg::R8(6), 1, 2)); // or a, [hl]
-        map.insert(0xb7, Opcode::new("OR", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0xb0] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0xb1] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0xb2] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0xb3] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0xb4] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0xb5] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0xb6] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(6), 1, 2)); // or a, [hl]
+        table[0xb7] = Some(Opcode::new("OR", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // or a, n8
-        map.insert(0xf6, Opcode::new("OR", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xf6] = Some(Opcode::new("OR", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // pop r16stk
-        map.insert(0xc1, Opcode::new("POP", TargetReg::R16stk(0), TargetReg::None, 1, 3));
-        map.insert(0xd1, Opcode::new("POP", TargetReg::R16stk(1), TargetReg::None, 1, 3));
-        map.insert(0xe1, Opcode::new("POP", TargetReg::R16stk(2), TargetReg::None, 1, 3));
-        map.insert(0xf1, Opcode::new("POP", TargetReg::R16stk(3), TargetReg::None, 1, 3));
+        table[0xc1] = Some(Opcode::new("POP", TargetReg::R16stk(0), TargetReg::None, 1, 3));
+        table[0xd1] = Some(Opcode::new("POP", TargetReg::R16stk(1), TargetReg::None, 1, 3));
+        table[0xe1] = Some(Opcode::new("POP", TargetReg::R16stk(2), TargetReg::None, 1, 3));
+        table[0xf1] = Some(Opcode::new("POP", TargetReg::R16stk(3), TargetReg::None, 1, 3));
 
         // push r16stk
-        map.insert(0xc5, Opcode::new("PUSH", TargetReg::R16stk(0), TargetReg::None, 1, 4));
-        map.insert(0xd5, Opcode::new("PUSH", TargetReg::R16stk(1), TargetReg::None, 1, 4));
-        map.insert(0xe5, Opcode::new("PUSH", TargetReg::R16stk(2), TargetReg::None, 1, 4));
-        map.insert(0xf5, Opcode::new("PUSH", TargetReg::R16stk(3), TargetReg::None, 1, 4));
+        table[0xc5] = Some(Opcode::new("PUSH", TargetReg::R16stk(0), TargetReg::None, 1, 4));
+        table[0xd5] = Some(Opcode::new("PUSH", TargetReg::R16stk(1), TargetReg::None, 1, 4));
+        table[0xe5] = Some(Opcode::new("PUSH", TargetReg::R16stk(2), TargetReg::None, 1, 4));
+        table[0xf5] = Some(Opcode::new("PUSH", TargetReg::R16stk(3), TargetReg::None, 1, 4));
 
         // ret
-        map.insert(0xc9, Opcode::new("RET", TargetReg::None, TargetReg::None, 1, 4));
+        table[0xc9] = Some(Opcode::new("RET", TargetReg::None, TargetReg::None, 1, 4));
 
         // ret cc
-        map.insert(0xc0, Opcode::new("RET", TargetReg::Cond(0), TargetReg::None, 1, 2));
-        map.insert(0xc8, Opcode::new("RET", TargetReg::Cond(1), TargetReg::None, 1, 2));
-        map.insert(0xd0, Opcode::new("RET", TargetReg::Cond(2), TargetReg::None, 1, 2));
-        map.insert(0xd8, Opcode::new("RET", TargetReg::Cond(3), TargetReg::None, 1, 2));
+        table[0xc0] = Some(Opcode::new("RET", TargetReg::Cond(0), TargetReg::None, 1, 2));
+        table[0xc8] = Some(Opcode::new("RET", TargetReg::Cond(1), TargetReg::None, 1, 2));
+        table[0xd0] = Some(Opcode::new("RET", TargetReg::Cond(2), TargetReg::None, 1, 2));
+        table[0xd8] = Some(Opcode::new("RET", TargetReg::Cond(3), TargetReg::None, 1, 2));
 
         // reti
-        map.insert(0xd9, Opcode::new("RETI", TargetReg::None, TargetReg::None, 1, 4));
+        table[0xd9] = Some(Opcode::new("RETI", TargetReg::None, TargetReg::None, 1, 4));
 
         // rla
-        map.insert(0x17, Opcode::new("RLA", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x17] = Some(Opcode::new("RLA", TargetReg::None, TargetReg::None, 1, 1));
 
         // rlca
-        map.insert(0x07, Opcode::new("RLCA", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x07] = Some(Opcode::new("RLCA", TargetReg::None, TargetReg::None, 1, 1));
 
         // rra
-        map.insert(0x1f, Opcode::new("RRA", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x1f] = Some(Opcode::new("RRA", TargetReg::None, TargetReg::None, 1, 1));
 
         // rrca
-        map.insert(0x0f, Opcode::new("RRCA", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x0f] = Some(Opcode::new("RRCA", TargetReg::None, TargetReg::None, 1, 1));
 
         // rst tgt3
-        map.insert(0xc7, Opcode::new("RST", TargetReg::Tgt3(0), TargetReg::None, 1, 4));
-        map.insert(0xcf, Opcode::new("RST", TargetReg::Tgt3(1), TargetReg::None, 1, 4));
-        map.insert(0xd7, Opcode::new("RST", TargetReg::Tgt3(2), TargetReg::None, 1, 4));
-        map.insert(0xdf, Opcode::new("RST", TargetReg::Tgt3(3), TargetReg::None, 1, 4));
-        map.insert(0xe7, Opcode::new("RST", TargetReg::Tgt3(4), TargetReg::None, 1, 4));
-        map.insert(0xef, Opcode::new("RST", TargetReg::Tgt3(5), TargetReg::None, 1, 4));
-        map.insert(0xf7, Opcode::new("RST", TargetReg::Tgt3(6), TargetReg::None, 1, 4));
-        map.insert(0xff, Opcode::new("RST", TargetReg::Tgt3(7), TargetReg::None, 1, 4));
+        table[0xc7] = Some(Opcode::new("RST", TargetReg::Tgt3(0), TargetReg::None, 1, 4));
+        table[0xcf] = Some(Opcode::new("RST", TargetReg::Tgt3(1), TargetReg::None, 1, 4));
+        table[0xd7] = Some(Opcode::new("RST", TargetReg::Tgt3(2), TargetReg::None, 1, 4));
+        table[0xdf] = Some(Opcode::new("RST", TargetReg::Tgt3(3), TargetReg::None, 1, 4));
+        table[0xe7] = Some(Opcode::new("RST", TargetReg::Tgt3(4), TargetReg::None, 1, 4));
+        table[0xef] = Some(Opcode::new("RST", TargetReg::Tgt3(5), TargetReg::None, 1, 4));
+        table[0xf7] = Some(Opcode::new("RST", TargetReg::Tgt3(6), TargetReg::None, 1, 4));
+        table[0xff] = Some(Opcode::new("RST", TargetReg::Tgt3(7), TargetReg::None, 1, 4));
 
         // sbc a, r8
-        map.insert(0x98, Opcode::new("SBC", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0x99, Opcode::new("SBC", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0x9a, Opcode::new("SBC", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0x9b, Opcode::new("SBC", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0x9c, Opcode::new("SBC", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0x9d, Opcode::new("SBC", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0x9e, Opcode::new("SBC", TargetReg::A, TargetReg::R8(6), 1, 2)); // sbc a, [hl]
-        map.insert(0x9f, Opcode::new("SBC", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0x98] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0x99] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0x9a] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0x9b] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0x9c] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0x9d] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0x9e] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(6), 1, 2)); // sbc a, [hl]
+        table[0x9f] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // sbc a, imm8
-        map.insert(0xde, Opcode::new("SBC", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xde] = Some(Opcode::new("SBC", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // scf
-        map.insert(0x37, Opcode::new("SCF", TargetReg::None, TargetReg::None, 1, 1));
+        table[0x37] = Some(Opcode::new("SCF", TargetReg::None, TargetReg::None, 1, 1));
 
         // stop
-        map.insert(0x10, Opcode::new("STOP", TargetReg::None, TargetReg::None, 2, 0));
+        table[0x10] = Some(Opcode::new("STOP", TargetReg::None, TargetReg::None, 2, 1));
 
         // sub a, r8
-        map.insert(0x90, Opcode::new("SUB", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0x91, Opcode::new("SUB", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0x92, Opcode::new("SUB", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0x93, Opcode::new("SUB", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0x94, Opcode::new("SUB", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0x95, Opcode::new("SUB", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0x96, Opcode::new("SUB", TargetReg::A, TargetReg::R8(6), 1, 2)); // sub a, [hl]
-        map.insert(0x97, Opcode::new("SUB", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0x90] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0x91] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0x92] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0x93] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0x94] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0x95] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0x96] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(6), 1, 2)); // sub a, [hl]
+        table[0x97] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // sub a, imm8
-        map.insert(0xd6, Opcode::new("SUB", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xd6] = Some(Opcode::new("SUB", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // xor a, r8
-        map.insert(0xa8, Opcode::new("XOR", TargetReg::A, TargetReg::R8(0), 1, 1));
-        map.insert(0xa9, Opcode::new("XOR", TargetReg::A, TargetReg::R8(1), 1, 1));
-        map.insert(0xaa, Opcode::new("XOR", TargetReg::A, TargetReg::R8(2), 1, 1));
-        map.insert(0xab, Opcode::new("XOR", TargetReg::A, TargetReg::R8(3), 1, 1));
-        map.insert(0xac, Opcode::new("XOR", TargetReg::A, TargetReg::R8(4), 1, 1));
-        map.insert(0xad, Opcode::new("XOR", TargetReg::A, TargetReg::R8(5), 1, 1));
-        map.insert(0xae, Opcode::new("XOR", TargetReg::A, TargetReg::R8(6), 1, 2)); // xor a, [hl]
-        map.insert(0xaf, Opcode::new("XOR", TargetReg::A, TargetReg::R8(7), 1, 1));
+        table[0xa8] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(0), 1, 1));
+        table[0xa9] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(1), 1, 1));
+        table[0xaa] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(2), 1, 1));
+        table[0xab] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(3), 1, 1));
+        table[0xac] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(4), 1, 1));
+        table[0xad] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(5), 1, 1));
+        table[0xae] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(6), 1, 2)); // xor a, [hl]
+        table[0xaf] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::R8(7), 1, 1));
 
         // xor a, n8
-        map.insert(0xee, Opcode::new("XOR", TargetReg::A, TargetReg::Imm8, 2, 2));
+        table[0xee] = Some(Opcode::new("XOR", TargetReg::A, TargetReg::Imm8, 2, 2));
 
         // Prefix
-        map.insert(0xcb, Opcode::new("CB", TargetReg::None, TargetReg::None, 0, 0));
+        table[0xcb] = Some(Opcode::new("CB", TargetReg::None, TargetReg::None, 0, 0));
 
-        map
+        table
     };
 
-    pub static ref CPU_PREFIXED_OP_CODES: HashMap<u8, Opcode> = {
-        let mut map = HashMap::new();
+    pub static ref CPU_PREFIXED_OP_CODES: [Option<Opcode>; 256] = {
+        let mut table: [Option<Opcode>; 256] = std::array::from_fn(|_| None);
 
         // bit b3, r8
-        map.insert(0x40, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(0), 2, 2));
-        map.insert(0x41, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(1), 2, 2));
-        map.insert(0x42, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(2), 2, 2));
-        map.insert(0x43, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(3), 2, 2));
-        map.insert(0x44, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(4), 2, 2));
-        map.insert(0x45, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(5), 2, 2));
-        map.insert(0x46, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x47, Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x48, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(0), 2, 2));
-        map.insert(0x49, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(1), 2, 2));
-        map.insert(0x4a, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(2), 2, 2));
-        map.insert(0x4b, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(3), 2, 2));
-        map.insert(0x4c, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(4), 2, 2));
-        map.insert(0x4d, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(5), 2, 2));
-        map.insert(0x4e, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x4f, Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x50, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(0), 2, 2));
-        map.insert(0x51, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(1), 2, 2));
-        map.insert(0x52, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(2), 2, 2));
-        map.insert(0x53, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(3), 2, 2));
-        map.insert(0x54, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(4), 2, 2));
-        map.insert(0x55, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(5), 2, 2));
-        map.insert(0x56, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x57, Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x58, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(0), 2, 2));
-        map.insert(0x59, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(1), 2, 2));
-        map.insert(0x5a, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(2), 2, 2));
-        map.insert(0x5b, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(3), 2, 2));
-        map.insert(0x5c, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(4), 2, 2));
-        map.insert(0x5d, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(5), 2, 2));
-        map.insert(0x5e, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x5f, Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x60, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(0), 2, 2));
-        map.insert(0x61, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(1), 2, 2));
-        map.insert(0x62, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(2), 2, 2));
-        map.insert(0x63, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(3), 2, 2));
-        map.insert(0x64, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(4), 2, 2));
-        map.insert(0x65, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(5), 2, 2));
-        map.insert(0x66, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x67, Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x68, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(0), 2, 2));
-        map.insert(0x69, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(1), 2, 2));
-        map.insert(0x6a, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(2), 2, 2));
-        map.insert(0x6b, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(3), 2, 2));
-        map.insert(0x6c, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(4), 2, 2));
-        map.insert(0x6d, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(5), 2, 2));
-        map.insert(0x6e, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x6f, Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x70, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(0), 2, 2));
-        map.insert(0x71, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(1), 2, 2));
-        map.insert(0x72, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(2), 2, 2));
-        map.insert(0x73, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(3), 2, 2));
-        map.insert(0x74, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(4), 2, 2));
-        map.insert(0x75, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(5), 2, 2));
-        map.insert(0x76, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x77, Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x78, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(0), 2, 2));
-        map.insert(0x79, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(1), 2, 2));
-        map.insert(0x7a, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(2), 2, 2));
-        map.insert(0x7b, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(3), 2, 2));
-        map.insert(0x7c, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(4), 2, 2));
-        map.insert(0x7d, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(5), 2, 2));
-        map.insert(0x7e, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
-        map.insert(0x7f, Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(7), 2, 2));
+        table[0x40] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(0), 2, 2));
+        table[0x41] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(1), 2, 2));
+        table[0x42] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(2), 2, 2));
+        table[0x43] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(3), 2, 2));
+        table[0x44] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(4), 2, 2));
+        table[0x45] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(5), 2, 2));
+        table[0x46] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x47] = Some(Opcode::new("BIT", TargetReg::B3(0), TargetReg::R8(7), 2, 2));
+
+        table[0x48] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(0), 2, 2));
+        table[0x49] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(1), 2, 2));
+        table[0x4a] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(2), 2, 2));
+        table[0x4b] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(3), 2, 2));
+        table[0x4c] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(4), 2, 2));
+        table[0x4d] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(5), 2, 2));
+        table[0x4e] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x4f] = Some(Opcode::new("BIT", TargetReg::B3(1), TargetReg::R8(7), 2, 2));
+
+        table[0x50] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(0), 2, 2));
+        table[0x51] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(1), 2, 2));
+        table[0x52] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(2), 2, 2));
+        table[0x53] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(3), 2, 2));
+        table[0x54] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(4), 2, 2));
+        table[0x55] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(5), 2, 2));
+        table[0x56] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x57] = Some(Opcode::new("BIT", TargetReg::B3(2), TargetReg::R8(7), 2, 2));
+
+        table[0x58] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(0), 2, 2));
+        table[0x59] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(1), 2, 2));
+        table[0x5a] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(2), 2, 2));
+        table[0x5b] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(3), 2, 2));
+        table[0x5c] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(4), 2, 2));
+        table[0x5d] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(5), 2, 2));
+        table[0x5e] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x5f] = Some(Opcode::new("BIT", TargetReg::B3(3), TargetReg::R8(7), 2, 2));
+
+        table[0x60] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(0), 2, 2));
+        table[0x61] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(1), 2, 2));
+        table[0x62] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(2), 2, 2));
+        table[0x63] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(3), 2, 2));
+        table[0x64] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(4), 2, 2));
+        table[0x65] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(5), 2, 2));
+        table[0x66] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x67] = Some(Opcode::new("BIT", TargetReg::B3(4), TargetReg::R8(7), 2, 2));
+
+        table[0x68] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(0), 2, 2));
+        table[0x69] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(1), 2, 2));
+        table[0x6a] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(2), 2, 2));
+        table[0x6b] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(3), 2, 2));
+        table[0x6c] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(4), 2, 2));
+        table[0x6d] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(5), 2, 2));
+        table[0x6e] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x6f] = Some(Opcode::new("BIT", TargetReg::B3(5), TargetReg::R8(7), 2, 2));
+
+        table[0x70] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(0), 2, 2));
+        table[0x71] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(1), 2, 2));
+        table[0x72] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(2), 2, 2));
+        table[0x73] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(3), 2, 2));
+        table[0x74] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(4), 2, 2));
+        table[0x75] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(5), 2, 2));
+        table[0x76] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x77] = Some(Opcode::new("BIT", TargetReg::B3(6), TargetReg::R8(7), 2, 2));
+
+        table[0x78] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(0), 2, 2));
+        table[0x79] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(1), 2, 2));
+        table[0x7a] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(2), 2, 2));
+        table[0x7b] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(3), 2, 2));
+        table[0x7c] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(4), 2, 2));
+        table[0x7d] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(5), 2, 2));
+        table[0x7e] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(6), 2, 3)); // bit u3, [hl]
+        table[0x7f] = Some(Opcode::new("BIT", TargetReg::B3(7), TargetReg::R8(7), 2, 2));
 
         // res u3, r8
-        map.insert(0x80, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(0), 2, 2));
-        map.insert(0x81, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(1), 2, 2));
-        map.insert(0x82, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(2), 2, 2));
-        map.insert(0x83, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(3), 2, 2));
-        map.insert(0x84, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(4), 2, 2));
-        map.insert(0x85, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(5), 2, 2));
-        map.insert(0x86, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0x87, Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x88, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(0), 2, 2));
-        map.insert(0x89, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(1), 2, 2));
-        map.insert(0x8a, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(2), 2, 2));
-        map.insert(0x8b, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(3), 2, 2));
-        map.insert(0x8c, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(4), 2, 2));
-        map.insert(0x8d, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(5), 2, 2));
-        map.insert(0x8e, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0x8f, Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x90, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(0), 2, 2));
-        map.insert(0x91, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(1), 2, 2));
-        map.insert(0x92, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(2), 2, 2));
-        map.insert(0x93, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(3), 2, 2));
-        map.insert(0x94, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(4), 2, 2));
-        map.insert(0x95, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(5), 2, 2));
-        map.insert(0x96, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0x97, Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(7), 2, 2));
-
-        map.insert(0x98, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(0), 2, 2));
-        map.insert(0x99, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(1), 2, 2));
-        map.insert(0x9a, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(2), 2, 2));
-        map.insert(0x9b, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(3), 2, 2));
-        map.insert(0x9c, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(4), 2, 2));
-        map.insert(0x9d, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(5), 2, 2));
-        map.insert(0x9e, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0x9f, Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xa0, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(0), 2, 2));
-        map.insert(0xa1, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(1), 2, 2));
-        map.insert(0xa2, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(2), 2, 2));
-        map.insert(0xa3, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(3), 2, 2));
-        map.insert(0xa4, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(4), 2, 2));
-        map.insert(0xa5, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(5), 2, 2));
-        map.insert(0xa6, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0xa7, Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xa8, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(0), 2, 2));
-        map.insert(0xa9, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(1), 2, 2));
-        map.insert(0xaa, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(2), 2, 2));
-        map.insert(0xab, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(3), 2, 2));
-        map.insert(0xac, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(4), 2, 2));
-        map.insert(0xad, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(5), 2, 2));
-        map.insert(0xae, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0xaf, Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xb0, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(0), 2, 2));
-        map.insert(0xb1, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(1), 2, 2));
-        map.insert(0xb2, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(2), 2, 2));
-        map.insert(0xb3, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(3), 2, 2));
-        map.insert(0xb4, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(4), 2, 2));
-        map.insert(0xb5, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(5), 2, 2));
-        map.insert(0xb6, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0xb7, Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xb8, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(0), 2, 2));
-        map.insert(0xb9, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(1), 2, 2));
-        map.insert(0xba, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(2), 2, 2));
-        map.insert(0xbb, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(3), 2, 2));
-        map.insert(0xbc, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(4), 2, 2));
-        map.insert(0xbd, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(5), 2, 2));
-        map.insert(0xbe, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(6), 2, 4)); // res u3, [hl]
-        map.insert(0xbf, Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(7), 2, 2));
+        table[0x80] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(0), 2, 2));
+        table[0x81] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(1), 2, 2));
+        table[0x82] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(2), 2, 2));
+        table[0x83] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(3), 2, 2));
+        table[0x84] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(4), 2, 2));
+        table[0x85] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(5), 2, 2));
+        table[0x86] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0x87] = Some(Opcode::new("RES", TargetReg::B3(0), TargetReg::R8(7), 2, 2));
+
+        table[0x88] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(0), 2, 2));
+        table[0x89] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(1), 2, 2));
+        table[0x8a] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(2), 2, 2));
+        table[0x8b] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(3), 2, 2));
+        table[0x8c] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(4), 2, 2));
+        table[0x8d] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(5), 2, 2));
+        table[0x8e] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0x8f] = Some(Opcode::new("RES", TargetReg::B3(1), TargetReg::R8(7), 2, 2));
+
+        table[0x90] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(0), 2, 2));
+        table[0x91] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(1), 2, 2));
+        table[0x92] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(2), 2, 2));
+        table[0x93] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(3), 2, 2));
+        table[0x94] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(4), 2, 2));
+        table[0x95] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(5), 2, 2));
+        table[0x96] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0x97] = Some(Opcode::new("RES", TargetReg::B3(2), TargetReg::R8(7), 2, 2));
+
+        table[0x98] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(0), 2, 2));
+        table[0x99] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(1), 2, 2));
+        table[0x9a] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(2), 2, 2));
+        table[0x9b] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(3), 2, 2));
+        table[0x9c] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(4), 2, 2));
+        table[0x9d] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(5), 2, 2));
+        table[0x9e] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0x9f] = Some(Opcode::new("RES", TargetReg::B3(3), TargetReg::R8(7), 2, 2));
+
+        table[0xa0] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(0), 2, 2));
+        table[0xa1] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(1), 2, 2));
+        table[0xa2] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(2), 2, 2));
+        table[0xa3] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(3), 2, 2));
+        table[0xa4] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(4), 2, 2));
+        table[0xa5] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(5), 2, 2));
+        table[0xa6] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0xa7] = Some(Opcode::new("RES", TargetReg::B3(4), TargetReg::R8(7), 2, 2));
+
+        table[0xa8] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(0), 2, 2));
+        table[0xa9] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(1), 2, 2));
+        table[0xaa] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(2), 2, 2));
+        table[0xab] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(3), 2, 2));
+        table[0xac] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(4), 2, 2));
+        table[0xad] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(5), 2, 2));
+        table[0xae] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0xaf] = Some(Opcode::new("RES", TargetReg::B3(5), TargetReg::R8(7), 2, 2));
+
+        table[0xb0] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(0), 2, 2));
+        table[0xb1] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(1), 2, 2));
+        table[0xb2] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(2), 2, 2));
+        table[0xb3] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(3), 2, 2));
+        table[0xb4] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(4), 2, 2));
+        table[0xb5] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(5), 2, 2));
+        table[0xb6] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0xb7] = Some(Opcode::new("RES", TargetReg::B3(6), TargetReg::R8(7), 2, 2));
+
+        table[0xb8] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(0), 2, 2));
+        table[0xb9] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(1), 2, 2));
+        table[0xba] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(2), 2, 2));
+        table[0xbb] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(3), 2, 2));
+        table[0xbc] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(4), 2, 2));
+        table[0xbd] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(5), 2, 2));
+        table[0xbe] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(6), 2, 4)); // res u3, [hl]
+        table[0xbf] = Some(Opcode::new("RES", TargetReg::B3(7), TargetReg::R8(7), 2, 2));
 
         // rl r8
-        map.insert(0x10, Opcode::new("RL", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x11, Opcode::new("RL", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x12, Opcode::new("RL", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x13, Opcode::new("RL", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x14, Opcode::new("RL", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x15, Opcode::new("RL", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x16, Opcode::new("RL", TargetReg::R8(6), TargetReg::None, 2, 4)); // rr [hl]
-        map.insert(0x17, Opcode::new("RL", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x10] = Some(Opcode::new("RL", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x11] = Some(Opcode::new("RL", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x12] = Some(Opcode::new("RL", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x13] = Some(Opcode::new("RL", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x14] = Some(Opcode::new("RL", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x15] = Some(Opcode::new("RL", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x16] = Some(Opcode::new("RL", TargetReg::R8(6), TargetReg::None, 2, 4)); // rr [hl]
+        table[0x17] = Some(Opcode::new("RL", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // rlc r8
-        map.insert(0x00, Opcode::new("RLC", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x01, Opcode::new("RLC", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x02, Opcode::new("RLC", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x03, Opcode::new("RLC", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x04, Opcode::new("RLC", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x05, Opcode::new("RLC", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x06, Opcode::new("RLC", TargetReg::R8(6), TargetReg::None, 2, 4)); // rlc [hl]
-        map.insert(0x07, Opcode::new("RLC", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x00] = Some(Opcode::new("RLC", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x01] = Some(Opcode::new("RLC", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x02] = Some(Opcode::new("RLC", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x03] = Some(Opcode::new("RLC", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x04] = Some(Opcode::new("RLC", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x05] = Some(Opcode::new("RLC", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x06] = Some(Opcode::new("RLC", TargetReg::R8(6), TargetReg::None, 2, 4)); // rlc [hl]
+        table[0x07] = Some(Opcode::new("RLC", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // rr r8
-        map.insert(0x18, Opcode::new("RR", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x19, Opcode::new("RR", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x1a, Opcode::new("RR", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x1b, Opcode::new("RR", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x1c, Opcode::new("RR", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x1d, Opcode::new("RR", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x1e, Opcode::new("RR", TargetReg::R8(6), TargetReg::None, 2, 4)); // rr [hl]
-        map.insert(0x1f, Opcode::new("RR", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x18] = Some(Opcode::new("RR", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x19] = Some(Opcode::new("RR", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x1a] = Some(Opcode::new("RR", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x1b] = Some(Opcode::new("RR", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x1c] = Some(Opcode::new("RR", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x1d] = Some(Opcode::new("RR", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x1e] = Some(Opcode::new("RR", TargetReg::R8(6), TargetReg::None, 2, 4)); // rr [hl]
+        table[0x1f] = Some(Opcode::new("RR", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // rrc r8
-        map.insert(0x08, Opcode::new("RRC", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x09, Opcode::new("RRC", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x0a, Opcode::new("RRC", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x0b, Opcode::new("RRC", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x0c, Opcode::new("RRC", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x0d, Opcode::new("RRC", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x0e, Opcode::new("RRC", TargetReg::R8(6), TargetReg::None, 2, 4)); // rrc [hl]
-        map.insert(0x0f, Opcode::new("RRC", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x08] = Some(Opcode::new("RRC", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x09] = Some(Opcode::new("RRC", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x0a] = Some(Opcode::new("RRC", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x0b] = Some(Opcode::new("RRC", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x0c] = Some(Opcode::new("RRC", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x0d] = Some(Opcode::new("RRC", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x0e] = Some(Opcode::new("RRC", TargetReg::R8(6), TargetReg::None, 2, 4)); // rrc [hl]
+        table[0x0f] = Some(Opcode::new("RRC", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // set b3, r8
-        map.insert(0xc0, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(0), 2, 2));
-        map.insert(0xc1, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(1), 2, 2));
-        map.insert(0xc2, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(2), 2, 2));
-        map.insert(0xc3, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(3), 2, 2));
-        map.insert(0xc4, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(4), 2, 2));
-        map.insert(0xc5, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(5), 2, 2));
-        map.insert(0xc6, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xc7, Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xc8, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(0), 2, 2));
-        map.insert(0xc9, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(1), 2, 2));
-        map.insert(0xca, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(2), 2, 2));
-        map.insert(0xcb, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(3), 2, 2));
-        map.insert(0xcc, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(4), 2, 2));
-        map.insert(0xcd, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(5), 2, 2));
-        map.insert(0xce, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xcf, Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xd0, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(0), 2, 2));
-        map.insert(0xd1, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(1), 2, 2));
-        map.insert(0xd2, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(2), 2, 2));
-        map.insert(0xd3, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(3), 2, 2));
-        map.insert(0xd4, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(4), 2, 2));
-        map.insert(0xd5, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(5), 2, 2));
-        map.insert(0xd6, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xd7, Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xd8, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(0), 2, 2));
-        map.insert(0xd9, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(1), 2, 2));
-        map.insert(0xda, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(2), 2, 2));
-        map.insert(0xdb, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(3), 2, 2));
-        map.insert(0xdc, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(4), 2, 2));
-        map.insert(0xdd, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(5), 2, 2));
-        map.insert(0xde, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xdf, Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xe0, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(0), 2, 2));
-        map.insert(0xe1, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(1), 2, 2));
-        map.insert(0xe2, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(2), 2, 2));
-        map.insert(0xe3, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(3), 2, 2));
-        map.insert(0xe4, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(4), 2, 2));
-        map.insert(0xe5, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(5), 2, 2));
-        map.insert(0xe6, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xe7, Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xe8, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(0), 2, 2));
-        map.insert(0xe9, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(1), 2, 2));
-        map.insert(0xea, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(2), 2, 2));
-        map.insert(0xeb, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(3), 2, 2));
-        map.insert(0xec, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(4), 2, 2));
-        map.insert(0xed, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(5), 2, 2));
-        map.insert(0xee, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xef, Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xf0, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(0), 2, 2));
-        map.insert(0xf1, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(1), 2, 2));
-        map.insert(0xf2, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(2), 2, 2));
-        map.insert(0xf3, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(3), 2, 2));
-        map.insert(0xf4, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(4), 2, 2));
-        map.insert(0xf5, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(5), 2, 2));
-        map.insert(0xf6, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xf7, Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(7), 2, 2));
-
-        map.insert(0xf8, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(0), 2, 2));
-        map.insert(0xf9, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(1), 2, 2));
-        map.insert(0xfa, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(2), 2, 2));
-        map.insert(0xfb, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(3), 2, 2));
-        map.insert(0xfc, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(4), 2, 2));
-        map.insert(0xfd, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(5), 2, 2));
-        map.insert(0xfe, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(6), 2, 4)); // set b3, [hl]
-        map.insert(0xff, Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(7), 2, 2));
+        table[0xc0] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(0), 2, 2));
+        table[0xc1] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(1), 2, 2));
+        table[0xc2] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(2), 2, 2));
+        table[0xc3] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(3), 2, 2));
+        table[0xc4] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(4), 2, 2));
+        table[0xc5] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(5), 2, 2));
+        table[0xc6] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xc7] = Some(Opcode::new("SET", TargetReg::B3(0), TargetReg::R8(7), 2, 2));
+
+        table[0xc8] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(0), 2, 2));
+        table[0xc9] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(1), 2, 2));
+        table[0xca] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(2), 2, 2));
+        table[0xcb] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(3), 2, 2));
+        table[0xcc] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(4), 2, 2));
+        table[0xcd] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(5), 2, 2));
+        table[0xce] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xcf] = Some(Opcode::new("SET", TargetReg::B3(1), TargetReg::R8(7), 2, 2));
+
+        table[0xd0] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(0), 2, 2));
+        table[0xd1] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(1), 2, 2));
+        table[0xd2] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(2), 2, 2));
+        table[0xd3] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(3), 2, 2));
+        table[0xd4] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(4), 2, 2));
+        table[0xd5] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(5), 2, 2));
+        table[0xd6] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xd7] = Some(Opcode::new("SET", TargetReg::B3(2), TargetReg::R8(7), 2, 2));
+
+        table[0xd8] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(0), 2, 2));
+        table[0xd9] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(1), 2, 2));
+        table[0xda] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(2), 2, 2));
+        table[0xdb] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(3), 2, 2));
+        table[0xdc] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(4), 2, 2));
+        table[0xdd] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(5), 2, 2));
+        table[0xde] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xdf] = Some(Opcode::new("SET", TargetReg::B3(3), TargetReg::R8(7), 2, 2));
+
+        table[0xe0] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(0), 2, 2));
+        table[0xe1] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(1), 2, 2));
+        table[0xe2] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(2), 2, 2));
+        table[0xe3] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(3), 2, 2));
+        table[0xe4] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(4), 2, 2));
+        table[0xe5] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(5), 2, 2));
+        table[0xe6] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xe7] = Some(Opcode::new("SET", TargetReg::B3(4), TargetReg::R8(7), 2, 2));
+
+        table[0xe8] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(0), 2, 2));
+        table[0xe9] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(1), 2, 2));
+        table[0xea] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(2), 2, 2));
+        table[0xeb] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(3), 2, 2));
+        table[0xec] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(4), 2, 2));
+        table[0xed] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(5), 2, 2));
+        table[0xee] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xef] = Some(Opcode::new("SET", TargetReg::B3(5), TargetReg::R8(7), 2, 2));
+
+        table[0xf0] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(0), 2, 2));
+        table[0xf1] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(1), 2, 2));
+        table[0xf2] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(2), 2, 2));
+        table[0xf3] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(3), 2, 2));
+        table[0xf4] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(4), 2, 2));
+        table[0xf5] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(5), 2, 2));
+        table[0xf6] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xf7] = Some(Opcode::new("SET", TargetReg::B3(6), TargetReg::R8(7), 2, 2));
+
+        table[0xf8] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(0), 2, 2));
+        table[0xf9] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(1), 2, 2));
+        table[0xfa] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(2), 2, 2));
+        table[0xfb] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(3), 2, 2));
+        table[0xfc] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(4), 2, 2));
+        table[0xfd] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(5), 2, 2));
+        table[0xfe] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(6), 2, 4)); // set b3, [hl]
+        table[0xff] = Some(Opcode::new("SET", TargetReg::B3(7), TargetReg::R8(7), 2, 2));
 
         // sla r8
-        map.insert(0x20, Opcode::new("SLA", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x21, Opcode::new("SLA", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x22, Opcode::new("SLA", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x23, Opcode::new("SLA", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x24, Opcode::new("SLA", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x25, Opcode::new("SLA", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x26, Opcode::new("SLA", TargetReg::R8(6), TargetReg::None, 2, 4)); // sla [hl]
-        map.insert(0x27, Opcode::new("SLA", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x20] = Some(Opcode::new("SLA", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x21] = Some(Opcode::new("SLA", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x22] = Some(Opcode::new("SLA", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x23] = Some(Opcode::new("SLA", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x24] = Some(Opcode::new("SLA", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x25] = Some(Opcode::new("SLA", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x26] = Some(Opcode::new("SLA", TargetReg::R8(6), TargetReg::None, 2, 4)); // sla [hl]
+        table[0x27] = Some(Opcode::new("SLA", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // sra r8
-        map.insert(0x28, Opcode::new("SRA", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x29, Opcode::new("SRA", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x2a, Opcode::new("SRA", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x2b, Opcode::new("SRA", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x2c, Opcode::new("SRA", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x2d, Opcode::new("SRA", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x2e, Opcode::new("SRA", TargetReg::R8(6), TargetReg::None, 2, 4)); // sra [hl]
-        map.insert(0x2f, Opcode::new("SRA", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x28] = Some(Opcode::new("SRA", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x29] = Some(Opcode::new("SRA", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x2a] = Some(Opcode::new("SRA", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x2b] = Some(Opcode::new("SRA", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x2c] = Some(Opcode::new("SRA", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x2d] = Some(Opcode::new("SRA", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x2e] = Some(Opcode::new("SRA", TargetReg::R8(6), TargetReg::None, 2, 4)); // sra [hl]
+        table[0x2f] = Some(Opcode::new("SRA", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // srl r8
-        map.insert(0x38, Opcode::new("SRL", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x39, Opcode::new("SRL", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x3a, Opcode::new("SRL", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x3b, Opcode::new("SRL", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x3c, Opcode::new("SRL", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x3d, Opcode::new("SRL", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x3e, Opcode::new("SRL", TargetReg::R8(6), TargetReg::None, 2, 4)); // srl [hl]
-        map.insert(0x3f, Opcode::new("SRL", TargetReg::R8(7), TargetReg::None, 2, 2));
+        table[0x38] = Some(Opcode::new("SRL", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x39] = Some(Opcode::new("SRL", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x3a] = Some(Opcode::new("SRL", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x3b] = Some(Opcode::new("SRL", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x3c] = Some(Opcode::new("SRL", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x3d] = Some(Opcode::new("SRL", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x3e] = Some(Opcode::new("SRL", TargetReg::R8(6), TargetReg::None, 2, 4)); // srl [hl]
+        table[0x3f] = Some(Opcode::new("SRL", TargetReg::R8(7), TargetReg::None, 2, 2));
 
         // swap r8
-        map.insert(0x30, Opcode::new("SWAP", TargetReg::R8(0), TargetReg::None, 2, 2));
-        map.insert(0x31, Opcode::new("SWAP", TargetReg::R8(1), TargetReg::None, 2, 2));
-        map.insert(0x32, Opcode::new("SWAP", TargetReg::R8(2), TargetReg::None, 2, 2));
-        map.insert(0x33, Opcode::new("SWAP", TargetReg::R8(3), TargetReg::None, 2, 2));
-        map.insert(0x34, Opcode::new("SWAP", TargetReg::R8(4), TargetReg::None, 2, 2));
-        map.insert(0x35, Opcode::new("SWAP", TargetReg::R8(5), TargetReg::None, 2, 2));
-        map.insert(0x36, Opcode::new("SWAP", TargetReg::R8(6), TargetReg::None, 2, 4)); // swap [hl]
-        map.insert(0x37, Opcode::new("SWAP", TargetReg::R8(7), TargetReg::None, 2, 2));
-
-        map
+        table[0x30] = Some(Opcode::new("SWAP", TargetReg::R8(0), TargetReg::None, 2, 2));
+        table[0x31] = Some(Opcode::new("SWAP", TargetReg::R8(1), TargetReg::None, 2, 2));
+        table[0x32] = Some(Opcode::new("SWAP", TargetReg::R8(2), TargetReg::None, 2, 2));
+        table[0x33] = Some(Opcode::new("SWAP", TargetReg::R8(3), TargetReg::None, 2, 2));
+        table[0x34] = Some(Opcode::new("SWAP", TargetReg::R8(4), TargetReg::None, 2, 2));
+        table[0x35] = Some(Opcode::new("SWAP", TargetReg::R8(5), TargetReg::None, 2, 2));
+        table[0x36] = Some(Opcode::new("SWAP", TargetReg::R8(6), TargetReg::None, 2, 4)); // swap [hl]
+        table[0x37] = Some(Opcode::new("SWAP", TargetReg::R8(7), TargetReg::None, 2, 2));
+
+        table
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Instruction lengths and M-cycles from Pan Docs, indexed by opcode. Conditional jumps,
+    // calls and returns are the not taken cycles, 0 is unused
+    #[rustfmt::skip]
+    const LENGTHS: [u16; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 1, 3, 3, 2, 1,
+        1, 1, 3, 0, 3, 1, 2, 1, 1, 1, 3, 0, 3, 0, 2, 1,
+        2, 1, 1, 0, 0, 1, 2, 1, 2, 1, 3, 0, 0, 0, 2, 1,
+        2, 1, 1, 1, 0, 1, 2, 1, 2, 1, 3, 1, 0, 0, 2, 1,
+    ];
+
+    #[rustfmt::skip]
+    const CYCLES: [u8; 256] = [
+        1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+        1, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 2, 2, 2, 2, 2, 1, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+        2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 1, 3, 6, 2, 4,
+        2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+        3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+        3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+    ];
+
+    // CB prefixed cycles include the prefix. (HL) operands take 4, or 3 for BIT which only reads
+    const PREFIXED_CYCLES: [u8; 256] = {
+        let mut cycles = [2; 256];
+        let mut op = 0;
+        while op < 256 {
+            if op % 8 == 6 {
+                cycles[op] = if op >= 0x40 && op < 0x80 { 3 } else { 4 };
+            }
+            op += 1;
+        }
+        cycles
     };
+
+    // Panics listing every opcode whose bytes or cycles disagree with the reference tables.
+    // HALT keeps PC on itself until woken and the CB prefix is counted in the prefixed opcode,
+    // so both are left out
+    fn check_table(
+        table: &[Option<Opcode>; 256],
+        lengths: &[u16; 256],
+        cycles: &[u8; 256],
+        prefix: &str,
+    ) {
+        let mut errors = Vec::new();
+        for (byte, opcode) in table.iter().enumerate() {
+            let (len, cyc) = (lengths[byte], cycles[byte]);
+            match opcode {
+                _ if prefix.is_empty() && (byte == 0x76 || byte == 0xcb) => {}
+                Some(op) if op.bytes != len || op.cycles != cyc => errors.push(format!(
+                    "{prefix}{byte:02X} {}: {} bytes {} cycles, expected {len} bytes {cyc} cycles",
+                    op.name, op.bytes, op.cycles
+                )),
+                Some(op) if len == 0 => {
+                    errors.push(format!("{prefix}{byte:02X} {} is unused", op.name))
+                }
+                None if len != 0 => errors.push(format!("{prefix}{byte:02X} missing")),
+                _ => {}
+            }
+        }
+        assert!(
+            errors.is_empty(),
+            "Opcode table errors:\n{}",
+            errors.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_table_matches_pan_docs() {
+        check_table(&CPU_OP_CODES, &LENGTHS, &CYCLES, "");
+    }
+
+    #[test]
+    fn test_prefixed_table_matches_pan_docs() {
+        check_table(&CPU_PREFIXED_OP_CODES, &[2; 256], &PREFIXED_CYCLES, "CB ");
+    }
 }
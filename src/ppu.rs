@@ -1,6 +1,8 @@
-use crate::egui::Color32;
 use bitflags::bitflags;
 
+use crate::render::{Layers, Palette};
+use crate::state::{State, StateError, StateReader, StateWriter};
+
 // 0xFF40
 bitflags! {
     #[derive(PartialEq, Debug, Clone)]
@@ -54,13 +56,137 @@ enum Mode {
 #[derive(Debug)]
 pub enum DisplayStatus {
     DoNothing,
+    HBlank,
     OAMScan,
     NewScanline, // Changed from
     NewFrame,
 }
 
+// VRAM is 0x8000 - 0x9FFF. CGB has a second bank selected through VBK (0xFF4F)
+pub struct Vram {
+    pub banks: [[u8; 0x2000]; 2],
+    pub bank: usize, // Bank the CPU currently sees
+}
+
+impl State for Vram {
+    fn save(&self, w: &mut StateWriter) {
+        self.banks.save(w);
+        self.bank.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.banks.load(r)?;
+        self.bank.load(r)?;
+        Ok(())
+    }
+}
+
+impl Vram {
+    pub fn new() -> Self {
+        Self {
+            banks: [[0; 0x2000]; 2],
+            bank: 0,
+        }
+    }
+
+    // Read from a specific bank regardless of VBK. Used by the renderer
+    pub fn read(&self, bank: usize, addr: u16) -> u8 {
+        let mirrored_addr = addr - 0x8000;
+        assert!(mirrored_addr < 0x2000);
+        self.banks[bank][mirrored_addr as usize]
+    }
+
+    pub fn write(&mut self, bank: usize, addr: u16, val: u8) {
+        let mirrored_addr = addr - 0x8000;
+        assert!(mirrored_addr < 0x2000);
+        self.banks[bank][mirrored_addr as usize] = val;
+    }
+
+    // VBK: only bit 0 is used, the rest read back as 1
+    pub fn bank_read(&self) -> u8 {
+        0xfe | self.bank as u8
+    }
+
+    pub fn bank_write(&mut self, val: u8) {
+        self.bank = (val & 0x01) as usize;
+    }
+}
+
+// CGB palette RAM. 8 palettes of 4 colors, each color is 2 bytes of little endian RGB555.
+// Accessed through a specification register (BCPS/OCPS) holding the index and auto increment
+// bit, and a data register (BCPD/OCPD) that reads/writes the byte at that index
+pub struct ColorPalettes {
+    pub data: [u8; 64],
+    pub index: u8,
+    pub auto_increment: bool,
+}
+
+impl State for ColorPalettes {
+    fn save(&self, w: &mut StateWriter) {
+        self.data.save(w);
+        self.index.save(w);
+        self.auto_increment.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.data.load(r)?;
+        self.index.load(r)?;
+        self.auto_increment.load(r)?;
+        Ok(())
+    }
+}
+
+impl ColorPalettes {
+    pub fn new() -> Self {
+        Self {
+            data: [0xff; 64], // White until the game writes its palettes
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn spec_read(&self) -> u8 {
+        ((self.auto_increment as u8) << 7) | 0x40 | self.index
+    }
+
+    pub fn spec_write(&mut self, val: u8) {
+        self.index = val & 0x3f;
+        self.auto_increment = val & 0x80 > 0;
+    }
+
+    pub fn data_read(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    // Writes advance the index when auto increment is set, even if the PPU has the
+    // palettes locked and the write itself is dropped. Reads never do
+    pub fn data_write(&mut self, val: u8, locked: bool) {
+        if !locked {
+            self.data[self.index as usize] = val;
+        }
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    // Color color_id (0-3) of palette (0-7) as 8 bit RGB
+    pub fn rgb(&self, palette: u8, color_id: u8) -> (u8, u8, u8) {
+        let i = 8 * palette as usize + 2 * color_id as usize;
+        let color = u16::from_le_bytes([self.data[i], self.data[i + 1]]);
+        let r = (color & 0x1f) as u8;
+        let g = ((color >> 5) & 0x1f) as u8;
+        let b = ((color >> 10) & 0x1f) as u8;
+        // Scale 5 bit channels to 8 bits so 0x1f maps to 0xff
+        (
+            (r << 3) | (r >> 2),
+            (g << 3) | (g >> 2),
+            (b << 3) | (b >> 2),
+        )
+    }
+}
+
 pub struct Ppu {
-    pub vram: [u8; 0x2000],
+    pub vram: Vram,
     pub oam: [u8; 0xA0],
 
     pub control: Control,
@@ -70,39 +196,105 @@ pub struct Ppu {
     pub scx: u8,
     pub wy: u8,
     pub wx: u8,
-    pub window_counter: usize,
+    pub window_line_counter: u8, // Window row being drawn. Only advances on lines the window was shown
     pub bg_palette: u8,
     pub obp0: u8,
     pub obp1: u8,
-    pub bcps: u8,
-    pub bcpd: u8,
+    pub bg_palettes: ColorPalettes,  // CGB only
+    pub obj_palettes: ColorPalettes, // CGB only
+    // Colours for the DMG shades. Objects can use their own like on the Super Game Boy.
+    // A display setting, so not saved
+    pub palette: Palette,
+    pub obj_palette: Palette,
+    pub cgb: bool,
     pub cycle: usize,
     pub scanline: u8,
     mode: Mode,
-    pub scanline_oams: Vec<usize>, // hold the up to 10 OAMs on current scanline. Referenced by first byte in four byte sequence
-
-    // GUI
-    pub bg_screen: [Color32; 144 * 160],
-    pub win_screen: [Color32; 144 * 160],
-    pub spr_screen: [Color32; 144 * 160],
-    pub tilemap_one: [Color32; 256 * 256],
-    pub tilemap_two: [Color32; 256 * 256],
-    pub sprites: [Color32; 64 * 40],
+    stat_irq_line: bool, // All enabled STAT sources OR'd together. LCD interrupt fires on its rising edge
+    pub mode3_dots: usize, // Length of mode 3 on this line, worked out when it starts
+    pub scanline_oams: Vec<usize>, // hold the up to 10 OAMs on current scanline in priority order. Referenced by first byte in four byte sequence
+
+    // GUI. Debug views, packed RGB like Frame
+    pub debug_layers: bool, // Draw each layer to layers as well. Off unless the GUI shows them
+    pub layers: Layers,
+    // Boxed to keep the Ppu small enough to build on a test thread's stack
+    pub tilemap_one: Box<[u8; 3 * 256 * 256]>,
+    pub tilemap_two: Box<[u8; 3 * 256 * 256]>,
+    pub sprites: [u8; 3 * 64 * 40],
+}
+
+// GUI debug buffers are rebuilt while running and are not saved
+impl State for Ppu {
+    fn save(&self, w: &mut StateWriter) {
+        self.vram.save(w);
+        self.oam.save(w);
+        self.control.bits().save(w);
+        self.status.bits().save(w);
+        self.lyc.save(w);
+        self.scy.save(w);
+        self.scx.save(w);
+        self.wy.save(w);
+        self.wx.save(w);
+        self.window_line_counter.save(w);
+        self.bg_palette.save(w);
+        self.obp0.save(w);
+        self.obp1.save(w);
+        self.bg_palettes.save(w);
+        self.obj_palettes.save(w);
+        self.cgb.save(w);
+        self.cycle.save(w);
+        self.scanline.save(w);
+        (self.mode as u8).save(w);
+        self.stat_irq_line.save(w);
+        self.mode3_dots.save(w);
+        self.scanline_oams.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.vram.load(r)?;
+        self.oam.load(r)?;
+        self.control = Control::from_bits_retain(r.read()?);
+        self.status = Status::from_bits_retain(r.read()?);
+        self.lyc.load(r)?;
+        self.scy.load(r)?;
+        self.scx.load(r)?;
+        self.wy.load(r)?;
+        self.wx.load(r)?;
+        self.window_line_counter.load(r)?;
+        self.bg_palette.load(r)?;
+        self.obp0.load(r)?;
+        self.obp1.load(r)?;
+        self.bg_palettes.load(r)?;
+        self.obj_palettes.load(r)?;
+        self.cgb.load(r)?;
+        self.cycle.load(r)?;
+        self.scanline.load(r)?;
+        self.mode = match r.read::<u8>()? {
+            0 => Mode::MODE2,
+            1 => Mode::MODE3,
+            2 => Mode::MODE0,
+            _ => Mode::MODE1,
+        };
+        self.stat_irq_line.load(r)?;
+        self.mode3_dots.load(r)?;
+        self.scanline_oams.load(r)?;
+        Ok(())
+    }
 }
 
 impl Ppu {
     const MODE2_END: usize = 20;
     const MODE3_START: usize = 21;
-    const MODE3_END: usize = 43 + Ppu::MODE2_END;
-    const MODE0_START: usize = Ppu::MODE3_END + 1;
+    // Mode 3 takes at least 172 dots and HBlank gets whatever is left of the 456
+    pub const MODE3_MIN_DOTS: usize = 172;
     const MODE0_END: usize = 113;
     //const SCANLINE_LENGTH: usize = 113;
     const MAX_SCANLINE: u8 = 153;
     const MODE1_SCANLINE_START: u8 = 144;
 
-    pub fn new() -> Self {
+    pub fn new(cgb: bool) -> Self {
         Self {
-            vram: [0; 0x2000],
+            vram: Vram::new(),
             oam: [0; 0xA0],
             control: Control::from_bits_retain(0x80),
             status: Status::from_bits_retain(0),
@@ -111,24 +303,43 @@ impl Ppu {
             scx: 0,
             wy: 0,
             wx: 0,
-            window_counter: 0,
+            window_line_counter: 0,
             bg_palette: 0,
             obp0: 0,
             obp1: 0,
-            bcps: 0,
-            bcpd: 0,
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
+            palette: Palette::CLASSIC,
+            obj_palette: Palette::CLASSIC,
+            cgb,
             mode: Mode::MODE2,
+            stat_irq_line: false,
+            mode3_dots: Ppu::MODE3_MIN_DOTS,
             scanline_oams: Vec::with_capacity(10),
 
             cycle: 0,
             scanline: 0,
 
-            bg_screen: [Color32::from_rgb(0, 0, 0); 144 * 160],
-            win_screen: [Color32::from_rgb(0, 0, 0); 144 * 160],
-            spr_screen: [Color32::from_rgb(0, 0, 0); 144 * 160],
-            tilemap_one: [Color32::BLACK; 256 * 256],
-            tilemap_two: [Color32::BLACK; 256 * 256],
-            sprites: [Color32::BLACK; 64 * 40],
+            debug_layers: false,
+            layers: Layers::new(),
+            tilemap_one: Box::new([0; 3 * 256 * 256]),
+            tilemap_two: Box::new([0; 3 * 256 * 256]),
+            sprites: [0; 3 * 64 * 40],
+        }
+    }
+
+    // Same colours for the background, window and objects
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.obj_palette = palette;
+    }
+
+    // The lightest DMG shade, or white on CGB
+    pub fn lcd_off_rgb(&self) -> (u8, u8, u8) {
+        if self.cgb {
+            (255, 255, 255)
+        } else {
+            self.palette.0[0]
         }
     }
 
@@ -139,7 +350,9 @@ impl Ppu {
         if prior_lcd_status && val & 0x80 == 0 {
             self.scanline = 0;
             self.cycle = 0;
+            self.window_line_counter = 0;
             self.mode = Mode::MODE0;
+            self.stat_irq_line = false;
         }
     }
 
@@ -166,16 +379,65 @@ impl Ppu {
         (self.status.bits() & 0xfc) + mode
     }
 
+    // Screen pixels that would have been shifted out by now on this line. The first
+    // 12 dots of mode 3 fetch tiles before anything is drawn
+    pub fn mode3_pixels(&self) -> usize {
+        if !self.is_mode3() {
+            return 0;
+        }
+        let dots = (self.cycle - Ppu::MODE3_START) * 4;
+        dots.saturating_sub(12).min(160)
+    }
+
+    // The PPU owns VRAM during pixel transfer. Nothing is blocked with the LCD off
+    pub fn is_hblank(&self) -> bool {
+        self.control.contains(Control::lcd_enable) && self.mode == Mode::MODE0
+    }
+
+    pub fn is_mode3(&self) -> bool {
+        self.control.contains(Control::lcd_enable) && self.mode == Mode::MODE3
+    }
+
+    // The PPU owns OAM during OAM scan and pixel transfer
+    pub fn is_mode2_or_3(&self) -> bool {
+        self.control.contains(Control::lcd_enable) && matches!(self.mode, Mode::MODE2 | Mode::MODE3)
+    }
+
+    // 0xFF69 BCPD. Palette RAM can't be accessed during pixel transfer
+    pub fn bcpd_read(&self) -> u8 {
+        if self.is_mode3() {
+            0xff
+        } else {
+            self.bg_palettes.data_read()
+        }
+    }
+
+    pub fn bcpd_write(&mut self, val: u8) {
+        let locked = self.is_mode3();
+        self.bg_palettes.data_write(val, locked);
+    }
+
+    // 0xFF6B OCPD
+    pub fn ocpd_read(&self) -> u8 {
+        if self.is_mode3() {
+            0xff
+        } else {
+            self.obj_palettes.data_read()
+        }
+    }
+
+    pub fn ocpd_write(&mut self, val: u8) {
+        let locked = self.is_mode3();
+        self.obj_palettes.data_write(val, locked);
+    }
+
+    // CPU access goes through the bank selected by VBK
     pub fn read_vram(&self, addr: u16) -> u8 {
-        let mirrored_addr = addr - 0x8000;
-        assert!(mirrored_addr < 0x2000);
-        self.vram[mirrored_addr as usize]
+        self.vram.read(self.vram.bank, addr)
     }
 
     pub fn write_vram(&mut self, addr: u16, val: u8) {
-        let mirrored_addr = addr - 0x8000;
-        assert!(mirrored_addr < 0x2000);
-        self.vram[mirrored_addr as usize] = val;
+        self.vram.write(self.vram.bank, addr, val);
     }
 
     pub fn oam_read(&self, addr: u16) -> u8 {
@@ -190,21 +452,72 @@ impl Ppu {
         self.oam[mirrored_addr as usize] = val;
     }
 
-    pub fn oam_dma(&mut self, page: [u8; 0xA0]) {
-        self.oam = page;
+    // Whether the window covers any of the given line
+    pub fn window_shown_on(&self, line: u8) -> bool {
+        self.control.contains(Control::window_enable)
+            && line >= self.wy
+            && self.wx <= 166
+            && line < 144
     }
 
     // Called once Ppu has entered Mode 2. Scan objects that are on current scanline and put into scanline_oams
     pub fn oam_scan(&mut self) {
         self.scanline_oams.clear();
+        let height = if self.control.contains(Control::obj_size) {
+            16
+        } else {
+            8
+        };
+        // Only Y decides which objects take the 10 slots. Ones off the left or right edge
+        // still use theirs up
         for i in 0..40 {
-            let y_byte = self.oam[4 * i];
-            let in_scanline = self.scanline + 16 >= y_byte
-                && self.scanline + 8 * (!self.control.contains(Control::obj_size) as u8) < y_byte;
+            let top = self.oam[4 * i] as usize;
+            let line = self.scanline as usize + 16;
+            let in_scanline = line >= top && line < top + height;
             if in_scanline && self.scanline_oams.len() < 10 {
                 self.scanline_oams.push(i)
             }
         }
+        // Order by drawing priority once per scanline. On DMG the smaller X wins
+        // and the lower OAM index breaks ties. CGB uses OAM order alone
+        if !self.cgb {
+            let oam = &self.oam;
+            self.scanline_oams.sort_by_key(|&i| (oam[4 * i + 1], i));
+        }
+        self.mode3_dots = self.mode3_length();
+    }
+
+    // Mode 3 is stretched by discarding SCX % 8 pixels at the start, by the window
+    // restarting the fetcher and by every object fetch. An object costs 6 dots plus up to 5
+    // more waiting on the background fetch, only paid by the first object in each tile.
+    // At most 289 dots, which still leaves HBlank 87
+    fn mode3_length(&self) -> usize {
+        let mut dots = Ppu::MODE3_MIN_DOTS + (self.scx % 8) as usize;
+        if self.window_shown_on(self.scanline) {
+            dots += 6;
+        }
+        if self.control.contains(Control::obj_enable) {
+            let mut tiles_waited = Vec::with_capacity(10);
+            for &i in &self.scanline_oams {
+                let x = self.oam[4 * i + 1] as usize;
+                // Off the right edge, never fetched
+                if x >= 168 {
+                    continue;
+                }
+                dots += 6;
+                let tile = (x + self.scx as usize) / 8;
+                if !tiles_waited.contains(&tile) {
+                    tiles_waited.push(tile);
+                    dots += 5usize.saturating_sub((x + self.scx as usize) % 8);
+                }
+            }
+        }
+        dots
+    }
+
+    // Last M-cycle of mode 3 on this line
+    fn mode3_end(&self) -> usize {
+        Ppu::MODE3_START + self.mode3_dots.div_ceil(4) - 1
     }
 
     // 456 cycles per scanline. 154 scanlines, last 10 (144-153 inclusive) are vblank
@@ -221,39 +534,22 @@ impl Ppu {
             self.cycle %= Ppu::MODE0_END;
             self.scanline += 1;
 
-            // increment window internal counter if window enabled
-            if self.control.contains(Control::window_enable)
-                && self.scanline > self.wy
-                && self.wx < 167
-                && self.scanline < 144
-            {
-                self.window_counter += 1;
+            // Advance the window line if the window was visible on the line just drawn
+            if self.window_shown_on(self.scanline - 1) {
+                self.window_line_counter = self.window_line_counter.wrapping_add(1);
             }
 
             // After vblank, reset to scanline 0
             if self.scanline > Ppu::MAX_SCANLINE {
                 self.scanline = 0;
+                self.window_line_counter = 0;
                 self.mode = Mode::MODE2;
             }
 
             // vblank has started
             if self.scanline == Ppu::MODE1_SCANLINE_START {
                 self.mode = Mode::MODE1;
-                self.window_counter = 0;
                 result.2 = true;
-                if self.status.contains(Status::mode_one_select) {
-                    // Trigger LCD Interrupt through return
-                    result.1 = true;
-                }
-            }
-
-            // Check for LYC == LY interrupt
-            if self.scanline == self.lyc {
-                self.status.insert(Status::compare);
-                // Trigger LCD Interrupt through return
-                if self.status.contains(Status::lyc_select) {
-                    result.1 = true;
-                }
             }
         }
 
@@ -262,10 +558,10 @@ impl Ppu {
                 0..=Ppu::MODE2_END => {
                     self.mode = Mode::MODE2;
                 }
-                Ppu::MODE3_START..=Ppu::MODE3_END => {
+                cycle if cycle <= self.mode3_end() => {
                     self.mode = Mode::MODE3;
                 }
-                Ppu::MODE0_START..=Ppu::MODE0_END => {
+                cycle if cycle <= Ppu::MODE0_END => {
                     self.mode = Mode::MODE0;
                 }
                 _ => {
@@ -276,28 +572,16 @@ impl Ppu {
         // If mode changed then trigger mode interrupt (if Stat for that mode is set)
         if prior_mode != self.mode {
             if self.mode == Mode::MODE0 {
-                // Entered HBlank. Do nothing
-                result.0 = DisplayStatus::DoNothing;
-                if self.status.contains(Status::mode_zero_select) {
-                    // Trigger LCD Interrupt through return
-                    result.1 = true;
-                }
+                // Entered HBlank. HBlank DMA copies a block
+                result.0 = DisplayStatus::HBlank;
             }
             if self.mode == Mode::MODE1 {
                 // Entered VBlank. Display new frame
                 result.0 = DisplayStatus::NewFrame;
-                if self.status.contains(Status::mode_one_select) {
-                    // Trigger LCD Interrupt through return
-                    result.1 = true;
-                }
             }
             if self.mode == Mode::MODE2 {
                 // Entered Mode 2. Do OAM Scan
                 result.0 = DisplayStatus::OAMScan;
-                if self.status.contains(Status::mode_two_select) {
-                    // Trigger LCD Interrupt through return
-                    result.1 = true;
-                }
             }
             if self.mode == Mode::MODE3 {
                 // Entered drawing stage. Draw new scanline
@@ -319,6 +603,242 @@ impl Ppu {
             self.status = Status::from_bits_retain((self.status.bits() & 0b1111_1100) | new_mode);
         }
 
+        // LYC == LY is checked every tick so LYC writes and the early LY = 0 on line 153 are
+        // seen straight away. Cleared once they stop matching
+        self.update_compare();
+
+        // Trigger LCD Interrupt through return only when the STAT line goes from low to high.
+        // Any source that is already high blocks the others from firing a new interrupt
+        let stat_line = self.stat_line_high();
+        if stat_line && !self.stat_irq_line {
+            result.1 = true;
+        }
+        self.stat_irq_line = stat_line;
+
         result
     }
+
+    // LY as the CPU reads it. Line 153 only shows as 153 for its first M-cycle, then LY reads
+    // 0 for the rest of it as well as line 0
+    pub fn ly(&self) -> u8 {
+        if self.scanline == Ppu::MAX_SCANLINE && self.cycle > 1 {
+            0
+        } else {
+            self.scanline
+        }
+    }
+
+    // The compare bit updates right away, any interrupt comes on the next tick
+    pub fn write_lyc(&mut self, val: u8) {
+        self.lyc = val;
+        if self.control.contains(Control::lcd_enable) {
+            self.update_compare();
+        }
+    }
+
+    fn update_compare(&mut self) {
+        self.status.set(Status::compare, self.ly() == self.lyc);
+    }
+
+    // Hardware ORs all enabled STAT conditions into a single line
+    fn stat_line_high(&self) -> bool {
+        let lyc = self.status.contains(Status::lyc_select) && self.status.contains(Status::compare);
+        let mode = match self.mode {
+            Mode::MODE0 => self.status.contains(Status::mode_zero_select),
+            Mode::MODE1 => self.status.contains(Status::mode_one_select),
+            Mode::MODE2 => self.status.contains(Status::mode_two_select),
+            Mode::MODE3 => false,
+        };
+        lyc || mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE_CYCLES: usize = Ppu::MODE0_END + 1;
+
+    // Tick one M-cycle at a time, scanning OAM when mode 3 starts as Bus does. Returns how
+    // many LCD interrupts fired
+    fn run(ppu: &mut Ppu, cycles: usize) -> usize {
+        let mut interrupts = 0;
+        for _ in 0..cycles {
+            let (display, lcd, _) = ppu.tick(1);
+            if let DisplayStatus::NewScanline = display {
+                ppu.oam_scan();
+            }
+            interrupts += lcd as usize;
+        }
+        interrupts
+    }
+
+    fn run_to_line(ppu: &mut Ppu, line: u8) {
+        while ppu.scanline != line {
+            run(ppu, 1);
+        }
+    }
+
+    // LCD on with STAT selecting the given interrupt sources, at the start of line
+    fn stat_ppu(select: Status, line: u8) -> Ppu {
+        let mut ppu = Ppu::new(false);
+        ppu.write_to_ctrl(0x91);
+        ppu.write_status(select.bits());
+        run_to_line(&mut ppu, line);
+        ppu
+    }
+
+    #[test]
+    fn test_stat_one_interrupt_per_line() {
+        // Mode 0 runs straight into the next line's mode 2, so with both selected the line
+        // never drops in between and each line only interrupts once
+        for select in [
+            Status::mode_zero_select,
+            Status::mode_two_select,
+            Status::mode_zero_select | Status::mode_two_select,
+        ] {
+            let mut ppu = stat_ppu(select.clone(), 10);
+            assert_eq!(run(&mut ppu, 10 * LINE_CYCLES), 10, "{select:?}");
+        }
+    }
+
+    #[test]
+    fn test_lyc_blocked_by_hblank() {
+        let mut ppu = stat_ppu(Status::lyc_select, 4);
+        ppu.write_lyc(5);
+        assert_eq!(run(&mut ppu, 2 * LINE_CYCLES), 1);
+
+        // Line 4's HBlank keeps the line high into line 5, so LYC = LY can't raise it again
+        let mut ppu = stat_ppu(Status::lyc_select | Status::mode_zero_select, 4);
+        ppu.write_lyc(5);
+        assert_eq!(run(&mut ppu, 2 * LINE_CYCLES), 1);
+        assert_eq!(ppu.scanline, 6);
+        // Cleared once LY moves past LYC
+        assert!(!ppu.status.contains(Status::compare));
+        assert_eq!(run(&mut ppu, LINE_CYCLES), 1);
+    }
+
+    #[test]
+    fn test_vram_banks() {
+        let mut ppu = Ppu::new(true);
+        ppu.write_vram(0x8000, 0x12);
+        ppu.vram.bank_write(0xff);
+        assert_eq!(ppu.vram.bank_read(), 0xff);
+        assert_eq!(ppu.read_vram(0x8000), 0x00);
+        ppu.write_vram(0x8000, 0x34);
+        ppu.write_vram(0x9fff, 0x56);
+        ppu.vram.bank_write(0x00);
+        assert_eq!(ppu.vram.bank_read(), 0xfe);
+        assert_eq!(ppu.read_vram(0x8000), 0x12);
+        assert_eq!(ppu.read_vram(0x9fff), 0x00);
+        // The renderer reads either bank whatever VBK says
+        assert_eq!(ppu.vram.read(1, 0x8000), 0x34);
+        assert_eq!(ppu.vram.read(1, 0x9fff), 0x56);
+    }
+
+    #[test]
+    fn test_palette_auto_increment() {
+        let mut palettes = ColorPalettes::new();
+        palettes.spec_write(0xbe);
+        assert_eq!(palettes.spec_read(), 0xfe);
+        for val in [0x11, 0x22, 0x33] {
+            palettes.data_write(val, false);
+        }
+        // Wrapped from 0x3F back to 0
+        assert_eq!(palettes.index, 0x01);
+        assert_eq!(palettes.data[0x3e..], [0x11, 0x22]);
+        assert_eq!(palettes.data[0], 0x33);
+
+        // Without auto increment the index stays put, and reads never move it
+        palettes.spec_write(0x05);
+        palettes.data_write(0x44, false);
+        palettes.data_write(0x55, false);
+        assert_eq!(palettes.data_read(), 0x55);
+        assert_eq!(palettes.data_read(), 0x55);
+        assert_eq!(palettes.spec_read(), 0x45);
+    }
+
+    #[test]
+    fn test_locked_palette_write_still_increments() {
+        let mut palettes = ColorPalettes::new();
+        palettes.spec_write(0x80);
+        palettes.data_write(0x00, true);
+        assert_eq!(palettes.data[0], 0xff);
+        assert_eq!(palettes.index, 1);
+    }
+
+    #[test]
+    fn test_palette_rgb() {
+        let mut palettes = ColorPalettes::new();
+        assert_eq!(palettes.rgb(7, 3), (255, 255, 255));
+        // Palette 2 colour 1 is at index 8 * 2 + 2. Red 0x1F, green 0x10, blue 0
+        palettes.spec_write(0x80 | 18);
+        let color = 0x1fu16 | (0x10 << 5);
+        for byte in color.to_le_bytes() {
+            palettes.data_write(byte, false);
+        }
+        assert_eq!(palettes.rgb(2, 1), (255, 132, 0));
+        assert_eq!(palettes.rgb(2, 0), (255, 255, 255));
+    }
+
+    #[test]
+    fn test_lyc_write_mid_line() {
+        let mut ppu = stat_ppu(Status::lyc_select, 10);
+        run(&mut ppu, 50);
+        ppu.write_lyc(10);
+        assert!(ppu.status.contains(Status::compare));
+        ppu.write_lyc(11);
+        assert!(!ppu.status.contains(Status::compare));
+        // The interrupt follows on the next tick, still on this line
+        ppu.write_lyc(10);
+        assert_eq!(run(&mut ppu, 1), 1);
+        assert_eq!(ppu.scanline, 10);
+    }
+
+    #[test]
+    fn test_lyc_set_to_passed_line() {
+        let mut ppu = stat_ppu(Status::lyc_select, 20);
+        ppu.write_lyc(20);
+        assert!(ppu.status.contains(Status::compare));
+        ppu.write_lyc(19);
+        assert!(!ppu.status.contains(Status::compare));
+        // Nothing fires for the next 100 lines
+        assert_eq!(run(&mut ppu, 100 * LINE_CYCLES), 0);
+        assert!(!ppu.status.contains(Status::compare));
+    }
+
+    // M-cycles from the start of mode 3 to HBlank on line 1, and the whole of line 1
+    fn mode3_and_line_cycles(ppu: &mut Ppu) -> (usize, usize) {
+        run_to_line(ppu, 1);
+        let (mut mode3, mut line) = (0, 0);
+        while ppu.scanline == 1 {
+            run(ppu, 1);
+            mode3 += ppu.is_mode3() as usize;
+            line += 1;
+        }
+        (mode3, line)
+    }
+
+    #[test]
+    fn test_mode3_length_with_sprites() {
+        let mut ppu = Ppu::new(false);
+        ppu.write_to_ctrl(0x93);
+        let (mode3, line) = mode3_and_line_cycles(&mut ppu);
+        assert_eq!(ppu.mode3_dots, Ppu::MODE3_MIN_DOTS);
+        assert_eq!(mode3, Ppu::MODE3_MIN_DOTS / 4);
+
+        // 10 sprites on line 1 in separate tiles, each lined up with the background fetch.
+        // 6 dots for the fetch and 5 waiting on the background
+        let mut ppu = Ppu::new(false);
+        ppu.write_to_ctrl(0x93);
+        for i in 0..10 {
+            ppu.oam[4 * i] = 16;
+            ppu.oam[4 * i + 1] = 8 + 16 * i as u8;
+        }
+        let (sprites_mode3, sprites_line) = mode3_and_line_cycles(&mut ppu);
+        assert_eq!(ppu.mode3_dots, Ppu::MODE3_MIN_DOTS + 10 * 11);
+        assert_eq!(sprites_mode3, (Ppu::MODE3_MIN_DOTS + 10 * 11).div_ceil(4));
+        // HBlank gives up what mode 3 takes
+        assert_eq!(sprites_line, line);
+    }
 }
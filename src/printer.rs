@@ -0,0 +1,186 @@
+// Game Boy Printer on the link cable. The Game Boy drives the clock and sends packets:
+// 0x88 0x33, command, compression, data length (little endian), data, checksum (little
+// endian), then two 0x00 bytes the printer answers with 0x81 and its status. Printed images
+// are saved as PNGs
+use chrono::Local;
+
+use crate::serial::SerialLink;
+
+use std::io;
+use std::path::PathBuf;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+// Magic, command, compression and length
+const HEADER_LEN: usize = 6;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0x01;
+const BUSY: u8 = 0x02;
+const UNPROCESSED: u8 = 0x08;
+
+// The paper is 20 tiles wide
+const WIDTH: u32 = 160;
+const TILE_BYTES: usize = 16;
+// Shades for the four palette values, white to black
+const SHADES: [u8; 4] = [255, 170, 85, 0];
+
+pub struct Printer {
+    dir: PathBuf,    // Printouts go here
+    packet: Vec<u8>, // Bytes of the packet so far, from the magic on
+    image: Vec<u8>,  // 2bpp tile data waiting for a print command, 20 tiles to a row
+    status: u8,
+    prints: u32, // Keeps names unique when several prints land in the same second
+}
+
+impl Printer {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            packet: Vec::new(),
+            image: Vec::new(),
+            status: 0,
+            prints: 0,
+        }
+    }
+
+    fn data_len(&self) -> usize {
+        u16::from_le_bytes([self.packet[4], self.packet[5]]) as usize
+    }
+
+    // Run a complete packet
+    fn process(&mut self) {
+        let data_len = self.data_len();
+        let body = &self.packet[2..HEADER_LEN + data_len];
+        let checksum = body
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        let expected = u16::from_le_bytes([
+            self.packet[HEADER_LEN + data_len],
+            self.packet[HEADER_LEN + data_len + 1],
+        ]);
+        if checksum != expected {
+            self.status |= CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !CHECKSUM_ERROR;
+
+        let data = &self.packet[HEADER_LEN..HEADER_LEN + data_len];
+        match self.packet[2] {
+            INIT => {
+                self.image.clear();
+                self.status = 0;
+            }
+            // Sheets, margins, palette and exposure. Only the palette matters here
+            PRINT if data_len >= 4 => {
+                let palette = data[2];
+                if let Err(err) = self.print(palette) {
+                    eprintln!("Could not save printout: {err}");
+                }
+                self.image.clear();
+                self.status = (self.status & !UNPROCESSED) | BUSY;
+            }
+            // An empty data packet marks the end of the image
+            DATA if data_len > 0 => {
+                if self.packet[3] & 0x01 != 0 {
+                    let data = decompress(data);
+                    self.image.extend_from_slice(&data);
+                } else {
+                    self.image.extend_from_slice(data);
+                }
+                self.status |= UNPROCESSED;
+            }
+            _ => {}
+        }
+    }
+
+    // Save the buffered image. Palette 0 is taken as the usual 0xE4, as the printer does
+    fn print(&mut self, palette: u8) -> io::Result<()> {
+        let tile_rows = self.image.len() / (TILE_BYTES * 20);
+        if tile_rows == 0 {
+            return Ok(());
+        }
+        let palette = if palette == 0 { 0xe4 } else { palette };
+        let image = image::GrayImage::from_fn(WIDTH, 8 * tile_rows as u32, |x, y| {
+            let (x, y) = (x as usize, y as usize);
+            let tile = (y / 8) * 20 + x / 8;
+            let addr = tile * TILE_BYTES + 2 * (y % 8);
+            let bit = 7 - x % 8;
+            let lo = (self.image[addr] >> bit) & 1;
+            let hi = (self.image[addr + 1] >> bit) & 1;
+            let shade = (palette >> (2 * (hi << 1 | lo))) & 0b11;
+            image::Luma([SHADES[shade as usize]])
+        });
+
+        self.prints += 1;
+        let path = self.dir.join(format!(
+            "print_{}_{}.png",
+            Local::now().format("%Y%m%d_%H%M%S"),
+            self.prints
+        ));
+        image.save(&path).map_err(io::Error::other)?;
+        eprintln!("Printed to {}", path.display());
+        Ok(())
+    }
+}
+
+// Run length encoding. A control byte with bit 7 set repeats the next byte (control & 0x7F) + 2
+// times, otherwise the next control + 1 bytes are copied as is
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                out.resize(out.len() + (control & 0x7f) as usize + 2, byte);
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+impl SerialLink for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        // Anything before the magic is ignored
+        if self.packet.len() < MAGIC.len() && byte != MAGIC[self.packet.len()] {
+            self.packet.clear();
+            if byte == MAGIC[0] {
+                self.packet.push(byte);
+            }
+            return 0x00;
+        }
+        self.packet.push(byte);
+        if self.packet.len() < HEADER_LEN {
+            return 0x00;
+        }
+        // The checksum is followed by the two bytes the printer answers
+        let checksum_end = HEADER_LEN + self.data_len() + 2;
+        if self.packet.len() <= checksum_end {
+            0x00
+        } else if self.packet.len() == checksum_end + 1 {
+            self.process();
+            0x81
+        } else {
+            self.packet.clear();
+            let status = self.status;
+            // Printing is over by the next time it's asked
+            self.status &= !BUSY;
+            status
+        }
+    }
+
+    // The printer never drives the clock
+    fn respond(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
@@ -1,39 +1,196 @@
 use crate::ppu::{Control, Ppu};
-use eframe::egui::{self, Color32};
+use crate::state::{State, StateError, StateReader, StateWriter};
+use bitflags::bitflags;
 
-// white, light gray, dark gray, black
-const GB_PALETTE: [(u8, u8, u8); 4] = [(155, 188, 15), (139, 172, 15), (48, 98, 48), (15, 56, 15)];
+use std::path::Path;
+use std::{fs, io};
 
+// Colours the four DMG shades are drawn with: white, light gray, dark gray, black
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette(pub [(u8, u8, u8); 4]);
+
+impl Palette {
+    pub const CLASSIC: Palette =
+        Palette([(155, 188, 15), (139, 172, 15), (48, 98, 48), (15, 56, 15)]);
+    pub const GRAYSCALE: Palette =
+        Palette([(255, 255, 255), (170, 170, 170), (85, 85, 85), (0, 0, 0)]);
+    // BGB's default
+    pub const BGB: Palette =
+        Palette([(224, 248, 208), (136, 192, 112), (52, 104, 86), (8, 24, 32)]);
+    pub const HIGH_CONTRAST: Palette =
+        Palette([(255, 255, 255), (192, 192, 192), (64, 64, 64), (0, 0, 0)]);
+
+    pub const PRESETS: [(&'static str, Palette); 4] = [
+        ("classic", Palette::CLASSIC),
+        ("grayscale", Palette::GRAYSCALE),
+        ("bgb", Palette::BGB),
+        ("high-contrast", Palette::HIGH_CONTRAST),
+    ];
+
+    pub fn preset(name: &str) -> Option<Palette> {
+        Palette::PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))
+            .map(|&(_, palette)| palette)
+    }
+
+    // Four hex colours lightest first, like "#E0F8D0 #88C070 #346856 #081820". Commas,
+    // quotes and brackets are skipped so a JSON array of strings works too
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let colors = text
+            .split(|c: char| c.is_whitespace() || ",[]\"'".contains(c))
+            .filter(|word| !word.is_empty())
+            .map(|word| {
+                let hex = word.trim_start_matches('#').trim_start_matches("0x");
+                match u32::from_str_radix(hex, 16) {
+                    Ok(rgb) if hex.len() == 6 => {
+                        Ok(((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+                    }
+                    _ => Err(format!("\"{word}\" is not a hex colour")),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let colors: [(u8, u8, u8); 4] = colors
+            .try_into()
+            .map_err(|colors: Vec<_>| format!("expected 4 colours, got {}", colors.len()))?;
+        Ok(Palette(colors))
+    }
+
+    // A preset name, otherwise a palette file
+    pub fn load(name_or_path: &str) -> io::Result<Palette> {
+        if let Some(palette) = Palette::preset(name_or_path) {
+            return Ok(palette);
+        }
+        let text = fs::read_to_string(Path::new(name_or_path))?;
+        Palette::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+// The background, window and sprites each drawn alone, black where the layer has nothing.
+// Only kept up to date while Ppu::debug_layers is set
+pub struct Layers {
+    pub background: Frame,
+    pub window: Frame,
+    pub sprites: Frame,
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        Self {
+            background: Frame::new(),
+            window: Frame::new(),
+            sprites: Frame::new(),
+        }
+    }
+}
+
+// CGB BG map attributes. Stored in VRAM bank 1 at the same address as the tile id
+bitflags! {
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    struct BgAttributes: u8 {
+        // BG colors 1-3 are drawn over sprites
+        const priority = 0b1000_0000;
+        const y_flip = 0b0100_0000;
+        const x_flip = 0b0010_0000;
+        // Tile data comes from VRAM bank 1
+        const bank = 0b0000_1000;
+        // BG palette number 0-7
+        const palette = 0b0000_0111;
+    }
+}
+
+// 160x144 screen as packed RGB, 3 bytes per pixel row by row. Frontends convert to
+// whatever their textures want
 #[derive(Clone)]
 pub struct Frame {
-    pub data: Vec<egui::Color32>,
+    pub data: Vec<u8>,
+}
+
+impl State for Frame {
+    fn save(&self, w: &mut StateWriter) {
+        w.write(&self.data);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let len = self.data.len();
+        self.data.copy_from_slice(r.take(len)?);
+        Ok(())
+    }
 }
 
 impl Frame {
-    const WIDTH: usize = 160;
-    const HEIGHT: usize = 144;
+    pub const WIDTH: usize = 160;
+    pub const HEIGHT: usize = 144;
 
     pub fn new() -> Frame {
         Self {
-            data: vec![Color32::PLACEHOLDER; Frame::WIDTH * Frame::HEIGHT],
+            data: vec![0; 3 * Frame::WIDTH * Frame::HEIGHT],
+        }
+    }
+
+    // What the screen shows with the LCD off, see Ppu::lcd_off_rgb
+    pub fn blank(&mut self, rgb: (u8, u8, u8)) {
+        for i in 0..Frame::WIDTH * Frame::HEIGHT {
+            put_rgb(&mut self.data, i, rgb);
         }
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
-        let color = egui::Color32::from_rgb(rgb.0, rgb.1, rgb.2);
-        let base = y * Frame::WIDTH + x;
-        self.data[base] = color;
+        put_rgb(&mut self.data, y * Frame::WIDTH + x, rgb);
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = 3 * (y * Frame::WIDTH + x);
+        (self.data[base], self.data[base + 1], self.data[base + 2])
+    }
+
+    // Ready for an RGB24 texture or image with a pitch of 3 * WIDTH
+    pub fn as_rgb_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    // 64 bit FNV-1a of the pixels. Stable across platforms and versions, for comparing
+    // against golden values
+    pub fn hash(&self) -> u64 {
+        self.data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
     }
+}
 
-    // pub fn _get_pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
-    //     let base = y * Frame::WIDTH + x;
-    //     base = self.data[base];
-    // }
+// Draw a whole frame from the PPU's current registers, VRAM and OAM without running any
+// timing. For checking rendering against hand built VRAM setups. LY and the window line
+// counter are left wherever the last line put them
+pub fn render_frame(ppu: &mut Ppu) -> Frame {
+    let mut frame = Frame::new();
+    ppu.window_line_counter = 0;
+    for y in 0..Frame::HEIGHT {
+        render_scanline(ppu, y, &mut frame);
+    }
+    frame
 }
 
-// returns (tile_id, x_pos, y_pos)
-fn get_win_tile_id(ppu: &Ppu, x: usize, y: usize) -> (u8, u8, u8, bool) {
-    // Translate screen x, y coords onto window tile map by subtracting WX/WY
+// Draw line y of frame the same way, moving LY to it and scanning OAM first. Moves the
+// window line counter on if the window covered the line
+pub fn render_scanline(ppu: &mut Ppu, y: usize, frame: &mut Frame) {
+    ppu.scanline = y as u8;
+    ppu.oam_scan();
+    for x in 0..Frame::WIDTH {
+        render_pixel(ppu, x, y, frame);
+    }
+    if ppu.window_shown_on(y as u8) {
+        ppu.window_line_counter = ppu.window_line_counter.wrapping_add(1);
+    }
+}
+
+// Store pixel i of a packed RGB buffer
+fn put_rgb(buf: &mut [u8], i: usize, rgb: (u8, u8, u8)) {
+    buf[3 * i..3 * i + 3].copy_from_slice(&[rgb.0, rgb.1, rgb.2]);
+}
+
+// returns (tile map address, x_pos, y_pos, is_window)
+fn get_win_tile_addr(ppu: &Ppu, x: usize, y: usize) -> (u16, u8, u8, bool) {
+    // Translate screen x onto the window tile map by subtracting WX. y is the window line counter
     let x_pos = x + 7 - ppu.wx as usize; // Plus 7 since WX is corner upper left + 7 pixels for some reason
     let y_pos = y;
     let tilemap_base = if ppu.control.contains(Control::window_map_area) {
@@ -46,7 +203,7 @@ fn get_win_tile_id(ppu: &Ppu, x: usize, y: usize) -> (u8, u8, u8, bool) {
     let x_p = (x_pos % 8) as u8;
     let y_p = (y_pos % 8) as u8;
     (
-        ppu.read_vram(tilemap_base + tile_x as u16 + 32 * tile_y as u16),
+        tilemap_base + tile_x as u16 + 32 * tile_y as u16,
         x_p,
         y_p,
         true,
@@ -54,7 +211,7 @@ fn get_win_tile_id(ppu: &Ppu, x: usize, y: usize) -> (u8, u8, u8, bool) {
 }
 
 // x,y are screen coordinates i.e 0 <= x < 160 and 0 <= y < 144
-fn get_bg_tile_id(ppu: &Ppu, x: usize, y: usize) -> (u8, u8, u8, bool) {
+fn get_bg_tile_addr(ppu: &Ppu, x: usize, y: usize) -> (u16, u8, u8, bool) {
     // Translate screen x,y coords onto the tile map by using scroll registers
     let x_pos = (x + ppu.scx as usize) % 256;
     let y_pos = (y + ppu.scy as usize) % 256;
@@ -68,79 +225,139 @@ fn get_bg_tile_id(ppu: &Ppu, x: usize, y: usize) -> (u8, u8, u8, bool) {
     let x_p = (x_pos % 8) as u8;
     let y_p = (y_pos % 8) as u8;
     (
-        ppu.read_vram(tilemap_base + tile_x as u16 + 32 * tile_y as u16),
+        tilemap_base + tile_x as u16 + 32 * tile_y as u16,
         x_p,
         y_p,
         false,
     )
 }
 
-fn get_sprite(ppu: &Ppu, x: usize, y: usize) -> (u8, bool) {
-    let mut valid_objs = Vec::new();
-    for i in ppu.scanline_oams.iter() {
-        let x_byte = ppu.oam[4 * i + 1];
-        let valid = x + 8 >= x_byte as usize && x < x_byte as usize;
-        if valid {
-            valid_objs.push((x_byte, *i));
-        }
-    }
-    valid_objs.sort();
-    let sprites: Vec<usize> = valid_objs.into_iter().map(|(_x, id)| id).collect();
-    resolve_sprite_overlap(ppu, x, y, &sprites)
+// Raw sprite pixel. The OBJ palette is only applied when composing the final pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SpritePixel {
+    pub color_id: u8,      // 2-bit color index. Never 0 since 0 is transparent
+    pub palette: u8,       // DMG: 0 = OBP0, 1 = OBP1. CGB: OBJ palette 0-7
+    pub bg_priority: bool, // true = BG colors 1-3 are drawn over this sprite
 }
 
-fn resolve_sprite_overlap(ppu: &Ppu, x: usize, y: usize, sprites: &[usize]) -> (u8, bool) {
-    for sprite_index in sprites {
-        let mut y_pos = y as u8 + 16 - ppu.oam[4 * sprite_index];
-        let mut x_pos = x as u8 + 8 - ppu.oam[4 * sprite_index + 1];
-        let tile_index = ppu.oam[4 * sprite_index + 2];
-        let sprite_attr = ppu.oam[4 * sprite_index + 3];
+// Raw BG or window pixel before the palette is applied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BgPixel {
+    pub color_id: u8,
+    pub attributes: u8, // CGB BG map attributes. 0 on DMG
+    pub is_window: bool,
+}
 
-        if sprite_attr & 0b0010_0000 > 0 {
-            x_pos = 7 - x_pos;
-        }
-        if sprite_attr & 0b0100_0000 > 0 {
-            y_pos = 7 + (8 * ppu.control.contains(Control::obj_size) as u8) - y_pos;
+impl SpritePixel {
+    fn rgb(&self, ppu: &Ppu) -> (u8, u8, u8) {
+        if ppu.cgb {
+            return ppu.obj_palettes.rgb(self.palette, self.color_id);
         }
-
-        let obj_id = if ppu.control.contains(Control::obj_size) && y_pos >= 8 {
-            get_pixel_data(ppu, x_pos, y_pos - 8, tile_index | 0x01, true)
-        } else if ppu.control.contains(Control::obj_size) {
-            get_pixel_data(ppu, x_pos, y_pos, tile_index & 0xfe, true)
+        let palette = if self.palette == 1 {
+            ppu.obp1
         } else {
-            get_pixel_data(ppu, x_pos, y_pos, tile_index, true)
+            ppu.obp0
         };
+        ppu.obj_palette.0[((palette >> (2 * self.color_id)) & 0b11) as usize]
+    }
+}
 
-        if obj_id != 0 {
-            let color = if sprite_attr & 0b0001_0000 > 0 {
-                (ppu.obp1 & (0b11 << (2 * obj_id))) >> (2 * obj_id)
-            } else {
-                (ppu.obp0 & (0b11 << (2 * obj_id))) >> (2 * obj_id)
-            };
-            return (color, sprite_attr & 0b1000_0000 > 0);
-        }
+// scanline_oams is already in priority order so the first opaque pixel wins.
+// None means no sprite covers the pixel or every sprite is transparent there
+fn get_sprite(ppu: &Ppu, x: usize, y: usize) -> Option<SpritePixel> {
+    ppu.scanline_oams
+        .iter()
+        .find_map(|&sprite_index| sprite_pixel(ppu, x, y, sprite_index))
+}
+
+// Position of screen pixel (x, y) within a sprite, None if the sprite doesn't cover it.
+// OAM holds the sprite's corner offset by (8, 16) so sprites can hang off the top and left
+// edges. X = 0 or Y = 0 puts them entirely off screen
+fn sprite_offset(ppu: &Ppu, x: usize, y: usize, sprite_index: usize) -> Option<(u8, u8)> {
+    let height = if ppu.control.contains(Control::obj_size) {
+        16
+    } else {
+        8
+    };
+    let x_pos = (x + 8)
+        .checked_sub(ppu.oam[4 * sprite_index + 1] as usize)
+        .filter(|&x_pos| x_pos < 8)?;
+    let y_pos = (y + 16)
+        .checked_sub(ppu.oam[4 * sprite_index] as usize)
+        .filter(|&y_pos| y_pos < height)?;
+    Some((x_pos as u8, y_pos as u8))
+}
+
+// Tile and row within it for a row of a sprite. Y flip inverts over the whole sprite,
+// so in 8x16 mode rows 0-7 come from the bottom tile and 8-15 from the top when flipped.
+// Bit 0 of the tile index is ignored for 8x16: top tile is even, bottom is odd
+fn obj_tile_row(tile_index: u8, y_pos: u8, tall: bool, y_flip: bool) -> (u8, u8) {
+    let height = if tall { 16 } else { 8 };
+    let y_pos = if y_flip { height - 1 - y_pos } else { y_pos };
+    match (tall, y_pos >= 8) {
+        (false, _) => (tile_index, y_pos),
+        (true, false) => (tile_index & 0xfe, y_pos),
+        (true, true) => (tile_index | 0x01, y_pos - 8),
     }
-    // Return 0xff if obj_id is 0 for all previous sprites.
-    // This means pixel is transparent for all the sprites.
-    (0xff, true)
 }
 
-// Need a relative x and y to the upper left pixel of tile/obj
-fn get_pixel_data(ppu: &Ppu, x: u8, y: u8, tile_id: u8, is_obj: bool) -> u8 {
-    let x = x as u16; // x coordinate of current tile
-    let y = y as u16; // y coordinate of current tile
+// Returns None if the sprite's pixel at (x, y) is transparent or off the sprite
+pub(crate) fn sprite_pixel(
+    ppu: &Ppu,
+    x: usize,
+    y: usize,
+    sprite_index: usize,
+) -> Option<SpritePixel> {
+    let tall = ppu.control.contains(Control::obj_size);
+    let (mut x_pos, y_pos) = sprite_offset(ppu, x, y, sprite_index)?;
+    let tile_index = ppu.oam[4 * sprite_index + 2];
+    let sprite_attr = ppu.oam[4 * sprite_index + 3];
 
+    if sprite_attr & 0b0010_0000 > 0 {
+        x_pos = 7 - x_pos;
+    }
+    let (tile, row) = obj_tile_row(tile_index, y_pos, tall, sprite_attr & 0b0100_0000 > 0);
+
+    // CGB sprites can take their tile data from VRAM bank 1
+    let bank = (ppu.cgb && sprite_attr & 0b0000_1000 > 0) as usize;
+    let obj_id = get_pixel_data(ppu, x_pos, row, tile, true, bank);
+
+    // Color index 0 is transparent for sprites regardless of what the palette maps it to
+    if obj_id == 0 {
+        return None;
+    }
+    Some(SpritePixel {
+        color_id: obj_id,
+        palette: if ppu.cgb {
+            sprite_attr & 0b0000_0111
+        } else {
+            (sprite_attr & 0b0001_0000) >> 4
+        },
+        bg_priority: sprite_attr & 0b1000_0000 > 0,
+    })
+}
+
+// Address of the first byte of a tile's data
+pub(crate) fn tile_addr(ppu: &Ppu, tile_id: u8, is_obj: bool) -> u16 {
     // if is_obj = true then we want else case base to be 0x8000
     // if is_obj = false then we need to check
     let adjust = !is_obj && !ppu.control.contains(Control::bg_win_mode);
-    let tile_base = if tile_id > 127 {
+    if tile_id > 127 {
         0x8800 + 16 * (tile_id as u16 - 128)
     } else {
         0x8000 + 16 * (tile_id as u16) + 0x1000 * (adjust as u16)
-    };
+    }
+}
+
+// Need a relative x and y to the upper left pixel of tile/obj
+fn get_pixel_data(ppu: &Ppu, x: u8, y: u8, tile_id: u8, is_obj: bool, bank: usize) -> u8 {
+    let x = x as u16; // x coordinate of current tile
+    let y = y as u16; // y coordinate of current tile
+
+    let tile_base = tile_addr(ppu, tile_id, is_obj);
     let inverted_x = 7 - x; // Invert so that x=0 corresponds to bit 7 of color index
-    let lo = (ppu.read_vram(tile_base + 2 * y) & (1 << inverted_x)) > 0;
-    let hi = (ppu.read_vram(tile_base + 2 * y + 1) & (1 << inverted_x)) > 0;
+    let lo = (ppu.vram.read(bank, tile_base + 2 * y) & (1 << inverted_x)) > 0;
+    let hi = (ppu.vram.read(bank, tile_base + 2 * y + 1) & (1 << inverted_x)) > 0;
     match (lo, hi) {
         (false, false) => 0,
         (true, false) => 1,
@@ -151,51 +368,89 @@ fn get_pixel_data(ppu: &Ppu, x: u8, y: u8, tile_id: u8, is_obj: bool) -> u8 {
 
 fn render_pixel(ppu: &mut Ppu, x: usize, y: usize, frame: &mut Frame) {
     // If pixel is in window area, fetch window pixel. Otherwise fetch background pixel
-    let (tile_id, x_pos, y_pos, is_window) = if ppu.control.contains(Control::window_enable)
-        && x + 7 >= ppu.wx as usize
-        && y >= ppu.wy as usize
-    {
-        //eprintln!("Scanline: {}, window: {}, wy: {}", ppu.scanline, ppu.window_counter, ppu.wy);
-        get_win_tile_id(ppu, x, ppu.window_counter)
+    let (map_addr, mut x_pos, mut y_pos, is_window) =
+        if ppu.control.contains(Control::window_enable)
+            && x + 7 >= ppu.wx as usize
+            && y >= ppu.wy as usize
+        {
+            //eprintln!("Scanline: {}, window: {}, wy: {}", ppu.scanline, ppu.window_line_counter, ppu.wy);
+            get_win_tile_addr(ppu, x, ppu.window_line_counter as usize)
+        } else {
+            get_bg_tile_addr(ppu, x, y)
+        };
+    let tile_id = ppu.vram.read(0, map_addr);
+    let attr = if ppu.cgb {
+        BgAttributes::from_bits_retain(ppu.vram.read(1, map_addr))
     } else {
-        get_bg_tile_id(ppu, x, y)
+        BgAttributes::empty()
+    };
+    if attr.contains(BgAttributes::x_flip) {
+        x_pos = 7 - x_pos;
+    }
+    if attr.contains(BgAttributes::y_flip) {
+        y_pos = 7 - y_pos;
+    }
+    let bank = attr.contains(BgAttributes::bank) as usize;
+    let bg = BgPixel {
+        color_id: get_pixel_data(ppu, x_pos, y_pos, tile_id, false, bank),
+        attributes: attr.bits(),
+        is_window,
     };
-    let pixel_id = get_pixel_data(ppu, x_pos, y_pos, tile_id, false);
-    let bg_pixel = (ppu.bg_palette & (0b11 << (2 * pixel_id))) >> (2 * pixel_id);
+    let sprite = get_sprite(ppu, x, y);
+    draw_pixel(ppu, x, y, bg, sprite, frame);
+}
 
-    // Sprite Pixel
-    let (obj_color, bg_over_obj) = get_sprite(ppu, x, y);
-    let obj_pixel = if (bg_over_obj && pixel_id > 0) || obj_color == 0xff {
-        None
+// Mix the BG and sprite pixels at (x, y) and draw the result to Frame
+pub(crate) fn draw_pixel(
+    ppu: &mut Ppu,
+    x: usize,
+    y: usize,
+    bg: BgPixel,
+    sprite: Option<SpritePixel>,
+    frame: &mut Frame,
+) {
+    let attr = BgAttributes::from_bits_retain(bg.attributes);
+    let pixel_id = bg.color_id;
+    let bg_rgb = if ppu.cgb {
+        let palette = (attr & BgAttributes::palette).bits();
+        ppu.bg_palettes.rgb(palette, pixel_id)
     } else {
-        Some(obj_color)
+        let bg_pixel = (ppu.bg_palette & (0b11 << (2 * pixel_id))) >> (2 * pixel_id);
+        ppu.palette.0[bg_pixel as usize]
     };
 
-    // Record for GUI
-    if is_window {
-        let color = GB_PALETTE[bg_pixel as usize];
-        ppu.win_screen[x + 160 * y] = Color32::from_rgb(color.0, color.1, color.2);
-        ppu.bg_screen[x + 160 * y] = Color32::BLACK;
-    } else {
-        let color = GB_PALETTE[bg_pixel as usize];
-        ppu.win_screen[x + 160 * y] = Color32::BLACK;
-        ppu.bg_screen[x + 160 * y] = Color32::from_rgb(color.0, color.1, color.2);
-    }
-    if let Some(pixel) = obj_pixel {
-        let color = GB_PALETTE[pixel as usize];
-        ppu.spr_screen[x + 160 * y] = Color32::from_rgb(color.0, color.1, color.2);
-    } else {
-        ppu.spr_screen[x + 160 * y] = Color32::BLACK;
+    // Sprite Pixel. With BG/Window disabled the background counts as color 0.
+    // On CGB that bit instead removes all BG priority so sprites are always on top
+    let bg_enabled = ppu.control.contains(Control::bg_win_enable);
+    let bg_id = if bg_enabled { pixel_id } else { 0 };
+    let bg_over_obj = |sprite: &SpritePixel| {
+        bg_id > 0 && (sprite.bg_priority || attr.contains(BgAttributes::priority))
+    };
+    let obj_rgb = sprite
+        .filter(|sprite| !bg_over_obj(sprite))
+        .map(|sprite| sprite.rgb(ppu));
+
+    // Layer views for the GUI, only while someone is looking at them
+    if ppu.debug_layers {
+        let black = (0, 0, 0);
+        let (win_rgb, only_bg_rgb) = if bg.is_window {
+            (bg_rgb, black)
+        } else {
+            (black, bg_rgb)
+        };
+        ppu.layers.window.set_pixel(x, y, win_rgb);
+        ppu.layers.background.set_pixel(x, y, only_bg_rgb);
+        ppu.layers.sprites.set_pixel(x, y, obj_rgb.unwrap_or(black));
     }
 
     // Decide which has priority and draw to Frame
-    let pixel = match (ppu.control.contains(Control::obj_enable), obj_pixel) {
-        (true, Some(obj_pixel)) => GB_PALETTE[obj_pixel as usize],
+    let pixel = match (ppu.control.contains(Control::obj_enable), obj_rgb) {
+        (true, Some(obj_rgb)) => obj_rgb,
         _ => {
-            if ppu.control.contains(Control::bg_win_enable) {
-                GB_PALETTE[bg_pixel as usize]
+            if bg_enabled || ppu.cgb {
+                bg_rgb
             } else {
-                GB_PALETTE[0]
+                ppu.palette.0[0]
             }
         }
     };
@@ -203,77 +458,145 @@ fn render_pixel(ppu: &mut Ppu, x: usize, y: usize, frame: &mut Frame) {
     frame.set_pixel(x, y, pixel);
 }
 
-pub fn render_scanline(ppu: &mut Ppu, frame: &mut Frame) {
-    let current_scanline = ppu.scanline as usize;
-    for i in 0..Frame::WIDTH {
-        render_pixel(ppu, i, current_scanline, frame);
+// Draws each line a few pixels at a time as mode 3 goes on, so SCX, LCDC and palette
+// writes made during the line apply from the next pixel. Used without accurate_ppu
+pub struct LineRenderer {
+    x: usize,     // Next pixel to draw
+    active: bool, // Still drawing the current line
+}
+
+impl State for LineRenderer {
+    fn save(&self, w: &mut StateWriter) {
+        self.x.save(w);
+        self.active.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.x.load(r)?;
+        self.active.load(r)?;
+        Ok(())
+    }
+}
+
+impl LineRenderer {
+    pub fn new() -> Self {
+        Self {
+            x: 0,
+            active: false,
+        }
+    }
+
+    // Called when mode 3 starts, after the OAM scan
+    pub fn start_line(&mut self) {
+        self.x = 0;
+        self.active = true;
+    }
+
+    // Catch up to the PPU. Once mode 3 is over the rest of the line is drawn at once
+    pub fn tick(&mut self, ppu: &mut Ppu, frame: &mut Frame) {
+        if !self.active {
+            return;
+        }
+        let end = if ppu.is_mode3() {
+            ppu.mode3_pixels()
+        } else {
+            Frame::WIDTH
+        };
+        let y = ppu.scanline as usize;
+        while self.x < end {
+            render_pixel(ppu, self.x, y, frame);
+            self.x += 1;
+        }
+        self.active = self.x < Frame::WIDTH;
     }
 }
 
 // For GUI
 // Tilemap 1: 0x9800 - 0x9BFF
 pub fn tilemap_one(ppu: &mut Ppu) {
+    let pixels = tilemap(ppu, 0x9800);
+    ppu.tilemap_one.copy_from_slice(&pixels);
+}
+
+// For GUI
+// Tilemap 2: 0x9C00 - 0x9FFF
+pub fn tilemap_two(ppu: &mut Ppu) {
+    let pixels = tilemap(ppu, 0x9C00);
+    ppu.tilemap_two.copy_from_slice(&pixels);
+}
+
+// Draw the whole 256x256 tile map at base. On CGB each tile's attributes pick its
+// bank, flips and palette the same as when rendering
+fn tilemap(ppu: &Ppu, base: u16) -> Vec<u8> {
+    let mut pixels = vec![0; 3 * 256 * 256];
     for i in 0..1024 {
         let tile_x = i as usize % 32;
         let tile_y = i as usize / 32;
-        let tile_id = ppu.read_vram(0x9800 + i);
-        let tile_addr = if tile_id > 127 {
-            0x8800 + 16 * (tile_id as u16 - 128)
+        let tile_id = ppu.vram.read(0, base + i);
+        let attr = if ppu.cgb {
+            BgAttributes::from_bits_retain(ppu.vram.read(1, base + i))
         } else {
-            0x8000
-                + 16 * (tile_id as u16)
-                + 0x1000 * (!ppu.control.contains(Control::bg_win_mode) as u16)
+            BgAttributes::empty()
         };
-        for y in 0..8 {
-            let lo_byte = ppu.read_vram(tile_addr + 2 * y);
-            let hi_byte = ppu.read_vram(tile_addr + 2 * y + 1);
-            for x in 0..8 {
-                let pixel = match (lo_byte & (0x80 >> x) > 0, hi_byte & (0x80 >> x) > 0) {
-                    (false, false) => 0,
-                    (true, false) => 1,
-                    (false, true) => 2,
-                    (true, true) => 3,
+        let bank = attr.contains(BgAttributes::bank) as usize;
+        for y in 0..8u8 {
+            for x in 0..8u8 {
+                let x_pos = if attr.contains(BgAttributes::x_flip) {
+                    7 - x
+                } else {
+                    x
+                };
+                let y_pos = if attr.contains(BgAttributes::y_flip) {
+                    7 - y
+                } else {
+                    y
+                };
+                let pixel = get_pixel_data(ppu, x_pos, y_pos, tile_id, false, bank);
+                let color = if ppu.cgb {
+                    ppu.bg_palettes
+                        .rgb((attr & BgAttributes::palette).bits(), pixel)
+                } else {
+                    let bg_pixel = (ppu.bg_palette & (0b11 << (2 * pixel))) >> (2 * pixel);
+                    ppu.palette.0[bg_pixel as usize]
                 };
-                let bg_pixel = (ppu.bg_palette & (0b11 << (2 * pixel))) >> (2 * pixel);
-                let color = GB_PALETTE[bg_pixel as usize];
-                ppu.tilemap_one[8 * tile_x + x + 32 * 8 * (8 * tile_y + y as usize)] =
-                    Color32::from_rgb(color.0, color.1, color.2);
+                let i = 8 * tile_x + x as usize + 32 * 8 * (8 * tile_y + y as usize);
+                put_rgb(&mut pixels, i, color);
             }
         }
     }
+    pixels
 }
 
 // For GUI
-// Tilemap 2: 0x9C00 - 0x9FFF
-pub fn tilemap_two(ppu: &mut Ppu) {
-    for i in 0..1024 {
-        let tile_x = i as usize % 32;
-        let tile_y = i as usize / 32;
-        let tile_id = ppu.read_vram(0x9C00 + i);
-        let tile_addr = if tile_id > 127 {
-            0x8800 + 16 * (tile_id as u16 - 128)
-        } else {
-            0x8000
-                + 16 * (tile_id as u16)
-                + 0x1000 * (!ppu.control.contains(Control::bg_win_mode) as u16)
-        };
-        for y in 0..8 {
-            let lo_byte = ppu.read_vram(tile_addr + 2 * y);
-            let hi_byte = ppu.read_vram(tile_addr + 2 * y + 1);
+// All 384 tiles at 0x8000-0x97FF, 16 per row, giving a 128x192 image. Drawn with BGP,
+// or BG palette 0 on CGB. Only VRAM bank 0
+pub fn tile_data(ppu: &Ppu) -> Vec<u8> {
+    let mut pixels = vec![0; 3 * 128 * 192];
+    for tile in 0..384usize {
+        let tile_x = tile % 16;
+        let tile_y = tile / 16;
+        let base = 0x8000 + 16 * tile as u16;
+        for y in 0..8u16 {
+            let lo_byte = ppu.vram.read(0, base + 2 * y);
+            let hi_byte = ppu.vram.read(0, base + 2 * y + 1);
             for x in 0..8 {
-                let pixel = match (lo_byte & (0x80 >> x) > 0, hi_byte & (0x80 >> x) > 0) {
-                    (false, false) => 0,
-                    (true, false) => 1,
-                    (false, true) => 2,
-                    (true, true) => 3,
+                let lo = (lo_byte >> (7 - x)) & 1;
+                let hi = (hi_byte >> (7 - x)) & 1;
+                let pixel = (hi << 1) | lo;
+                let color = if ppu.cgb {
+                    ppu.bg_palettes.rgb(0, pixel)
+                } else {
+                    ppu.palette.0[((ppu.bg_palette >> (2 * pixel)) & 0b11) as usize]
                 };
-                let bg_pixel = (ppu.bg_palette & (0b11 << (2 * pixel))) >> (2 * pixel);
-                let color = GB_PALETTE[bg_pixel as usize];
-                ppu.tilemap_two[8 * tile_x + x + 32 * 8 * (8 * tile_y + y as usize)] =
-                    Color32::from_rgb(color.0, color.1, color.2);
+                put_rgb(
+                    &mut pixels,
+                    8 * tile_x + x + 128 * (8 * tile_y + y as usize),
+                    color,
+                );
             }
         }
     }
+    pixels
 }
 
 pub fn oam_map(ppu: &mut Ppu) {
@@ -284,8 +607,8 @@ pub fn oam_map(ppu: &mut Ppu) {
         let palette_select = ppu.oam[4 * i + 3] & 0x10 > 0;
         let tile_addr = 0x8000 + 16 * tile_id as u16;
         for y in 0..8 {
-            let lo_byte = ppu.read_vram(tile_addr + 2 * y);
-            let hi_byte = ppu.read_vram(tile_addr + 2 * y + 1);
+            let lo_byte = ppu.vram.read(0, tile_addr + 2 * y);
+            let hi_byte = ppu.vram.read(0, tile_addr + 2 * y + 1);
             for x in 0..8 {
                 let pixel = match (lo_byte & (0x80 >> x) > 0, hi_byte & (0x80 >> x) > 0) {
                     (false, false) => 0,
@@ -298,9 +621,9 @@ pub fn oam_map(ppu: &mut Ppu) {
                 } else {
                     (ppu.obp0 & (0b11 << (2 * pixel))) >> (2 * pixel)
                 };
-                let color = GB_PALETTE[spr_pixel as usize];
-                ppu.sprites[8 * tile_x + x + 8 * 8 * (8 * tile_y + y as usize)] =
-                    Color32::from_rgb(color.0, color.1, color.2);
+                let color = ppu.obj_palette.0[spr_pixel as usize];
+                let i = 8 * tile_x + x + 8 * 8 * (8 * tile_y + y as usize);
+                put_rgb(&mut ppu.sprites, i, color);
             }
         }
     }
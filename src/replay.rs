@@ -0,0 +1,114 @@
+use crate::joypad::JoypadAction;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+// Identifies a recording file. Followed by 10 byte events: cycle (u64 LE), action, pressed
+const MAGIC: &[u8; 4] = b"GBIR";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct JoypadEvent {
+    pub action: JoypadAction,
+    pub pressed: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum ReplayMode {
+    Recording,
+    Playback,
+}
+
+// Joypad events stamped with the machine cycle they happened on. Replaying them against the
+// same ROM reproduces the run exactly since the emulator is otherwise deterministic
+pub struct InputRecorder {
+    pub events: Vec<(u64, JoypadEvent)>,
+    mode: ReplayMode,
+    next: usize,        // Next event to hand out during playback
+    file: Option<File>, // Events are appended as they happen so a crash keeps the recording
+}
+
+impl InputRecorder {
+    pub fn record(path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        Ok(Self {
+            events: Vec::new(),
+            mode: ReplayMode::Recording,
+            next: 0,
+            file: Some(file),
+        })
+    }
+
+    pub fn playback(path: &Path) -> io::Result<Self> {
+        let events = Self::from_bytes(&fs::read(path)?)?;
+        Ok(Self {
+            events,
+            mode: ReplayMode::Playback,
+            next: 0,
+            file: None,
+        })
+    }
+
+    pub fn is_playback(&self) -> bool {
+        self.mode == ReplayMode::Playback
+    }
+
+    pub fn push(&mut self, cycle: u64, event: JoypadEvent) -> io::Result<()> {
+        self.events.push((cycle, event));
+        if let Some(file) = &mut self.file {
+            file.write_all(&Self::event_bytes(cycle, event))?;
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    // Returns the next event once its timestamp is reached. Call until None
+    pub fn poll(&mut self, cycle: u64) -> Option<JoypadEvent> {
+        let &(timestamp, event) = self.events.get(self.next)?;
+        if timestamp > cycle {
+            return None;
+        }
+        self.next += 1;
+        Some(event)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for &(cycle, event) in &self.events {
+            bytes.extend_from_slice(&Self::event_bytes(cycle, event));
+        }
+        bytes
+    }
+
+    fn event_bytes(cycle: u64, event: JoypadEvent) -> [u8; 10] {
+        let mut bytes = [0; 10];
+        bytes[..8].copy_from_slice(&cycle.to_le_bytes());
+        bytes[8] = JoypadAction::ALL
+            .iter()
+            .position(|&action| action == event.action)
+            .unwrap() as u8;
+        bytes[9] = event.pressed as u8;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> io::Result<Vec<(u64, JoypadEvent)>> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        let Some(body) = bytes.strip_prefix(MAGIC) else {
+            return Err(invalid("not an input recording"));
+        };
+        if body.len() % 10 != 0 {
+            return Err(invalid("input recording is truncated"));
+        }
+        body.chunks_exact(10)
+            .map(|chunk| {
+                let cycle = u64::from_le_bytes(chunk[..8].try_into().unwrap());
+                let action = *JoypadAction::ALL
+                    .get(chunk[8] as usize)
+                    .ok_or_else(|| invalid("unknown joypad action in input recording"))?;
+                let pressed = chunk[9] > 0;
+                Ok((cycle, JoypadEvent { action, pressed }))
+            })
+            .collect()
+    }
+}
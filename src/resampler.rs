@@ -0,0 +1,144 @@
+use crate::state::{State, StateError, StateReader, StateWriter};
+
+// APU ticks per second, one per M-cycle
+pub const INPUT_RATE: f64 = 1_048_576.0;
+
+// Converts the APU's (left, right) output from once per M-cycle to the audio device rate.
+// A phase accumulator moves on by output rate / input rate each tick. When it wraps, an
+// output sample falls between the last tick and this one and is linearly interpolated
+// between their samples. Only those two ticks need mixing, which tick() says
+pub struct Resampler {
+    base_step: f64,   // Output samples per tick at the nominal rate
+    step: f64,        // base_step after the rate adjustment
+    phase: f64,       // Progress towards the next output sample
+    due: Option<f32>, // Set by tick() when an output sample falls between previous and this tick
+    previous: (f32, f32),
+}
+
+impl Resampler {
+    pub fn new(output_rate: u32) -> Self {
+        let step = output_rate as f64 / INPUT_RATE;
+        Self {
+            base_step: step,
+            step,
+            phase: 0.0,
+            due: None,
+            previous: (0.0, 0.0),
+        }
+    }
+
+    // Output ratio > 1.0 makes more samples per input sample, to refill a draining audio queue
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.step = self.base_step * ratio;
+    }
+
+    // Move on one tick. Returns whether this tick's sample is needed, either to finish an output
+    // sample now or as the one before the next. If so it must be handed to push()
+    pub fn tick(&mut self) -> bool {
+        self.phase += self.step;
+        self.due = if self.phase >= 1.0 {
+            self.phase -= 1.0;
+            // phase is now how far past the output sample this tick is
+            Some((1.0 - self.phase / self.step) as f32)
+        } else {
+            None
+        };
+        self.due.is_some() || self.phase + self.step >= 1.0
+    }
+
+    // This tick's sample. Returns the output sample if one fell since the previous tick
+    pub fn push(&mut self, sample: (f32, f32)) -> Option<(f32, f32)> {
+        let previous = self.previous;
+        self.previous = sample;
+        self.due.take().map(|t| {
+            (
+                previous.0 + (sample.0 - previous.0) * t,
+                previous.1 + (sample.1 - previous.1) * t,
+            )
+        })
+    }
+}
+
+// The rate and ratio come from the frontend and are left as they are
+impl State for Resampler {
+    fn save(&self, w: &mut StateWriter) {
+        self.phase.save(w);
+        self.previous.0.save(w);
+        self.previous.1.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.phase.load(r)?;
+        self.previous.0.load(r)?;
+        self.previous.1.load(r)?;
+        self.due = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Feed samples one tick each, keeping every output sample's left channel
+    fn resample(resampler: &mut Resampler, samples: impl Iterator<Item = f32>) -> Vec<f32> {
+        let mut output = Vec::new();
+        for sample in samples {
+            // Pushing every tick is the same as only pushing those tick() asks for
+            resampler.tick();
+            if let Some((left, right)) = resampler.push((sample, -sample)) {
+                assert_eq!(left, -right);
+                output.push(left);
+            }
+        }
+        output
+    }
+
+    #[test]
+    fn test_output_count() {
+        for rate in [44_100, 48_000] {
+            let mut resampler = Resampler::new(rate);
+            let output = resample(&mut resampler, (0..INPUT_RATE as usize).map(|_| 0.0));
+            assert!(output.len().abs_diff(rate as usize) <= 1, "{rate}");
+        }
+        // A ratio of 1.01 stretches it to 1% more
+        let mut resampler = Resampler::new(48_000);
+        resampler.set_ratio(1.01);
+        let output = resample(&mut resampler, (0..INPUT_RATE as usize).map(|_| 0.0));
+        assert!(output.len().abs_diff(48_480) <= 1);
+    }
+
+    #[test]
+    fn test_interpolation() {
+        // Tick n's sample is n, so each output sample is the time it falls at: k / step
+        let mut resampler = Resampler::new(44_100);
+        let output = resample(&mut resampler, (1..=2000).map(|n| n as f32));
+        assert_eq!(output.len(), 84);
+        for (k, sample) in (1..).zip(output) {
+            let expected = k as f64 * INPUT_RATE / 44_100.0;
+            assert!(
+                (sample as f64 - expected).abs() < 1e-3,
+                "{sample} vs {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_square_wave_frequency() {
+        // 1 kHz square wave for a second
+        let half_period = INPUT_RATE / 2000.0;
+        let square = (0..INPUT_RATE as usize).map(|n| {
+            if ((n as f64 / half_period) as u64).is_multiple_of(2) {
+                1.0
+            } else {
+                -1.0
+            }
+        });
+        let output = resample(&mut Resampler::new(48_000), square);
+        let rising_edges = output
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] >= 0.0)
+            .count();
+        assert!(rising_edges.abs_diff(1000) <= 1, "{rising_edges}");
+    }
+}
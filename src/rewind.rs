@@ -0,0 +1,244 @@
+use crate::cpu::Cpu;
+
+use std::collections::VecDeque;
+
+// Snapshot every other frame and keep about 10 seconds of them
+pub const DEFAULT_INTERVAL: u32 = 2;
+pub const DEFAULT_CAPACITY: usize = 300;
+pub const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+// Ring buffer of save states for rewinding. Only the newest state is kept whole, each older
+// one is stored as a run length encoded XOR against the state after it. Most of memory is
+// unchanged between snapshots so those are small. The oldest states are dropped first once
+// either the state count or the memory cap is reached
+pub struct Rewind {
+    interval: u32,            // Frames between snapshots
+    capacity: usize,          // Most states kept, including the newest
+    max_bytes: usize,         // Cap on the memory used by all states
+    frames: u32,              // Frames since the last snapshot
+    newest: Vec<u8>,          // Empty when nothing is recorded
+    older: VecDeque<Vec<u8>>, // Deltas, oldest at the front
+    bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, capacity: usize, max_bytes: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            max_bytes,
+            frames: 0,
+            newest: Vec::new(),
+            older: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    // Number of states that can be rewound to
+    pub fn len(&self) -> usize {
+        if self.newest.is_empty() {
+            0
+        } else {
+            self.older.len() + 1
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames = 0;
+        self.newest.clear();
+        self.older.clear();
+        self.bytes = 0;
+    }
+
+    // Call once after each emulated frame. Snapshots every interval frames
+    pub fn record(&mut self, cpu: &Cpu) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+        self.push(cpu.save_state());
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if !self.newest.is_empty() {
+            let delta = encode(&state, &self.newest);
+            self.bytes += delta.len();
+            self.older.push_back(delta);
+        }
+        self.bytes = self.bytes - self.newest.len() + state.len();
+        self.newest = state;
+
+        while self.older.len() + 1 > self.capacity || self.bytes > self.max_bytes {
+            let Some(oldest) = self.older.pop_front() else {
+                break;
+            };
+            self.bytes -= oldest.len();
+        }
+    }
+
+    // Newest recorded state, removed from the buffer. The one before it becomes the newest
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.newest.is_empty() {
+            return None;
+        }
+        let state = std::mem::take(&mut self.newest);
+        self.bytes -= state.len();
+        if let Some(delta) = self.older.pop_back() {
+            self.bytes -= delta.len();
+            self.newest = decode(&state, &delta);
+            self.bytes += self.newest.len();
+        }
+        self.frames = 0;
+        Some(state)
+    }
+
+    // Step back to the newest recorded state. Returns false once there's nothing left
+    pub fn rewind(&mut self, cpu: &mut Cpu) -> bool {
+        let Some(state) = self.pop() else {
+            return false;
+        };
+        if let Err(err) = cpu.load_state(&state) {
+            eprintln!("Could not rewind: {err}");
+            self.clear();
+            return false;
+        }
+        true
+    }
+}
+
+// Delta of old against new: old's length as u32 LE, then pairs of (unchanged byte count,
+// changed byte count) as u32 LE covering the bytes both share, each followed by the changed
+// bytes XORed with new. Whatever old has past the end of new follows as is
+fn encode(new: &[u8], old: &[u8]) -> Vec<u8> {
+    let common = new.len().min(old.len());
+    let (new, tail) = (&new[..common], &old[common..]);
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&(old.len() as u32).to_le_bytes());
+    let mut i = 0;
+    while i < common {
+        let same = new[i..]
+            .iter()
+            .zip(&old[i..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        i += same;
+        let changed = new[i..]
+            .iter()
+            .zip(&old[i..])
+            .take_while(|(a, b)| a != b)
+            .count();
+        delta.extend_from_slice(&(same as u32).to_le_bytes());
+        delta.extend_from_slice(&(changed as u32).to_le_bytes());
+        delta.extend(
+            new[i..i + changed]
+                .iter()
+                .zip(&old[i..])
+                .map(|(a, b)| a ^ b),
+        );
+        i += changed;
+    }
+    delta.extend_from_slice(tail);
+    delta
+}
+
+// Rebuild the old state from the new one and its delta
+fn decode(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let read_u32 = |d: usize| u32::from_le_bytes(delta[d..d + 4].try_into().unwrap()) as usize;
+    let old_len = read_u32(0);
+    let common = new.len().min(old_len);
+    let mut old = new[..common].to_vec();
+    let mut i = 0;
+    let mut d = 4;
+    while i < common {
+        let same = read_u32(d);
+        let changed = read_u32(d + 4);
+        d += 8;
+        i += same;
+        for (byte, diff) in old[i..i + changed].iter_mut().zip(&delta[d..d + changed]) {
+            *byte ^= diff;
+        }
+        i += changed;
+        d += changed;
+    }
+    old.extend_from_slice(&delta[d..]);
+    old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+    use crate::gameboy::Gameboy;
+
+    // Draws striped tiles and keeps scrolling them so every frame is different
+    #[rustfmt::skip]
+    const SCROLL: [u8; 27] = [
+        0xaf,             // XOR A
+        0xe0, 0x40,       // LDH (LCDC), A
+        0x21, 0x00, 0x80, // LD HL, $8000
+        0x06, 0x10,       // LD B, 16
+        0x3e, 0x0f,       // fill: LD A, $0F
+        0x22,             // LD (HL+), A
+        0x05,             // DEC B
+        0x20, 0xfa,       // JR NZ, fill
+        0x3e, 0x91,       // LD A, $91
+        0xe0, 0x40,       // LDH (LCDC), A
+        0xf0, 0x43,       // loop: LDH A, (SCX)
+        0x3c,             // INC A
+        0xe0, 0x43,       // LDH (SCX), A
+        0x18, 0xf9,       // JR loop
+        0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_delta_round_trip() {
+        let new = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        for old in [
+            vec![1, 2, 3, 4, 5, 6, 7, 8],
+            vec![1, 9, 9, 4, 5, 6, 0, 8],
+            vec![9, 2, 3],
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            vec![],
+        ] {
+            assert_eq!(decode(&new, &encode(&new, &old)), old);
+        }
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut rewind = Rewind::new(1, 3, usize::MAX);
+        for i in 0..5u8 {
+            rewind.push(vec![i; 16]);
+        }
+        assert_eq!(rewind.len(), 3);
+        assert_eq!(rewind.pop(), Some(vec![4; 16]));
+        assert_eq!(rewind.pop(), Some(vec![3; 16]));
+        assert_eq!(rewind.pop(), Some(vec![2; 16]));
+        assert_eq!(rewind.pop(), None);
+    }
+
+    #[test]
+    fn test_rewind_60_frames() {
+        let mut gameboy = Gameboy::new(&test_rom(&[(0x0150, &SCROLL)])).unwrap();
+        let mut rewind = Rewind::new(1, DEFAULT_CAPACITY, DEFAULT_MAX_BYTES);
+        let mut hashes = Vec::new();
+        for _ in 0..90 {
+            hashes.push(gameboy.run_frame().hash());
+            rewind.record(&gameboy.cpu);
+        }
+
+        // The newest state is the current frame, so one more to go back 60
+        for _ in 0..61 {
+            assert!(rewind.rewind(&mut gameboy.cpu));
+        }
+        assert_eq!(rewind.len(), 29);
+        for hash in &hashes[30..40] {
+            assert_eq!(gameboy.run_frame().hash(), *hash);
+        }
+    }
+}
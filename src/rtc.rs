@@ -0,0 +1,261 @@
+// MBC3 real time clock. Kept as a count since a base time instead of ticking
+// registers, so it keeps going while the emulator is closed. The registers are worked out
+// from the count when the game latches them
+use crate::state::{State, StateError, StateReader, StateWriter};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The counter is kept in milliseconds so halting and restarting doesn't drop part seconds
+const SECOND: u64 = 1000;
+const DAY: u64 = 86400 * SECOND;
+// The day counter is 9 bits. Going past 511 wraps it and sets the carry bit
+const COUNTER_WRAP: u64 = 512 * DAY;
+// Save file footer: live and latched registers as 5 u32 each, then a u64 timestamp. The
+// older 44 byte form has a u32 timestamp
+pub const FOOTER_LEN: usize = 48;
+
+// Where the time comes from. Swapped out to test the clock without waiting
+pub trait Clock: Send {
+    fn now(&self) -> u64; // Unix time in milliseconds
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64)
+    }
+}
+
+pub struct Rtc {
+    clock: Box<dyn Clock>,
+    base: u64, // Unix time in ms the counter was last 0. Only used while running
+    held: u64, // Counter while halted
+    halted: bool,
+    carry: bool,      // Stays set until the game clears it
+    latched: [u8; 5], // S, M, H, DL, DH as of the last latch
+}
+
+// The clock is not saved, only what it's measured against
+impl State for Rtc {
+    fn save(&self, w: &mut StateWriter) {
+        self.base.save(w);
+        self.held.save(w);
+        self.halted.save(w);
+        self.carry.save(w);
+        self.latched.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.base.load(r)?;
+        self.held.load(r)?;
+        self.halted.load(r)?;
+        self.carry.load(r)?;
+        self.latched.load(r)
+    }
+}
+
+impl Rtc {
+    pub fn new(clock: Box<dyn Clock>) -> Self {
+        let base = clock.now();
+        Self {
+            clock,
+            base,
+            held: 0,
+            halted: false,
+            carry: false,
+            latched: [0; 5],
+        }
+    }
+
+    // Milliseconds on the counter, wrapping the day counter if it has run past 511
+    fn counter(&mut self) -> u64 {
+        let mut counter = if self.halted {
+            self.held
+        } else {
+            self.clock.now().saturating_sub(self.base)
+        };
+        if counter >= COUNTER_WRAP {
+            self.carry = true;
+            let wrapped = counter - counter % COUNTER_WRAP;
+            counter %= COUNTER_WRAP;
+            if self.halted {
+                self.held = counter;
+            } else {
+                self.base += wrapped;
+            }
+        }
+        counter
+    }
+
+    fn set_counter(&mut self, counter: u64) {
+        if self.halted {
+            self.held = counter;
+        } else {
+            self.base = self.clock.now().saturating_sub(counter);
+        }
+    }
+
+    // Live S, M, H, DL, DH
+    fn registers(&mut self) -> [u8; 5] {
+        let counter = self.counter();
+        let days = counter / DAY;
+        let seconds = counter / SECOND;
+        [
+            (seconds % 60) as u8,
+            (seconds / 60 % 60) as u8,
+            (seconds / 3600 % 24) as u8,
+            days as u8,
+            ((days >> 8) as u8 & 0x01) | ((self.halted as u8) << 6) | ((self.carry as u8) << 7),
+        ]
+    }
+
+    // Copy the live registers for reading. While halted every latch gives the same time
+    pub fn latch(&mut self) {
+        self.latched = self.registers();
+    }
+
+    // reg is 0-4 for S, M, H, DL, DH. Reads see the latched copy
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched[reg as usize]
+    }
+
+    // Writes set the live clock, moving the base so it carries on from the new time.
+    // Halting freezes the count where it is and clearing the halt bit picks up from there.
+    // Writing the seconds restarts the current second as on hardware
+    pub fn write(&mut self, reg: u8, val: u8) {
+        let part_second = if reg == 0 { 0 } else { self.counter() % SECOND };
+        let mut regs = self.registers();
+        regs[reg as usize] = val;
+        self.latched[reg as usize] = val;
+
+        let dh = regs[4];
+        self.carry = dh & 0x80 != 0;
+        self.halted = dh & 0x40 != 0;
+        self.set_counter(to_counter(regs.map(u64::from)) + part_second);
+    }
+
+    // Footer appended to the cartridge RAM in the .sav file, in the layout VBA-M and BGB use
+    pub fn footer(&mut self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        for reg in self.registers().into_iter().chain(self.latched) {
+            footer.extend_from_slice(&(reg as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&(self.clock.now() / SECOND).to_le_bytes());
+        footer
+    }
+
+    // Time that passed between saving and now is added on unless the clock was halted
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() < 44 {
+            return;
+        }
+        let word = |i: usize| u32::from_le_bytes(footer[i * 4..i * 4 + 4].try_into().unwrap());
+        let saved_at = match footer.get(40..48) {
+            Some(bytes) => u64::from_le_bytes(bytes.try_into().unwrap()),
+            None => word(10) as u64,
+        };
+        for i in 0..5 {
+            self.latched[i] = word(i + 5) as u8;
+        }
+
+        let regs = [0, 1, 2, 3, 4].map(|i| word(i) as u64 & 0xff);
+        let counter = to_counter(regs);
+        self.carry = regs[4] & 0x80 != 0;
+        self.halted = regs[4] & 0x40 != 0;
+        self.held = counter;
+        self.base = (saved_at * SECOND).saturating_sub(counter);
+    }
+}
+
+// S, M, H, DL, DH to milliseconds. Out of range values just count for more
+fn to_counter([s, m, h, dl, dh]: [u64; 5]) -> u64 {
+    ((dh & 0x01) << 8 | dl) * DAY + (h * 3600 + m * 60 + s) * SECOND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    // Time that only moves when the test says so
+    #[derive(Clone)]
+    struct MockClock(Arc<AtomicU64>);
+
+    impl MockClock {
+        fn advance(&self, ms: u64) {
+            self.0.fetch_add(ms, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    fn setup() -> (Rtc, MockClock) {
+        let clock = MockClock(Arc::new(AtomicU64::new(1_700_000_000 * SECOND)));
+        (Rtc::new(Box::new(clock.clone())), clock)
+    }
+
+    fn latched(rtc: &mut Rtc) -> [u8; 5] {
+        rtc.latch();
+        [0, 1, 2, 3, 4].map(|reg| rtc.read(reg))
+    }
+
+    #[test]
+    fn test_counts_real_time() {
+        let (mut rtc, clock) = setup();
+        clock.advance(DAY + (2 * 3600 + 3 * 60 + 4) * SECOND);
+        assert_eq!(latched(&mut rtc), [4, 3, 2, 1, 0]);
+
+        // Reads keep the latched time until the next latch
+        clock.advance(10 * SECOND);
+        assert_eq!(rtc.read(0), 4);
+        assert_eq!(latched(&mut rtc)[0], 14);
+    }
+
+    #[test]
+    fn test_day_counter_carry() {
+        let (mut rtc, clock) = setup();
+        clock.advance(511 * DAY);
+        assert_eq!(latched(&mut rtc)[3..], [0xff, 0x01]);
+        clock.advance(DAY);
+        assert_eq!(latched(&mut rtc)[3..], [0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_halt_freezes_latch() {
+        let (mut rtc, clock) = setup();
+        clock.advance(30 * SECOND);
+        rtc.write(4, 0x40);
+        let halted = latched(&mut rtc);
+        assert_eq!(halted, [30, 0, 0, 0, 0x40]);
+
+        clock.advance(90 * SECOND);
+        assert_eq!(latched(&mut rtc), halted);
+
+        // Clearing the halt bit carries on from the frozen time
+        rtc.write(4, 0x00);
+        clock.advance(5 * SECOND);
+        assert_eq!(latched(&mut rtc), [35, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_footer_adds_time_while_closed() {
+        let (mut rtc, clock) = setup();
+        clock.advance(100 * SECOND);
+        let footer = rtc.footer();
+        assert_eq!(footer.len(), FOOTER_LEN);
+
+        clock.advance(3600 * SECOND);
+        let mut loaded = Rtc::new(Box::new(clock.clone()));
+        loaded.load_footer(&footer);
+        assert_eq!(latched(&mut loaded), [40, 1, 1, 0, 0]);
+    }
+}
@@ -10,12 +10,13 @@ use sdl2::keyboard::Keycode;
 // use sdl2::video::{Window, WindowContext};
 //use sdl2::EventPump;
 
-//use crate::joypad::Joypad;
+//use gb_emulator::joypad::Joypad;
 
 // const WIDTH: f64 = 160.0;
 // const HEIGHT: f64 = 144.0;
 
-pub fn setup() -> AudioQueue<f32> {
+// Opens an interleaved stereo queue. The device may pick a different rate, check spec().freq
+pub fn setup(sample_rate: i32) -> AudioQueue<f32> {
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
 
@@ -36,8 +37,8 @@ pub fn setup() -> AudioQueue<f32> {
     //Audio system
     let audio_subsystem = sdl_context.audio().unwrap();
     let desired_spec = AudioSpecDesired {
-        freq: Some(44_100),
-        channels: Some(1),
+        freq: Some(sample_rate),
+        channels: Some(2),
         samples: Some(1024),
     };
     let audio_device = audio_subsystem
@@ -0,0 +1,153 @@
+// Link cable backends. The side with the internal clock drives each transfer: it sends its
+// SB byte and waits for the other side's SB in return. The side on the external clock
+// answers whenever a byte has arrived and it has a transfer pending
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+// How long the clock master waits for an answer before reading 0xFF as if unplugged.
+// The whole emulator waits with it, so keep it short
+const REPLY_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Messages are a tag byte then the data byte
+const TRANSFER: u8 = 0;
+const REPLY: u8 = 1;
+
+pub trait SerialLink: Send {
+    // Send a byte as the clock master and return the other side's byte
+    fn exchange(&mut self, byte: u8) -> u8;
+    // As the clock follower, answer a byte the master sent with ours and return theirs.
+    // None if nothing has been sent
+    fn respond(&mut self, byte: u8) -> Option<u8>;
+}
+
+// Nothing plugged in. The data line is pulled high so every transfer reads 0xFF
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xff
+    }
+
+    fn respond(&mut self, _byte: u8) -> Option<u8> {
+        None
+    }
+}
+
+// Two links joined in process, for running two Gameboys side by side
+pub struct ChannelLink {
+    transfers_out: Sender<u8>,
+    replies_out: Sender<u8>,
+    transfers: Receiver<u8>,
+    replies: Receiver<u8>,
+}
+
+impl ChannelLink {
+    pub fn pair() -> (ChannelLink, ChannelLink) {
+        let (transfers_a, transfers_from_b) = mpsc::channel();
+        let (transfers_b, transfers_from_a) = mpsc::channel();
+        let (replies_a, replies_from_b) = mpsc::channel();
+        let (replies_b, replies_from_a) = mpsc::channel();
+        (
+            ChannelLink {
+                transfers_out: transfers_a,
+                replies_out: replies_a,
+                transfers: transfers_from_a,
+                replies: replies_from_a,
+            },
+            ChannelLink {
+                transfers_out: transfers_b,
+                replies_out: replies_b,
+                transfers: transfers_from_b,
+                replies: replies_from_b,
+            },
+        )
+    }
+}
+
+impl SerialLink for ChannelLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        // Drop any answer that came in after an earlier timeout
+        while self.replies.try_recv().is_ok() {}
+        if self.transfers_out.send(byte).is_err() {
+            return 0xff;
+        }
+        self.replies.recv_timeout(REPLY_TIMEOUT).unwrap_or(0xff)
+    }
+
+    fn respond(&mut self, byte: u8) -> Option<u8> {
+        let received = self.transfers.try_recv().ok()?;
+        let _ = self.replies_out.send(byte);
+        Some(received)
+    }
+}
+
+// Link to another instance over TCP. A reader thread sorts incoming messages into transfers
+// and replies so polling for a transfer doesn't touch the socket
+pub struct TcpLink {
+    stream: TcpStream,
+    transfers: Receiver<u8>,
+    replies: Receiver<u8>,
+}
+
+impl TcpLink {
+    // Wait for the other instance to connect
+    pub fn listen(port: u16) -> io::Result<TcpLink> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        println!("Waiting for link cable connection on port {port}");
+        let (stream, peer) = listener.accept()?;
+        println!("Link cable connected to {peer}");
+        TcpLink::new(stream)
+    }
+
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpLink> {
+        TcpLink::new(TcpStream::connect(addr)?)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        let mut reader = stream.try_clone()?;
+        let (transfer_tx, transfers) = mpsc::channel();
+        let (reply_tx, replies) = mpsc::channel();
+        thread::spawn(move || {
+            let mut message = [0; 2];
+            while reader.read_exact(&mut message).is_ok() {
+                let sent = match message[0] {
+                    TRANSFER => transfer_tx.send(message[1]),
+                    _ => reply_tx.send(message[1]),
+                };
+                if sent.is_err() {
+                    break;
+                }
+            }
+            eprintln!("Link cable disconnected");
+        });
+        Ok(TcpLink {
+            stream,
+            transfers,
+            replies,
+        })
+    }
+
+    fn send(&mut self, tag: u8, byte: u8) -> bool {
+        self.stream.write_all(&[tag, byte]).is_ok()
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        while self.replies.try_recv().is_ok() {}
+        if !self.send(TRANSFER, byte) {
+            return 0xff;
+        }
+        self.replies.recv_timeout(REPLY_TIMEOUT).unwrap_or(0xff)
+    }
+
+    fn respond(&mut self, byte: u8) -> Option<u8> {
+        let received = self.transfers.try_recv().ok()?;
+        self.send(REPLY, byte);
+        Some(received)
+    }
+}
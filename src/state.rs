@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+// Save state layout: MAGIC, VERSION, ROM hash (u64), then Cpu followed by Bus.
+// Bump VERSION whenever a component changes what it writes
+const MAGIC: &[u8; 4] = b"GBSS";
+const VERSION: u8 = 28;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u8),
+    RomMismatch,
+    Truncated,
+    SizeMismatch, // A saved buffer doesn't match the size of the one it is loaded into
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "data is not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {version} is not supported")
+            }
+            StateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::SizeMismatch => write!(f, "save state does not match this cartridge"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+// Implemented by every piece of emulator state. Fields are written in declaration order
+// and read back in the same order
+pub trait State {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> Self {
+        let mut w = Self {
+            data: MAGIC.to_vec(),
+        };
+        w.data.push(VERSION);
+        rom_hash.save(&mut w);
+        w
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    // Checks the header before any state is touched
+    pub fn new(data: &'a [u8], rom_hash: u64) -> Result<Self, StateError> {
+        let data = data.strip_prefix(MAGIC).ok_or(StateError::NotAState)?;
+        let mut r = Self { data };
+        let version: u8 = r.read()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if r.read::<u64>()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(r)
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read<T: State + Default>(&mut self) -> Result<T, StateError> {
+        let mut val = T::default();
+        val.load(self)?;
+        Ok(val)
+    }
+}
+
+// FNV-1a over the whole ROM so a state can't be loaded into a different game
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+macro_rules! impl_state_int {
+    ($($t:ty),*) => {
+        $(
+            impl State for $t {
+                fn save(&self, w: &mut StateWriter) {
+                    w.write(&self.to_le_bytes());
+                }
+
+                fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+                    let bytes = r.take(std::mem::size_of::<$t>())?;
+                    *self = <$t>::from_le_bytes(bytes.try_into().unwrap());
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_state_int!(u8, u16, u32, u64, f32, f64);
+
+// usize is stored as u64 so states move between 32 and 64 bit builds
+impl State for usize {
+    fn save(&self, w: &mut StateWriter) {
+        (*self as u64).save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        *self = r.read::<u64>()? as usize;
+        Ok(())
+    }
+}
+
+impl State for bool {
+    fn save(&self, w: &mut StateWriter) {
+        (*self as u8).save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        *self = r.read::<u8>()? > 0;
+        Ok(())
+    }
+}
+
+impl<T: State, const N: usize> State for [T; N] {
+    fn save(&self, w: &mut StateWriter) {
+        for item in self {
+            item.save(w);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for item in self {
+            item.load(r)?;
+        }
+        Ok(())
+    }
+}
+
+// Length prefixed. Loading resizes the Vec to the saved length
+impl<T: State + Default> State for Vec<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        for item in self {
+            item.save(w);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let len: usize = r.read()?;
+        self.clear();
+        for _ in 0..len {
+            self.push(r.read()?);
+        }
+        Ok(())
+    }
+}
+
+// Same layout as Vec
+impl<T: State + Default> State for VecDeque<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        for item in self {
+            item.save(w);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let len: usize = r.read()?;
+        self.clear();
+        for _ in 0..len {
+            self.push_back(r.read()?);
+        }
+        Ok(())
+    }
+}
+
+// Buffers sized by the cartridge header keep their size. A saved length that differs is rejected
+pub fn load_buffer(buf: &mut [u8], r: &mut StateReader) -> Result<(), StateError> {
+    let len: usize = r.read()?;
+    if len != buf.len() {
+        return Err(StateError::SizeMismatch);
+    }
+    buf.copy_from_slice(r.take(len)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+    use crate::gameboy::Gameboy;
+
+    use std::fs;
+
+    fn tetris() -> Gameboy {
+        let rom = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/tetris.gb")).unwrap();
+        Gameboy::new(&rom).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut gameboy = tetris();
+        for _ in 0..40 {
+            gameboy.run_frame();
+        }
+        let saved = gameboy.cpu.save_state();
+
+        let mut loaded = tetris();
+        loaded.cpu.load_state(&saved).unwrap();
+        assert_eq!(loaded.cpu.save_state(), saved);
+
+        // Both carry on the same way
+        for _ in 0..20 {
+            gameboy.run_frame();
+            loaded.run_frame();
+        }
+        assert_eq!(
+            loaded.cpu.bus.last_frame.hash(),
+            gameboy.cpu.bus.last_frame.hash()
+        );
+        assert_eq!(loaded.cpu.save_state(), gameboy.cpu.save_state());
+    }
+
+    #[test]
+    fn test_rom_mismatch() {
+        let saved = tetris().cpu.save_state();
+        let mut other = Gameboy::new(&test_rom(&[])).unwrap();
+        let before = other.cpu.save_state();
+
+        assert_eq!(other.cpu.load_state(&saved), Err(StateError::RomMismatch));
+        // Nothing was overwritten
+        assert_eq!(other.cpu.save_state(), before);
+    }
+
+    #[test]
+    fn test_bad_headers() {
+        let mut gameboy = tetris();
+        let saved = gameboy.cpu.save_state();
+
+        assert_eq!(
+            gameboy.cpu.load_state(b"not a state"),
+            Err(StateError::NotAState)
+        );
+        let mut old = saved.clone();
+        old[MAGIC.len()] = VERSION - 1;
+        assert_eq!(
+            gameboy.cpu.load_state(&old),
+            Err(StateError::UnsupportedVersion(VERSION - 1))
+        );
+
+        // Running on a bit makes a truncated load's partial overwrite show
+        for _ in 0..10 {
+            gameboy.run_frame();
+        }
+        let before = gameboy.cpu.save_state();
+        assert_eq!(
+            gameboy.cpu.load_state(&saved[..saved.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(gameboy.cpu.save_state(), before);
+    }
+}
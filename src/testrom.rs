@@ -0,0 +1,254 @@
+// Runs blargg and mooneye test ROMs headlessly and works out whether they passed.
+// blargg ROMs print "Passed" or "Failed" over serial, and newer ones also leave a result at
+// 0xA000 behind the signature DE B0 61. mooneye ROMs finish by running LD B, B with the
+// Fibonacci numbers 3, 5, 8, 13, 21, 34 in B, C, D, E, H, L on success or all 0x42 on failure
+use crate::cartridge::CartridgeError;
+use crate::gameboy::Gameboy;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Frames a ROM gets before it counts as timed out. About a minute of emulated time
+pub const DEFAULT_TIMEOUT_FRAMES: u32 = 3600;
+
+const LD_B_B: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Outcome {
+    Passed,
+    Failed(String), // Whatever the ROM reported
+    Timeout,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub frames: u32, // Frames run before the outcome was known
+}
+
+// Run a ROM until it reports a result or timeout frames have passed
+pub fn run(rom: &[u8], timeout: u32) -> Result<(Outcome, u32), CartridgeError> {
+    let mut gameboy = Gameboy::new(rom)?;
+    for frame in 0..timeout {
+        loop {
+            if let Some(outcome) = mooneye_result(&gameboy) {
+                return Ok((outcome, frame));
+            }
+            if gameboy.step(false).is_some() {
+                break;
+            }
+        }
+        if let Some(outcome) = blargg_result(&gameboy) {
+            return Ok((outcome, frame + 1));
+        }
+    }
+    Ok((Outcome::Timeout, timeout))
+}
+
+// Run a blargg ROM from disk for up to max_cycles M-cycles. Only the serial text and the
+// 0xA000 result count, so a ROM still printing at the end is a timeout
+pub fn run_blargg_test(rom_path: &Path, max_cycles: u64) -> TestResult {
+    let name = rom_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let mut gameboy = match fs::read(rom_path)
+        .map_err(CartridgeError::from)
+        .and_then(|rom| Gameboy::new(&rom))
+    {
+        Ok(gameboy) => gameboy,
+        Err(err) => {
+            return TestResult {
+                name,
+                outcome: Outcome::Failed(format!("could not load: {err}")),
+                frames: 0,
+            }
+        }
+    };
+
+    let mut frames = 0;
+    let mut outcome = Outcome::Timeout;
+    while gameboy.cpu.bus.total_cycles < max_cycles {
+        if gameboy.step(false).is_none() {
+            continue;
+        }
+        frames += 1;
+        if let Some(result) = blargg_result(&gameboy) {
+            outcome = result;
+            break;
+        }
+    }
+    if outcome == Outcome::Timeout {
+        outcome = blargg_result(&gameboy).unwrap_or(Outcome::Timeout);
+    }
+    TestResult {
+        name,
+        outcome,
+        frames,
+    }
+}
+
+// Run every .gb and .gbc file in dir, sorted by name. timeout gives each ROM its frame budget.
+// A missing directory gives no results rather than an error so runs without ROMs still pass
+pub fn run_dir(dir: &Path, timeout: impl Fn(&Path) -> u32) -> io::Result<Vec<TestResult>> {
+    if !dir.is_dir() {
+        eprintln!("No test ROMs at {}, skipping", dir.display());
+        return Ok(Vec::new());
+    }
+    let mut paths: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "gb" || ext == "gbc")
+        })
+        .collect();
+    paths.sort();
+
+    let mut results = Vec::new();
+    for path in paths {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let rom = fs::read(&path)?;
+        let (outcome, frames) = match run(&rom, timeout(&path)) {
+            Ok(result) => result,
+            Err(err) => (Outcome::Failed(format!("could not load: {err}")), 0),
+        };
+        results.push(TestResult {
+            name,
+            outcome,
+            frames,
+        });
+    }
+    Ok(results)
+}
+
+// Print one line per ROM then the totals. Returns true if every ROM passed
+pub fn print_summary(results: &[TestResult]) -> bool {
+    for result in results {
+        match &result.outcome {
+            Outcome::Passed => println!("PASS     {} ({} frames)", result.name, result.frames),
+            Outcome::Failed(reason) => {
+                println!(
+                    "FAIL     {} ({} frames): {reason}",
+                    result.name, result.frames
+                )
+            }
+            Outcome::Timeout => println!("TIMEOUT  {} ({} frames)", result.name, result.frames),
+        }
+    }
+    let passed = results
+        .iter()
+        .filter(|result| result.outcome == Outcome::Passed)
+        .count();
+    println!("{passed}/{} passed", results.len());
+    passed == results.len()
+}
+
+// Checked before each instruction so the registers are read at the LD B, B itself
+fn mooneye_result(gameboy: &Gameboy) -> Option<Outcome> {
+    let cpu = &gameboy.cpu;
+    // In prefixed mode 0x40 is the operand of BIT 0, B
+    if cpu.prefixed_mode || cpu.bus.peek(cpu.program_counter) != LD_B_B {
+        return None;
+    }
+    let regs = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+    if regs == MOONEYE_PASS {
+        Some(Outcome::Passed)
+    } else if regs == MOONEYE_FAIL {
+        Some(Outcome::Failed("registers all 0x42".to_string()))
+    } else {
+        None
+    }
+}
+
+fn blargg_result(gameboy: &Gameboy) -> Option<Outcome> {
+    let bus = &gameboy.cpu.bus;
+    let serial = String::from_utf8_lossy(&bus.serial_output);
+    if serial.contains("Passed") {
+        return Some(Outcome::Passed);
+    }
+    if serial.contains("Failed") {
+        return Some(Outcome::Failed(serial.trim().to_string()));
+    }
+
+    let signature = [bus.peek(0xA001), bus.peek(0xA002), bus.peek(0xA003)];
+    if signature != BLARGG_SIGNATURE {
+        return None;
+    }
+    match bus.peek(0xA000) {
+        BLARGG_RUNNING => None,
+        0 => Some(Outcome::Passed),
+        code => {
+            // Result text is a zero terminated string from 0xA004
+            let text: Vec<u8> = (0xA004..0xBFFF)
+                .map(|addr| bus.peek(addr))
+                .take_while(|&byte| byte != 0)
+                .collect();
+            Some(Outcome::Failed(format!(
+                "code {code}: {}",
+                String::from_utf8_lossy(&text).trim()
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test_rom;
+
+    // Prints the zero terminated text at 0x0200 over serial one byte at a time, waiting for
+    // each transfer to finish, then loops forever
+    #[rustfmt::skip]
+    const PRINT: [u8; 23] = [
+        0x21, 0x00, 0x02, // LD HL, $0200
+        0x2a,             // LD A, (HL+)
+        0xb7,             // OR A
+        0x28, 0x0e,       // JR Z, done
+        0xe0, 0x01,       // LDH (SB), A
+        0x3e, 0x81,       // LD A, $81
+        0xe0, 0x02,       // LDH (SC), A
+        0xf0, 0x02,       // LDH A, (SC)
+        0xcb, 0x7f,       // BIT 7, A
+        0x20, 0xfa,       // JR NZ, -6
+        0x18, 0xee,       // JR loop
+        0x18, 0xfe,       // done: JR done
+    ];
+
+    fn serial_rom(text: &[u8]) -> Vec<u8> {
+        let mut message = text.to_vec();
+        message.push(0);
+        test_rom(&[(0x0150, &PRINT), (0x0200, &message)])
+    }
+
+    #[test]
+    fn test_serial_output() {
+        let mut gameboy = Gameboy::new(&serial_rom(b"Hello\n")).unwrap();
+        for _ in 0..5 {
+            gameboy.run_frame();
+        }
+        assert_eq!(gameboy.cpu.bus.serial_output, b"Hello\n");
+    }
+
+    #[test]
+    fn test_blargg_serial_result() {
+        let (outcome, _) = run(&serial_rom(b"cpu_instrs\n\nPassed\n"), 10).unwrap();
+        assert_eq!(outcome, Outcome::Passed);
+
+        let (outcome, _) = run(&serial_rom(b"Failed #2\n"), 10).unwrap();
+        assert_eq!(outcome, Outcome::Failed("Failed #2".to_string()));
+
+        let (outcome, frames) = run(&serial_rom(b"still going"), 10).unwrap();
+        assert_eq!((outcome, frames), (Outcome::Timeout, 10));
+    }
+}
@@ -1,36 +1,73 @@
+use crate::state::{State, StateError, StateReader, StateWriter};
+
 pub struct Timer {
-    pub divider_counter: u8, // DIV
-    divider_cycle: u8,
-    pub timer_counter: u8, // TIMA
-    timer_cycle: usize,
-    pub timer_modulo: u8, // TMA
-    pub tac_enable: bool, // TAC - enable
-    pub tac_clock: usize, // TAC - clock select
+    pub internal_counter: u16, // Increments every T-cycle. DIV is the upper byte
+    pub timer_counter: u8,     // TIMA
+    pub timer_modulo: u8,      // TMA
+    pub tac_enable: bool,      // TAC - enable
+    pub tac_clock: usize,      // TAC - clock select
+    // TIMA reads 0 for 4 T-cycles after overflowing before TMA is loaded and the interrupt fires
+    pub tima_overflow_pending: bool,
+    pub tima_overflow_cycles: u8,
+}
+
+impl State for Timer {
+    fn save(&self, w: &mut StateWriter) {
+        self.internal_counter.save(w);
+        self.timer_counter.save(w);
+        self.timer_modulo.save(w);
+        self.tac_enable.save(w);
+        self.tac_clock.save(w);
+        self.tima_overflow_pending.save(w);
+        self.tima_overflow_cycles.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.internal_counter.load(r)?;
+        self.timer_counter.load(r)?;
+        self.timer_modulo.load(r)?;
+        self.tac_enable.load(r)?;
+        self.tac_clock.load(r)?;
+        self.tima_overflow_pending.load(r)?;
+        self.tima_overflow_cycles.load(r)?;
+        Ok(())
+    }
 }
 
 impl Timer {
-    const TIMER_CYCLES: [usize; 4] = [256, 4, 16, 64];
+    // Bit of internal_counter watched by each TAC clock select.
+    // 4096 Hz, 262144 Hz, 65536 Hz, 16384 Hz
+    const TIMER_BITS: [u16; 4] = [9, 3, 5, 7];
 
     pub fn new() -> Self {
         Self {
-            divider_counter: 0,
-            divider_cycle: 0,
+            internal_counter: 0,
             timer_counter: 0,
-            timer_cycle: 0,
             timer_modulo: 0,
             tac_enable: false,
             tac_clock: 0,
+            tima_overflow_pending: false,
+            tima_overflow_cycles: 0,
         }
     }
 
     // FF04 DIV
+    pub fn div_read(&self) -> u8 {
+        (self.internal_counter >> 8) as u8
+    }
+
+    // Any write resets the whole counter. If the watched bit was set this is a falling
+    // edge and TIMA increments
     pub fn div_write(&mut self) {
-        self.divider_counter = 0;
+        let signal = self.timer_signal();
+        self.internal_counter = 0;
+        self.falling_edge(signal);
     }
 
-    // FF05 TIMA
+    // FF05 TIMA. Writing during the overflow delay cancels the TMA reload and the interrupt
     pub fn tima_write(&mut self, val: u8) {
         self.timer_counter = val;
+        self.tima_overflow_pending = false;
     }
 
     // FF06 TMA
@@ -38,10 +75,13 @@ impl Timer {
         self.timer_modulo = val;
     }
 
-    // FF07 TAC
+    // FF07 TAC. Disabling the timer or switching clocks can also drop the signal and
+    // increment TIMA
     pub fn tac_write(&mut self, val: u8) {
+        let signal = self.timer_signal();
         self.tac_enable = val & 0b0000_0100 > 0;
         self.tac_clock = (val & 0b0000_0011) as usize;
+        self.falling_edge(signal);
     }
 
     pub fn tac_read(&self) -> u8 {
@@ -49,36 +89,85 @@ impl Timer {
         tac_enable + self.tac_clock as u8
     }
 
-    fn divider_tick(&mut self, cycles: u8) {
-        self.divider_cycle += cycles;
-        if self.divider_cycle as usize >= Timer::TIMER_CYCLES[3] {
-            self.divider_counter = self.divider_counter.wrapping_add(1);
-            self.divider_cycle -= Timer::TIMER_CYCLES[3] as u8;
+    // DIV bit 4, or bit 5 at double speed so it stays at 512 Hz. The APU frame sequencer
+    // steps on its falling edge
+    pub fn apu_signal(&self, double_speed: bool) -> bool {
+        self.internal_counter & (1 << (12 + double_speed as u32)) > 0
+    }
+
+    // TIMA is clocked by the selected counter bit AND'd with the enable bit
+    fn timer_signal(&self) -> bool {
+        self.tac_enable && self.internal_counter & (1 << Timer::TIMER_BITS[self.tac_clock]) > 0
+    }
+
+    fn falling_edge(&mut self, prior_signal: bool) {
+        if prior_signal && !self.timer_signal() {
+            self.tima_increment();
         }
     }
 
-    fn timer_tick(&mut self, cycles: u8) -> bool {
-        if self.tac_enable {
-            self.timer_cycle += cycles as usize;
+    // On overflow TIMA holds 0 and the reload is left to tick
+    fn tima_increment(&mut self) {
+        let (val, carry) = self.timer_counter.overflowing_add(1);
+        self.timer_counter = val;
+        if carry {
+            self.tima_overflow_pending = true;
+            self.tima_overflow_cycles = 4;
         }
-        while self.tac_enable && self.timer_cycle >= Timer::TIMER_CYCLES[self.tac_clock] {
-            let (val, carry) = self.timer_counter.overflowing_add(1);
-            self.timer_cycle -= Timer::TIMER_CYCLES[self.tac_clock];
-            if carry {
-                self.timer_counter = self.timer_modulo;
-                return true;
-            } else {
-                self.timer_counter = val;
+    }
+
+    // Cycles are M-cycles. Returns true if a timer interrupt
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles {
+            // Finish an overflow from a previous cycle
+            if self.tima_overflow_pending {
+                self.tima_overflow_cycles = self.tima_overflow_cycles.saturating_sub(4);
+                if self.tima_overflow_cycles == 0 {
+                    self.tima_overflow_pending = false;
+                    self.timer_counter = self.timer_modulo;
+                    interrupt = true;
+                }
             }
+
+            let signal = self.timer_signal();
+            self.internal_counter = self.internal_counter.wrapping_add(4);
+            self.falling_edge(signal);
+        }
+        interrupt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timer at 262144 Hz, one TIMA step every 4 M-cycles, ticked until TIMA overflows
+    fn overflowed_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.tac_write(0b101);
+        timer.tma_write(0x42);
+        timer.timer_counter = 0xff;
+        while !timer.tima_overflow_pending {
+            assert!(!timer.tick(1));
         }
-        false
+        timer
     }
 
-    pub fn tick(&mut self, cycles: u8) -> bool {
-        // Divider
-        self.divider_tick(cycles);
+    #[test]
+    fn test_overflow_reloads_after_delay() {
+        let mut timer = overflowed_timer();
+        // TIMA holds 0 for the cycle after overflowing
+        assert_eq!(timer.timer_counter, 0);
+        assert!(timer.tick(1));
+        assert_eq!(timer.timer_counter, 0x42);
+    }
 
-        // Timer Counter. Returns true if a timer interrupt
-        self.timer_tick(cycles)
+    #[test]
+    fn test_tima_write_cancels_reload() {
+        let mut timer = overflowed_timer();
+        timer.tima_write(0x10);
+        assert!(!timer.tick(1));
+        assert_eq!(timer.timer_counter, 0x10);
     }
 }
@@ -0,0 +1,90 @@
+// blargg's test ROMs, one test each, run through testrom::run_blargg_test. The ROMs aren't
+// checked in: put them under roms/blargg/ as in blargg's gb-test-roms repo and run with
+// --features integration_tests. A missing ROM fails its test, since the feature is opt in
+#![cfg(feature = "integration_tests")]
+
+use gb_emulator::testrom::{self, Outcome};
+
+use std::path::Path;
+
+// A minute of emulated time. The slowest ROMs here finish in under half that
+const MAX_CYCLES: u64 = 60 * 1_048_576;
+
+fn blargg(rom: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("roms/blargg")
+        .join(rom);
+    assert!(path.exists(), "No ROM at {}", path.display());
+    let result = testrom::run_blargg_test(&path, MAX_CYCLES);
+    assert_eq!(result.outcome, Outcome::Passed, "{rom}");
+}
+
+#[test]
+fn cpu_instrs_01_special() {
+    blargg("cpu_instrs/individual/01-special.gb");
+}
+
+#[test]
+fn cpu_instrs_02_interrupts() {
+    blargg("cpu_instrs/individual/02-interrupts.gb");
+}
+
+#[test]
+fn cpu_instrs_03_op_sp_hl() {
+    blargg("cpu_instrs/individual/03-op sp,hl.gb");
+}
+
+#[test]
+fn cpu_instrs_04_op_r_imm() {
+    blargg("cpu_instrs/individual/04-op r,imm.gb");
+}
+
+#[test]
+fn cpu_instrs_05_op_rp() {
+    blargg("cpu_instrs/individual/05-op rp.gb");
+}
+
+#[test]
+fn cpu_instrs_06_ld_r_r() {
+    blargg("cpu_instrs/individual/06-ld r,r.gb");
+}
+
+#[test]
+fn cpu_instrs_07_jr_jp_call_ret_rst() {
+    blargg("cpu_instrs/individual/07-jr,jp,call,ret,rst.gb");
+}
+
+#[test]
+fn cpu_instrs_08_misc_instrs() {
+    blargg("cpu_instrs/individual/08-misc instrs.gb");
+}
+
+#[test]
+fn cpu_instrs_09_op_r_r() {
+    blargg("cpu_instrs/individual/09-op r,r.gb");
+}
+
+#[test]
+fn cpu_instrs_10_bit_ops() {
+    blargg("cpu_instrs/individual/10-bit ops.gb");
+}
+
+#[test]
+fn cpu_instrs_11_op_a_hl() {
+    blargg("cpu_instrs/individual/11-op a,(hl).gb");
+}
+
+#[test]
+fn instr_timing() {
+    blargg("instr_timing/instr_timing.gb");
+}
+
+#[test]
+fn mem_timing() {
+    blargg("mem_timing/mem_timing.gb");
+}
+
+#[test]
+fn halt_bug() {
+    blargg("halt_bug.gb");
+}
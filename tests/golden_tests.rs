@@ -0,0 +1,85 @@
+// Frames compared against the hashes checked in under tests/goldens. Run with
+// GB_UPDATE_GOLDENS=1 to write new ones after a deliberate rendering change
+use gb_emulator::capture;
+use gb_emulator::joypad::JoypadAction;
+use gb_emulator::ppu::Ppu;
+use gb_emulator::render::{self, Frame};
+use gb_emulator::Gameboy;
+
+use std::fs;
+use std::path::Path;
+
+fn assert_golden(frame: &Frame, name: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/goldens");
+    assert!(
+        capture::check_golden(frame, &dir, name).unwrap(),
+        "{name} doesn't match its golden hash"
+    );
+}
+
+// Background, window and sprites all poked straight into the PPU, no ROM needed
+#[test]
+fn test_hand_built_scene() {
+    let mut ppu = Ppu::new(false);
+    // Tiles 0-3 are solid colours 0-3, tile 4 a checkerboard of 1 and 2
+    for color in 0..4u16 {
+        for row in 0..8 {
+            ppu.vram
+                .write(0, 0x8000 + 16 * color + 2 * row, (color & 1) as u8 * 0xff);
+            ppu.vram.write(
+                0,
+                0x8000 + 16 * color + 2 * row + 1,
+                (color >> 1) as u8 * 0xff,
+            );
+        }
+    }
+    for row in 0..8 {
+        let pattern = if row % 2 == 0 { 0xaa } else { 0x55 };
+        ppu.vram.write(0, 0x8040 + 2 * row, pattern);
+        ppu.vram.write(0, 0x8040 + 2 * row + 1, !pattern);
+    }
+    // Background map at 0x9800 is bands of colours 0-3 with a checkerboard column, the window
+    // map at 0x9C00 is all colour 3
+    for i in 0..0x400u16 {
+        let tile = if i % 32 == 10 { 4 } else { i / 32 % 4 };
+        ppu.vram.write(0, 0x9800 + i, tile as u8);
+        ppu.vram.write(0, 0x9c00 + i, 3);
+    }
+    // Sprites in a diagonal. Every other one is solid colour 3 on OBP1 behind the background
+    for i in 0..20usize {
+        ppu.oam[4 * i] = 16 + 7 * i as u8;
+        ppu.oam[4 * i + 1] = 8 + 8 * i as u8;
+        ppu.oam[4 * i + 2] = if i % 2 == 0 { 4 } else { 3 };
+        ppu.oam[4 * i + 3] = if i % 2 == 0 { 0x00 } else { 0x90 };
+    }
+    ppu.scx = 3;
+    ppu.scy = 5;
+    ppu.wx = 7 + 120;
+    ppu.wy = 100;
+    ppu.bg_palette = 0xe4;
+    ppu.obp0 = 0xd2;
+    ppu.obp1 = 0x1b;
+    // LCD, window at 0x9C00, window, tile data at 0x8000, sprites and background on
+    ppu.write_to_ctrl(0xf3);
+    assert_golden(&render::render_frame(&mut ppu), "hand_built_scene");
+}
+
+// Tetris on its copyright screen, then in the menus after pressing Start
+#[test]
+fn test_tetris() {
+    let rom = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/roms/tetris.gb")).unwrap();
+    let mut gameboy = Gameboy::new(&rom).unwrap();
+    for frame in 1..=500 {
+        match frame {
+            301 => gameboy.press_button(JoypadAction::Start),
+            311 => gameboy.release_button(JoypadAction::Start),
+            _ => {}
+        }
+        gameboy.run_frame();
+        match frame {
+            100 => assert_golden(&gameboy.cpu.bus.last_frame, "tetris_100"),
+            500 => assert_golden(&gameboy.cpu.bus.last_frame, "tetris_500"),
+            _ => {}
+        }
+    }
+}
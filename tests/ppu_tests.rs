@@ -0,0 +1,319 @@
+// Scanline rendering from hand built VRAM and OAM, without running any PPU timing
+use gb_emulator::ppu::Ppu;
+use gb_emulator::render::{self, Frame};
+
+// LCD and BG on, tile data at 0x8000, 8x8 objects on
+const LCDC: u8 = 0x93;
+// Palette index n maps to shade n
+const IDENTITY: u8 = 0xe4;
+
+// BG tiles come from tile 0 (the tile map is all zeroes), objects from tile 1
+const BG_TILE: usize = 0;
+const OBJ_TILE: usize = 1;
+
+// DMG PPU with vram copied in from 0x8000 and oam from 0xFE00. palette is used for the
+// background and both object palettes
+fn make_ppu_with_tiles(vram: &[u8], oam: &[u8], lcdc: u8, palette: u8) -> Ppu {
+    let mut ppu = Ppu::new(false);
+    for (i, &byte) in vram.iter().enumerate() {
+        ppu.vram.write(0, 0x8000 + i as u16, byte);
+    }
+    ppu.oam[..oam.len()].copy_from_slice(oam);
+    ppu.write_to_ctrl(lcdc);
+    ppu.bg_palette = palette;
+    ppu.obp0 = palette;
+    ppu.obp1 = palette;
+    ppu
+}
+
+// 2bpp tile of one colour
+fn solid_tile(color: u8) -> [u8; 16] {
+    rows_tile([color; 8])
+}
+
+// 2bpp tile with each row a single colour, top to bottom
+fn rows_tile(colors: [u8; 8]) -> [u8; 16] {
+    let mut tile = [0; 16];
+    for (row, color) in colors.into_iter().enumerate() {
+        tile[2 * row] = if color & 1 != 0 { 0xff } else { 0x00 };
+        tile[2 * row + 1] = if color & 2 != 0 { 0xff } else { 0x00 };
+    }
+    tile
+}
+
+// Put tile data for tile index at its place in vram, growing vram to fit
+fn set_tile(vram: &mut Vec<u8>, index: usize, tile: [u8; 16]) {
+    if vram.len() < 16 * (index + 1) {
+        vram.resize(16 * (index + 1), 0);
+    }
+    vram[16 * index..16 * (index + 1)].copy_from_slice(&tile);
+}
+
+// Tile data with a solid background tile and a solid object tile
+fn tiles(bg: u8, obj: u8) -> Vec<u8> {
+    let mut vram = vec![0; 16 * 2];
+    vram[16 * BG_TILE..16 * BG_TILE + 16].copy_from_slice(&solid_tile(bg));
+    vram[16 * OBJ_TILE..16 * OBJ_TILE + 16].copy_from_slice(&solid_tile(obj));
+    vram
+}
+
+// OAM entry in screen coordinates, Y + 16 and X + 8 as stored
+fn oam_entry(x: i32, y: i32, tile: u8, attributes: u8) -> [u8; 4] {
+    [(y + 16) as u8, (x + 8) as u8, tile, attributes]
+}
+
+fn sprite(x: i32, y: i32, attributes: u8) -> [u8; 4] {
+    oam_entry(x, y, OBJ_TILE as u8, attributes)
+}
+
+fn line(ppu: &mut Ppu, y: usize) -> Frame {
+    let mut frame = Frame::new();
+    render::render_scanline(ppu, y, &mut frame);
+    frame
+}
+
+// Shade of each pixel of line y, looked up from the RGB in Frame's data
+fn shades(ppu: &Ppu, frame: &Frame, y: usize) -> Vec<usize> {
+    let row = &frame.as_rgb_bytes()[3 * Frame::WIDTH * y..3 * Frame::WIDTH * (y + 1)];
+    row.chunks(3)
+        .map(|rgb| {
+            ppu.palette
+                .0
+                .iter()
+                .position(|&shade| [shade.0, shade.1, shade.2] == rgb)
+                .expect("pixel isn't one of the palette's shades")
+        })
+        .collect()
+}
+
+// Shade of pixel x down the first lines of the screen
+fn column(ppu: &mut Ppu, x: usize, lines: usize) -> Vec<usize> {
+    let mut frame = Frame::new();
+    (0..lines)
+        .map(|y| {
+            render::render_scanline(ppu, y, &mut frame);
+            shades(ppu, &frame, y)[x]
+        })
+        .collect()
+}
+
+#[test]
+fn test_solid_background() {
+    for color in 0..4 {
+        let mut ppu = make_ppu_with_tiles(&tiles(color, 0), &[], LCDC, IDENTITY);
+        let frame = line(&mut ppu, 0);
+        assert_eq!(shades(&ppu, &frame, 0), vec![color as usize; Frame::WIDTH]);
+    }
+    // The palette picks the shade for each index. 0x1B reverses them
+    let mut ppu = make_ppu_with_tiles(&tiles(1, 0), &[], LCDC, 0x1b);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(shades(&ppu, &frame, 0), vec![2; Frame::WIDTH]);
+}
+
+#[test]
+fn test_sprite_at_x0_hidden() {
+    let mut ppu = make_ppu_with_tiles(&tiles(0, 3), &sprite(-8, 0, 0), LCDC, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(shades(&ppu, &frame, 0), vec![0; Frame::WIDTH]);
+}
+
+#[test]
+fn test_sprite_over_background() {
+    let mut ppu = make_ppu_with_tiles(&tiles(1, 3), &sprite(8, 0, 0), LCDC, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    let shades = shades(&ppu, &frame, 0);
+    assert_eq!(shades[..8], [1; 8]);
+    assert_eq!(shades[8..16], [3; 8]);
+    assert_eq!(shades[16..], [1; Frame::WIDTH - 16]);
+}
+
+#[test]
+fn test_bg_priority_sprite_behind_colors_1_to_3() {
+    for bg in 0..4 {
+        let mut ppu = make_ppu_with_tiles(&tiles(bg, 3), &sprite(8, 0, 0x80), LCDC, IDENTITY);
+        let frame = line(&mut ppu, 0);
+        let expected = if bg == 0 { 3 } else { bg as usize };
+        assert_eq!(
+            shades(&ppu, &frame, 0)[8..16],
+            [expected; 8],
+            "BG colour {bg}"
+        );
+    }
+}
+
+#[test]
+fn test_equal_x_overlap() {
+    let mut vram = tiles(0, 0);
+    set_tile(&mut vram, 2, solid_tile(1));
+    set_tile(&mut vram, 3, solid_tile(3));
+    // Same X, so the lower OAM index is on top whichever tile it has
+    for (first, second, expected) in [(2, 3, 1), (3, 2, 3)] {
+        let oam = [oam_entry(8, 0, first, 0), oam_entry(8, 0, second, 0)].concat();
+        let mut ppu = make_ppu_with_tiles(&vram, &oam, LCDC, IDENTITY);
+        let frame = line(&mut ppu, 0);
+        assert_eq!(shades(&ppu, &frame, 0)[8..16], [expected; 8]);
+    }
+}
+
+#[test]
+fn test_y_flipped_tall_sprite() {
+    let mut vram = tiles(0, 0);
+    // Top tile has a colour 3 first row, the bottom one is all colour 2
+    set_tile(&mut vram, 2, rows_tile([3, 1, 1, 1, 1, 1, 1, 1]));
+    set_tile(&mut vram, 3, solid_tile(2));
+    // Flipped over all 16 rows: the bottom tile comes first and the top tile's first row last
+    let oam = oam_entry(8, 0, 2, 0x40);
+    let mut ppu = make_ppu_with_tiles(&vram, &oam, LCDC | 0x04, IDENTITY);
+    assert_eq!(
+        column(&mut ppu, 8, 16),
+        [2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 3]
+    );
+}
+
+#[test]
+fn test_compositing() {
+    // OBP0 maps every index to black, so a transparent colour 0 drawn through the palette
+    // would show as a black box
+    for bg in [0, 2] {
+        for obj in [0, 3] {
+            for priority in [false, true] {
+                let attributes = if priority { 0x80 } else { 0x00 };
+                let mut ppu =
+                    make_ppu_with_tiles(&tiles(bg, obj), &sprite(8, 0, attributes), LCDC, IDENTITY);
+                ppu.obp0 = 0xff;
+                let frame = line(&mut ppu, 0);
+                let expected = if obj == 0 || (priority && bg != 0) {
+                    bg as usize
+                } else {
+                    3
+                };
+                assert_eq!(
+                    shades(&ppu, &frame, 0)[8..16],
+                    [expected; 8],
+                    "BG colour {bg}, OBJ colour {obj}, BG priority {priority}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_bg_disabled() {
+    // With LCDC bit 0 clear the DMG background is colour 0, so sprites show even with
+    // BG priority set
+    let mut ppu = make_ppu_with_tiles(&tiles(2, 3), &sprite(8, 0, 0x80), LCDC & !0x01, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    let line_shades = shades(&ppu, &frame, 0);
+    assert_eq!(line_shades[..8], [0; 8]);
+    assert_eq!(line_shades[8..16], [3; 8]);
+
+    // And with objects off only the background is left
+    let mut ppu = make_ppu_with_tiles(&tiles(2, 3), &sprite(8, 0, 0), LCDC & !0x02, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(shades(&ppu, &frame, 0), vec![2; Frame::WIDTH]);
+}
+
+#[test]
+fn test_cgb_bg_attributes() {
+    let mut ppu = Ppu::new(true);
+    ppu.write_to_ctrl(LCDC);
+    // Tile 0 is colour 1 in bank 0 and colour 3 in bank 1
+    for (i, byte) in solid_tile(1).into_iter().enumerate() {
+        ppu.vram.write(0, 0x8000 + i as u16, byte);
+    }
+    for (i, byte) in solid_tile(3).into_iter().enumerate() {
+        ppu.vram.write(1, 0x8000 + i as u16, byte);
+    }
+    // Second map entry takes its tile from bank 1 with palette 2
+    ppu.vram.write(1, 0x9801, 0x08 | 0x02);
+    // Palette 2 colour 3 is red and palette 0 colour 1 blue
+    ppu.bg_palettes.spec_write(0x80 | (8 * 2 + 2 * 3));
+    for byte in 0x001fu16.to_le_bytes() {
+        ppu.bg_palettes.data_write(byte, false);
+    }
+    ppu.bg_palettes.spec_write(0x80 | 2);
+    for byte in 0x7c00u16.to_le_bytes() {
+        ppu.bg_palettes.data_write(byte, false);
+    }
+
+    let frame = line(&mut ppu, 0);
+    assert_eq!(frame.pixel(0, 0), (0, 0, 255));
+    assert_eq!(frame.pixel(8, 0), (255, 0, 0));
+    assert_eq!(frame.pixel(16, 0), (0, 0, 255));
+}
+
+#[test]
+fn test_dmg_smaller_x_wins_cgb_oam_order() {
+    let mut vram = tiles(0, 0);
+    set_tile(&mut vram, 2, solid_tile(1));
+    set_tile(&mut vram, 3, solid_tile(3));
+    // OAM 0 is further right but first in OAM. They overlap on pixels 12-15
+    let oam = [oam_entry(12, 0, 3, 0), oam_entry(8, 0, 2, 0)].concat();
+
+    let mut ppu = make_ppu_with_tiles(&vram, &oam, LCDC, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(
+        shades(&ppu, &frame, 0)[8..20],
+        [1, 1, 1, 1, 1, 1, 1, 1, 3, 3, 3, 3]
+    );
+
+    // CGB goes by OAM order alone. Palette RAM starts out white, so make colour 3 black
+    let mut ppu = Ppu::new(true);
+    for (i, &byte) in vram.iter().enumerate() {
+        ppu.vram.write(0, 0x8000 + i as u16, byte);
+    }
+    ppu.oam[..oam.len()].copy_from_slice(&oam);
+    ppu.write_to_ctrl(LCDC);
+    ppu.obj_palettes.data[6..8].copy_from_slice(&[0, 0]);
+    let frame = line(&mut ppu, 0);
+    let dark: Vec<_> = (8..20).map(|x| frame.pixel(x, 0) == (0, 0, 0)).collect();
+    assert_eq!(dark, [[false; 4], [true; 4], [true; 4]].concat());
+}
+
+#[test]
+fn test_tall_sprite_halves() {
+    let mut vram = tiles(0, 0);
+    set_tile(&mut vram, 4, solid_tile(1));
+    set_tile(&mut vram, 5, solid_tile(2));
+    // (Y flip, line, shade). Bit 0 of the tile index is ignored, so tile 5 still starts at 4
+    for (flip, y, expected) in [(false, 3, 1), (false, 11, 2), (true, 3, 2), (true, 11, 1)] {
+        let attributes = if flip { 0x40 } else { 0x00 };
+        let oam = oam_entry(8, 0, 5, attributes);
+        let mut ppu = make_ppu_with_tiles(&vram, &oam, LCDC | 0x04, IDENTITY);
+        let frame = line(&mut ppu, y);
+        assert_eq!(
+            shades(&ppu, &frame, y)[8..16],
+            [expected; 8],
+            "Y flip {flip}, line {y}"
+        );
+    }
+}
+
+// OAM positions here are raw, as stored. Screen X is OAM X - 8 and screen Y is OAM Y - 16
+#[test]
+fn test_sprites_at_edges() {
+    // Columns 0-3 colour 1, 4-7 colour 3
+    let mut vram = tiles(0, 0);
+    set_tile(&mut vram, 2, [0xff, 0x0f].repeat(8).try_into().unwrap());
+
+    // X = 0 is entirely off screen but still uses up one of the 10 slots
+    let mut oam: Vec<u8> = [0x10, 0x00, 2, 0].repeat(10);
+    oam.extend([0x10, 0x10, 2, 0]);
+    let mut ppu = make_ppu_with_tiles(&vram, &oam, LCDC, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(shades(&ppu, &frame, 0), vec![0; Frame::WIDTH]);
+
+    // X = 4 shows only the right half
+    let mut ppu = make_ppu_with_tiles(&vram, &[0x10, 0x04, 2, 0], LCDC, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(shades(&ppu, &frame, 0)[..6], [3, 3, 3, 3, 0, 0]);
+
+    // Y = 10 hangs 6 rows off the top, leaving its last two rows on lines 0 and 1
+    let mut ppu = make_ppu_with_tiles(&vram, &[0x0a, 0x08, 2, 0], LCDC, IDENTITY);
+    assert_eq!(column(&mut ppu, 0, 4), [1, 1, 0, 0]);
+
+    // X = 167 leaves just its first column on the last pixel
+    let mut ppu = make_ppu_with_tiles(&vram, &[0x10, 167, 2, 0], LCDC, IDENTITY);
+    let frame = line(&mut ppu, 0);
+    assert_eq!(shades(&ppu, &frame, 0)[156..], [0, 0, 0, 1]);
+}